@@ -1,7 +1,8 @@
-use cosdata::models::lru_cache::{EvictStrategy, LRUCache, ProbEviction};
+use cosdata::models::lru_cache::{EvictStrategy, LRUCache, ProbEviction, ShardedLRUCache};
 use criterion::{criterion_group, criterion_main, Criterion};
 use half::f16;
 use rand::Rng;
+use std::thread;
 
 fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("lru cache");
@@ -29,6 +30,46 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 
     group.finish();
+
+    let mut group = c.benchmark_group("lru cache concurrent inserts");
+
+    let unsharded: LRUCache<u64, u64> = LRUCache::with_prob_eviction(100_000, 0.03125);
+    let sharded: ShardedLRUCache<u64, u64> =
+        ShardedLRUCache::with_prob_eviction(100_000, 0.03125, 16);
+
+    group.bench_function("unsharded", |b| {
+        b.iter(|| {
+            thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        let mut rng = rand::thread_rng();
+                        for _ in 0..1000 {
+                            let x = rng.gen_range(u64::MIN..u64::MAX);
+                            unsharded.insert(x, x);
+                        }
+                    });
+                }
+            })
+        })
+    });
+
+    group.bench_function("sharded", |b| {
+        b.iter(|| {
+            thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        let mut rng = rand::thread_rng();
+                        for _ in 0..1000 {
+                            let x = rng.gen_range(u64::MIN..u64::MAX);
+                            sharded.insert(x, x);
+                        }
+                    });
+                }
+            })
+        })
+    });
+
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::{atomic::Ordering, Arc};
 
 use crate::api_service::run_upload_sparse_vectors_in_transaction;
@@ -7,7 +8,7 @@ use crate::indexes::inverted_index_types::SparsePair;
 use crate::models::common::WaCustomError;
 use crate::models::rpc::DenseVector;
 use crate::models::types::MetricResult;
-use crate::storage::sparse_ann_query_basic::SparseAnnResult;
+use crate::storage::sparse_ann_query_basic::{sort_by_score_with_ties, SparseAnnResult};
 use crate::vector_store::get_sparse_embedding_by_id;
 use crate::{models::types::SparseVector, storage::sparse_ann_query_basic::SparseAnnQueryBasic};
 
@@ -221,7 +222,7 @@ pub(crate) async fn find_similar_sparse_vectors(
             .collect(),
     };
 
-    let intermediate_results = SparseAnnQueryBasic::new(sparse_vec)
+    let intermediate_results = SparseAnnQueryBasic::new(sparse_vec, &HashMap::new())
         .sequential_search_tshashmap(
             &inverted_index.root,
             inverted_index.root.root.quantization_bits,
@@ -237,6 +238,7 @@ pub(crate) async fn find_similar_sparse_vectors(
         intermediate_results,
         &find_similar_vectors.values,
         find_similar_vectors.top_k,
+        ctx.config.sparse_score_tie_epsilon,
     )
     .map_err(|e| VectorsError::FailedToFindSimilarVectors(e.to_string()))?;
 
@@ -248,6 +250,7 @@ fn finalize_sparse_ann_results(
     intermediate_results: Vec<SparseAnnResult>,
     query: &[SparsePair],
     k: Option<usize>,
+    tie_epsilon: f32,
 ) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
     let mut results = Vec::with_capacity(k.unwrap_or(intermediate_results.len()));
 
@@ -263,7 +266,9 @@ fn finalize_sparse_ann_results(
         results.push((id, MetricResult::DotProductDistance(DotProductDistance(dp))));
     }
 
-    results.sort_unstable_by(|(_, a), (_, b)| b.get_value().total_cmp(&a.get_value()));
+    sort_by_score_with_ties(&mut results, tie_epsilon, |(id, result)| {
+        (id.0, result.get_value())
+    });
     if let Some(k) = k {
         results.truncate(k);
     }
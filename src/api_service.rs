@@ -837,6 +837,12 @@ pub fn run_upload(
     dense_index.vec_raw_manager.flush_all()?;
     dense_index.index_manager.flush_all()?;
     dense_index.level_0_index_manager.flush_all()?;
+    dense_index
+        .cache
+        .write_file_checksum(current_version, false)?;
+    dense_index
+        .cache
+        .write_file_checksum(current_version, true)?;
 
     Ok(())
 }
@@ -848,6 +854,9 @@ pub async fn ann_vector_query(
     k: Option<usize>,
 ) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
     let dense_index = dense_index.clone();
+    if dense_index.cache.is_empty()? {
+        return Ok(Vec::new());
+    }
     let vec_hash = VectorId(u64::MAX - 1);
     let vector_list = dense_index.quantization_metric.quantize(
         &query,
@@ -881,6 +890,9 @@ pub async fn batch_ann_vector_query(
     queries: Vec<Vec<f32>>,
     k: Option<usize>,
 ) -> Result<Vec<Vec<(VectorId, MetricResult)>>, WaCustomError> {
+    if dense_index.cache.is_empty()? {
+        return Ok(vec![Vec::new(); queries.len()]);
+    }
     queries
         .into_par_iter()
         .map(|query| {
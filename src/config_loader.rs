@@ -18,6 +18,7 @@ pub struct Config {
     pub num_regions_to_load_on_restart: usize,
     pub inverted_index_data_file_parts: u8,
     pub sparse_raw_values_reranking_factor: usize,
+    pub sparse_score_tie_epsilon: f32,
 }
 
 #[derive(Deserialize, Clone)]
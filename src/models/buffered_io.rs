@@ -1,12 +1,13 @@
 use dashmap::DashMap;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::hash::Hash;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Weak};
 
 use super::lru_cache::LRUCache;
 
@@ -15,6 +16,21 @@ pub enum BufIoError {
     Io(io::Error),
     Locking,
     InvalidCursor(u64),
+    /// Structurally invalid data, e.g. a chunk link cycle, found at
+    /// `offset` of the file being read
+    Corrupt {
+        context: &'static str,
+        offset: u64,
+    },
+    /// The data file `idx` of a sharded index doesn't exist
+    MissingShard {
+        idx: u8,
+    },
+    /// A fixed size read at `offset` would go past the end of the file,
+    /// e.g. after following a corrupt link
+    UnexpectedEof {
+        offset: u64,
+    },
 }
 
 impl From<io::Error> for BufIoError {
@@ -29,6 +45,13 @@ impl fmt::Display for BufIoError {
             Self::Io(error) => write!(f, "IO error: {}", error),
             Self::Locking => f.write_str("Locking error"),
             Self::InvalidCursor(cursor) => write!(f, "Invalid cursor `{}`", cursor),
+            Self::Corrupt { context, offset } => {
+                write!(f, "Corrupt data: {} at offset {}", context, offset)
+            }
+            Self::MissingShard { idx } => write!(f, "Missing data file of shard {}", idx),
+            Self::UnexpectedEof { offset } => {
+                write!(f, "Unexpected end of file reading at offset {}", offset)
+            }
         }
     }
 }
@@ -78,11 +101,16 @@ impl Drop for BufferRegion {
 
 struct Cursor {
     position: u64,
+    // Closed and waiting in a thread's pool to be reused
+    recycled: bool,
 }
 
 impl Cursor {
     fn new() -> Self {
-        Cursor { position: 0 }
+        Cursor {
+            position: 0,
+            recycled: false,
+        }
     }
 }
 
@@ -125,6 +153,31 @@ impl<K: Hash + Eq + Clone> BufferManagerFactory<K> {
             .map(|bufman_ref| bufman_ref.value().clone())
     }
 
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Whether the file backing `key` has been opened through `get`
+    pub fn is_open(&self, key: &K) -> bool {
+        self.bufmans.contains_key(key)
+    }
+
+    /// Returns the path of the file backing `key`, without opening or
+    /// creating it
+    pub fn file_path(&self, key: &K) -> PathBuf {
+        (self.path_function)(&self.root_path, key)
+    }
+
+    /// Drops the buffer manager of `key`, discarding its unflushed
+    /// writes, and deletes the file backing it if there is one
+    pub fn remove(&self, key: &K) -> Result<(), BufIoError> {
+        self.bufmans.remove(key);
+        match fs::remove_file(self.file_path(key)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
     pub fn flush_all(&self) -> Result<(), BufIoError> {
         for bufman in self.bufmans.iter() {
             bufman.flush()?;
@@ -133,13 +186,61 @@ impl<K: Hash + Eq + Clone> BufferManagerFactory<K> {
     }
 }
 
+// Maximum number of closed cursors each thread keeps for reuse, per
+// buffer manager
+const MAX_RECYCLED_CURSORS: usize = 16;
+
+// Source of `BufferManager::id`
+static NEXT_BUFMAN_ID: AtomicU64 = AtomicU64::new(0);
+
+// Closed cursors of a buffer manager that a thread keeps for reuse, see
+// `BufferManager::open_cursor`. The cursors are dropped for good along
+// with the thread.
+struct CursorPool {
+    cursors: Weak<RwLock<HashMap<u64, Cursor>>>,
+    ids: Vec<u64>,
+}
+
+impl Drop for CursorPool {
+    fn drop(&mut self) {
+        let Some(cursors) = self.cursors.upgrade() else {
+            return;
+        };
+        let Ok(mut cursors) = cursors.write() else {
+            return;
+        };
+        for id in &self.ids {
+            cursors.remove(id);
+        }
+    }
+}
+
+thread_local! {
+    // Each thread's pools, by buffer manager id
+    static CURSOR_POOLS: RefCell<HashMap<u64, CursorPool>> = RefCell::new(HashMap::new());
+}
+
+/// Buffered, cursor based access to a single file.
+///
+/// All multi-byte values written and read through the `*_with_cursor`
+/// helpers use little-endian byte order regardless of the host, so
+/// index files can be shared between architectures.
 pub struct BufferManager {
     file: Arc<RwLock<File>>,
     regions: LRUCache<u64, Arc<BufferRegion>>,
-    cursors: RwLock<HashMap<u64, Cursor>>,
+    // Identifies the buffer manager's pool in each thread's
+    // `CURSOR_POOLS`
+    id: u64,
+    cursors: Arc<RwLock<HashMap<u64, Cursor>>>,
     next_cursor_id: AtomicU64,
     file_size: RwLock<u64>,
     buffer_size: usize,
+    seek_count: AtomicU64,
+    write_count: AtomicU64,
+    read_count: AtomicU64,
+    // Number of bytes read from the file at once when a region isn't
+    // buffered yet, 0 to read one region at a time
+    read_ahead: AtomicUsize,
 }
 
 impl BufferManager {
@@ -150,10 +251,15 @@ impl BufferManager {
         let mut this = Self {
             file: Arc::new(RwLock::new(file)),
             regions,
-            cursors: RwLock::new(HashMap::new()),
+            id: NEXT_BUFMAN_ID.fetch_add(1, Ordering::Relaxed),
+            cursors: Arc::new(RwLock::new(HashMap::new())),
             next_cursor_id: AtomicU64::new(0),
             file_size: RwLock::new(file_size),
             buffer_size,
+            seek_count: AtomicU64::new(0),
+            write_count: AtomicU64::new(0),
+            read_count: AtomicU64::new(0),
+            read_ahead: AtomicUsize::new(0),
         };
         this.regions.set_evict_hook(Some(|region| {
             if region.should_final_flush() {
@@ -164,12 +270,36 @@ impl BufferManager {
     }
 
     pub fn open_cursor(&self) -> Result<u64, BufIoError> {
-        let cursor_id = self.next_cursor_id.fetch_add(1, Ordering::SeqCst);
+        // Fails when the thread is exiting and its pools are gone
+        let recycled = CURSOR_POOLS
+            .try_with(|pools| {
+                pools
+                    .borrow_mut()
+                    .get_mut(&self.id)
+                    .and_then(|pool| pool.ids.pop())
+            })
+            .ok()
+            .flatten();
         let mut cursors = self.cursors.write().map_err(|_| BufIoError::Locking)?;
+        if let Some(cursor_id) = recycled {
+            // Positions are reset when a cursor is recycled
+            if let Some(cursor) = cursors.get_mut(&cursor_id) {
+                cursor.recycled = false;
+                return Ok(cursor_id);
+            }
+        }
+        let cursor_id = self.next_cursor_id.fetch_add(1, Ordering::SeqCst);
         cursors.insert(cursor_id, Cursor::new());
         Ok(cursor_id)
     }
 
+    /// Opens a cursor positioned at the end of the file.
+    pub fn append_cursor(&self) -> Result<u64, BufIoError> {
+        let cursor_id = self.open_cursor()?;
+        self.seek_with_cursor(cursor_id, self.file_size())?;
+        Ok(cursor_id)
+    }
+
     // @DOUBT: The caller will need to remember to call close_cursor,
     // other wise the cursors will keep accumulating. One way to
     // prevent that can be to implement Drop trait for the Cursor
@@ -177,56 +307,146 @@ impl BufferManager {
     // inside an Arc so that a reference to it can be shared with the
     // Cursor struct. Then in the Cursor::drop method, the cursor can
     // be removed from the hashmap.
+    //
+    // Each thread keeps a few of the cursors it closes in a pool of its
+    // own instead of dropping them, so that its next `open_cursor` calls
+    // can reuse them. The pool gives its cursors back when the thread
+    // exits.
+    //
+    // Closing a cursor that's already closed does nothing.
     pub fn close_cursor(&self, cursor_id: u64) -> Result<(), BufIoError> {
         let mut cursors = self.cursors.write().map_err(|_| BufIoError::Locking)?;
-        cursors.remove(&cursor_id);
+        let Some(cursor) = cursors.get_mut(&cursor_id) else {
+            return Ok(());
+        };
+        if cursor.recycled {
+            return Ok(());
+        }
+        let pooled = CURSOR_POOLS
+            .try_with(|pools| {
+                let mut pools = pools.borrow_mut();
+                if !pools.contains_key(&self.id) {
+                    // Forget the pools of dropped buffer managers
+                    pools.retain(|_, pool| pool.cursors.strong_count() > 0);
+                }
+                let pool = pools.entry(self.id).or_insert_with(|| CursorPool {
+                    cursors: Arc::downgrade(&self.cursors),
+                    ids: Vec::with_capacity(MAX_RECYCLED_CURSORS),
+                });
+                if pool.ids.len() < MAX_RECYCLED_CURSORS {
+                    pool.ids.push(cursor_id);
+                    true
+                } else {
+                    false
+                }
+            })
+            .unwrap_or(false);
+        if pooled {
+            cursor.position = 0;
+            cursor.recycled = true;
+        } else {
+            cursors.remove(&cursor_id);
+        }
         Ok(())
     }
 
     fn get_or_create_region(&self, position: u64) -> Result<Arc<BufferRegion>, BufIoError> {
         let start = position - (position % self.buffer_size as u64);
+        let read_ahead = self.read_ahead.load(Ordering::Relaxed);
+        if read_ahead > self.buffer_size && self.regions.get(&start).is_none() {
+            self.read_ahead_regions(start, read_ahead)?;
+        }
         let cached_region = self.regions.get_or_insert::<BufIoError>(start, || {
             let mut region = BufferRegion::new(start, self.file.clone(), self.buffer_size);
             let mut file = self.file.write().map_err(|_| BufIoError::Locking)?;
             file.seek(SeekFrom::Start(start)).map_err(BufIoError::Io)?;
             let buffer = region.buffer.get_mut().map_err(|_| BufIoError::Locking)?;
             let bytes_read = file.read(&mut buffer[..]).map_err(BufIoError::Io)?;
+            self.read_count.fetch_add(1, Ordering::Relaxed);
             region.end.store(bytes_read, Ordering::SeqCst);
             Ok(Arc::new(region))
         });
         cached_region.map(|r| r.inner())
     }
 
+    /// Reads `window` bytes (rounded up to whole regions) starting at
+    /// the region at `start` with a single read, and buffers the regions
+    /// that aren't buffered yet. Already buffered regions are kept as is
+    /// since they may hold writes that aren't flushed.
+    fn read_ahead_regions(&self, start: u64, window: usize) -> Result<(), BufIoError> {
+        let num_regions = window.div_ceil(self.buffer_size);
+        let mut bytes = vec![0; num_regions * self.buffer_size];
+        let bytes_read = {
+            let mut file = self.file.write().map_err(|_| BufIoError::Locking)?;
+            file.seek(SeekFrom::Start(start)).map_err(BufIoError::Io)?;
+            file.read(&mut bytes).map_err(BufIoError::Io)?
+        };
+        self.read_count.fetch_add(1, Ordering::Relaxed);
+        for (i, chunk) in bytes[..bytes_read].chunks(self.buffer_size).enumerate() {
+            let region_start = start + (i * self.buffer_size) as u64;
+            self.regions.get_or_insert::<BufIoError>(region_start, || {
+                let mut region =
+                    BufferRegion::new(region_start, self.file.clone(), self.buffer_size);
+                let buffer = region.buffer.get_mut().map_err(|_| BufIoError::Locking)?;
+                buffer[..chunk.len()].copy_from_slice(chunk);
+                region.end.store(chunk.len(), Ordering::SeqCst);
+                Ok(Arc::new(region))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Sets the number of bytes read from the file at once when a read
+    /// hits a region that isn't buffered, so that traversing a chunk
+    /// made of many small fields takes a single read. Values up to the
+    /// buffer size disable read-ahead.
+    pub fn set_read_ahead(&self, window: usize) {
+        self.read_ahead.store(window, Ordering::Relaxed);
+    }
+
+    pub fn read_ahead(&self) -> usize {
+        self.read_ahead.load(Ordering::Relaxed)
+    }
+
     pub fn read_f32_with_cursor(&self, cursor_id: u64) -> Result<f32, BufIoError> {
-        let mut buffer = [0u8; 4];
-        self.read_with_cursor(cursor_id, &mut buffer)?;
-        Ok(f32::from_le_bytes(buffer))
+        Ok(f32::from_le_bytes(self.read_array_with_cursor(cursor_id)?))
     }
 
     pub fn read_u64_with_cursor(&self, cursor_id: u64) -> Result<u64, BufIoError> {
-        let mut buffer = [0u8; 8];
-        self.read_with_cursor(cursor_id, &mut buffer)?;
-        Ok(u64::from_le_bytes(buffer))
+        Ok(u64::from_le_bytes(self.read_array_with_cursor(cursor_id)?))
     }
 
     pub fn read_u32_with_cursor(&self, cursor_id: u64) -> Result<u32, BufIoError> {
-        let mut buffer = [0u8; 4];
-        self.read_with_cursor(cursor_id, &mut buffer)?;
-        Ok(u32::from_le_bytes(buffer))
+        Ok(u32::from_le_bytes(self.read_array_with_cursor(cursor_id)?))
     }
 
     pub fn read_u16_with_cursor(&self, cursor_id: u64) -> Result<u16, BufIoError> {
-        let mut buffer = [0u8; 2];
-        self.read_with_cursor(cursor_id, &mut buffer)?;
-        Ok(u16::from_le_bytes(buffer))
+        Ok(u16::from_le_bytes(self.read_array_with_cursor(cursor_id)?))
     }
 
     pub fn read_u8_with_cursor(&self, cursor_id: u64) -> Result<u8, BufIoError> {
-        let mut buffer = [0u8; 1];
+        Ok(u8::from_le_bytes(self.read_array_with_cursor(cursor_id)?))
+    }
+
+    // Reads exactly `N` bytes. Unlike `read_with_cursor`, this fails
+    // instead of returning fewer bytes at the end of the file, so the
+    // fixed size reads of the deserializers don't decode zeros past it.
+    fn read_array_with_cursor<const N: usize>(
+        &self,
+        cursor_id: u64,
+    ) -> Result<[u8; N], BufIoError> {
+        let offset = self.cursor_position(cursor_id)?;
+        if self.file_size().saturating_sub(offset) < N as u64 {
+            return Err(BufIoError::UnexpectedEof { offset });
+        }
+        let mut buffer = [0u8; N];
         self.read_with_cursor(cursor_id, &mut buffer)?;
-        Ok(u8::from_le_bytes(buffer))
+        Ok(buffer)
     }
 
+    /// Returns the position of the cursor. This only reads the cursor, it
+    /// neither loads nor flushes regions, so it can be freely interleaved
+    /// with reads and writes.
     pub fn cursor_position(&self, cursor_id: u64) -> Result<u64, BufIoError> {
         let cursors = self.cursors.read().map_err(|_| BufIoError::Locking)?;
         cursors
@@ -235,6 +455,14 @@ impl BufferManager {
             .ok_or_else(|| BufIoError::InvalidCursor(cursor_id))
     }
 
+    /// Returns the number of bytes between the cursor and the end of the
+    /// file, 0 if the cursor is past it. Like `cursor_position`, this has
+    /// no side effects.
+    pub fn remaining_bytes(&self, cursor_id: u64) -> Result<u64, BufIoError> {
+        let position = self.cursor_position(cursor_id)?;
+        Ok(self.file_size().saturating_sub(position))
+    }
+
     pub fn read_with_cursor(&self, cursor_id: u64, buf: &mut [u8]) -> Result<usize, BufIoError> {
         let mut curr_pos = {
             let cursors = self.cursors.read().map_err(|_| BufIoError::Locking)?;
@@ -318,6 +546,7 @@ impl BufferManager {
         };
 
         let input_size = buf.len();
+        self.write_count.fetch_add(1, Ordering::Relaxed);
 
         // Take write lock early to cover the entire write operation that might affect file size
         let mut file_size_guard = self.file_size.write().map_err(|_| BufIoError::Locking)?;
@@ -407,9 +636,27 @@ impl BufferManager {
             .ok_or_else(|| BufIoError::InvalidCursor(cursor_id))?;
 
         cursor.position = pos;
+        self.seek_count.fetch_add(1, Ordering::Relaxed);
         Ok(())
     }
 
+    /// Returns the number of cursor seeks performed on this buffer manager,
+    /// useful for comparing the access patterns of serialization layouts
+    pub fn seek_count(&self) -> u64 {
+        self.seek_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of writes performed on this buffer manager
+    pub fn write_count(&self) -> u64 {
+        self.write_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of reads from the underlying file, each
+    /// buffering one region or a read-ahead window
+    pub fn read_count(&self) -> u64 {
+        self.read_count.load(Ordering::Relaxed)
+    }
+
     pub fn flush(&self) -> Result<(), BufIoError> {
         for region in self.regions.values() {
             if region.should_final_flush() {
@@ -1091,6 +1338,184 @@ mod tests {
         bufman.close_cursor(cursor).unwrap();
     }
 
+    #[test]
+    fn test_remaining_bytes() {
+        let file = create_tmp_file_of_size(10).unwrap();
+        let bufman = BufferManager::new(file, BUFFER_SIZE).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+        assert_eq!(bufman.remaining_bytes(cursor).unwrap(), 10);
+
+        bufman.seek_with_cursor(cursor, 4).unwrap();
+        assert_eq!(bufman.remaining_bytes(cursor).unwrap(), 6);
+        let reads = bufman.read_count();
+        bufman.read_u32_with_cursor(cursor).unwrap();
+        assert_eq!(bufman.remaining_bytes(cursor).unwrap(), 2);
+        // Querying the position doesn't move the cursor nor read the file
+        assert_eq!(bufman.cursor_position(cursor).unwrap(), 8);
+        assert_eq!(bufman.cursor_position(cursor).unwrap(), 8);
+        assert_eq!(bufman.read_count(), reads + 1);
+
+        // 4 bytes with 2 left is rejected, without moving the cursor
+        assert!(bufman.read_u32_with_cursor(cursor).is_err());
+        assert_eq!(bufman.cursor_position(cursor).unwrap(), 8);
+        assert_eq!(bufman.read_u16_with_cursor(cursor).unwrap(), 0);
+        assert_eq!(bufman.remaining_bytes(cursor).unwrap(), 0);
+        assert!(bufman.read_u8_with_cursor(cursor).is_err());
+
+        // Writes past the end grow the file
+        bufman.update_u64_with_cursor(cursor, 1).unwrap();
+        bufman.seek_with_cursor(cursor, 12).unwrap();
+        assert_eq!(bufman.remaining_bytes(cursor).unwrap(), 6);
+        bufman.seek_with_cursor(cursor, 100).unwrap();
+        assert_eq!(bufman.remaining_bytes(cursor).unwrap(), 0);
+
+        bufman.close_cursor(cursor).unwrap();
+    }
+
+    #[test]
+    fn test_read_past_eof() {
+        let file = create_tmp_file_of_size(10).unwrap();
+        let bufman = BufferManager::new(file, BUFFER_SIZE).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+
+        // Straddling the end of the file
+        bufman.seek_with_cursor(cursor, 8).unwrap();
+        assert!(matches!(
+            bufman.read_u32_with_cursor(cursor),
+            Err(BufIoError::UnexpectedEof { offset: 8 })
+        ));
+        assert!(matches!(
+            bufman.read_u64_with_cursor(cursor),
+            Err(BufIoError::UnexpectedEof { offset: 8 })
+        ));
+        assert_eq!(bufman.read_u16_with_cursor(cursor).unwrap(), 0);
+
+        // Seeking past the end, as a corrupt link would
+        bufman.seek_with_cursor(cursor, 1000).unwrap();
+        assert!(matches!(
+            bufman.read_u8_with_cursor(cursor),
+            Err(BufIoError::UnexpectedEof { offset: 1000 })
+        ));
+        assert_eq!(bufman.file_size(), 10);
+
+        bufman.close_cursor(cursor).unwrap();
+    }
+
+    #[test]
+    fn test_cursor_reuse() {
+        let file = create_tmp_file_of_size(1000).unwrap();
+        let bufman = Arc::new(BufferManager::new(file, BUFFER_SIZE).unwrap());
+
+        for _ in 0..1000 {
+            let c1 = bufman.open_cursor().unwrap();
+            let c2 = bufman.open_cursor().unwrap();
+            let c3 = bufman.open_cursor().unwrap();
+            assert!(c1 != c2 && c2 != c3 && c1 != c3);
+            // Recycled cursors always start at the beginning of the file
+            for cursor in [c1, c2, c3] {
+                assert_eq!(0, bufman.cursor_position(cursor).unwrap());
+                bufman.seek_with_cursor(cursor, 500).unwrap();
+            }
+            bufman.close_cursor(c3).unwrap();
+            bufman.close_cursor(c2).unwrap();
+            bufman.close_cursor(c1).unwrap();
+        }
+
+        assert_eq!(3, bufman.next_cursor_id.load(Ordering::SeqCst));
+        assert_eq!(3, bufman.cursors.read().unwrap().len());
+
+        // Closing a cursor twice doesn't pool it twice
+        let cursor = bufman.open_cursor().unwrap();
+        bufman.close_cursor(cursor).unwrap();
+        bufman.close_cursor(cursor).unwrap();
+        let c1 = bufman.open_cursor().unwrap();
+        let c2 = bufman.open_cursor().unwrap();
+        assert_ne!(c1, c2);
+        bufman.close_cursor(c1).unwrap();
+        bufman.close_cursor(c2).unwrap();
+
+        // Other threads have pools of their own, which give their cursors
+        // back when the threads exit
+        for _ in 0..10 {
+            let bm = bufman.clone();
+            thread::spawn(move || {
+                let cursors: Vec<_> = (0..3).map(|_| bm.open_cursor().unwrap()).collect();
+                for cursor in cursors {
+                    bm.close_cursor(cursor).unwrap();
+                }
+                let cursors: Vec<_> = (0..3).map(|_| bm.open_cursor().unwrap()).collect();
+                for cursor in cursors {
+                    bm.close_cursor(cursor).unwrap();
+                }
+                assert_eq!(6, bm.cursors.read().unwrap().len());
+            })
+            .join()
+            .unwrap();
+        }
+        assert_eq!(33, bufman.next_cursor_id.load(Ordering::SeqCst));
+        assert_eq!(3, bufman.cursors.read().unwrap().len());
+        let cursors: Vec<_> = (0..3).map(|_| bufman.open_cursor().unwrap()).collect();
+        assert_eq!(33, bufman.next_cursor_id.load(Ordering::SeqCst));
+
+        // The pool is bounded, extra cursors are dropped on close
+        let extra: Vec<_> = (0..MAX_RECYCLED_CURSORS * 2)
+            .map(|_| bufman.open_cursor().unwrap())
+            .collect();
+        for cursor in cursors.into_iter().chain(extra) {
+            bufman.close_cursor(cursor).unwrap();
+        }
+        assert_eq!(MAX_RECYCLED_CURSORS, bufman.cursors.read().unwrap().len());
+    }
+
+    // A u16, a u32, a u64 and an f32 as laid out on disk, least
+    // significant byte first
+    const ARCHIVE_LE: [u8; 18] = [
+        0x34, 0x12, // 0x1234
+        0x78, 0x56, 0x34, 0x12, // 0x12345678
+        0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01, // 0x0123456789abcdef
+        0x00, 0x00, 0xc0, 0xbf, // -1.5
+    ];
+
+    #[test]
+    fn test_read_little_endian_fixture() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&ARCHIVE_LE).unwrap();
+        let bufman = BufferManager::new(file, BUFFER_SIZE).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+
+        assert_eq!(bufman.read_u16_with_cursor(cursor).unwrap(), 0x1234);
+        assert_eq!(bufman.read_u32_with_cursor(cursor).unwrap(), 0x12345678);
+        assert_eq!(
+            bufman.read_u64_with_cursor(cursor).unwrap(),
+            0x0123456789abcdef
+        );
+        assert_eq!(bufman.read_f32_with_cursor(cursor).unwrap(), -1.5);
+
+        bufman.close_cursor(cursor).unwrap();
+    }
+
+    #[test]
+    fn test_write_little_endian_fixture() {
+        let mut file = tempfile().unwrap();
+        let bufman = BufferManager::new(file.try_clone().unwrap(), BUFFER_SIZE).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+
+        bufman.update_u16_with_cursor(cursor, 0x1234).unwrap();
+        bufman.update_u32_with_cursor(cursor, 0x12345678).unwrap();
+        bufman
+            .update_u64_with_cursor(cursor, 0x0123456789abcdef)
+            .unwrap();
+        bufman.update_f32_with_cursor(cursor, -1.5).unwrap();
+        bufman.close_cursor(cursor).unwrap();
+        bufman.flush().unwrap();
+
+        // The bytes on disk match the fixture, independent of the host
+        let mut raw = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut raw).unwrap();
+        assert_eq!(raw, ARCHIVE_LE);
+    }
+
     #[test]
     fn test_read_large_file() {
         let mut rng = rand::thread_rng();
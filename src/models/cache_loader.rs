@@ -2,11 +2,14 @@ use super::buffered_io::{BufIoError, BufferManager, BufferManagerFactory};
 use super::common::TSHashTable;
 use super::file_persist::read_prop_from_file;
 use super::fixedset::VersionedInvertedFixedSetIndex;
-use super::lazy_load::{FileIndex, LazyItem, LazyItemVec, VectorData};
-use super::lru_cache::LRUCache;
-use super::prob_lazy_load::lazy_item::{ProbLazyItem, ProbLazyItemState, ReadyState};
+use super::identity_collections::IdentityMapKey;
+use super::lazy_load::{EagerLazyItem, FileIndex, LazyItem, LazyItemVec, VectorData};
+use super::lru_cache::{LRUCache, ShardedLRUCache};
+use super::prob_lazy_load::lazy_item::{
+    AtomicSharedNode, ProbLazyItem, ProbLazyItemState, ReadyState, RetiredState,
+};
 use super::prob_node::{ProbNode, SharedNode};
-use super::serializer::dense::DenseSerialize;
+use super::serializer::dense::{DenseSerialize, NeighborEncoding};
 use super::serializer::inverted::InvertedIndexSerialize;
 use super::serializer::CustomSerialize;
 use super::types::*;
@@ -18,30 +21,79 @@ use crate::storage::inverted_index_sparse_ann::{
 };
 use crate::storage::inverted_index_sparse_ann_basic::InvertedIndexSparseAnnNodeBasicDashMap;
 use crate::storage::inverted_index_sparse_ann_basic::{
-    InvertedIndexSparseAnnNodeBasic, InvertedIndexSparseAnnNodeBasicTSHashmapData,
+    calculate_path, InvertedIndexSparseAnnNodeBasic, InvertedIndexSparseAnnNodeBasicTSHashmapData,
 };
 use crate::storage::inverted_index_sparse_ann_new_ds::InvertedIndexNewDSNode;
+use crate::storage::sparse_ann_query_basic::QueryStats;
 use crate::storage::Storage;
 use arcshift::ArcShift;
 use dashmap::DashMap;
 use probabilistic_collections::cuckoo::CuckooFilter;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher24;
+use std::any::{Any, TypeId};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::TryLockError;
-use std::sync::{atomic::AtomicBool, Arc, Mutex, RwLock, Weak};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock, RwLockWriteGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use xxhash_rust::xxh3::Xxh3;
 
 macro_rules! define_cache_items {
     ($($variant:ident = $type:ty),+ $(,)?) => {
         #[derive(Clone)]
         pub enum CacheItem {
-            $($variant(LazyItem<$type>)),+
+            $($variant(LazyItem<$type>),)+
+            /// A `LazyItem` of a type defined outside of this crate, keyed
+            /// by the `TypeId` of the item's data type. See
+            /// `CacheItem::dynamic`.
+            Dynamic(TypeId, Arc<dyn Any + Send + Sync>),
+        }
+
+        impl CacheItem {
+            pub fn touch(&self) {
+                match self {
+                    $(Self::$variant(item) => item.touch(),)+
+                    Self::Dynamic(..) => {}
+                }
+            }
+
+            pub fn decay(&self) -> bool {
+                match self {
+                    $(Self::$variant(item) => item.decay(),)+
+                    Self::Dynamic(..) => false,
+                }
+            }
+
+            /// Estimated number of bytes held by the cached item, 0 if
+            /// its data is not loaded or its type is dynamic
+            pub fn heap_size(&self) -> usize {
+                match self {
+                    $(Self::$variant(item) => <$type as Cacheable>::heap_size(item),)+
+                    Self::Dynamic(..) => 0,
+                }
+            }
         }
 
 
+        /// Types that can be stored in the `NodeRegistry`.
+        ///
+        /// The built-in types each have their own `CacheItem` variant. Other
+        /// types can implement this trait on top of the `Dynamic` variant
+        /// using `CacheItem::downcast` and `CacheItem::dynamic`.
         pub trait Cacheable: Clone + 'static {
             fn from_cache_item(cache_item: CacheItem) -> Option<LazyItem<Self>>;
             fn into_cache_item(item: LazyItem<Self>) -> CacheItem;
+            fn heap_size(item: &LazyItem<Self>) -> usize;
         }
 
         $(
@@ -57,11 +109,41 @@ macro_rules! define_cache_items {
                 fn into_cache_item(item: LazyItem<Self>) -> CacheItem {
                     CacheItem::$variant(item)
                 }
+
+                fn heap_size(item: &LazyItem<Self>) -> usize {
+                    item.get_lazy_data()
+                        .and_then(|mut data| data.get().clone())
+                        .map_or(0, |data| size_of::<$type>() + HeapSize::heap_size(&*data))
+                }
             }
         )+
     };
 }
 
+impl CacheItem {
+    /// Wraps an item of a type without its own variant
+    pub fn dynamic<T>(item: LazyItem<T>) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        Self::Dynamic(TypeId::of::<T>(), Arc::new(item))
+    }
+
+    /// Unwraps an item stored with `dynamic`, `None` if the item
+    /// is of another type
+    pub fn downcast<T>(self) -> Option<LazyItem<T>>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        match self {
+            Self::Dynamic(type_id, item) if type_id == TypeId::of::<T>() => {
+                item.downcast_ref::<LazyItem<T>>().cloned()
+            }
+            _ => None,
+        }
+    }
+}
+
 define_cache_items! {
     MergedNode = MergedNode,
     Storage = Storage,
@@ -78,18 +160,139 @@ define_cache_items! {
     VectorData = STM<VectorData>,
 }
 
+/// Bytes owned by a value on the heap, not counting `size_of::<Self>()`
+/// itself. Used to estimate the memory held by the cache.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl HeapSize for f32 {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for u32 {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for Storage {
+    fn heap_size(&self) -> usize {
+        match self {
+            Storage::UnsignedByte { quant_vec, .. } => quant_vec.capacity(),
+            Storage::SubByte { quant_vec, .. } => {
+                quant_vec.capacity() * size_of::<Vec<u8>>()
+                    + quant_vec.iter().map(Vec::capacity).sum::<usize>()
+            }
+            Storage::HalfPrecisionFP { quant_vec, .. } => {
+                quant_vec.capacity() * size_of::<half::f16>()
+            }
+            Storage::FullPrecisionFP { vec, .. } => vec.capacity() * size_of::<f32>(),
+        }
+    }
+}
+
+impl HeapSize for NodeProp {
+    fn heap_size(&self) -> usize {
+        size_of::<Storage>() + self.value.heap_size()
+    }
+}
+
+impl HeapSize for MergedNode {
+    fn heap_size(&self) -> usize {
+        let prop_size = match self.get_prop() {
+            PropState::Ready(prop) => {
+                size_of::<NodeProp>() + size_of::<Storage>() + prop.value.heap_size()
+            }
+            PropState::Pending(_) => 0,
+        };
+        prop_size + self.neighbors.len() * size_of::<EagerLazyItem<MergedNode, MetricResult>>()
+    }
+}
+
+impl<T: Clone + 'static> HeapSize for InvertedIndexItem<T> {
+    fn heap_size(&self) -> usize {
+        self.data.len() * (size_of::<IdentityMapKey>() + size_of::<LazyItem<T>>())
+    }
+}
+
+impl HeapSize for InvertedIndexSparseAnnNode {
+    fn heap_size(&self) -> usize {
+        self.data
+            .iter()
+            .map(|items| items.len() * size_of::<LazyItem<u32>>())
+            .sum()
+    }
+}
+
+impl HeapSize for InvertedIndexSparseAnnNodeBasic {
+    fn heap_size(&self) -> usize {
+        self.data
+            .iter()
+            .map(|items| items.read().unwrap().capacity() * size_of::<LazyItem<u32>>())
+            .sum()
+    }
+}
+
+impl HeapSize for InvertedIndexSparseAnnNodeBasicDashMap {
+    fn heap_size(&self) -> usize {
+        self.data.len() * (size_of::<u32>() + size_of::<u8>())
+    }
+}
+
+impl HeapSize for InvertedIndexNewDSNode {
+    fn heap_size(&self) -> usize {
+        self.data
+            .iter()
+            .map(|growable| growable.items.len() * size_of::<LazyItem<STM<VectorData>>>())
+            .sum()
+    }
+}
+
+impl HeapSize for InvertedIndexSparseAnn {
+    fn heap_size(&self) -> usize {
+        size_of::<InvertedIndexSparseAnnNode>() + self.root.heap_size()
+    }
+}
+
+impl HeapSize for STM<VectorData> {
+    fn heap_size(&self) -> usize {
+        size_of::<[u32; 64]>()
+    }
+}
+
 pub struct NodeRegistry {
-    cuckoo_filter: RwLock<CuckooFilter<u64>>,
+    // Skips registry lookups of items that were never cached, `None` if
+    // disabled, in which case every lookup goes to the registry
+    cuckoo_filter: Option<RwLock<CuckooFilter<u64>>>,
     registry: LRUCache<u64, CacheItem>,
     bufmans: Arc<BufferManagerFactory<Hash>>,
 }
 
 impl NodeRegistry {
     pub fn new(cuckoo_filter_capacity: usize, bufmans: Arc<BufferManagerFactory<Hash>>) -> Self {
-        let cuckoo_filter = CuckooFilter::new(cuckoo_filter_capacity);
-        let registry = LRUCache::with_prob_eviction(1000, 0.03125);
+        Self::with_cuckoo_filter(cuckoo_filter_capacity, bufmans, true)
+    }
+
+    /// Same as `new`, but the cuckoo filter can be disabled. For small
+    /// indices it's mostly overhead, and its false positives cost a
+    /// wasted registry lookup anyway.
+    pub fn with_cuckoo_filter(
+        cuckoo_filter_capacity: usize,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        use_cuckoo_filter: bool,
+    ) -> Self {
+        let cuckoo_filter =
+            use_cuckoo_filter.then(|| RwLock::new(CuckooFilter::new(cuckoo_filter_capacity)));
+        let mut registry = LRUCache::with_prob_eviction(1000, 0.03125);
+        registry.set_evict_on_insert(true);
+        // Frequently accessed items get a second chance before being
+        // evicted, see `LazyItem::decay`
+        registry.set_retain_hook(Some(CacheItem::decay));
         NodeRegistry {
-            cuckoo_filter: RwLock::new(cuckoo_filter),
+            cuckoo_filter,
             registry,
             bufmans,
         }
@@ -99,6 +302,68 @@ impl NodeRegistry {
         self.bufmans.clone()
     }
 
+    /// Writes the contents of the cuckoo filter to `w`, so that a
+    /// restarted process can restore it with `load_filter` instead of
+    /// rebuilding it.
+    pub fn save_filter(&self, w: &mut dyn Write) -> Result<(), BufIoError> {
+        let cuckoo_filter = self
+            .cuckoo_filter()?
+            .read()
+            .map_err(|_| BufIoError::Locking)?;
+        bincode::serialize_into(w, &*cuckoo_filter)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        Ok(())
+    }
+
+    /// Replaces the cuckoo filter with one written by `save_filter`.
+    ///
+    /// Meant to be called on startup, before any item is cached, as the
+    /// keys inserted so far are dropped along with the current filter.
+    /// Fails with `InvalidData` if the saved filter has a different
+    /// capacity than this registry's.
+    pub fn load_filter(&self, r: &mut dyn Read) -> Result<(), BufIoError> {
+        let saved: CuckooFilter<u64> = bincode::deserialize_from(r)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let mut cuckoo_filter = self
+            .cuckoo_filter()?
+            .write()
+            .map_err(|_| BufIoError::Locking)?;
+        if saved.capacity() != cuckoo_filter.capacity() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Cuckoo filter saved with capacity {} doesn't match the registry's capacity {}",
+                    saved.capacity(),
+                    cuckoo_filter.capacity()
+                ),
+            )
+            .into());
+        }
+        *cuckoo_filter = saved;
+        Ok(())
+    }
+
+    fn cuckoo_filter(&self) -> Result<&RwLock<CuckooFilter<u64>>, BufIoError> {
+        self.cuckoo_filter.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The registry has no cuckoo filter",
+            )
+            .into()
+        })
+    }
+
+    /// Caches an already loaded item at `file_index`, so that
+    /// `get_object` returns it without calling the load function.
+    pub fn register<T: Cacheable>(&self, file_index: &FileIndex, item: LazyItem<T>) {
+        let combined_index = Self::combine_index(file_index);
+        self.registry
+            .insert(combined_index, T::into_cache_item(item));
+        if let Some(cuckoo_filter) = &self.cuckoo_filter {
+            cuckoo_filter.write().unwrap().insert(&combined_index);
+        }
+    }
+
     pub fn get_object<T: Cacheable, F>(
         self: Arc<Self>,
         file_index: FileIndex,
@@ -123,13 +388,21 @@ impl NodeRegistry {
         let combined_index = Self::combine_index(&file_index);
 
         {
-            let cuckoo_filter = self.cuckoo_filter.read().unwrap();
+            let cuckoo_filter = self
+                .cuckoo_filter
+                .as_ref()
+                .map(|cuckoo_filter| cuckoo_filter.read().unwrap());
             println!("Acquired read lock on cuckoo_filter");
 
-            // Initial check with Cuckoo filter
-            if cuckoo_filter.contains(&combined_index) {
+            // Initial check with Cuckoo filter, without one every lookup
+            // goes straight to the registry
+            if cuckoo_filter
+                .as_ref()
+                .is_none_or(|cuckoo_filter| cuckoo_filter.contains(&combined_index))
+            {
                 println!("FileIndex found in cuckoo_filter");
                 if let Some(obj) = self.registry.get(&combined_index) {
+                    obj.touch();
                     if let Some(item) = T::from_cache_item(obj) {
                         println!("Object found in registry, returning");
                         return Ok(item);
@@ -159,7 +432,7 @@ impl NodeRegistry {
             return Ok(LazyItem::Valid {
                 data: ArcShift::new(None),
                 file_index: ArcShift::new(Some(file_index)),
-                decay_counter: 0,
+                decay_counter: Arc::new(AtomicUsize::new(0)),
                 persist_flag: Arc::new(AtomicBool::new(true)),
                 versions: LazyItemVec::new(),
                 version_id,
@@ -186,11 +459,14 @@ impl NodeRegistry {
         match cached_item {
             CachedValue::Hit(item) => {
                 println!("Object found in registry after load, returning");
+                item.touch();
                 Ok(T::from_cache_item(item).unwrap())
             }
             CachedValue::Miss(item) => {
-                println!("Inserting key into cuckoo_filter");
-                self.cuckoo_filter.write().unwrap().insert(&combined_index);
+                if let Some(cuckoo_filter) = &self.cuckoo_filter {
+                    println!("Inserting key into cuckoo_filter");
+                    cuckoo_filter.write().unwrap().insert(&combined_index);
+                }
 
                 println!("Returning newly created LazyItem");
                 Ok(T::from_cache_item(item).unwrap())
@@ -221,11 +497,17 @@ impl NodeRegistry {
         )
     }
 
+    /// Packs the offset into the upper and the version id into the lower
+    /// 32 bits of the key. `u64::MAX` is reserved for `FileIndex::Invalid`.
     pub fn combine_index(file_index: &FileIndex) -> u64 {
         match file_index {
             FileIndex::Valid {
                 offset, version_id, ..
-            } => ((offset.0 as u64) << 32) | (**version_id as u64),
+            } => {
+                let key = (u64::from(offset.0) << 32) | u64::from(**version_id);
+                debug_assert_ne!(key, u64::MAX, "key collides with FileIndex::Invalid");
+                key
+            }
             FileIndex::Invalid => u64::MAX, // Use max u64 value for Invalid
         }
     }
@@ -242,486 +524,7022 @@ impl NodeRegistry {
     // }
 }
 
-pub struct DenseIndexCache {
-    registry: LRUCache<u64, SharedNode>,
-    props_registry: DashMap<u64, Weak<NodeProp>>,
-    bufmans: Arc<BufferManagerFactory<Hash>>,
-    level_0_bufmans: Arc<BufferManagerFactory<Hash>>,
-    prop_file: Arc<RwLock<File>>,
-    loading_items: TSHashTable<u64, Arc<Mutex<bool>>>,
-    // A global lock to prevent deadlocks during batch loading of cache entries when `max_loads > 1`.
-    //
-    // This lock ensures that only one thread is allowed to load large batches of nodes (where `max_loads > 1`)
-    // at any given time. If multiple threads attempt to load interconnected nodes in parallel with high `max_loads`,
-    // it can lead to a deadlock situation due to circular dependencies between the locks. By serializing access to
-    // large batch loads, this mutex ensures that only one thread can initiate a batch load with a high `max_loads`
-    // value, preventing such circular waiting conditions. Threads with `max_loads = 1` can still load nodes in parallel
-    // without causing conflicts, allowing for efficient loading of smaller batches.
-    batch_load_lock: Mutex<()>,
+/// Range of HNSW levels of the nodes stored in a version's index files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelInfo {
+    pub min_level: HNSWLevel,
+    pub max_level: HNSWLevel,
 }
 
-unsafe impl Send for DenseIndexCache {}
-unsafe impl Sync for DenseIndexCache {}
+impl LevelInfo {
+    /// Whether the version only stores level-0 nodes
+    pub fn is_level_0(&self) -> bool {
+        self.max_level.0 == 0
+    }
 
-impl DenseIndexCache {
-    pub fn new(
-        bufmans: Arc<BufferManagerFactory<Hash>>,
-        level_0_bufmans: Arc<BufferManagerFactory<Hash>>,
-        prop_file: Arc<RwLock<File>>,
-    ) -> Self {
-        let registry = LRUCache::with_prob_eviction(100_000_000, 0.03125);
-        let props_registry = DashMap::new();
+    // The range widened to include `level`, or just `level` if no range
+    // was recorded yet
+    fn including(info: Option<Self>, level: HNSWLevel) -> Self {
+        match info {
+            Some(info) => Self {
+                min_level: HNSWLevel(info.min_level.0.min(level.0)),
+                max_level: HNSWLevel(info.max_level.0.max(level.0)),
+            },
+            None => Self {
+                min_level: level,
+                max_level: level,
+            },
+        }
+    }
+}
+
+/// Metadata of a version's index file, see `DenseIndexCache::read_header`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionFileHeader {
+    /// Layout version of the nodes in the file
+    pub format_version: u16,
+    /// Serialized size of the nodes in the file, see
+    /// `ProbNode::get_serialized_size`
+    pub node_size: u32,
+    pub level_info: Option<LevelInfo>,
+    /// XXH3 digest of the file, see `DenseIndexCache::write_file_checksum`
+    pub checksum: Option<u64>,
+    /// File the version continues in once this one is full, see
+    /// `DenseIndexCache::version_chain`
+    pub continuation: Option<Hash>,
+    /// Nodes start at multiples of this, each node being padded up to
+    /// its stride, see `DenseIndexCache::with_node_alignment`
+    pub node_alignment: u32,
+    /// File this one is the continuation of, if any. Its version id is
+    /// then taken, and can't be used for a version of its own.
+    pub continues: Option<Hash>,
+}
 
+impl VersionFileHeader {
+    /// Layout version of the nodes written by `DenseIndexCache::append_node`
+    pub const FORMAT_VERSION: u16 = 1;
+    const MAGIC: [u8; 4] = *b"CVFH";
+    // magic (4), format version (2), node size (4), then a presence byte
+    // followed by the value for the levels (2), checksum (8) and
+    // continuation (4), the node alignment (4), and a presence byte
+    // followed by the file continued (4)
+    const SERIALIZED_SIZE: usize = 36;
+    // Headers written before continuation files recorded the file they
+    // continue
+    const UNLINKED_SIZE: usize = 31;
+    // Headers written before the node alignment was added
+    const UNPADDED_SIZE: usize = 27;
+
+    // Header of a file no node has been appended to yet
+    fn empty() -> Self {
         Self {
-            registry,
-            props_registry,
-            bufmans,
-            level_0_bufmans,
-            prop_file,
-            loading_items: TSHashTable::new(16),
-            batch_load_lock: Mutex::new(()),
+            format_version: Self::FORMAT_VERSION,
+            node_size: 0,
+            level_info: None,
+            checksum: None,
+            continuation: None,
+            node_alignment: 1,
+            continues: None,
         }
     }
 
-    pub fn get_prop(
-        &self,
-        offset: FileOffset,
-        length: BytesToRead,
-    ) -> Result<Arc<NodeProp>, BufIoError> {
-        let key = Self::get_prop_key(offset, length);
-        if let Some(prop) = self
-            .props_registry
-            .get(&key)
-            .and_then(|prop| prop.upgrade())
-        {
-            return Ok(prop);
+    fn encode(&self) -> [u8; Self::SERIALIZED_SIZE] {
+        let mut bytes = [0u8; Self::SERIALIZED_SIZE];
+        bytes[0..4].copy_from_slice(&Self::MAGIC);
+        bytes[4..6].copy_from_slice(&self.format_version.to_le_bytes());
+        bytes[6..10].copy_from_slice(&self.node_size.to_le_bytes());
+        if let Some(level_info) = self.level_info {
+            bytes[10] = 1;
+            bytes[11] = level_info.min_level.0;
+            bytes[12] = level_info.max_level.0;
         }
-        let mut prop_file_guard = self.prop_file.write().unwrap();
-        let prop = Arc::new(read_prop_from_file(
-            (offset, length),
-            &mut *prop_file_guard,
-        )?);
-        drop(prop_file_guard);
-        let weak = Arc::downgrade(&prop);
-        self.props_registry.insert(key, weak);
-        Ok(prop)
+        if let Some(checksum) = self.checksum {
+            bytes[13] = 1;
+            bytes[14..22].copy_from_slice(&checksum.to_le_bytes());
+        }
+        if let Some(continuation) = self.continuation {
+            bytes[22] = 1;
+            bytes[23..27].copy_from_slice(&(*continuation).to_le_bytes());
+        }
+        bytes[27..31].copy_from_slice(&self.node_alignment.to_le_bytes());
+        if let Some(continues) = self.continues {
+            bytes[31] = 1;
+            bytes[32..36].copy_from_slice(&(*continues).to_le_bytes());
+        }
+        bytes
     }
 
-    pub fn insert_lazy_object(&self, version: Hash, offset: u32, item: SharedNode) {
-        let combined_index = (offset as u64) << 32 | (*version as u64);
-        if let Some(node) = unsafe { &*item }.get_lazy_data() {
-            let prop_key = Self::get_prop_key(node.prop.location.0, node.prop.location.1);
-            self.props_registry
-                .insert(prop_key, Arc::downgrade(&node.prop));
+    fn from_bytes(bytes: &[u8]) -> Result<Self, BufIoError> {
+        let invalid = |msg: &str| -> BufIoError {
+            io::Error::new(io::ErrorKind::InvalidData, msg.to_string()).into()
+        };
+        if ![
+            Self::SERIALIZED_SIZE,
+            Self::UNLINKED_SIZE,
+            Self::UNPADDED_SIZE,
+        ]
+        .contains(&bytes.len())
+        {
+            return Err(invalid("Malformed version file header"));
+        }
+        if bytes[0..4] != Self::MAGIC {
+            return Err(invalid("Bad magic number in version file header"));
         }
-        self.registry.insert(combined_index, item);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            format_version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            node_size: u32_at(6),
+            level_info: (bytes[10] != 0).then(|| LevelInfo {
+                min_level: HNSWLevel(bytes[11]),
+                max_level: HNSWLevel(bytes[12]),
+            }),
+            checksum: (bytes[13] != 0)
+                .then(|| u64::from_le_bytes(bytes[14..22].try_into().unwrap())),
+            continuation: (bytes[22] != 0).then(|| Hash::from(u32_at(23))),
+            // Nodes of older files aren't padded
+            node_alignment: if bytes.len() >= Self::UNLINKED_SIZE {
+                u32_at(27)
+            } else {
+                1
+            },
+            continues: (bytes.len() == Self::SERIALIZED_SIZE && bytes[31] != 0)
+                .then(|| Hash::from(u32_at(32))),
+        })
     }
+}
 
-    pub fn force_load_single_object(
-        &self,
-        file_index: FileIndex,
-        is_level_0: bool,
-    ) -> Result<SharedNode, BufIoError> {
-        let combined_index = Self::combine_index(&file_index, is_level_0);
-        let mut skipm = HashSet::new();
-        skipm.insert(combined_index);
-        let bufmans = if is_level_0 {
-            &self.level_0_bufmans
-        } else {
-            &self.bufmans
-        };
-        let data = ProbNode::deserialize(bufmans, file_index, self, 0, &mut skipm, is_level_0)?;
-        let (file_offset, version_number, version_id) = match file_index {
-            FileIndex::Valid {
-                offset,
-                version_number,
-                version_id,
-            } => (offset, version_number, version_id),
-            FileIndex::Invalid => unreachable!(),
-        };
-        let state = ProbLazyItemState::Ready(ReadyState {
-            data,
-            file_offset,
-            version_id,
-            version_number,
-        });
+/// Flag for cooperatively cancelling long running loads, such as
+/// `load_region`, from another thread. Clones share the same flag.
+///
+/// A token can also be given a timeout, after which it reports being
+/// cancelled on its own.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
 
-        let item = ProbLazyItem::new_from_state(state, is_level_0);
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        self.registry.insert(combined_index.clone(), item.clone());
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
 
-        Ok(item)
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
     }
 
-    pub fn get_lazy_object(
-        &self,
-        file_index: FileIndex,
-        max_loads: u16,
-        skipm: &mut HashSet<u64>,
-        is_level_0: bool,
-    ) -> Result<SharedNode, BufIoError> {
-        let combined_index = Self::combine_index(&file_index, is_level_0);
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
 
-        if let Some(item) = self.registry.get(&combined_index) {
-            return Ok(item);
-        }
+// Number of resident nodes compared against disk per scrubber pass
+const SCRUB_SAMPLE_SIZE: usize = 64;
+// Factor by which the scrubber's interval can grow while it backs off
+const SCRUB_MAX_BACKOFF: u32 = 8;
 
-        if max_loads == 0 || !skipm.insert(combined_index) {
-            return Ok(ProbLazyItem::new_pending(file_index, is_level_0));
-        }
+/// Counters of a `DenseIndexCache`, see `DenseIndexCache::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DenseIndexCacheStats {
+    /// Resident nodes the scrubber compared against their on-disk copy
+    pub scrubbed_nodes: usize,
+    /// Scrubbed nodes whose on-disk copy didn't match the resident one
+    pub scrub_mismatches: usize,
+}
 
-        let mut mutex = self
-            .loading_items
-            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
-        let mut load_complete = mutex.lock().unwrap();
+/// Fixed size fields of a node as stored on disk, see
+/// `DenseIndexCache::get_node_header`. Nodes don't store their vector id,
+/// it's part of the prop at `prop_location`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeHeader {
+    pub hnsw_level: HNSWLevel,
+    pub prop_location: (FileOffset, BytesToRead),
+    pub version_id: Hash,
+    pub version_number: u16,
+    /// `FileIndex::Invalid` for a missing link
+    pub parent: FileIndex,
+    pub child: FileIndex,
+    pub root_version: FileIndex,
+    pub neighbors_count: u16,
+    pub neighbor_encoding: NeighborEncoding,
+}
 
-        loop {
-            // check again
-            if let Some(item) = self.registry.get(&combined_index) {
-                return Ok(item);
-            }
+// Positions of the fields of a serialized `ProbNode` that follow its
+// neighbors length, which depend on the neighbors count and encoding
+#[derive(Debug, Clone, Copy)]
+struct NodeLayout {
+    encoding: NeighborEncoding,
+    neighbors_len: usize,
+}
 
-            // another thread loaded the data but its not in the registry (got evicted), retry
-            if *load_complete {
-                drop(load_complete);
-                mutex = self
-                    .loading_items
-                    .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
-                load_complete = mutex.lock().unwrap();
-                continue;
-            }
+impl NodeLayout {
+    // Level (1), prop location (8), parent, child and root version links
+    // (10 each), followed by the neighbors length (2)
+    const HEADER_SIZE: usize = 41;
 
-            break;
+    fn from_len(len: u16) -> Self {
+        let (encoding, neighbors_len) = NeighborEncoding::from_len(len);
+        Self {
+            encoding,
+            neighbors_len,
         }
+    }
 
-        let (file_offset, version_number, version_id) = if let FileIndex::Valid {
-            offset,
-            version_number,
-            version_id,
-        } = &file_index
-        {
-            (*offset, *version_number, *version_id)
-        } else {
-            (FileOffset(0), 0, 0.into())
-        };
-
-        let bufmans = if is_level_0 {
-            &self.level_0_bufmans
-        } else {
-            &self.bufmans
-        };
-
-        let data =
-            ProbNode::deserialize(bufmans, file_index, self, max_loads - 1, skipm, is_level_0)?;
-        let state = ProbLazyItemState::Ready(ReadyState {
-            data,
-            file_offset,
-            version_id,
-            version_number,
-        });
+    // Reads the layout from the node's bytes, which have to hold at least
+    // the header
+    fn of(node: &[u8]) -> Self {
+        Self::from_len(u16::from_le_bytes([node[39], node[40]]))
+    }
 
-        let item = ProbLazyItem::new_from_state(state, is_level_0);
+    fn slot_size(&self) -> usize {
+        self.encoding.slot_size() as usize
+    }
 
-        self.registry.insert(combined_index.clone(), item.clone());
+    fn size(&self) -> usize {
+        Self::HEADER_SIZE + self.neighbors_len * self.slot_size() + 80
+    }
 
-        *load_complete = true;
-        self.loading_items.delete(&combined_index);
+    // Start of the slot of the `i`th neighbor, beginning with its id
+    fn neighbor_slot(&self, i: usize) -> usize {
+        Self::HEADER_SIZE + i * self.slot_size()
+    }
 
-        Ok(item)
+    // Link to the `i`th neighbor, which inline neighbors don't have, see
+    // `NeighborEncoding::Inline`
+    fn neighbor_link(&self, i: usize) -> Option<usize> {
+        (self.encoding == NeighborEncoding::Indexed).then(|| self.neighbor_slot(i) + 4)
     }
 
+    // Link to the `i`th of the 8 versions
+    fn version_link(&self, i: usize) -> usize {
+        self.neighbor_slot(self.neighbors_len) + i * 10
+    }
+}
+
+/// Path from a node to the Merkle root of its version file, see
+/// `DenseIndexCache::merkle_proof`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Hash of the node's bytes
+    pub leaf: [u8; 32],
+    /// Sibling hashes from the leaf up to the root, each with whether it's
+    /// the left one of the pair. Levels where the node had no sibling are
+    /// skipped.
+    pub siblings: Vec<([u8; 32], bool)>,
+}
+
+impl MerkleProof {
+    /// Root of the tree the proof was taken from
+    pub fn root(&self) -> [u8; 32] {
+        self.siblings
+            .iter()
+            .fold(self.leaf, |hash, (sibling, is_left)| {
+                if *is_left {
+                    merkle_parent(sibling, &hash)
+                } else {
+                    merkle_parent(&hash, sibling)
+                }
+            })
+    }
+
+    pub fn verify(&self, root: &[u8; 32]) -> bool {
+        self.root() == *root
+    }
+}
+
+// Leaves and inner nodes are hashed with distinct prefixes, so that an
+// inner node can't pass for a node of the file
+fn merkle_leaf(bytes: &[u8]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(bytes)
+        .finalize()
+        .into()
+}
+
+fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+// Levels of the tree over `leaves`, from the leaves up to the root. An
+// unpaired last hash moves up a level as is.
+fn merkle_levels(leaves: Vec<[u8; 32]>) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![leaves];
+    while levels.last().unwrap().len() > 1 {
+        let level = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => merkle_parent(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(level);
+    }
+    levels
+}
+
+/// How `DenseIndexCache` derives registry keys from node locations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RegistryKeyScheme {
+    /// Bit-packs the location, see `DenseIndexCache::combine_index`.
+    /// Cheap, but limited to 31-bit offsets.
+    #[default]
+    Packed,
+    /// Hashes the location, see `DenseIndexCache::hash_index`. Supports
+    /// the full 32-bit offset range, with a negligible chance of collision.
+    Hashed,
+}
+
+/// Old to new `(offset, length)` of the props moved by
+/// `DenseIndexCache::defrag_props`
+pub type PropRelocationMap = HashMap<(FileOffset, BytesToRead), (FileOffset, BytesToRead)>;
+
+/// Strong references to the props read by `DenseIndexCache::get_prop`,
+/// keeping them resident beyond the nodes that use them, see
+/// `DenseIndexCache::with_prop_pool_bytes`. Also holds the props read
+/// ahead by `DenseIndexCache::prefetch_neighbor_props`.
+///
+/// The pool shares the props with the nodes rather than owning them, so
+/// `bytes` only counts what the pool holds: dropping a prop frees nothing
+/// while a node still holds it.
+#[derive(Default)]
+struct PropPool {
+    // Prop key to the prop and its last use
+    props: HashMap<u64, (Arc<NodeProp>, u64)>,
+    // Last use to prop key, least recently used first
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl PropPool {
+    fn touch(&mut self, key: u64, prop: &Arc<NodeProp>) {
+        self.tick += 1;
+        match self.props.get_mut(&key) {
+            Some((_, last_use)) => {
+                self.recency.remove(last_use);
+                *last_use = self.tick;
+            }
+            None => {
+                self.bytes += prop.heap_size();
+                self.props.insert(key, (prop.clone(), self.tick));
+            }
+        }
+        self.recency.insert(self.tick, key);
+    }
+
+    fn get(&self, key: u64) -> Option<Arc<NodeProp>> {
+        self.props.get(&key).map(|(prop, _)| prop.clone())
+    }
+
+    fn take(&mut self, key: u64) -> Option<Arc<NodeProp>> {
+        let (prop, last_use) = self.props.remove(&key)?;
+        self.recency.remove(&last_use);
+        self.bytes -= prop.heap_size();
+        Some(prop)
+    }
+
+    /// Drops the least recently used props until the pool fits in
+    /// `max_bytes`, returning their keys.
+    fn shrink_to(&mut self, max_bytes: usize) -> Vec<u64> {
+        let mut dropped = Vec::new();
+        while self.bytes > max_bytes {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            let (prop, _) = self.props.remove(&key).unwrap();
+            self.bytes -= prop.heap_size();
+            dropped.push(key);
+        }
+        dropped
+    }
+}
+
+// Fixed point scale of the miss rate tracked by `AdaptiveMaxLoads`
+const MISS_RATE_ONE: u32 = 1 << 16;
+
+// Weight of the latest access in the miss rate, as a right shift, i.e.
+// each access moves the rate 1/16th of the way towards 0 or 1
+const MISS_RATE_SHIFT: u32 = 4;
+
+/// Batch size policy of `DenseIndexCache::with_adaptive_max_loads`,
+/// scaling `max_loads` with an exponentially weighted average of the
+/// registry's recent miss rate
+struct AdaptiveMaxLoads {
+    min: u16,
+    max: u16,
+    // Miss rate scaled by `MISS_RATE_ONE`
+    miss_rate: AtomicU32,
+}
+
+impl AdaptiveMaxLoads {
+    fn record(&self, hit: bool) {
+        let target = if hit { 0 } else { MISS_RATE_ONE };
+        let _ = self
+            .miss_rate
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rate| {
+                Some(if target > rate {
+                    rate + ((target - rate) >> MISS_RATE_SHIFT).max(1)
+                } else {
+                    rate - ((rate - target) >> MISS_RATE_SHIFT).min(rate)
+                })
+            });
+    }
+
+    fn max_loads(&self) -> u16 {
+        let rate = self.miss_rate.load(Ordering::Relaxed) as u64;
+        let span = (self.max - self.min) as u64;
+        self.min + ((span * rate) / MISS_RATE_ONE as u64) as u16
+    }
+}
+
+/// Spares the nodes of a version from eviction while a query against it
+/// runs, see `DenseIndexCache::begin_query`. The version stays active
+/// until every guard taken for it has been dropped.
+pub struct QueryGuard {
+    active_versions: Arc<DashMap<Hash, usize>>,
+    version: Hash,
+}
+
+impl Drop for QueryGuard {
+    fn drop(&mut self) {
+        self.active_versions
+            .remove_if_mut(&self.version, |_, queries| {
+                *queries -= 1;
+                *queries == 0
+            });
+    }
+}
+
+// Version files pinned by snapshots, and the pinned ones whose removal
+// was deferred until they're no longer pinned
+#[derive(Default)]
+struct PinnedVersions {
+    pins: HashMap<Hash, usize>,
+    retired: HashSet<Hash>,
+}
+
+struct VersionPins {
+    state: Mutex<PinnedVersions>,
+    bufmans: Arc<BufferManagerFactory<Hash>>,
+    level_0_bufmans: Arc<BufferManagerFactory<Hash>>,
+}
+
+impl VersionPins {
+    fn pin(&self, versions: &[Hash]) {
+        let mut state = self.state.lock().unwrap();
+        for &version in versions {
+            *state.pins.entry(version).or_insert(0) += 1;
+        }
+    }
+
+    // Removes the files of `version` right away if it isn't pinned,
+    // otherwise once the last snapshot pinning it is dropped
+    fn retire(&self, version: Hash) -> Result<(), BufIoError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.pins.contains_key(&version) {
+                state.retired.insert(version);
+                return Ok(());
+            }
+        }
+        self.remove_files(version)
+    }
+
+    // Removes both files of `version` along with their headers
+    fn remove_files(&self, version: Hash) -> Result<(), BufIoError> {
+        for bufmans in [&self.bufmans, &self.level_0_bufmans] {
+            bufmans.remove(&version)?;
+            match fs::remove_file(header_path(bufmans, version)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Consistent read-only view of a set of version files, which stay on
+/// disk while it's alive, see `DenseIndexCache::snapshot`.
+///
+/// Version files retired by `DenseIndexCache::compact` while pinned are
+/// removed once the last snapshot pinning them, clones included, is
+/// dropped.
+pub struct IndexSnapshot {
+    pins: Arc<VersionPins>,
+    versions: Vec<Hash>,
+}
+
+impl IndexSnapshot {
+    /// The versions pinned by the snapshot, including the files they were
+    /// rolled over to
+    pub fn versions(&self) -> &[Hash] {
+        &self.versions
+    }
+}
+
+impl Clone for IndexSnapshot {
+    fn clone(&self) -> Self {
+        self.pins.pin(&self.versions);
+        Self {
+            pins: self.pins.clone(),
+            versions: self.versions.clone(),
+        }
+    }
+}
+
+impl Drop for IndexSnapshot {
+    fn drop(&mut self) {
+        let mut unpinned = Vec::new();
+        {
+            let mut state = self.pins.state.lock().unwrap();
+            for version in &self.versions {
+                let Some(pins) = state.pins.get_mut(version) else {
+                    continue;
+                };
+                *pins -= 1;
+                if *pins == 0 {
+                    state.pins.remove(version);
+                    if state.retired.remove(version) {
+                        unpinned.push(*version);
+                    }
+                }
+            }
+        }
+        for version in unpinned {
+            if let Err(err) = self.pins.remove_files(version) {
+                log::warn!(
+                    "Failed to remove the files of version {}: {}",
+                    *version,
+                    err
+                );
+            }
+        }
+    }
+}
+
+// The header of a version file is kept in a sidecar file next to it, as
+// nodes are aligned from the start of the index file
+fn header_path(bufmans: &BufferManagerFactory<Hash>, version_id: Hash) -> PathBuf {
+    let mut path = bufmans.file_path(&version_id).into_os_string();
+    path.push(".header");
+    path.into()
+}
+
+// Levels loaded by default, i.e. all of them
+const ALL_LEVELS: RangeInclusive<u8> = 0..=u8::MAX;
+
+// Default cap on the props kept by `prefetch_neighbor_props` until their
+// nodes load them
+const DEFAULT_MAX_PREFETCHED_PROP_BYTES: usize = 64 << 20;
+
+// The version log of a `DenseIndexCache`, locked for writing, see
+// `DenseIndexCache::version_log`
+type VersionIdsGuard<'a> = RwLockWriteGuard<'a, Option<(File, HashMap<u16, Hash>)>>;
+
+// The version log of an `InvertedIndexCache`, locked for writing, see
+// `InvertedIndexCache::version_log`
+type VersionsGuard<'a> = RwLockWriteGuard<'a, Option<(File, Vec<Hash>)>>;
+
+pub struct DenseIndexCache {
+    registry: ShardedLRUCache<u64, AtomicSharedNode<ProbNode>>,
+    props_registry: DashMap<u64, Weak<NodeProp>>,
+    // Strong references to props read ahead by `prefetch_neighbor_props`,
+    // handed over to the first node that loads them. Bounded by
+    // `max_prefetched_prop_bytes`, the least recently prefetched props
+    // are dropped beyond it.
+    prefetched_props: Mutex<PropPool>,
+    max_prefetched_prop_bytes: usize,
+    // Location of the newest level 0 node of each vector id, the version
+    // number is kept to order versions and to rebuild the `FileIndex`
+    id_index: DashMap<VectorId, (Hash, u16, FileOffset)>,
+    // Changes to `id_index`, appended so that locations survive restarts,
+    // see `location_log`
+    location_log: Mutex<Option<File>>,
+    // Whether the log has been replayed into `id_index`
+    locations_loaded: AtomicBool,
+    bufmans: Arc<BufferManagerFactory<Hash>>,
+    level_0_bufmans: Arc<BufferManagerFactory<Hash>>,
+    prop_file: Arc<RwLock<File>>,
+    loading_items: TSHashTable<u64, Arc<Mutex<bool>>>,
+    // A global lock to prevent deadlocks during batch loading of cache entries when `max_loads > 1`.
+    //
+    // This lock ensures that only one thread is allowed to load large batches of nodes (where `max_loads > 1`)
+    // at any given time. If multiple threads attempt to load interconnected nodes in parallel with high `max_loads`,
+    // it can lead to a deadlock situation due to circular dependencies between the locks. By serializing access to
+    // large batch loads, this mutex ensures that only one thread can initiate a batch load with a high `max_loads`
+    // value, preventing such circular waiting conditions. Threads with `max_loads = 1` can still load nodes in parallel
+    // without causing conflicts, allowing for efficient loading of smaller batches.
+    batch_load_lock: Mutex<()>,
+    // Serializes `append_node` calls so that concurrent appends get
+    // distinct offsets
+    append_lock: Mutex<()>,
+    // States replaced when `append_node` moves a node, kept for the
+    // lifetime of the cache as threads that loaded the node earlier may
+    // still be reading them
+    retired_states: Mutex<Vec<RetiredState<ProbNode>>>,
+    // Position in the registry the next scrubber pass starts at
+    scrub_cursor: AtomicUsize,
+    scrubbed_nodes: AtomicUsize,
+    scrub_mismatches: AtomicUsize,
+    // Appends that would grow a version file past this size go to a
+    // continuation file instead, see `append_node`
+    max_version_file_bytes: u64,
+    // Header of each `(version_id, is_level_0)` file read or written so
+    // far, see `read_header`
+    headers: DashMap<(Hash, bool), VersionFileHeader>,
+    key_scheme: RegistryKeyScheme,
+    // Number of running queries per version, whose nodes the registry
+    // doesn't evict, see `begin_query`
+    active_versions: Arc<DashMap<Hash, usize>>,
+    prop_pool: Mutex<PropPool>,
+    // Soft cap of the props held by the pool, 0 disables it. Props held by
+    // nodes aren't bounded by it, see `resident_prop_bytes`.
+    max_prop_pool_bytes: usize,
+    // Batch size of `get_object`, fixed at 1000 when not set
+    adaptive_max_loads: Option<AdaptiveMaxLoads>,
+    // Appended nodes start at multiples of this, see `with_node_alignment`
+    node_alignment: u32,
+    // Version files kept on disk for snapshots, see `snapshot`
+    version_pins: Arc<VersionPins>,
+    // Version log and the file holding each version number in it, opened
+    // on first use, see `register_version`
+    version_ids: RwLock<Option<(File, HashMap<u16, Hash>)>>,
+    // Set once `is_empty` finds nodes, so that queries don't scan the
+    // index files again
+    has_nodes: AtomicBool,
+}
+
+impl DenseIndexCache {
+    pub fn new(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        level_0_bufmans: Arc<BufferManagerFactory<Hash>>,
+        prop_file: Arc<RwLock<File>>,
+    ) -> Self {
+        Self::with_shards(bufmans, level_0_bufmans, prop_file, 1)
+    }
+
+    /// Constructs a cache whose node registry is split into `shard_count`
+    /// shards, so that concurrent loaders inserting nodes into different
+    /// shards don't contend with each other.
+    pub fn with_shards(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        level_0_bufmans: Arc<BufferManagerFactory<Hash>>,
+        prop_file: Arc<RwLock<File>>,
+        shard_count: usize,
+    ) -> Self {
+        let mut registry = ShardedLRUCache::with_prob_eviction(100_000_000, 0.03125, shard_count);
+        let active_versions = Arc::new(DashMap::new());
+        Self::spare_active_versions(&mut registry, &active_versions);
+        let props_registry = DashMap::new();
+        let version_pins = Arc::new(VersionPins {
+            state: Mutex::new(PinnedVersions::default()),
+            bufmans: bufmans.clone(),
+            level_0_bufmans: level_0_bufmans.clone(),
+        });
+
+        Self {
+            registry,
+            props_registry,
+            prefetched_props: Mutex::new(PropPool::default()),
+            max_prefetched_prop_bytes: DEFAULT_MAX_PREFETCHED_PROP_BYTES,
+            id_index: DashMap::new(),
+            location_log: Mutex::new(None),
+            locations_loaded: AtomicBool::new(false),
+            bufmans,
+            level_0_bufmans,
+            prop_file,
+            loading_items: TSHashTable::new(16),
+            batch_load_lock: Mutex::new(()),
+            append_lock: Mutex::new(()),
+            retired_states: Mutex::new(Vec::new()),
+            scrub_cursor: AtomicUsize::new(0),
+            scrubbed_nodes: AtomicUsize::new(0),
+            scrub_mismatches: AtomicUsize::new(0),
+            max_version_file_bytes: u64::MAX,
+            headers: DashMap::new(),
+            key_scheme: RegistryKeyScheme::default(),
+            active_versions,
+            prop_pool: Mutex::new(PropPool::default()),
+            max_prop_pool_bytes: 0,
+            adaptive_max_loads: None,
+            node_alignment: 1,
+            version_pins,
+            version_ids: RwLock::new(None),
+            has_nodes: AtomicBool::new(false),
+        }
+    }
+
+    fn spare_active_versions(
+        registry: &mut ShardedLRUCache<u64, AtomicSharedNode<ProbNode>>,
+        active_versions: &Arc<DashMap<Hash, usize>>,
+    ) {
+        let active_versions = active_versions.clone();
+        registry.set_retain_hook(Some(move |node: &AtomicSharedNode<ProbNode>| {
+            !active_versions.is_empty()
+                && active_versions.contains_key(&unsafe { &*node.load() }.get_current_version_id())
+        }));
+    }
+
+    /// Marks `version` as being queried, so that its nodes aren't evicted
+    /// from the registry until the returned guard is dropped.
+    pub fn begin_query(&self, version: Hash) -> QueryGuard {
+        *self.active_versions.entry(version).or_insert(0) += 1;
+        QueryGuard {
+            active_versions: self.active_versions.clone(),
+            version,
+        }
+    }
+
+    /// Passes every node evicted from the registry, along with its
+    /// registry key, to `sink`, e.g. to spill it to a secondary store. The
+    /// sink is called once the node is out of the registry and its locks
+    /// are released, so it may use the cache.
+    ///
+    /// Nodes are otherwise never evicted, as the neighbors still pointing
+    /// to an evicted node would load it again as a separate copy. With a
+    /// sink, the registry evicts as it fills up, the sink taking care of
+    /// the evicted nodes.
+    pub fn with_eviction_sink(
+        mut self,
+        sink: impl Fn(u64, SharedNode) + Send + Sync + 'static,
+    ) -> Self {
+        self.registry.set_evict_on_insert(true);
+        self.registry
+            .set_eviction_sink(Some(move |key, node: AtomicSharedNode<ProbNode>| {
+                sink(key, node.load())
+            }));
+        self
+    }
+
+    /// Caps the size of the version files `append_node` writes to; once
+    /// the next node doesn't fit, appending continues in a new file.
+    pub fn with_max_version_file_bytes(mut self, max_version_file_bytes: u64) -> Self {
+        self.max_version_file_bytes = max_version_file_bytes;
+        self
+    }
+
+    /// Keeps the most recently used props resident, up to about
+    /// `max_prop_pool_bytes` of them, so nodes that are reloaded don't have
+    /// to read their props again. Beyond the cap, the pool drops the least
+    /// recently used props, which are freed once no node holds them
+    /// anymore and are read again by the next node that needs them.
+    ///
+    /// The cap bounds what the pool adds on top of the props held by the
+    /// resident nodes, not the memory used by props overall: see
+    /// [`Self::resident_prop_bytes`] for that and
+    /// [`Self::pooled_prop_bytes`] for the pool's share.
+    pub fn with_prop_pool_bytes(mut self, max_prop_pool_bytes: usize) -> Self {
+        self.max_prop_pool_bytes = max_prop_pool_bytes;
+        self
+    }
+
+    /// Caps the props read ahead by `prefetch_neighbor_props` and not
+    /// loaded by a node yet at about `max_prefetched_prop_bytes`, 64 MiB by
+    /// default. Beyond it the oldest prefetched props are dropped, to be
+    /// read again if their nodes are loaded after all.
+    pub fn with_prefetched_prop_bytes(mut self, max_prefetched_prop_bytes: usize) -> Self {
+        self.max_prefetched_prop_bytes = max_prefetched_prop_bytes;
+        self
+    }
+
+    /// Tunes the batch size of `get_object` between `min_loads` and
+    /// `max_loads` by the recent miss rate of the registry, loading more
+    /// of the graph at once while it's cold and as little as possible
+    /// while the working set is cached.
+    pub fn with_adaptive_max_loads(mut self, min_loads: u16, max_loads: u16) -> Self {
+        assert!(
+            min_loads >= 1 && min_loads <= max_loads,
+            "invalid max_loads range"
+        );
+        self.adaptive_max_loads = Some(AdaptiveMaxLoads {
+            min: min_loads,
+            max: max_loads,
+            miss_rate: AtomicU32::new(0),
+        });
+        self
+    }
+
+    /// Batch size `get_object` currently loads with when it gets to do a
+    /// batch load
+    pub fn effective_max_loads(&self) -> u16 {
+        self.adaptive_max_loads
+            .as_ref()
+            .map_or(1000, |adaptive| adaptive.max_loads())
+    }
+
+    /// Pads the nodes `append_node` writes up to a multiple of `alignment`
+    /// bytes, e.g. a cache line or a page, so that memory mapped readers
+    /// don't have to access nodes straddling a boundary. The alignment
+    /// is recorded in the header of each file when its first node is
+    /// appended, and files are read and appended to with the alignment
+    /// they were written with, regardless of this setting.
+    pub fn with_node_alignment(mut self, alignment: u32) -> Self {
+        assert_ne!(alignment, 0, "node alignment must be positive");
+        self.node_alignment = alignment;
+        self
+    }
+
+    /// Distance between the starts of consecutive nodes of `node_size`
+    /// bytes in new files, the size padded to the node alignment
+    pub fn node_stride(&self, node_size: u32) -> u32 {
+        node_size.next_multiple_of(self.node_alignment)
+    }
+
+    // Alignment of the nodes in the version file as recorded in its
+    // header. Files without nodes yet take the configured alignment,
+    // while ones written without a header have unpadded nodes.
+    fn file_node_alignment(&self, version_id: Hash, is_level_0: bool) -> Result<u32, BufIoError> {
+        if let Some(header) = self.header(version_id, is_level_0)? {
+            if header.node_size != 0 {
+                return Ok(header.node_alignment);
+            }
+        }
+        let bufmans = self.version_bufmans(is_level_0);
+        let is_empty = if bufmans.is_open(&version_id) || bufmans.file_path(&version_id).exists() {
+            bufmans.get(version_id)?.file_size() == 0
+        } else {
+            true
+        };
+        Ok(if is_empty { self.node_alignment } else { 1 })
+    }
+
+    /// Selects how registry keys are derived from node locations. Has to
+    /// be set before any node is loaded.
+    pub fn with_key_scheme(mut self, key_scheme: RegistryKeyScheme) -> Self {
+        self.key_scheme = key_scheme;
+        self
+    }
+
+    /// Fraction of the node registry's capacity that's in use
+    pub fn registry_fill_ratio(&self) -> f64 {
+        self.registry.fill_ratio()
+    }
+
+    /// Estimated memory held by the props that are alive, whether kept
+    /// resident by nodes or by the prop pool
+    pub fn resident_prop_bytes(&self) -> usize {
+        self.props_registry
+            .iter()
+            .filter_map(|prop| prop.upgrade())
+            .map(|prop| prop.heap_size())
+            .sum()
+    }
+
+    /// Estimated memory of the props held by the prop pool, which stays
+    /// under `max_prop_pool_bytes` up to the last prop pooled. Props also
+    /// held by nodes remain resident when the pool drops them.
+    pub fn pooled_prop_bytes(&self) -> usize {
+        self.prop_pool.lock().unwrap().bytes
+    }
+
+    pub fn stats(&self) -> DenseIndexCacheStats {
+        DenseIndexCacheStats {
+            scrubbed_nodes: self.scrubbed_nodes.load(Ordering::Relaxed),
+            scrub_mismatches: self.scrub_mismatches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Smallest version id among the nodes in the registry, `None` if it's
+    /// empty, e.g. to tell which version files no resident node reads
+    /// from. Taken from the nodes rather than the registry keys, which
+    /// only embed the version id with `RegistryKeyScheme::Packed`.
+    pub fn min_resident_version(&self) -> Option<Hash> {
+        self.registry
+            .values()
+            .map(|node| unsafe { &*node.load() }.get_current_version_id())
+            .min_by_key(|version_id| **version_id)
+    }
+
+    /// Compares a sample of the resident nodes with their copy on disk,
+    /// starting where the previous pass left off, and returns the number
+    /// of mismatches found.
+    ///
+    /// Only the fields that don't change once a node is written are
+    /// compared: the HNSW level, the prop location and the neighbors
+    /// count. Nodes that haven't been written yet are skipped.
+    pub fn scrub(&self) -> Result<usize, BufIoError> {
+        let nodes: Vec<SharedNode> = self.registry.values().map(|node| node.load()).collect();
+        if nodes.is_empty() {
+            return Ok(0);
+        }
+        let sample_size = SCRUB_SAMPLE_SIZE.min(nodes.len());
+        let start = self.scrub_cursor.fetch_add(sample_size, Ordering::Relaxed) % nodes.len();
+
+        let mut mismatches = 0;
+        for &node in nodes.iter().cycle().skip(start).take(sample_size) {
+            let node = unsafe { &*node };
+            let (
+                Some(data),
+                FileIndex::Valid {
+                    offset, version_id, ..
+                },
+            ) = (node.get_lazy_data(), node.get_file_index())
+            else {
+                continue;
+            };
+            let bufman = if node.is_level_0 {
+                self.level_0_bufmans.get(version_id)?
+            } else {
+                self.bufmans.get(version_id)?
+            };
+            // level, prop location, 3 links and the neighbors count
+            if offset.0 as u64 + 41 > bufman.file_size() {
+                continue;
+            }
+
+            let cursor = bufman.open_cursor()?;
+            bufman.seek_with_cursor(cursor, offset.0 as u64)?;
+            let hnsw_level = HNSWLevel(bufman.read_u8_with_cursor(cursor)?);
+            let prop_location = (
+                FileOffset(bufman.read_u32_with_cursor(cursor)?),
+                BytesToRead(bufman.read_u32_with_cursor(cursor)?),
+            );
+            // The parent, child and root version links can be updated in
+            // memory before being written
+            bufman.seek_with_cursor(cursor, offset.0 as u64 + 39)?;
+            let neighbors_count =
+                NodeLayout::from_len(bufman.read_u16_with_cursor(cursor)?).neighbors_len;
+            bufman.close_cursor(cursor)?;
+
+            self.scrubbed_nodes.fetch_add(1, Ordering::Relaxed);
+            if hnsw_level != data.hnsw_level
+                || prop_location != data.prop.location
+                || neighbors_count != data.get_neighbors_raw().len()
+            {
+                log::warn!(
+                    "Node at offset {} of version {} differs from its copy on disk",
+                    offset.0,
+                    *version_id
+                );
+                self.scrub_mismatches.fetch_add(1, Ordering::Relaxed);
+                mismatches += 1;
+            }
+        }
+        Ok(mismatches)
+    }
+
+    /// Spawns a thread that calls `scrub` every `interval`, until the
+    /// returned token is cancelled or the cache is dropped. Mismatches
+    /// are logged and counted in `stats`.
+    ///
+    /// While a batch load is running the scrubber backs off, doubling
+    /// its interval up to `SCRUB_MAX_BACKOFF` times the given one.
+    pub fn spawn_scrubber(self: &Arc<Self>, interval: Duration) -> CancellationToken {
+        let cache = Arc::downgrade(self);
+        let token = CancellationToken::new();
+        let scrubber_token = token.clone();
+
+        thread::spawn(move || {
+            let mut backoff = 1;
+            loop {
+                thread::sleep(interval * backoff);
+                if scrubber_token.is_cancelled() {
+                    break;
+                }
+                let Some(cache) = cache.upgrade() else {
+                    break;
+                };
+                if matches!(
+                    cache.batch_load_lock.try_lock(),
+                    Err(TryLockError::WouldBlock)
+                ) {
+                    backoff = (backoff * 2).min(SCRUB_MAX_BACKOFF);
+                    continue;
+                }
+                backoff = 1;
+                if let Err(err) = cache.scrub() {
+                    log::warn!("Scrubber pass failed: {}", err);
+                }
+            }
+        });
+
+        token
+    }
+
+    /// Shared prop of nodes that have no prop, i.e. whose prop location
+    /// has a length of 0. It holds an empty vector and is never read
+    /// from the prop file or registered in `props_registry`.
+    pub fn empty_prop() -> Arc<NodeProp> {
+        static EMPTY_PROP: OnceLock<Arc<NodeProp>> = OnceLock::new();
+        EMPTY_PROP
+            .get_or_init(|| {
+                Arc::new(NodeProp {
+                    id: VectorId(u64::MAX),
+                    value: Arc::new(Storage::FullPrecisionFP {
+                        mag: 0.0,
+                        vec: Vec::new(),
+                    }),
+                    location: (FileOffset(0), BytesToRead(0)),
+                })
+            })
+            .clone()
+    }
+
+    /// Returns the prop at the given location, reading it from the prop
+    /// file if it's not resident. Zero-length props resolve to
+    /// [`Self::empty_prop`].
+    pub fn get_prop(
+        &self,
+        offset: FileOffset,
+        length: BytesToRead,
+    ) -> Result<Arc<NodeProp>, BufIoError> {
+        if length.0 == 0 {
+            return Ok(Self::empty_prop());
+        }
+        let key = Self::get_prop_key(offset, length);
+        // The node being loaded takes over prefetched props
+        let prefetched = self.prefetched_props.lock().unwrap().take(key);
+        if let Some(prop) = prefetched {
+            self.pool_prop(key, &prop);
+            return Ok(prop);
+        }
+        if let Some(prop) = self
+            .props_registry
+            .get(&key)
+            .and_then(|prop| prop.upgrade())
+        {
+            self.pool_prop(key, &prop);
+            return Ok(prop);
+        }
+        let mut prop_file_guard = self.prop_file.write().unwrap();
+        let prop = Arc::new(read_prop_from_file(
+            (offset, length),
+            &mut *prop_file_guard,
+        )?);
+        drop(prop_file_guard);
+        let weak = Arc::downgrade(&prop);
+        self.props_registry.insert(key, weak);
+        self.pool_prop(key, &prop);
+        Ok(prop)
+    }
+
+    fn pool_prop(&self, key: u64, prop: &Arc<NodeProp>) {
+        if self.max_prop_pool_bytes == 0 {
+            return;
+        }
+        let dropped = {
+            let mut prop_pool = self.prop_pool.lock().unwrap();
+            prop_pool.touch(key, prop);
+            prop_pool.shrink_to(self.max_prop_pool_bytes)
+        };
+        // Forget the props that no node holds either
+        for key in dropped {
+            self.props_registry
+                .remove_if(&key, |_, prop| prop.strong_count() == 0);
+        }
+    }
+
+    /// Returns the prop if it's already resident, without reading the
+    /// prop file.
+    pub fn try_get_prop(&self, offset: FileOffset, length: BytesToRead) -> Option<Arc<NodeProp>> {
+        if length.0 == 0 {
+            return Some(Self::empty_prop());
+        }
+        let key = Self::get_prop_key(offset, length);
+        self.props_registry
+            .get(&key)
+            .and_then(|prop| prop.upgrade())
+            .or_else(|| self.prefetched_props.lock().unwrap().get(key))
+    }
+
+    /// Rewrites the prop file with only the `live` props,
+    /// back to back in the order of their old offsets, dropping the dead
+    /// regions left behind by overwritten and deleted nodes.
+    ///
+    /// The compacted props are written to a new file, synced and renamed
+    /// over the old one, so a crash leaves either file intact. The new
+    /// file then replaces the open one. The prop file is the `prop.data`
+    /// kept in the index directory, next to the index files.
+    ///
+    /// Returns where each live prop was moved to, for the caller to patch
+    /// the prop locations of the nodes referencing them. Since the old
+    /// locations are gone, the resident props are all forgotten and read
+    /// again from their new locations. Zero-length locations don't refer
+    /// to the file and are left out of the map.
+    pub fn defrag_props(
+        &self,
+        live: &[(FileOffset, BytesToRead)],
+    ) -> Result<PropRelocationMap, BufIoError> {
+        let prop_path = self.bufmans.root_path().join("prop.data");
+        let mut live: Vec<_> = live
+            .iter()
+            .copied()
+            .filter(|(_, length)| length.0 > 0)
+            .collect();
+        live.sort_unstable_by_key(|(offset, length)| (offset.0, length.0));
+        live.dedup();
+
+        let mut prop_file = self.prop_file.write().unwrap();
+        let file_size = prop_file.metadata()?.len();
+        let mut bytes = Vec::new();
+        let mut relocations = PropRelocationMap::with_capacity(live.len());
+        for (offset, length) in live {
+            if offset.0 as u64 + length.0 as u64 > file_size {
+                return Err(BufIoError::Corrupt {
+                    context: "prop out of range",
+                    offset: offset.0 as u64,
+                });
+            }
+            let new_offset = FileOffset(bytes.len() as u32);
+            bytes.resize(bytes.len() + length.0 as usize, 0);
+            prop_file.seek(SeekFrom::Start(offset.0 as u64))?;
+            prop_file.read_exact(&mut bytes[new_offset.0 as usize..])?;
+            relocations.insert((offset, length), (new_offset, length));
+        }
+
+        let mut tmp_name = prop_path.as_os_str().to_owned();
+        tmp_name.push(".defrag");
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut tmp_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp_file.write_all(&bytes)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+        fs::rename(&tmp_path, &prop_path)?;
+        if let Some(dir) = prop_path.parent() {
+            // Persists the rename itself
+            File::open(dir)?.sync_all()?;
+        }
+        *prop_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&prop_path)?;
+        drop(prop_file);
+
+        self.props_registry.clear();
+        *self.prefetched_props.lock().unwrap() = PropPool::default();
+        *self.prop_pool.lock().unwrap() = PropPool::default();
+        Ok(relocations)
+    }
+
+    /// Reads the props of the node's neighbors ahead of scoring them.
+    ///
+    /// Neighbors that are not loaded yet only have their prop location
+    /// read from the index file. All missing props are then read under a
+    /// single `prop_file` lock acquisition, and kept resident until the
+    /// neighbor nodes themselves are loaded.
+    pub fn prefetch_neighbor_props(&self, node: &SharedNode) -> Result<(), BufIoError> {
+        let node = unsafe { &**node }.try_get_data(self)?;
+        let mut locations = Vec::new();
+        for neighbor in node.get_neighbors() {
+            let neighbor = unsafe { &*neighbor };
+            // Loaded neighbors already hold their prop
+            if neighbor.is_ready() {
+                continue;
+            }
+            let FileIndex::Valid {
+                offset, version_id, ..
+            } = neighbor.get_file_index()
+            else {
+                continue;
+            };
+            let bufman = if neighbor.is_level_0 {
+                self.level_0_bufmans.get(version_id)?
+            } else {
+                self.bufmans.get(version_id)?
+            };
+            let cursor = bufman.open_cursor()?;
+            // The prop location follows the HNSW level byte
+            bufman.seek_with_cursor(cursor, offset.0 as u64 + 1)?;
+            let prop_offset = FileOffset(bufman.read_u32_with_cursor(cursor)?);
+            let prop_length = BytesToRead(bufman.read_u32_with_cursor(cursor)?);
+            bufman.close_cursor(cursor)?;
+            if self.try_get_prop(prop_offset, prop_length).is_none() {
+                locations.push((prop_offset, prop_length));
+            }
+        }
+        if locations.is_empty() {
+            return Ok(());
+        }
+
+        let mut prop_file_guard = self.prop_file.write().unwrap();
+        let props = locations
+            .into_iter()
+            .map(|location| read_prop_from_file(location, &mut prop_file_guard).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(prop_file_guard);
+
+        let dropped = {
+            let mut prefetched_props = self.prefetched_props.lock().unwrap();
+            for prop in props {
+                let key = Self::get_prop_key(prop.location.0, prop.location.1);
+                self.props_registry.insert(key, Arc::downgrade(&prop));
+                prefetched_props.touch(key, &prop);
+            }
+            prefetched_props.shrink_to(self.max_prefetched_prop_bytes)
+        };
+        // Forget the dropped props unless a node loaded them meanwhile
+        for key in dropped {
+            self.props_registry
+                .remove_if(&key, |_, prop| prop.strong_count() == 0);
+        }
+        Ok(())
+    }
+
+    /// Caches `item` as the node at `offset` of the `version` file.
+    ///
+    /// # Safety
+    ///
+    /// `item` must be a valid pointer that stays alive as long as the
+    /// cache.
+    pub unsafe fn insert_lazy_object(&self, version: Hash, offset: u32, item: SharedNode) {
+        // SAFETY: the caller guarantees the pointer is valid
+        let item_ref = unsafe { &*item };
+        let combined_index = self.index_key(
+            &FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_number: item_ref.get_current_version_number(),
+                version_id: version,
+            },
+            item_ref.is_level_0,
+        );
+        if let Some(node) = item_ref.get_lazy_data() {
+            let prop_key = Self::get_prop_key(node.prop.location.0, node.prop.location.1);
+            self.props_registry
+                .insert(prop_key, Arc::downgrade(&node.prop));
+        }
+        self.registry
+            .insert(combined_index, AtomicSharedNode::new(item));
+    }
+
+    // Indexes the location of a level 0 node written by `append_node`.
+    // Nodes that are only loaded are already indexed where they were
+    // written, so loading never takes the log.
+    fn record_location(
+        &self,
+        id: &VectorId,
+        version: Hash,
+        version_number: u16,
+        offset: FileOffset,
+    ) -> Result<(), BufIoError> {
+        let mut log = self.location_log()?;
+        if self.update_location(id, (version, version_number, offset)) {
+            Self::log_location(&mut log, id, Some((version, version_number, offset)))?;
+        }
+        Ok(())
+    }
+
+    // Sets the location of the id unless a newer version of it is
+    // recorded already, returns whether the location changed
+    fn update_location(&self, id: &VectorId, new: (Hash, u16, FileOffset)) -> bool {
+        let mut changed = true;
+        self.id_index
+            .entry(id.clone())
+            .and_modify(|location| {
+                if new.1 >= location.1 && *location != new {
+                    *location = new;
+                } else {
+                    changed = false;
+                }
+            })
+            .or_insert(new);
+        changed
+    }
+
+    // Records in the location log are the vector id (8), the version id
+    // (4), the version number (2) and the offset (4), the latter being
+    // `u32::MAX` for ids removed from the index
+    const LOCATION_RECORD_SIZE: usize = 18;
+
+    // Opens the location log kept next to the level 0 files, replaying the
+    // locations recorded by earlier runs into `id_index` first. The index
+    // is changed while holding the returned guard, so that the changes
+    // are logged in order.
+    fn location_log(&self) -> Result<MutexGuard<'_, Option<File>>, BufIoError> {
+        let mut log = self.location_log.lock().map_err(|_| BufIoError::Locking)?;
+        if log.is_none() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(self.level_0_bufmans.root_path().join("locations.log"))?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            // A record cut short by a crash is ignored
+            for record in bytes.chunks_exact(Self::LOCATION_RECORD_SIZE) {
+                let id = VectorId(u64::from_le_bytes(record[0..8].try_into().unwrap()));
+                let offset = u32::from_le_bytes(record[14..18].try_into().unwrap());
+                if offset == u32::MAX {
+                    self.id_index.remove(&id);
+                    continue;
+                }
+                let version = Hash::from(u32::from_le_bytes(record[8..12].try_into().unwrap()));
+                let version_number = u16::from_le_bytes([record[12], record[13]]);
+                self.update_location(&id, (version, version_number, FileOffset(offset)));
+            }
+            *log = Some(file);
+            self.locations_loaded.store(true, Ordering::Release);
+        }
+        Ok(log)
+    }
+
+    fn log_location(
+        log: &mut Option<File>,
+        id: &VectorId,
+        location: Option<(Hash, u16, FileOffset)>,
+    ) -> Result<(), BufIoError> {
+        let (version, version_number, offset) =
+            location.unwrap_or((Hash::from(0), 0, FileOffset(u32::MAX)));
+        let mut record = [0u8; Self::LOCATION_RECORD_SIZE];
+        record[0..8].copy_from_slice(&id.0.to_le_bytes());
+        record[8..12].copy_from_slice(&(*version).to_le_bytes());
+        record[12..14].copy_from_slice(&version_number.to_le_bytes());
+        record[14..18].copy_from_slice(&offset.0.to_le_bytes());
+        log.as_mut().unwrap().write_all(&record)?;
+        Ok(())
+    }
+
+    /// Writes the node at the end of the `version_id` file and caches it,
+    /// returning its new offset.
+    ///
+    /// The node itself is what gets cached, so reading it back through
+    /// `get_object` right after is a cache hit that returns the same
+    /// `SharedNode`, without deserializing the bytes just written.
+    ///
+    /// The offset is rounded up to a multiple of the node stride, as nodes
+    /// are aligned from the start of the file with the alignment recorded
+    /// in its header, see `with_node_alignment`.
+    ///
+    /// If the version file has been rolled over (see
+    /// `with_max_version_file_bytes`), the node is written to the last
+    /// file of the chain, and its version id is that of the continuation.
+    ///
+    /// # Safety
+    ///
+    /// `node` must be a valid pointer that stays alive as long as the
+    /// cache. The node is moved to the new location, so no other thread may
+    /// update it or change its state meanwhile, see `ProbLazyItem::relocate`.
+    pub unsafe fn append_node(
+        &self,
+        version_id: Hash,
+        node: SharedNode,
+        is_level_0: bool,
+    ) -> Result<FileOffset, BufIoError> {
+        let bufmans = if is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        };
+        // SAFETY: the caller guarantees the pointer is valid
+        let node_ref = unsafe { &*node };
+        let Some(data) = node_ref.get_lazy_data() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot append a node that is not loaded",
+            )
+            .into());
+        };
+        let node_size = ProbNode::get_serialized_size(data.get_neighbors_raw().len()) as u64;
+
+        // Reserving the offset and writing the node must not interleave
+        // with other appends, otherwise they could pick the same offset
+        let _append_guard = self.append_lock.lock().unwrap();
+        self.log_version(node_ref.get_current_version_number(), version_id, false)?;
+        let version_id = *self.version_chain(version_id, is_level_0)?.last().unwrap();
+        let mut bufman = bufmans.get(version_id)?;
+        let mut version_id = version_id;
+        let mut alignment = self.file_node_alignment(version_id, is_level_0)?;
+        let node_stride = node_size.next_multiple_of(alignment as u64);
+        let mut cursor = bufman.append_cursor()?;
+        let mut offset = bufman.cursor_position(cursor)?.div_ceil(node_stride) * node_stride;
+        // An empty file takes the node even if it's larger than the cap
+        if offset > 0 && offset + node_size > self.max_version_file_bytes {
+            bufman.close_cursor(cursor)?;
+            version_id = self.roll_over(version_id, is_level_0)?;
+            bufman = bufmans.get(version_id)?;
+            cursor = bufman.append_cursor()?;
+            offset = 0;
+            alignment = self.node_alignment;
+        }
+        // Packed registry keys only have room for 31-bit offsets, see
+        // `combine_index`
+        if offset >= self.max_node_offset() {
+            bufman.close_cursor(cursor)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Node offset {} exceeds the registry key range", offset),
+            )
+            .into());
+        }
+        let offset = FileOffset(offset as u32);
+        // SAFETY: the caller guarantees the node isn't changed meanwhile
+        let retired = unsafe { node_ref.relocate(version_id, offset) };
+        self.retired_states.lock().unwrap().push(retired);
+        // `relocate` moved the node
+        let data = node_ref.get_lazy_data().unwrap();
+        bufman.seek_with_cursor(cursor, offset.0 as u64)?;
+        data.serialize_with_layout(
+            bufmans,
+            version_id,
+            cursor,
+            NeighborEncoding::Indexed,
+            alignment,
+        )?;
+        bufman.close_cursor(cursor)?;
+        let level = data.hnsw_level;
+        self.update_header(version_id, is_level_0, |header| {
+            if header.node_size == 0 {
+                header.node_size = node_size as u32;
+                header.node_alignment = alignment;
+            }
+            header.level_info = Some(LevelInfo::including(header.level_info, level));
+            // The stored digest no longer matches once the file grows
+            header.checksum = None;
+        })?;
+        drop(_append_guard);
+
+        let file_index = node_ref.get_file_index();
+        if let Some(data) = node_ref.get_lazy_data() {
+            let prop_key = Self::get_prop_key(data.prop.location.0, data.prop.location.1);
+            self.props_registry
+                .insert(prop_key, Arc::downgrade(&data.prop));
+            if is_level_0 {
+                self.record_location(
+                    &data.prop.id,
+                    version_id,
+                    node_ref.get_current_version_number(),
+                    offset,
+                )?;
+            }
+        }
+        self.registry.insert(
+            self.index_key(&file_index, is_level_0),
+            AtomicSharedNode::new(node),
+        );
+        Ok(offset)
+    }
+
+    /// Write-through insert: serializes the node in the `version` file and
+    /// caches the same `SharedNode` under its new location, so reading it
+    /// back right after is a cache hit. See `append_node`, which this is.
+    ///
+    /// # Safety
+    ///
+    /// Same as `append_node`.
+    pub unsafe fn put_and_cache(
+        &self,
+        version: Hash,
+        node: SharedNode,
+        is_level_0: bool,
+    ) -> Result<FileOffset, BufIoError> {
+        unsafe { self.append_node(version, node, is_level_0) }
+    }
+
+    /// Returns the location of the newest level 0 node inserted for the
+    /// given vector id, regardless of its version.
+    ///
+    /// Locations are logged next to the level 0 files, so the ones
+    /// recorded before a restart are known too.
+    pub fn locate(&self, id: VectorId) -> Result<Option<FileIndex>, BufIoError> {
+        if !self.locations_loaded.load(Ordering::Acquire) {
+            // Opening the log replays it into the index
+            drop(self.location_log()?);
+        }
+        Ok(self.id_index.get(&id).map(|location| FileIndex::Valid {
+            offset: location.2,
+            version_number: location.1,
+            version_id: location.0,
+        }))
+    }
+
+    /// Drops the vector id from the location index, e.g. after the vector
+    /// has been deleted. Returns whether the id was present.
+    pub fn remove_location(&self, id: &VectorId) -> Result<bool, BufIoError> {
+        let mut log = self.location_log()?;
+        if self.id_index.remove(id).is_none() {
+            return Ok(false);
+        }
+        Self::log_location(&mut log, id, None)?;
+        Ok(true)
+    }
+
+    pub fn force_load_single_object(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<SharedNode, BufIoError> {
+        let FileIndex::Valid {
+            offset: file_offset,
+            version_number,
+            version_id,
+        } = file_index
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot load a node from an invalid file index",
+            )
+            .into());
+        };
+        let combined_index = self.index_key(&file_index, is_level_0);
+        let mut skipm = HashSet::new();
+        skipm.insert(combined_index);
+        let bufmans = if is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        };
+        let data = ProbNode::deserialize(
+            bufmans,
+            file_index,
+            self,
+            0,
+            usize::MAX,
+            &ALL_LEVELS,
+            &mut skipm,
+            is_level_0,
+        )?;
+        let state = ProbLazyItemState::Ready(ReadyState {
+            data,
+            file_offset,
+            version_id,
+            version_number,
+        });
+
+        let item = ProbLazyItem::new_from_state(state, is_level_0);
+
+        self.registry
+            .insert(combined_index.clone(), AtomicSharedNode::new(item));
+
+        Ok(item)
+    }
+
+    /// Loads the node behind a pending item (e.g. one created when
+    /// `max_loads` ran out) and turns the item itself into a ready one, so
+    /// existing references to it see the data. Ready items are returned
+    /// as is.
+    ///
+    /// Like `get_object`, a node that's cached already is returned rather
+    /// than loaded again, in which case the item is left pending.
+    ///
+    /// # Safety
+    ///
+    /// `item` must be a valid pointer, and no other thread may be reading
+    /// its state meanwhile, as the pending state is replaced.
+    pub unsafe fn resolve_pending(&self, item: SharedNode) -> Result<SharedNode, BufIoError> {
+        // SAFETY: the caller guarantees the pointer is valid
+        let node = unsafe { &*item };
+        let file_index = match node.unsafe_get_state() {
+            ProbLazyItemState::Ready(_) => return Ok(item),
+            ProbLazyItemState::Pending(file_index) => *file_index,
+        };
+        let (file_offset, version_number, version_id) = match file_index {
+            FileIndex::Valid {
+                offset,
+                version_number,
+                version_id,
+            } => (offset, version_number, version_id),
+            FileIndex::Invalid => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Cannot resolve a pending item with an invalid FileIndex",
+                )
+                .into())
+            }
+        };
+        let combined_index = self.index_key(&file_index, node.is_level_0);
+        let mutex = self
+            .loading_items
+            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+        let mut load_complete = mutex.lock().unwrap();
+        if let Some(cached) = self.registry.get(&combined_index).map(|item| item.load()) {
+            return Ok(cached);
+        }
+        let mut skipm = HashSet::new();
+        skipm.insert(combined_index);
+        let bufmans = if node.is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        };
+        let data = ProbNode::deserialize(
+            bufmans,
+            file_index,
+            self,
+            0,
+            usize::MAX,
+            &ALL_LEVELS,
+            &mut skipm,
+            node.is_level_0,
+        )?;
+        // Loading the neighbors may have cached the node through another
+        // link to it
+        let resolved = self
+            .registry
+            .get_or_insert(combined_index, || {
+                node.set_state(ProbLazyItemState::Ready(ReadyState {
+                    data,
+                    file_offset,
+                    version_id,
+                    version_number,
+                }));
+                Ok::<_, BufIoError>(AtomicSharedNode::new(item))
+            })?
+            .inner()
+            .load();
+
+        *load_complete = true;
+        self.loading_items.delete(&combined_index);
+
+        Ok(resolved)
+    }
+
+    /// Drops a single node from the cache, e.g. when it's known to be
+    /// stale after being overwritten in a newer version.
+    ///
+    /// The node's prop entry is dropped as well if no other node
+    /// references it. Returns whether the node was present in the
+    /// cache; a subsequent `get_object` call reloads it from disk.
+    pub fn evict(&self, file_index: FileIndex, is_level_0: bool) -> bool {
+        let combined_index = self.index_key(&file_index, is_level_0);
+        let Some(item) = self.registry.remove(&combined_index) else {
+            return false;
+        };
+        if let Some(node) = unsafe { &*item.load() }.get_lazy_data() {
+            let prop_key = Self::get_prop_key(node.prop.location.0, node.prop.location.1);
+            // The evicted node itself still holds one reference
+            self.props_registry
+                .remove_if(&prop_key, |_, prop| prop.strong_count() <= 1);
+        }
+        true
+    }
+
+    pub fn get_lazy_object(
+        &self,
+        file_index: FileIndex,
+        max_loads: u16,
+        neighbor_fanout: usize,
+        skipm: &mut HashSet<u64>,
+        is_level_0: bool,
+    ) -> Result<SharedNode, BufIoError> {
+        self.get_lazy_object_in_levels(
+            file_index,
+            max_loads,
+            neighbor_fanout,
+            &ALL_LEVELS,
+            skipm,
+            is_level_0,
+        )
+    }
+
+    /// Same as `get_lazy_object`, but the parents and children of the
+    /// loaded nodes are only loaded if their HNSW level is in `levels`,
+    /// and are left pending otherwise
+    pub fn get_lazy_object_in_levels(
+        &self,
+        file_index: FileIndex,
+        max_loads: u16,
+        neighbor_fanout: usize,
+        levels: &RangeInclusive<u8>,
+        skipm: &mut HashSet<u64>,
+        is_level_0: bool,
+    ) -> Result<SharedNode, BufIoError> {
+        let combined_index = self.index_key(&file_index, is_level_0);
+
+        if let Some(item) = self.registry.get(&combined_index).map(|item| item.load()) {
+            return Ok(item);
+        }
+
+        if max_loads == 0 || !skipm.insert(combined_index) {
+            return Ok(ProbLazyItem::new_pending(file_index, is_level_0));
+        }
+
+        let mut mutex = self
+            .loading_items
+            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+        let mut load_complete = mutex.lock().unwrap();
+
+        loop {
+            // check again
+            if let Some(item) = self.registry.get(&combined_index).map(|item| item.load()) {
+                return Ok(item);
+            }
+
+            // another thread loaded the data but its not in the registry (got evicted), retry
+            if *load_complete {
+                drop(load_complete);
+                mutex = self
+                    .loading_items
+                    .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+                load_complete = mutex.lock().unwrap();
+                continue;
+            }
+
+            break;
+        }
+
+        let (file_offset, version_number, version_id) = if let FileIndex::Valid {
+            offset,
+            version_number,
+            version_id,
+        } = &file_index
+        {
+            (*offset, *version_number, *version_id)
+        } else {
+            (FileOffset(0), 0, 0.into())
+        };
+
+        let bufmans = if is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        };
+
+        let data = ProbNode::deserialize(
+            bufmans,
+            file_index,
+            self,
+            max_loads - 1,
+            neighbor_fanout,
+            levels,
+            skipm,
+            is_level_0,
+        )?;
+        let state = ProbLazyItemState::Ready(ReadyState {
+            data,
+            file_offset,
+            version_id,
+            version_number,
+        });
+
+        let item = ProbLazyItem::new_from_state(state, is_level_0);
+
+        self.registry
+            .insert(combined_index.clone(), AtomicSharedNode::new(item));
+
+        *load_complete = true;
+        self.loading_items.delete(&combined_index);
+
+        Ok(item)
+    }
+
+    /// Loads up to 1000 consecutive nodes of `node_size` bytes starting at
+    /// `region_start`, stepping by the node size padded to the alignment
+    /// recorded in the file's header, see `with_node_alignment`.
     pub fn load_region(
         &self,
-        region_start: u32,
-        version_number: u16,
-        version_id: Hash,
-        node_size: u32,
-        is_level_0: bool,
-    ) -> Result<Vec<SharedNode>, BufIoError> {
-        let bufman = if is_level_0 {
-            self.level_0_bufmans.get(version_id)?
-        } else {
-            self.bufmans.get(version_id)?
+        region_start: u32,
+        version_number: u16,
+        version_id: Hash,
+        node_size: u32,
+        is_level_0: bool,
+    ) -> Result<Vec<SharedNode>, BufIoError> {
+        self.load_region_cancellable(
+            region_start,
+            version_number,
+            version_id,
+            node_size,
+            is_level_0,
+            &CancellationToken::new(),
+        )
+    }
+
+    /// Same as `load_region`, but checks `token` between node loads and
+    /// returns the nodes loaded so far once it's cancelled.
+    pub fn load_region_cancellable(
+        &self,
+        region_start: u32,
+        version_number: u16,
+        version_id: Hash,
+        node_size: u32,
+        is_level_0: bool,
+        token: &CancellationToken,
+    ) -> Result<Vec<SharedNode>, BufIoError> {
+        let bufman = if is_level_0 {
+            self.level_0_bufmans.get(version_id)?
+        } else {
+            self.bufmans.get(version_id)?
+        };
+        let file_size = bufman.file_size();
+        if region_start as u64 > file_size {
+            return Ok(Vec::new());
+        }
+        println!(
+            "Loading region: {}, version: {}, is_level_0: {}",
+            region_start, version_number, is_level_0
+        );
+        let node_stride =
+            node_size.next_multiple_of(self.file_node_alignment(version_id, is_level_0)?);
+        let cap = ((file_size - region_start as u64) / node_stride as u64).min(1000) as usize;
+        let mut nodes = Vec::with_capacity(cap);
+        for i in 0..1000 {
+            let offset = FileOffset(i * node_stride + region_start);
+            if offset.0 as u64 >= file_size || token.is_cancelled() {
+                break;
+            }
+            let file_index = FileIndex::Valid {
+                offset,
+                version_number,
+                version_id,
+            };
+            let node = self.force_load_single_object(file_index, is_level_0)?;
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    // Retrieves an object from the cache, attempting to batch load if possible, based on the state of the batch load lock.
+    //
+    // This function first attempts to acquire the `batch_load_lock` using a non-blocking `try_lock`. If successful,
+    // it sets a high `max_loads` value (1000, or the adaptive one of `with_adaptive_max_loads`), allowing for a larger batch load. This is the preferred scenario where
+    // the system is capable of performing a more efficient batch load, loading multiple nodes at once. If the lock is
+    // already held (i.e., another thread is performing a large batch load), the function falls back to a lower `max_loads`
+    // value (1), effectively loading nodes one at a time to avoid blocking or deadlocking.
+    //
+    // The key idea here is to **always attempt to load as many nodes as possible** (with `max_loads = 1000`) unless
+    // another thread is already performing a large load, in which case the function resorts to a smaller load size.
+    // This dynamic loading strategy balances efficient batch loading with the need to avoid blocking or deadlocks in high-concurrency situations.
+    //
+    // After determining the appropriate `max_loads`, the function proceeds by calling `get_lazy_object`, which handles
+    // the actual loading process, and retrieves the lazy-loaded data.
+    pub fn get_object(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<SharedNode, BufIoError> {
+        self.get_object_with_fanout(file_index, is_level_0, usize::MAX)
+    }
+
+    /// Same as `get_object`, but returns `Ok(None)` if the file of the
+    /// version `file_index` points into doesn't exist, instead of loading
+    /// from the empty file that opening it would create.
+    pub fn get_object_opt(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<Option<SharedNode>, BufIoError> {
+        if let FileIndex::Valid { version_id, .. } = &file_index {
+            let bufmans = self.version_bufmans(is_level_0);
+            if !bufmans.is_open(version_id) && !bufmans.file_path(version_id).exists() {
+                return Ok(None);
+            }
+        }
+        self.get_object(file_index, is_level_0).map(Some)
+    }
+
+    /// Records `version_id` as the file holding the nodes of
+    /// `version_number`, replacing any earlier one. `append_node` records
+    /// the version it's given for a number not seen yet.
+    ///
+    /// Version ids are logged next to the upper level files, so the ones
+    /// registered before a restart are known too.
+    pub fn register_version(
+        &self,
+        version_number: u16,
+        version_id: Hash,
+    ) -> Result<(), BufIoError> {
+        self.log_version(version_number, version_id, true)
+    }
+
+    /// Returns the version id registered for `version_number`, see
+    /// `register_version`.
+    pub fn resolve_version(&self, version_number: u16) -> Result<Option<Hash>, BufIoError> {
+        self.with_version_ids(|version_ids| version_ids.get(&version_number).copied())
+    }
+
+    // Records in the version log are the version number (2) and the
+    // version id (4), later records replacing earlier ones
+    const VERSION_RECORD_SIZE: usize = 6;
+
+    // Registers the version id for the version number, unless one is
+    // registered already and `replace` is false
+    fn log_version(
+        &self,
+        version_number: u16,
+        version_id: Hash,
+        replace: bool,
+    ) -> Result<(), BufIoError> {
+        let is_registered = |version_ids: &HashMap<u16, Hash>| {
+            version_ids
+                .get(&version_number)
+                .is_some_and(|registered| *registered == version_id || !replace)
+        };
+        // Nodes appended to an already registered version are the common
+        // case, and only need the read lock
+        if self.with_version_ids(is_registered)? {
+            return Ok(());
+        }
+        let mut version_ids = self.version_log()?;
+        let (log, version_ids) = version_ids.as_mut().unwrap();
+        if is_registered(version_ids) {
+            return Ok(());
+        }
+        // Continuation ids are picked among the ids unused at the time,
+        // so a version id seen for the first time may have been taken
+        if !version_ids.values().any(|id| *id == version_id) {
+            for is_level_0 in [false, true] {
+                if let Some(continues) = self
+                    .header(version_id, is_level_0)?
+                    .and_then(|header| header.continues)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "Version id {} is taken by a continuation of version {}",
+                            *version_id, *continues
+                        ),
+                    )
+                    .into());
+                }
+            }
+        }
+        let mut record = [0u8; Self::VERSION_RECORD_SIZE];
+        record[0..2].copy_from_slice(&version_number.to_le_bytes());
+        record[2..6].copy_from_slice(&(*version_id).to_le_bytes());
+        log.write_all(&record)?;
+        version_ids.insert(version_number, version_id);
+        Ok(())
+    }
+
+    // Calls `f` with the registered version ids under the read lock. The
+    // write lock is only taken to open the log on first use, and not at
+    // all while no log file exists, i.e. nothing was ever registered.
+    fn with_version_ids<R>(
+        &self,
+        f: impl FnOnce(&HashMap<u16, Hash>) -> R,
+    ) -> Result<R, BufIoError> {
+        {
+            let version_ids = self.version_ids.read().map_err(|_| BufIoError::Locking)?;
+            if let Some((_, version_ids)) = &*version_ids {
+                return Ok(f(version_ids));
+            }
+        }
+        if !self.bufmans.root_path().join("version_ids.log").exists() {
+            return Ok(f(&HashMap::new()));
+        }
+        let version_ids = self.version_log()?;
+        Ok(f(&version_ids.as_ref().unwrap().1))
+    }
+
+    // Opens the version log, reading the version ids registered by
+    // earlier runs
+    fn version_log(&self) -> Result<VersionIdsGuard<'_>, BufIoError> {
+        let mut version_ids = self.version_ids.write().map_err(|_| BufIoError::Locking)?;
+        if version_ids.is_none() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(self.bufmans.root_path().join("version_ids.log"))?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            // A record cut short by a crash is ignored
+            let registered = bytes
+                .chunks_exact(Self::VERSION_RECORD_SIZE)
+                .map(|record| {
+                    (
+                        u16::from_le_bytes([record[0], record[1]]),
+                        Hash::from(u32::from_le_bytes(record[2..6].try_into().unwrap())),
+                    )
+                })
+                .collect();
+            *version_ids = Some((file, registered));
+        }
+        Ok(version_ids)
+    }
+
+    /// Loads the node at `offset` of the file holding `version_number`,
+    /// i.e. the node's state as of that version. Fails with `NotFound` if
+    /// no file is registered for the version number.
+    pub fn get_object_as_of(
+        &self,
+        offset: FileOffset,
+        version_number: u16,
+        is_level_0: bool,
+    ) -> Result<SharedNode, BufIoError> {
+        let Some(version_id) = self.resolve_version(version_number)? else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No version file registered for version {}", version_number),
+            )
+            .into());
+        };
+        self.get_object(
+            FileIndex::Valid {
+                offset,
+                version_number,
+                version_id,
+            },
+            is_level_0,
+        )
+    }
+
+    // Same as `get_object`, but only the first `neighbor_fanout` neighbors of
+    // each loaded node are eagerly resolved, the rest are left pending and
+    // get loaded on first access. The loaded nodes still carry all of their
+    // neighbor references.
+    pub fn get_object_with_fanout(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+        neighbor_fanout: usize,
+    ) -> Result<SharedNode, BufIoError> {
+        if let Some(adaptive) = &self.adaptive_max_loads {
+            let combined_index = self.index_key(&file_index, is_level_0);
+            adaptive.record(self.registry.peek(&combined_index).is_some());
+        }
+        let (_lock, max_loads) = match self.batch_load_lock.try_lock() {
+            Ok(lock) => (Some(lock), self.effective_max_loads()),
+            Err(TryLockError::Poisoned(poison_err)) => panic!("lock error: {}", poison_err),
+            Err(TryLockError::WouldBlock) => (None, 1),
+        };
+        self.get_lazy_object(
+            file_index,
+            max_loads,
+            neighbor_fanout,
+            &mut HashSet::new(),
+            is_level_0,
+        )
+    }
+
+    /// Same as `get_object`, but only the nodes on the HNSW levels of
+    /// `levels` are loaded, the parent and child links leading out of them
+    /// are left pending and get loaded on first access. E.g. loading an
+    /// upper level node with `1..=u8::MAX` doesn't read the level-0 files
+    /// at all, when only the coarse levels are needed to route a query.
+    ///
+    /// The level of a node is only known once read, so the node at
+    /// `file_index` itself is loaded whatever its level, except that a
+    /// level-0 one is rejected if level 0 isn't in `levels`.
+    pub fn get_object_levels(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+        levels: RangeInclusive<u8>,
+    ) -> Result<SharedNode, BufIoError> {
+        if is_level_0 && !levels.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot load a level-0 node outside of the requested levels",
+            )
+            .into());
+        }
+        let (_lock, max_loads) = match self.batch_load_lock.try_lock() {
+            Ok(lock) => (Some(lock), self.effective_max_loads()),
+            Err(TryLockError::Poisoned(poison_err)) => panic!("lock error: {}", poison_err),
+            Err(TryLockError::WouldBlock) => (None, 1),
+        };
+        self.get_lazy_object_in_levels(
+            file_index,
+            max_loads,
+            usize::MAX,
+            &levels,
+            &mut HashSet::new(),
+            is_level_0,
+        )
+    }
+
+    /// Reads the fixed size fields of a node, without loading its prop,
+    /// links or neighbors, nor adding it to the registry
+    pub fn get_node_header(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<NodeHeader, BufIoError> {
+        let FileIndex::Valid {
+            offset,
+            version_number,
+            version_id,
+        } = file_index
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot read a node header from an invalid file index",
+            )
+            .into());
+        };
+        let bufman = if is_level_0 {
+            self.level_0_bufmans.get(version_id)?
+        } else {
+            self.bufmans.get(version_id)?
+        };
+        let cursor = bufman.open_cursor()?;
+        bufman.seek_with_cursor(cursor, offset.0 as u64)?;
+        let hnsw_level = HNSWLevel(bufman.read_u8_with_cursor(cursor)?);
+        let prop_location = (
+            FileOffset(bufman.read_u32_with_cursor(cursor)?),
+            BytesToRead(bufman.read_u32_with_cursor(cursor)?),
+        );
+        let read_link = || -> Result<FileIndex, BufIoError> {
+            let offset = bufman.read_u32_with_cursor(cursor)?;
+            let version_number = bufman.read_u16_with_cursor(cursor)?;
+            let version_id = Hash::from(bufman.read_u32_with_cursor(cursor)?);
+            Ok(if offset == u32::MAX {
+                FileIndex::Invalid
+            } else {
+                FileIndex::Valid {
+                    offset: FileOffset(offset),
+                    version_number,
+                    version_id,
+                }
+            })
+        };
+        let parent = read_link()?;
+        let child = read_link()?;
+        let root_version = read_link()?;
+        let layout = NodeLayout::from_len(bufman.read_u16_with_cursor(cursor)?);
+        bufman.close_cursor(cursor)?;
+
+        Ok(NodeHeader {
+            hnsw_level,
+            prop_location,
+            version_id,
+            version_number,
+            parent,
+            child,
+            root_version,
+            neighbors_count: layout.neighbors_len as u16,
+            neighbor_encoding: layout.encoding,
+        })
+    }
+
+    /// L2 norm of the node's vector, as stored with its prop at ingest, see
+    /// `Storage::norm`. Only the prop is loaded for a node that isn't
+    /// cached, and the node isn't added to the registry.
+    pub fn get_norm(&self, file_index: FileIndex, is_level_0: bool) -> Result<f32, BufIoError> {
+        let combined_index = self.index_key(&file_index, is_level_0);
+        if let Some(data) = self
+            .registry
+            .peek(&combined_index)
+            .and_then(|item| unsafe { &*item.load() }.get_lazy_data())
+        {
+            return Ok(data.prop.value.norm());
+        }
+        let (offset, length) = self.get_node_header(file_index, is_level_0)?.prop_location;
+        Ok(self.get_prop(offset, length)?.value.norm())
+    }
+
+    /// Same as `get_object`, but for one-off accesses such as full scans,
+    /// which would otherwise push the working set out of the cache
+    ///
+    /// A cached node is returned without updating its recency, and a node
+    /// that isn't cached is loaded without being added to the cache. Its
+    /// neighbors are linked to the cached nodes if present, and are left
+    /// pending otherwise.
+    pub fn peek_object(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<SharedNode, BufIoError> {
+        let combined_index = self.index_key(&file_index, is_level_0);
+        if let Some(item) = self.registry.peek(&combined_index).map(|item| item.load()) {
+            return Ok(item);
+        }
+
+        let FileIndex::Valid {
+            offset: file_offset,
+            version_number,
+            version_id,
+        } = file_index
+        else {
+            return Ok(ProbLazyItem::new_pending(file_index, is_level_0));
+        };
+        let bufmans = if is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        };
+        let mut skipm = HashSet::from([combined_index]);
+        let data = ProbNode::deserialize(
+            bufmans,
+            file_index,
+            self,
+            0,
+            usize::MAX,
+            &ALL_LEVELS,
+            &mut skipm,
+            is_level_0,
+        )?;
+        let state = ProbLazyItemState::Ready(ReadyState {
+            data,
+            file_offset,
+            version_id,
+            version_number,
+        });
+        Ok(ProbLazyItem::new_from_state(state, is_level_0))
+    }
+
+    /// Preloads the HNSW entry point and its direct neighbors on every
+    /// level into the registry, so the first query doesn't have to load
+    /// them from disk.
+    ///
+    /// `entry` is the top level entry node (see `DenseIndex::root_vec_offset`),
+    /// the levels below it are reached by following its child links.
+    pub fn warm_entry_points(&self, entry: FileIndex) -> Result<(), BufIoError> {
+        let mut current = Some((entry, false));
+        while let Some((file_index, is_level_0)) = current.take() {
+            // `max_loads = 1` loads just the node itself, its links are
+            // resolved explicitly below
+            let node =
+                self.get_lazy_object(file_index, 1, usize::MAX, &mut HashSet::new(), is_level_0)?;
+            let data = unsafe { &*node }.try_get_data(self)?;
+
+            for neighbor in data.get_neighbors_raw().iter() {
+                let Some((_, neighbor_node, _)) =
+                    (unsafe { neighbor.load(Ordering::Acquire).as_ref() })
+                else {
+                    continue;
+                };
+                let neighbor_node = unsafe { &**neighbor_node };
+                self.get_lazy_object(
+                    neighbor_node.get_file_index(),
+                    1,
+                    usize::MAX,
+                    &mut HashSet::new(),
+                    neighbor_node.is_level_0,
+                )?;
+            }
+
+            if let Some(child) = unsafe { data.get_child().as_ref() } {
+                current = Some((child.get_file_index(), child.is_level_0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads up to `budget` nodes reachable from `roots` through their
+    /// neighbors, highest degree first, so the hubs that give early queries
+    /// the most connectivity are in the cache before anything else.
+    ///
+    /// Degrees are peeked from the neighbor slots on disk, so only the
+    /// nodes that make the budget are actually loaded. Returns the loaded
+    /// nodes in the order they were loaded.
+    pub fn warm_by_degree(
+        &self,
+        roots: &[(FileIndex, bool)],
+        budget: usize,
+    ) -> Result<Vec<FileIndex>, BufIoError> {
+        let mut candidates = Vec::new();
+        // Ties are broken by discovery order, which keeps it a BFS among
+        // nodes of equal degree
+        let mut queue = BinaryHeap::new();
+        let mut seen = HashSet::new();
+        let mut loaded = Vec::new();
+
+        for &(file_index, is_level_0) in roots {
+            if seen.insert(self.index_key(&file_index, is_level_0)) {
+                let neighbors = self.peek_neighbors(file_index, is_level_0)?;
+                queue.push((neighbors.len(), Reverse(candidates.len())));
+                candidates.push((file_index, is_level_0, neighbors));
+            }
+        }
+
+        while loaded.len() < budget {
+            let Some((_, Reverse(idx))) = queue.pop() else {
+                break;
+            };
+            let (file_index, is_level_0, ref mut neighbors) = candidates[idx];
+            let neighbors = std::mem::take(neighbors);
+            self.get_lazy_object(file_index, 1, usize::MAX, &mut HashSet::new(), is_level_0)?;
+            loaded.push(file_index);
+
+            for neighbor in neighbors {
+                if seen.insert(self.index_key(&neighbor, is_level_0)) {
+                    let neighbors = self.peek_neighbors(neighbor, is_level_0)?;
+                    queue.push((neighbors.len(), Reverse(candidates.len())));
+                    candidates.push((neighbor, is_level_0, neighbors));
+                }
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Reads the file indices of the node's neighbors straight from its
+    /// neighbor slots on disk, skipping the empty ones, without loading
+    /// the node.
+    fn peek_neighbors(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<Vec<FileIndex>, BufIoError> {
+        let bytes = self.read_node_bytes(file_index, is_level_0)?;
+        self.neighbor_locations(&bytes)
+    }
+
+    // Locations of the neighbors of a serialized node, skipping the empty
+    // slots. Inline neighbors are looked up by id, see `locate`, and are
+    // skipped if their location isn't known.
+    fn neighbor_locations(&self, node: &[u8]) -> Result<Vec<FileIndex>, BufIoError> {
+        let layout = NodeLayout::of(node);
+        let mut locations = Vec::with_capacity(layout.neighbors_len);
+        for i in 0..layout.neighbors_len {
+            let location = match layout.neighbor_link(i) {
+                Some(at) => Self::read_link(node, at),
+                None => {
+                    let slot = layout.neighbor_slot(i);
+                    let id = u32::from_le_bytes(node[slot..slot + 4].try_into().unwrap());
+                    if id == u32::MAX {
+                        None
+                    } else {
+                        self.locate(VectorId(id as u64))?
+                    }
+                }
+            };
+            locations.extend(location);
+        }
+        Ok(locations)
+    }
+
+    // Reads the link at `at` of a serialized node, an offset (4), version
+    // number (2) and version id (4), `None` for an empty link
+    fn read_link(node: &[u8], at: usize) -> Option<FileIndex> {
+        let offset = u32::from_le_bytes(node[at..at + 4].try_into().unwrap());
+        (offset != u32::MAX).then(|| FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: u16::from_le_bytes([node[at + 4], node[at + 5]]),
+            version_id: Hash::from(u32::from_le_bytes(
+                node[at + 6..at + 10].try_into().unwrap(),
+            )),
+        })
+    }
+
+    /// Reports the loaded nodes with more than `threshold` versions linked
+    /// to them, longest chain first, e.g. to decide when to compact. Nodes
+    /// that are themselves versions of another node are reported too if
+    /// their own chain is long enough.
+    pub fn long_version_chains(&self, threshold: usize) -> Vec<(FileIndex, bool, usize)> {
+        let mut chains: Vec<_> = self
+            .registry
+            .values()
+            .filter_map(|node| {
+                let node = unsafe { &*node.load() };
+                let chain_len = node.version_chain_len();
+                (chain_len > threshold).then(|| (node.get_file_index(), node.is_level_0, chain_len))
+            })
+            .collect();
+        chains.sort_unstable_by_key(|(_, _, chain_len)| Reverse(*chain_len));
+        chains
+    }
+
+    /// Lists the `(version_id, offset, is_level_0)` of every node that's
+    /// loaded in the registry, e.g. to hand the hot set over to a new
+    /// process during a restart. See `warm_from_manifest`.
+    pub fn export_manifest(&self) -> Vec<(Hash, u32, bool)> {
+        let mut manifest: Vec<_> = self
+            .registry
+            .values()
+            .filter_map(|node| {
+                let node = unsafe { &*node.load() };
+                if !node.is_ready() {
+                    return None;
+                }
+                match node.get_file_index() {
+                    FileIndex::Valid {
+                        offset, version_id, ..
+                    } => Some((version_id, offset.0, node.is_level_0)),
+                    FileIndex::Invalid => None,
+                }
+            })
+            .collect();
+        manifest.sort_unstable_by_key(|(version_id, offset, is_level_0)| {
+            (**version_id, *offset, *is_level_0)
+        });
+        manifest
+    }
+
+    /// Loads the nodes listed by `export_manifest` into the registry.
+    ///
+    /// The manifest doesn't carry version numbers, `version_number`
+    /// resolves them from the version ids, e.g. through `VersionControl`.
+    pub fn warm_from_manifest(
+        &self,
+        manifest: &[(Hash, u32, bool)],
+        version_number: impl Fn(Hash) -> u16,
+    ) -> Result<(), BufIoError> {
+        for &(version_id, offset, is_level_0) in manifest {
+            let file_index = FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_number: version_number(version_id),
+                version_id,
+            };
+            self.get_lazy_object(file_index, 1, usize::MAX, &mut HashSet::new(), is_level_0)?;
+        }
+        Ok(())
+    }
+
+    /// Packs the version id into the lower 32 bits of the key, the offset
+    /// into the 31 bits above it and the level-0 flag into the top bit, so
+    /// offsets must stay below 2^31. `u64::MAX` is reserved for
+    /// `FileIndex::Invalid`.
+    pub fn combine_index(file_index: &FileIndex, is_level_0: bool) -> u64 {
+        let level_bit = if is_level_0 { 1u64 << 63 } else { 0 };
+        match file_index {
+            FileIndex::Valid {
+                offset, version_id, ..
+            } => {
+                debug_assert!(
+                    offset.0 < 1 << 31,
+                    "offset {} overlaps the level-0 bit",
+                    offset.0
+                );
+                let key = (u64::from(offset.0) << 32) | u64::from(**version_id) | level_bit;
+                debug_assert_ne!(key, u64::MAX, "key collides with FileIndex::Invalid");
+                key
+            }
+            FileIndex::Invalid => u64::MAX, // Use max u64 value for Invalid
+        }
+    }
+
+    /// Hashes the offset, version id and level-0 flag into the key, so
+    /// unlike `combine_index` it works for any offset. `u64::MAX` is
+    /// reserved for `FileIndex::Invalid`.
+    pub fn hash_index(file_index: &FileIndex, is_level_0: bool) -> u64 {
+        match file_index {
+            FileIndex::Valid {
+                offset, version_id, ..
+            } => {
+                let mut hasher = SipHasher24::new();
+                hasher.write_u32(offset.0);
+                hasher.write_u32(**version_id);
+                hasher.write_u8(is_level_0 as u8);
+                hasher.finish().min(u64::MAX - 1)
+            }
+            FileIndex::Invalid => u64::MAX,
+        }
+    }
+
+    /// Registry key of the node at `file_index`, according to the cache's
+    /// `RegistryKeyScheme`.
+    pub fn index_key(&self, file_index: &FileIndex, is_level_0: bool) -> u64 {
+        match self.key_scheme {
+            RegistryKeyScheme::Packed => Self::combine_index(file_index, is_level_0),
+            RegistryKeyScheme::Hashed => Self::hash_index(file_index, is_level_0),
+        }
+    }
+
+    /// Exclusive upper bound of the node offsets the registry keys can
+    /// tell apart.
+    fn max_node_offset(&self) -> u64 {
+        match self.key_scheme {
+            RegistryKeyScheme::Packed => 1 << 31,
+            RegistryKeyScheme::Hashed => 1 << 32,
+        }
+    }
+
+    /// Packs the offset into the upper and the length into the lower 32
+    /// bits of the key; both halves are 32-bit by type, so every location
+    /// maps to a distinct key.
+    pub fn get_prop_key(
+        FileOffset(file_offset): FileOffset,
+        BytesToRead(length): BytesToRead,
+    ) -> u64 {
+        const _: () = assert!(std::mem::size_of::<FileOffset>() == 4);
+        const _: () = assert!(std::mem::size_of::<BytesToRead>() == 4);
+        (u64::from(file_offset) << 32) | u64::from(length)
+    }
+
+    /// Returns true if the cache holds no nodes, neither resident nor in
+    /// any index file on disk, so queries have nothing to traverse.
+    ///
+    /// Once nodes are found, the answer is kept: nodes are only ever moved
+    /// between files, never dropped. An empty cache is checked again on
+    /// each call, as nodes may be written to its files directly.
+    pub fn is_empty(&self) -> Result<bool, BufIoError> {
+        if self.has_nodes.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let has_nodes = !self.registry.is_empty() || self.has_node_files()?;
+        if has_nodes {
+            self.has_nodes.store(true, Ordering::Relaxed);
+        }
+        Ok(!has_nodes)
+    }
+
+    // Whether any index file on disk holds nodes
+    fn has_node_files(&self) -> Result<bool, BufIoError> {
+        for version_id in self.list_versions()? {
+            for bufmans in [&self.bufmans, &self.level_0_bufmans] {
+                // Don't create the file of the other level as a side effect
+                if !bufmans.file_path(&version_id).exists() {
+                    continue;
+                }
+                if bufmans.get(version_id)?.file_size() > 0 {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Lists the ids of all versions that have an index file on disk,
+    /// covering both level-0 (`{hash}_0.index`) and higher level
+    /// (`{hash}.index`) files.
+    ///
+    /// Files that don't follow this naming scheme are skipped. The ids
+    /// are deduplicated and ordered by the version number they're
+    /// registered for, see `register_version`. Ids not registered for
+    /// any version number, e.g. the continuations of rolled over files,
+    /// come last, ordered by their hash value.
+    pub fn list_versions(&self) -> Result<Vec<Hash>, BufIoError> {
+        let mut ids = Vec::new();
+        for bufmans in [&self.bufmans, &self.level_0_bufmans] {
+            for entry in fs::read_dir(bufmans.root_path())? {
+                let file_name = entry?.file_name();
+                let Some(stem) = file_name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".index"))
+                else {
+                    continue;
+                };
+                let id = stem.strip_suffix("_0").unwrap_or(stem);
+                if let Ok(id) = id.parse::<u32>() {
+                    ids.push(id);
+                }
+            }
+        }
+        let mut version_numbers = HashMap::new();
+        self.with_version_ids(|version_ids| {
+            for (&version_number, version_id) in version_ids {
+                let min_number = version_numbers
+                    .entry(**version_id)
+                    .or_insert(version_number);
+                *min_number = (*min_number).min(version_number);
+            }
+        })?;
+        ids.sort_unstable_by_key(|id| {
+            (version_numbers.get(id).map_or(u32::MAX, |&n| n as u32), *id)
+        });
+        ids.dedup();
+        Ok(ids.into_iter().map(Hash::from).collect())
+    }
+
+    /// Returns the range of HNSW levels stored in the index files of a
+    /// version, so callers don't have to track `is_level_0` out of band.
+    ///
+    /// The range is read from the headers `append_node` keeps up to date,
+    /// combining those of the level-0 and the higher level file, along with
+    /// the files they were rolled over to; the range of a single file is
+    /// the `level_info` of its header, see `read_header`. Fails with
+    /// `NotFound` if no node was appended to the version.
+    pub fn file_level(&self, version_id: Hash) -> Result<LevelInfo, BufIoError> {
+        let mut range: Option<LevelInfo> = None;
+        for is_level_0 in [true, false] {
+            for version_id in self.version_chain(version_id, is_level_0)? {
+                let Some(info) = self
+                    .header(version_id, is_level_0)?
+                    .and_then(|header| header.level_info)
+                else {
+                    continue;
+                };
+                let range_with_min = LevelInfo::including(range, info.min_level);
+                range = Some(LevelInfo::including(Some(range_with_min), info.max_level));
+            }
+        }
+        range.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No nodes found for version {}", *version_id),
+            )
+            .into()
+        })
+    }
+
+    /// Returns the edges of the HNSW graph stored in the index files of a
+    /// version, as `(node_id, neighbor_id)` pairs sorted by node id, e.g.
+    /// for analysis and visualization.
+    ///
+    /// Like `file_level`, this walks the nodes of both the level-0 and the
+    /// higher level file, along with the files they were rolled over to.
+    /// Neighbor ids are read from the serialized neighbor lists, so only
+    /// the props are loaded, to get the ids of the nodes themselves. An
+    /// edge linking two nodes in both directions is returned as two pairs,
+    /// while one present on several levels is returned once.
+    pub fn export_edges(&self, version_id: Hash) -> Result<Vec<(u32, u32)>, BufIoError> {
+        let mut edges = BTreeSet::new();
+        for is_level_0 in [true, false] {
+            let bufmans = self.version_bufmans(is_level_0);
+            for version_id in self.version_chain(version_id, is_level_0)? {
+                if !bufmans.file_path(&version_id).exists() {
+                    continue;
+                }
+                let alignment = self.file_node_alignment(version_id, is_level_0)? as usize;
+                let bufman = bufmans.get(version_id)?;
+                let mut bytes = vec![0; bufman.file_size() as usize];
+                let cursor = bufman.open_cursor()?;
+                bufman.read_with_cursor(cursor, &mut bytes)?;
+                bufman.close_cursor(cursor)?;
+
+                let read_u32 =
+                    |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+                let mut offset = 0;
+                while offset + NodeLayout::HEADER_SIZE <= bytes.len() {
+                    let layout = NodeLayout::of(&bytes[offset..]);
+                    let size = layout.size();
+                    if offset + size > bytes.len() {
+                        return Err(BufIoError::Corrupt {
+                            context: "truncated node",
+                            offset: offset as u64,
+                        });
+                    }
+                    let prop = self.get_prop(
+                        FileOffset(read_u32(offset + 1)),
+                        BytesToRead(read_u32(offset + 5)),
+                    )?;
+                    let node_id = prop.id.0 as u32;
+                    // Neighbor slots start with the neighbor's id, empty ones
+                    // have a `u32::MAX` offset when indexed and id when inline
+                    for i in 0..layout.neighbors_len {
+                        let slot = offset + layout.neighbor_slot(i);
+                        let empty = match layout.neighbor_link(i) {
+                            Some(link) => read_u32(offset + link) == u32::MAX,
+                            None => read_u32(slot) == u32::MAX,
+                        };
+                        if !empty {
+                            edges.insert((node_id, read_u32(slot)));
+                        }
+                    }
+                    offset += size.next_multiple_of(alignment);
+                }
+            }
+        }
+        Ok(edges.into_iter().collect())
+    }
+
+    /// Fraction of the bytes of a version's index files that hold nodes
+    /// reachable from `roots`, the rest being nodes left behind by updates,
+    /// e.g. to decide whether compacting the version is worthwhile
+    ///
+    /// `roots` are upper level nodes, such as the entry point (see
+    /// `DenseIndex::root_vec_offset`). The graph is walked through the
+    /// links read from disk without loading any node, following links
+    /// into other versions' files too, but only the nodes stored in this
+    /// version's level-0 and higher level files are counted, each padded
+    /// to its stride. A version without any bytes is fully live.
+    pub fn fragmentation(&self, version_id: Hash, roots: &[FileIndex]) -> Result<f64, BufIoError> {
+        let mut total_bytes = 0;
+        for is_level_0 in [true, false] {
+            let bufmans = self.version_bufmans(is_level_0);
+            if bufmans.file_path(&version_id).exists() {
+                total_bytes += bufmans.get(version_id)?.file_size();
+            }
+        }
+        if total_bytes == 0 {
+            return Ok(1.0);
+        }
+
+        let alignments = [
+            self.file_node_alignment(version_id, false)?,
+            self.file_node_alignment(version_id, true)?,
+        ];
+        let mut live_bytes = 0;
+        self.walk_links(roots, |file_index, is_level_0, bytes| {
+            if file_index.get_version_id() == Some(version_id) {
+                let alignment = alignments[is_level_0 as usize];
+                live_bytes += (bytes.len() as u32).next_multiple_of(alignment) as u64;
+            }
+        })?;
+        Ok(live_bytes as f64 / total_bytes as f64)
+    }
+
+    // Walks the graph from the upper level `roots` through the links read
+    // from disk, without loading any node, following links into other
+    // versions' files too. Each node reached is passed once to `visit`,
+    // along with whether it's a level-0 one and its serialized bytes.
+    fn walk_links(
+        &self,
+        roots: &[FileIndex],
+        mut visit: impl FnMut(FileIndex, bool, &[u8]),
+    ) -> Result<(), BufIoError> {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<_> = roots.iter().map(|root| (*root, false)).collect();
+        while let Some((file_index, is_level_0)) = stack.pop() {
+            if file_index == FileIndex::Invalid {
+                continue;
+            }
+            if !seen.insert(self.index_key(&file_index, is_level_0)) {
+                continue;
+            }
+            let bytes = self.read_node_bytes(file_index, is_level_0)?;
+            visit(file_index, is_level_0, &bytes);
+
+            let level = bytes[0];
+            let layout = NodeLayout::of(&bytes);
+            // Parent, child and root version links follow the prop, then
+            // the neighbor slots and the 8 version links
+            let links = [(9, false), (19, level == 1), (29, is_level_0)]
+                .into_iter()
+                .chain((0..8).map(|i| (layout.version_link(i), is_level_0)));
+            for (at, link_is_level_0) in links {
+                if let Some(link) = Self::read_link(&bytes, at) {
+                    stack.push((link, link_is_level_0));
+                }
+            }
+            for link in self.neighbor_locations(&bytes)? {
+                stack.push((link, is_level_0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the nodes of a version's level-0 or higher level file that
+    /// can't be reached from `roots`, i.e. nodes written but never linked
+    /// into the graph, or left behind by updates
+    ///
+    /// The graph is walked as in `fragmentation`. The offsets of the
+    /// unreachable nodes are returned in file order.
+    pub fn find_orphans(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+        roots: &[FileIndex],
+    ) -> Result<Vec<FileOffset>, BufIoError> {
+        let bufmans = self.version_bufmans(is_level_0);
+        if !bufmans.file_path(&version_id).exists() {
+            return Ok(Vec::new());
+        }
+        let mut reachable = HashSet::new();
+        self.walk_links(roots, |file_index, node_is_level_0, _| {
+            if let FileIndex::Valid {
+                offset,
+                version_id: node_version_id,
+                ..
+            } = file_index
+            {
+                if node_version_id == version_id && node_is_level_0 == is_level_0 {
+                    reachable.insert(offset);
+                }
+            }
+        })?;
+
+        let alignment = self.file_node_alignment(version_id, is_level_0)?;
+        let bufman = bufmans.get(version_id)?;
+        let file_size = bufman.file_size();
+        let cursor = bufman.open_cursor()?;
+        let mut orphans = Vec::new();
+        let mut offset = 0;
+        // The node size follows from the neighbors length, which comes
+        // after the 39 bytes of node header
+        while offset + NodeLayout::HEADER_SIZE as u64 <= file_size {
+            bufman.seek_with_cursor(cursor, offset + 39)?;
+            let layout = NodeLayout::from_len(bufman.read_u16_with_cursor(cursor)?);
+            if !reachable.contains(&FileOffset(offset as u32)) {
+                orphans.push(FileOffset(offset as u32));
+            }
+            offset += (layout.size() as u64).next_multiple_of(alignment as u64);
+        }
+        bufman.close_cursor(cursor)?;
+        Ok(orphans)
+    }
+
+    /// Reads the node at `file_index` exactly as it's serialized on disk,
+    /// e.g. to ship it to a replica without serializing it again. See
+    /// `write_node_bytes` for the replica side.
+    pub fn read_node_bytes(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<Vec<u8>, BufIoError> {
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot read the bytes of a node with an invalid FileIndex",
+            )
+            .into());
+        };
+        let bufman = self.version_bufmans(is_level_0).get(version_id)?;
+        let cursor = bufman.open_cursor()?;
+        // The node size follows from the neighbors length, which comes
+        // after the 39 bytes of node header
+        bufman.seek_with_cursor(cursor, offset as u64 + 39)?;
+        let layout = NodeLayout::from_len(bufman.read_u16_with_cursor(cursor)?);
+        let mut bytes = vec![0; layout.size()];
+        bufman.seek_with_cursor(cursor, offset as u64)?;
+        let bytes_read = bufman.read_with_cursor(cursor, &mut bytes)?;
+        bufman.close_cursor(cursor)?;
+        if bytes_read != bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Node at offset {} of version {} is truncated",
+                    offset, *version_id
+                ),
+            )
+            .into());
+        }
+        Ok(bytes)
+    }
+
+    /// Writes node bytes obtained from `read_node_bytes` at `file_index`,
+    /// dropping any cached copy of the node at that location.
+    ///
+    /// The bytes refer to the node's prop, neighbors and other links by
+    /// their location, so these have to be replicated to the same
+    /// locations as well for the node to be loadable.
+    pub fn write_node_bytes(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+        bytes: &[u8],
+    ) -> Result<(), BufIoError> {
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot write the bytes of a node with an invalid FileIndex",
+            )
+            .into());
+        };
+        let node_size = bytes
+            .get(..NodeLayout::HEADER_SIZE)
+            .map(|header| NodeLayout::of(header).size());
+        let alignment = self.file_node_alignment(version_id, is_level_0)? as usize;
+        if node_size != Some(bytes.len())
+            || offset as usize % bytes.len().next_multiple_of(alignment) != 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes at offset {} are not a serialized node",
+                    bytes.len(),
+                    offset
+                ),
+            )
+            .into());
+        }
+        let bufman = self.version_bufmans(is_level_0).get(version_id)?;
+        let cursor = bufman.open_cursor()?;
+        bufman.seek_with_cursor(cursor, offset as u64)?;
+        bufman.update_with_cursor(cursor, bytes)?;
+        bufman.close_cursor(cursor)?;
+        self.evict(file_index, is_level_0);
+        Ok(())
+    }
+
+    // Offsets and hashes of the nodes of a version file, in offset order
+    fn merkle_leaves(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<Vec<(u32, [u8; 32])>, BufIoError> {
+        let alignment = self.file_node_alignment(version_id, is_level_0)? as usize;
+        let bufman = self.version_bufmans(is_level_0).get(version_id)?;
+        let mut bytes = vec![0; bufman.file_size() as usize];
+        let cursor = bufman.open_cursor()?;
+        bufman.read_with_cursor(cursor, &mut bytes)?;
+        bufman.close_cursor(cursor)?;
+
+        let mut leaves = Vec::new();
+        let mut offset = 0;
+        while offset + NodeLayout::HEADER_SIZE <= bytes.len() {
+            let size = NodeLayout::of(&bytes[offset..]).size();
+            if offset + size > bytes.len() {
+                return Err(BufIoError::Corrupt {
+                    context: "truncated node",
+                    offset: offset as u64,
+                });
+            }
+            leaves.push((offset as u32, merkle_leaf(&bytes[offset..offset + size])));
+            offset += size.next_multiple_of(alignment);
+        }
+        Ok(leaves)
+    }
+
+    /// Root of a Merkle tree over the nodes of the version file, hashed
+    /// with SHA-256 in offset order. Replicas holding the same nodes at
+    /// the same offsets have the same root, so comparing roots, and the
+    /// proofs of `merkle_proof` below them, tells which nodes differ.
+    pub fn merkle_root(&self, version_id: Hash, is_level_0: bool) -> Result<[u8; 32], BufIoError> {
+        let leaves = self.merkle_leaves(version_id, is_level_0)?;
+        if leaves.is_empty() {
+            return Ok(Sha256::digest(b"").into());
+        }
+        let levels = merkle_levels(leaves.into_iter().map(|(_, hash)| hash).collect());
+        Ok(levels.last().unwrap()[0])
+    }
+
+    /// Proof that the node at `offset` is part of the tree of
+    /// `merkle_root`. Fails with `NotFound` if no node starts at `offset`.
+    pub fn merkle_proof(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+        offset: FileOffset,
+    ) -> Result<MerkleProof, BufIoError> {
+        let leaves = self.merkle_leaves(version_id, is_level_0)?;
+        let Ok(mut index) = leaves.binary_search_by_key(&offset.0, |&(offset, _)| offset) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No node at offset {} of version {}", offset.0, *version_id),
+            )
+            .into());
+        };
+        let leaf = leaves[index].1;
+        let levels = merkle_levels(leaves.into_iter().map(|(_, hash)| hash).collect());
+        let mut siblings = Vec::new();
+        for level in &levels[..levels.len() - 1] {
+            let sibling = index ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                siblings.push((*hash, sibling < index));
+            }
+            index /= 2;
+        }
+        Ok(MerkleProof { leaf, siblings })
+    }
+
+    /// Appends the nodes of the `sources` version files, along with the
+    /// files they were rolled over to, to the files of `dst`, in the order
+    /// the sources are given, and rewrites the links (parent, child, root
+    /// version, neighbors and versions) that point into the merged files
+    /// to the new locations, so the merged graph can be traversed from
+    /// `dst` alone. The merged nodes are laid out with the alignment of
+    /// `dst`, and its header is updated with their levels.
+    ///
+    /// Version numbers are kept as is, only the file locations change.
+    /// The source files are left untouched, the location index is updated
+    /// to the merged nodes.
+    pub fn merge_versions(&self, sources: &[Hash], dst: Hash) -> Result<(), BufIoError> {
+        if sources.contains(&dst) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot merge version {} into itself", *dst),
+            )
+            .into());
+        }
+        let _append_guard = self.append_lock.lock().unwrap();
+
+        // (version id, is level 0, old offset) -> new offset
+        let mut relocations: HashMap<(u32, bool, u32), u32> = HashMap::new();
+        let mut merged = Vec::new();
+        for is_level_0 in [false, true] {
+            let mut node_size = None;
+            let mut nodes = Vec::new();
+            for &source in sources {
+                for version_id in self.version_chain(source, is_level_0)? {
+                    let mut unexpected_size = None;
+                    self.for_each_node(version_id, is_level_0, |offset, node| {
+                        if *node_size.get_or_insert(node.len()) != node.len() {
+                            unexpected_size.get_or_insert(offset);
+                        }
+                        nodes.push((version_id, offset, node.to_vec()));
+                    })?;
+                    if let Some(offset) = unexpected_size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Unexpected node size at offset {} of version {}",
+                                offset, *version_id
+                            ),
+                        )
+                        .into());
+                    }
+                }
+            }
+            let Some(node_size) = node_size else {
+                continue;
+            };
+            let alignment = self.file_node_alignment(dst, is_level_0)?;
+            let node_stride = node_size.next_multiple_of(alignment as usize);
+            let dst_bufman = self.version_bufmans(is_level_0).get(dst)?;
+            let base = dst_bufman.file_size().div_ceil(node_stride as u64) * node_stride as u64;
+            // Packed registry keys only have room for 31-bit offsets, see
+            // `combine_index`
+            if base + (nodes.len() * node_stride) as u64 > self.max_node_offset() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Merged file of version {} exceeds the registry key range",
+                        *dst
+                    ),
+                )
+                .into());
+            }
+            for (i, (version_id, old_offset, _)) in nodes.iter().enumerate() {
+                relocations.insert(
+                    (**version_id, is_level_0, *old_offset),
+                    (base + (i * node_stride) as u64) as u32,
+                );
+            }
+            merged.push((is_level_0, node_size, alignment, base, nodes));
+        }
+
+        // Each link is a 4 byte offset, a 2 byte version number and a 4
+        // byte version id
+        let relink = |node: &mut [u8], at: usize, is_level_0: bool| {
+            let offset = u32::from_le_bytes(node[at..at + 4].try_into().unwrap());
+            let version_id = u32::from_le_bytes(node[at + 6..at + 10].try_into().unwrap());
+            if let Some(new_offset) = relocations.get(&(version_id, is_level_0, offset)) {
+                node[at..at + 4].copy_from_slice(&new_offset.to_le_bytes());
+                node[at + 6..at + 10].copy_from_slice(&dst.to_le_bytes());
+            }
+        };
+        for (is_level_0, node_size, alignment, base, nodes) in merged {
+            let node_stride = node_size.next_multiple_of(alignment as usize);
+            let mut bytes = vec![0; (nodes.len() - 1) * node_stride + node_size];
+            let mut level_info = None;
+            for (i, (_, _, mut node)) in nodes.into_iter().enumerate() {
+                let hnsw_level = node[0];
+                let layout = NodeLayout::of(&node);
+                relink(&mut node, 9, false);
+                relink(&mut node, 19, hnsw_level == 1);
+                relink(&mut node, 29, is_level_0);
+                // Inline neighbors are found through the locations logged
+                // below instead
+                for i in 0..layout.neighbors_len {
+                    if let Some(at) = layout.neighbor_link(i) {
+                        relink(&mut node, at, is_level_0);
+                    }
+                }
+                for i in 0..8 {
+                    relink(&mut node, layout.version_link(i), is_level_0);
+                }
+                bytes[i * node_stride..i * node_stride + node_size].copy_from_slice(&node);
+                level_info = Some(LevelInfo::including(level_info, HNSWLevel(hnsw_level)));
+            }
+
+            let bufman = self.version_bufmans(is_level_0).get(dst)?;
+            let cursor = bufman.open_cursor()?;
+            bufman.seek_with_cursor(cursor, base)?;
+            bufman.update_with_cursor(cursor, &bytes)?;
+            bufman.close_cursor(cursor)?;
+            self.update_header(dst, is_level_0, |header| {
+                if header.node_size == 0 {
+                    header.node_size = node_size as u32;
+                    header.node_alignment = alignment;
+                }
+                let level_info = level_info.unwrap();
+                let with_min = LevelInfo::including(header.level_info, level_info.min_level);
+                header.level_info =
+                    Some(LevelInfo::including(Some(with_min), level_info.max_level));
+                header.checksum = None;
+            })?;
+        }
+
+        let mut log = self.location_log()?;
+        for mut location in self.id_index.iter_mut() {
+            let (version_id, version_number, FileOffset(offset)) = *location;
+            if let Some(&new_offset) = relocations.get(&(*version_id, true, offset)) {
+                *location = (dst, version_number, FileOffset(new_offset));
+                Self::log_location(&mut log, location.key(), Some(*location))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a snapshot of the `versions` files, along with the files they
+    /// were rolled over to, which `compact` won't remove until the
+    /// snapshot, and every clone of it, is dropped.
+    pub fn snapshot(&self, versions: &[Hash]) -> Result<IndexSnapshot, BufIoError> {
+        let mut pinned = Vec::new();
+        for &version_id in versions {
+            for is_level_0 in [false, true] {
+                for version_id in self.version_chain(version_id, is_level_0)? {
+                    if !pinned.contains(&version_id) {
+                        pinned.push(version_id);
+                    }
+                }
+            }
+        }
+        self.version_pins.pin(&pinned);
+        Ok(IndexSnapshot {
+            pins: self.version_pins.clone(),
+            versions: pinned,
+        })
+    }
+
+    /// Merges the `sources` version files, along with the files they were
+    /// rolled over to, into `dst`, see `merge_versions`, then removes the
+    /// merged files and their headers.
+    ///
+    /// Merged files are only removed once nothing links into them anymore:
+    /// files that the other version files on disk, or the nodes resident in
+    /// the cache, still link into are kept, and the removal of the files
+    /// pinned by a snapshot is deferred until the snapshot is dropped.
+    pub fn compact(&self, sources: &[Hash], dst: Hash) -> Result<(), BufIoError> {
+        self.merge_versions(sources, dst)?;
+        let mut merged = HashSet::new();
+        for &source in sources {
+            for is_level_0 in [false, true] {
+                merged.extend(self.version_chain(source, is_level_0)?);
+            }
+        }
+
+        let mut linked = self.resident_link_versions();
+        for version_id in self.list_versions()? {
+            if merged.contains(&version_id) {
+                continue;
+            }
+            for is_level_0 in [false, true] {
+                self.for_each_node(version_id, is_level_0, |_, node| {
+                    linked.extend(Self::node_link_versions(node));
+                })?;
+            }
+        }
+        for version_id in merged {
+            if linked.contains(&version_id) {
+                continue;
+            }
+            self.headers.remove(&(version_id, false));
+            self.headers.remove(&(version_id, true));
+            self.version_pins.retire(version_id)?;
+        }
+        Ok(())
+    }
+
+    // Calls `visit` with the offset and the bytes of each node of the
+    // version file, in file order. Files that don't exist have no nodes.
+    fn for_each_node(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+        mut visit: impl FnMut(u32, &[u8]),
+    ) -> Result<(), BufIoError> {
+        let bufmans = self.version_bufmans(is_level_0);
+        if !bufmans.is_open(&version_id) && !bufmans.file_path(&version_id).exists() {
+            return Ok(());
+        }
+        let alignment = self.file_node_alignment(version_id, is_level_0)? as usize;
+        let bufman = bufmans.get(version_id)?;
+        let mut bytes = vec![0; bufman.file_size() as usize];
+        let cursor = bufman.open_cursor()?;
+        bufman.read_with_cursor(cursor, &mut bytes)?;
+        bufman.close_cursor(cursor)?;
+
+        let mut offset = 0;
+        while offset + NodeLayout::HEADER_SIZE <= bytes.len() {
+            let size = NodeLayout::of(&bytes[offset..]).size();
+            if offset + size > bytes.len() {
+                return Err(BufIoError::Corrupt {
+                    context: "truncated node",
+                    offset: offset as u64,
+                });
+            }
+            visit(offset as u32, &bytes[offset..offset + size]);
+            offset += size.next_multiple_of(alignment);
+        }
+        Ok(())
+    }
+
+    // Version ids of the files the links of a serialized node point into
+    fn node_link_versions(node: &[u8]) -> Vec<Hash> {
+        let layout = NodeLayout::of(node);
+        // Links are a 4 byte offset, `u32::MAX` for no link, a 2 byte
+        // version number and a 4 byte version id. Inline neighbors have no
+        // link, they are found by id through the location index.
+        let neighbor_links = (0..layout.neighbors_len).filter_map(|i| layout.neighbor_link(i));
+        let version_links = (0..8).map(|i| layout.version_link(i));
+        [9, 19, 29]
+            .into_iter()
+            .chain(neighbor_links)
+            .chain(version_links)
+            .filter(|&at| node[at..at + 4] != [u8::MAX; 4])
+            .map(|at| {
+                Hash::from(u32::from_le_bytes(
+                    node[at + 6..at + 10].try_into().unwrap(),
+                ))
+            })
+            .collect()
+    }
+
+    // Version ids of the files the resident nodes are stored in or link
+    // into, as they may still be loaded from there
+    fn resident_link_versions(&self) -> HashSet<Hash> {
+        let mut versions = HashSet::new();
+        let mut add = |item: SharedNode| {
+            if let Some(item) = unsafe { item.as_ref() } {
+                versions.insert(item.get_current_version_id());
+            }
+        };
+        for node in self.registry.values() {
+            let node = node.load();
+            add(node);
+            let Some(data) = (unsafe { &*node }).get_lazy_data() else {
+                continue;
+            };
+            add(data.get_parent());
+            add(data.get_child());
+            add(data.root_version);
+            for neighbor in data.get_neighbors() {
+                add(neighbor);
+            }
+            for i in 0..data.versions.len() {
+                add(data.versions.get(i).unwrap());
+            }
+        }
+        versions
+    }
+
+    /// Returns the version ids of the files holding the nodes appended to
+    /// `version_id`, starting with `version_id` itself and followed by
+    /// the continuation files it was rolled over to, in order.
+    pub fn version_chain(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<Vec<Hash>, BufIoError> {
+        let mut chain = vec![version_id];
+        while let Some(next) = self.continuation(*chain.last().unwrap(), is_level_0)? {
+            if chain.contains(&next) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Rollover chain of version {} loops", *version_id),
+                )
+                .into());
+            }
+            chain.push(next);
+        }
+        Ok(chain)
+    }
+
+    fn continuation(&self, version_id: Hash, is_level_0: bool) -> Result<Option<Hash>, BufIoError> {
+        Ok(self
+            .header(version_id, is_level_0)?
+            .and_then(|header| header.continuation))
+    }
+
+    /// Starts a continuation file for the full `version_id` file and
+    /// records it in the full file's header, returning its version id.
+    /// The id is derived from the full file's and skips the ids in use,
+    /// i.e. registered for a version or having files. The continuation's
+    /// own header records the file it continues, so that the id isn't
+    /// later registered for a version, see `register_version`.
+    fn roll_over(&self, version_id: Hash, is_level_0: bool) -> Result<Hash, BufIoError> {
+        let registered: HashSet<Hash> =
+            self.with_version_ids(|version_ids| version_ids.values().copied().collect())?;
+        let mut attempt = 0u32;
+        let next = loop {
+            let mut hasher = SipHasher24::new();
+            hasher.write_u32(*version_id);
+            hasher.write_u8(is_level_0 as u8);
+            hasher.write_u32(attempt);
+            let candidate = Hash::from(hasher.finish() as u32);
+            let in_use = |bufmans: &BufferManagerFactory<Hash>| {
+                bufmans.is_open(&candidate) || bufmans.file_path(&candidate).exists()
+            };
+            if candidate != version_id
+                && !registered.contains(&candidate)
+                && !in_use(&self.bufmans)
+                && !in_use(&self.level_0_bufmans)
+            {
+                break candidate;
+            }
+            attempt += 1;
+        };
+        self.update_header(next, is_level_0, |header| {
+            header.continues = Some(version_id);
+        })?;
+        self.update_header(version_id, is_level_0, |header| {
+            header.continuation = Some(next);
+        })?;
+        Ok(next)
+    }
+
+    fn version_bufmans(&self, is_level_0: bool) -> &Arc<BufferManagerFactory<Hash>> {
+        if is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        }
+    }
+
+    fn compute_file_checksum(path: &Path) -> Result<u64, BufIoError> {
+        let mut file = File::open(path)?;
+        let mut hasher = Xxh3::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let bytes_read = file.read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buf[..bytes_read]);
+        }
+        Ok(hasher.digest())
+    }
+
+    /// Flushes the version file and stores a digest of its whole content
+    /// in its header, to be checked later by `verify_file_checksum`. The
+    /// digest is dropped again by the next `append_node` to the file.
+    ///
+    /// Does nothing if the version has no such file, e.g. as it has no
+    /// higher level nodes, rather than creating an empty one.
+    pub fn write_file_checksum(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<(), BufIoError> {
+        let bufmans = self.version_bufmans(is_level_0);
+        // Appends in between would leave the digest stale
+        let _append_guard = self.append_lock.lock().unwrap();
+        if bufmans.is_open(&version_id) {
+            bufmans.get(version_id)?.flush()?;
+        } else if !bufmans.file_path(&version_id).exists() {
+            return Ok(());
+        }
+        let checksum = Self::compute_file_checksum(&bufmans.file_path(&version_id))?;
+        self.update_header(version_id, is_level_0, |header| {
+            header.checksum = Some(checksum);
+        })
+    }
+
+    /// Recomputes the digest of the version file on disk and compares it
+    /// with the one stored in its header, without parsing the nodes. Fails
+    /// with `NotFound` if no digest was written for the file.
+    pub fn verify_file_checksum(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<bool, BufIoError> {
+        let stored = self
+            .stored_checksum(version_id, is_level_0)?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No checksum stored for version {}", *version_id),
+                )
+            })?;
+        let path = self.version_bufmans(is_level_0).file_path(&version_id);
+        Ok(Self::compute_file_checksum(&path)? == stored)
+    }
+
+    fn stored_checksum(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<Option<u64>, BufIoError> {
+        Ok(self
+            .header(version_id, is_level_0)?
+            .and_then(|header| header.checksum))
+    }
+
+    /// Copies the version file to `w` as is, and returns the number of
+    /// bytes copied. Writes not yet flushed are flushed first, so they're
+    /// included. If a digest was stored by `write_file_checksum`, the
+    /// copied bytes are checked against it, failing with `InvalidData` on
+    /// a mismatch, after the bytes were written to `w`.
+    pub fn backup_version(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+        w: &mut dyn Write,
+    ) -> Result<u64, BufIoError> {
+        let bufmans = self.version_bufmans(is_level_0);
+        // Don't create the file as a side effect
+        if !bufmans.is_open(&version_id) && !bufmans.file_path(&version_id).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No index file for version {}", *version_id),
+            )
+            .into());
+        }
+        let bufman = bufmans.get(version_id)?;
+        // The stored digest is of the file on disk, which the copy only
+        // matches once the buffered writes reach it
+        bufman.flush()?;
+        let file_size = bufman.file_size();
+        let cursor = bufman.open_cursor()?;
+        let mut hasher = Xxh3::new();
+        let mut copy = || -> Result<u64, BufIoError> {
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut copied = 0;
+            while copied < file_size {
+                let len = (file_size - copied).min(buf.len() as u64) as usize;
+                let bytes_read = bufman.read_with_cursor(cursor, &mut buf[..len])?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..bytes_read]);
+                w.write_all(&buf[..bytes_read])?;
+                copied += bytes_read as u64;
+            }
+            Ok(copied)
+        };
+        let copied = copy();
+        bufman.close_cursor(cursor)?;
+        let copied = copied?;
+
+        if let Some(stored) = self.stored_checksum(version_id, is_level_0)? {
+            if hasher.digest() != stored {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Checksum mismatch in the backup of version {}", *version_id),
+                )
+                .into());
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Reads the metadata of the version file from disk. It's kept up to
+    /// date by `append_node`, `write_file_checksum` and rollovers. Fails
+    /// with `NotFound` if the file has no header, e.g. as no node was
+    /// appended to it yet, and with `InvalidData` if the header is
+    /// malformed.
+    pub fn read_header(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<VersionFileHeader, BufIoError> {
+        let header =
+            VersionFileHeader::from_bytes(&fs::read(self.header_path(version_id, is_level_0))?)?;
+        self.headers.insert((version_id, is_level_0), header);
+        Ok(header)
+    }
+
+    /// Stores the metadata of the version file, replacing any previous
+    /// header.
+    pub fn write_header(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+        header: &VersionFileHeader,
+    ) -> Result<(), BufIoError> {
+        fs::write(self.header_path(version_id, is_level_0), header.encode())?;
+        self.headers.insert((version_id, is_level_0), *header);
+        Ok(())
+    }
+
+    // The header of the version file, or `None` for files without one
+    fn header(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+    ) -> Result<Option<VersionFileHeader>, BufIoError> {
+        if let Some(header) = self.headers.get(&(version_id, is_level_0)) {
+            return Ok(Some(*header));
+        }
+        match self.read_header(version_id, is_level_0) {
+            Ok(header) => Ok(Some(header)),
+            Err(BufIoError::Io(err)) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Applies `update` to the header of the version file, starting from
+    // an empty one if it has none, and writes it back if it changed
+    fn update_header(
+        &self,
+        version_id: Hash,
+        is_level_0: bool,
+        update: impl FnOnce(&mut VersionFileHeader),
+    ) -> Result<(), BufIoError> {
+        let current = self.header(version_id, is_level_0)?;
+        let mut header = current.unwrap_or_else(VersionFileHeader::empty);
+        update(&mut header);
+        if current != Some(header) {
+            self.write_header(version_id, is_level_0, &header)?;
+        }
+        Ok(())
+    }
+
+    fn header_path(&self, version_id: Hash, is_level_0: bool) -> PathBuf {
+        header_path(self.version_bufmans(is_level_0), version_id)
+    }
+
+    pub fn load_item<T: DenseSerialize>(
+        &self,
+        file_index: FileIndex,
+        is_level_0: bool,
+    ) -> Result<T, BufIoError> {
+        let mut skipm: HashSet<u64> = HashSet::new();
+
+        if file_index == FileIndex::Invalid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot deserialize with an invalid FileIndex",
+            )
+            .into());
+        };
+
+        let bufmans = if is_level_0 {
+            &self.level_0_bufmans
+        } else {
+            &self.bufmans
+        };
+
+        T::deserialize(
+            bufmans,
+            file_index,
+            self,
+            1000,
+            usize::MAX,
+            &ALL_LEVELS,
+            &mut skipm,
+            is_level_0,
+        )
+    }
+}
+
+/// Default cap on the number of sparse nodes `InvertedIndexCache` loads
+/// from disk concurrently
+pub const DEFAULT_MAX_IN_FLIGHT_LOADS: usize = 64;
+
+/// Counting semaphore bounding the number of concurrent loads
+struct LoadLimiter {
+    max: usize,
+    // Number of loads in flight, and the highest it has been
+    state: Mutex<(usize, usize)>,
+    released: Condvar,
+}
+
+struct LoadPermit<'a> {
+    limiter: &'a LoadLimiter,
+}
+
+impl LoadLimiter {
+    fn new(max: usize) -> Self {
+        assert!(max > 0, "At least one load must be allowed");
+        Self {
+            max,
+            state: Mutex::new((0, 0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until fewer than `max` loads are in flight
+    fn acquire(&self) -> LoadPermit<'_> {
+        let mut state = self
+            .released
+            .wait_while(self.state.lock().unwrap(), |(in_flight, _)| {
+                *in_flight >= self.max
+            })
+            .unwrap();
+        state.0 += 1;
+        state.1 = state.1.max(state.0);
+        LoadPermit { limiter: self }
+    }
+
+    fn peak(&self) -> usize {
+        self.state.lock().unwrap().1
+    }
+}
+
+impl Drop for LoadPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().0 -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+pub struct InvertedIndexCache {
+    data_registry: LRUCache<u64, AtomicSharedNode<InvertedIndexSparseAnnNodeBasicTSHashmapData>>,
+    sets_registry: LRUCache<u64, AtomicSharedNode<VersionedInvertedFixedSetIndex>>,
+    pub dim_bufman: Arc<BufferManager>,
+    pub data_bufmans: Arc<BufferManagerFactory<u8>>,
+    loading_data: TSHashTable<u64, Arc<Mutex<bool>>>,
+    loading_sets: TSHashTable<u64, Arc<Mutex<bool>>>,
+    pub data_file_parts: u8,
+    // Caps the number of nodes deserialized at once, so that many
+    // concurrent misses don't overwhelm the disk
+    load_limiter: LoadLimiter,
+    // Version log and the versions in it, opened on first use, see
+    // `record_version`
+    versions: RwLock<Option<(File, Vec<Hash>)>>,
+}
+
+impl InvertedIndexCache {
+    pub fn new(
+        dim_bufman: Arc<BufferManager>,
+        data_bufmans: Arc<BufferManagerFactory<u8>>,
+        data_file_parts: u8,
+    ) -> Self {
+        Self::with_max_in_flight_loads(
+            dim_bufman,
+            data_bufmans,
+            data_file_parts,
+            DEFAULT_MAX_IN_FLIGHT_LOADS,
+        )
+    }
+
+    /// Constructs a cache that loads at most `max_in_flight_loads` nodes
+    /// from disk at a time, further loads wait for one of them to finish.
+    pub fn with_max_in_flight_loads(
+        dim_bufman: Arc<BufferManager>,
+        data_bufmans: Arc<BufferManagerFactory<u8>>,
+        data_file_parts: u8,
+        max_in_flight_loads: usize,
+    ) -> Self {
+        let data_registry = LRUCache::with_prob_eviction(100_000_000, 0.03125);
+        let sets_registry = LRUCache::with_prob_eviction(100_000_000, 0.03125);
+
+        Self {
+            data_registry,
+            sets_registry,
+            dim_bufman,
+            data_bufmans,
+            loading_data: TSHashTable::new(16),
+            loading_sets: TSHashTable::new(16),
+            data_file_parts,
+            load_limiter: LoadLimiter::new(max_in_flight_loads),
+            versions: RwLock::new(None),
+        }
+    }
+
+    /// Records `version` as written to after the versions recorded so
+    /// far, unless it's recorded already. The order is kept in a log next
+    /// to the data files, for `doc_count_at` to tell which versions came
+    /// before a given one.
+    pub fn record_version(&self, version: Hash) -> Result<(), BufIoError> {
+        {
+            let versions = self.versions.read().map_err(|_| BufIoError::Locking)?;
+            if let Some((_, versions)) = &*versions {
+                if versions.contains(&version) {
+                    return Ok(());
+                }
+            }
+        }
+        let mut versions = self.version_log()?;
+        let (log, versions) = versions.as_mut().unwrap();
+        if !versions.contains(&version) {
+            log.write_all(&version.to_le_bytes())?;
+            versions.push(version);
+        }
+        Ok(())
+    }
+
+    // Opens the version log, reading the versions recorded by earlier runs
+    fn version_log(&self) -> Result<VersionsGuard<'_>, BufIoError> {
+        let mut versions = self.versions.write().map_err(|_| BufIoError::Locking)?;
+        if versions.is_none() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(self.data_bufmans.root_path().join("versions.log"))?;
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            // A record cut short by a crash is ignored
+            let recorded = bytes
+                .chunks_exact(4)
+                .map(|record| Hash::from(u32::from_le_bytes(record.try_into().unwrap())))
+                .collect();
+            *versions = Some((file, recorded));
+        }
+        Ok(versions)
+    }
+
+    /// Highest number of nodes that were being loaded at the same time
+    pub fn peak_in_flight_loads(&self) -> usize {
+        self.load_limiter.peak()
+    }
+
+    // Opening a data file through `data_bufmans` creates it if it's
+    // missing, which would surface a lost shard as reads past the end of
+    // an empty file
+    fn check_shard(&self, data_file_idx: u8) -> Result<(), BufIoError> {
+        if self.data_bufmans.is_open(&data_file_idx)
+            || self.data_bufmans.file_path(&data_file_idx).exists()
+        {
+            Ok(())
+        } else {
+            Err(BufIoError::MissingShard { idx: data_file_idx })
+        }
+    }
+
+    /// Recreates the data file of shard `idx` as an empty file if it's
+    /// missing, so that the index can be opened and written to again. The
+    /// nodes that were stored in the lost file aren't recovered, they have
+    /// to be reinserted.
+    pub fn repair_shard(&self, idx: u8) -> Result<(), BufIoError> {
+        if idx >= self.data_file_parts {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Shard {} out of range, the index has {} shards",
+                    idx, self.data_file_parts
+                ),
+            )
+            .into());
+        }
+        if self.check_shard(idx).is_err() {
+            log::warn!("Recreating the missing data file of shard {}", idx);
+            self.data_bufmans.get(idx)?;
+        }
+        Ok(())
+    }
+
+    /// Fraction of the data registry's capacity that's in use
+    pub fn data_registry_fill_ratio(&self) -> f64 {
+        self.data_registry.fill_ratio()
+    }
+
+    /// Fraction of the sets registry's capacity that's in use
+    pub fn sets_registry_fill_ratio(&self) -> f64 {
+        self.sets_registry.fill_ratio()
+    }
+
+    pub fn get_data(
+        &self,
+        file_offset: FileOffset,
+        data_file_idx: u8,
+    ) -> Result<*mut ProbLazyItem<InvertedIndexSparseAnnNodeBasicTSHashmapData>, BufIoError> {
+        self.get_data_with_stats(file_offset, data_file_idx, None)
+    }
+
+    /// Same as `get_data`, counting whether the item was found in the
+    /// registry or loaded from disk into `stats`
+    pub fn get_data_with_stats(
+        &self,
+        file_offset: FileOffset,
+        data_file_idx: u8,
+        mut stats: Option<&mut QueryStats>,
+    ) -> Result<*mut ProbLazyItem<InvertedIndexSparseAnnNodeBasicTSHashmapData>, BufIoError> {
+        let combined_index = Self::combine_index(file_offset, 0);
+
+        if let Some(item) = self
+            .data_registry
+            .get(&combined_index)
+            .map(|item| item.load())
+        {
+            if let Some(stats) = stats {
+                stats.nodes_from_cache += 1;
+            }
+            return Ok(item);
+        }
+
+        self.check_shard(data_file_idx)?;
+        let mut mutex = self
+            .loading_data
+            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+        let mut load_complete = mutex.lock().unwrap();
+
+        loop {
+            // check again
+            if let Some(item) = self
+                .data_registry
+                .get(&combined_index)
+                .map(|item| item.load())
+            {
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.nodes_from_cache += 1;
+                }
+                return Ok(item);
+            }
+
+            // another thread loaded the data but its not in the registry (got evicted), retry
+            if *load_complete {
+                drop(load_complete);
+                mutex = self
+                    .loading_data
+                    .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+                load_complete = mutex.lock().unwrap();
+                continue;
+            }
+
+            break;
+        }
+
+        let permit = self.load_limiter.acquire();
+        let data = InvertedIndexSparseAnnNodeBasicTSHashmapData::deserialize(
+            &self.dim_bufman,
+            &self.data_bufmans,
+            file_offset,
+            data_file_idx,
+            self.data_file_parts,
+            self,
+        )?;
+        drop(permit);
+        let state = ProbLazyItemState::Ready(ReadyState {
+            data,
+            file_offset,
+            version_id: 0.into(),
+            version_number: 0,
+        });
+
+        let item = ProbLazyItem::new_from_state(state, false);
+
+        self.data_registry
+            .insert(combined_index.clone(), AtomicSharedNode::new(item));
+
+        *load_complete = true;
+        self.loading_data.delete(&combined_index);
+
+        if let Some(stats) = stats {
+            stats.nodes_loaded += 1;
+        }
+        Ok(item)
+    }
+
+    pub fn get_sets(
+        &self,
+        file_offset: FileOffset,
+        data_file_idx: u8,
+    ) -> Result<*mut ProbLazyItem<VersionedInvertedFixedSetIndex>, BufIoError> {
+        self.get_sets_with_stats(file_offset, data_file_idx, None)
+    }
+
+    /// Same as `get_sets`, counting whether the item was found in the
+    /// registry or loaded from disk into `stats`
+    pub fn get_sets_with_stats(
+        &self,
+        file_offset: FileOffset,
+        data_file_idx: u8,
+        mut stats: Option<&mut QueryStats>,
+    ) -> Result<*mut ProbLazyItem<VersionedInvertedFixedSetIndex>, BufIoError> {
+        let combined_index = Self::combine_index(file_offset, 0);
+
+        if let Some(item) = self
+            .sets_registry
+            .get(&combined_index)
+            .map(|item| item.load())
+        {
+            if let Some(stats) = stats {
+                stats.nodes_from_cache += 1;
+            }
+            return Ok(item);
+        }
+
+        self.check_shard(data_file_idx)?;
+        let mut mutex = self
+            .loading_sets
+            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+        let mut load_complete = mutex.lock().unwrap();
+
+        loop {
+            // check again
+            if let Some(item) = self
+                .sets_registry
+                .get(&combined_index)
+                .map(|item| item.load())
+            {
+                if let Some(stats) = stats.as_deref_mut() {
+                    stats.nodes_from_cache += 1;
+                }
+                return Ok(item);
+            }
+
+            // another thread loaded the data but its not in the registry (got evicted), retry
+            if *load_complete {
+                drop(load_complete);
+                mutex = self
+                    .loading_sets
+                    .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
+                load_complete = mutex.lock().unwrap();
+                continue;
+            }
+
+            break;
+        }
+
+        let permit = self.load_limiter.acquire();
+        let dim_cursor = self.dim_bufman.open_cursor()?;
+        self.dim_bufman
+            .seek_with_cursor(dim_cursor, file_offset.0 as u64)?;
+        let data_offset = self.dim_bufman.read_u32_with_cursor(dim_cursor)?;
+        self.dim_bufman.close_cursor(dim_cursor)?;
+
+        let data = VersionedInvertedFixedSetIndex::deserialize(
+            &self.dim_bufman,
+            &self.data_bufmans,
+            FileOffset(data_offset),
+            data_file_idx,
+            self.data_file_parts,
+            self,
+        )?;
+        drop(permit);
+        let state = ProbLazyItemState::Ready(ReadyState {
+            data,
+            file_offset,
+            version_id: 0.into(),
+            version_number: 0,
+        });
+
+        let item = ProbLazyItem::new_from_state(state, false);
+
+        self.sets_registry
+            .insert(combined_index.clone(), AtomicSharedNode::new(item));
+
+        *load_complete = true;
+        self.loading_sets.delete(&combined_index);
+
+        if let Some(stats) = stats {
+            stats.nodes_loaded += 1;
+        }
+        Ok(item)
+    }
+
+    /// Loads the data and fixed sets of the nodes of `dims` into the
+    /// registries in parallel, so that the queries that follow find them
+    /// cached. Dimensions without a node in the index are skipped.
+    pub fn warm_dimensions(&self, dims: &[u32]) -> Result<(), BufIoError> {
+        dims.par_iter().try_for_each(|&dim_index| {
+            let Some((node_offset, qv)) = self.find_node_offset(dim_index)? else {
+                return Ok(());
+            };
+            let data_file_idx = (dim_index % self.data_file_parts as u32) as u8;
+            // Same layout as read by the node's deserialization
+            self.get_data(FileOffset(node_offset + 5), data_file_idx)?;
+            self.get_sets(FileOffset(node_offset + 69 + qv * 4), data_file_idx)?;
+            Ok(())
+        })
+    }
+
+    /// Number of distinct documents in the serialized index as of
+    /// `version`, counted from the postings of all the dimension nodes in
+    /// the dim file. A document in several dimensions is counted once.
+    ///
+    /// Only postings added in `version` or in the versions recorded before
+    /// it count, see `record_version`. Postings are never removed from
+    /// this index, so there are no deleted documents to leave out.
+    ///
+    /// Indexes written before versions were recorded have an empty log,
+    /// and all their postings count whatever the version. Otherwise,
+    /// `version` must have been recorded, or this fails with
+    /// `InvalidInput`.
+    pub fn doc_count_at(&self, version: Hash) -> Result<usize, BufIoError> {
+        let visible: Option<HashSet<Hash>> = {
+            let versions = self.version_log()?;
+            let versions = &versions.as_ref().unwrap().1;
+            if versions.is_empty() {
+                None
+            } else if let Some(position) = versions.iter().position(|v| *v == version) {
+                Some(versions[..=position].iter().copied().collect())
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Version {} is not recorded in the index", *version),
+                )
+                .into());
+            }
+        };
+        if self.dim_bufman.file_size() == 0 {
+            return Ok(0);
+        }
+        let mut docs = HashSet::new();
+        let cursor = self.dim_bufman.open_cursor()?;
+        let mut pending = vec![0u32];
+        while let Some(node_offset) = pending.pop() {
+            self.dim_bufman
+                .seek_with_cursor(cursor, node_offset as u64)?;
+            let dim_index = self.dim_bufman.read_u32_with_cursor(cursor)?;
+            let qv = self.read_node_qv(cursor, node_offset)?;
+            self.dim_bufman
+                .seek_with_cursor(cursor, (node_offset + 5 + qv * 4) as u64)?;
+            for _ in 0..16 {
+                let child_offset = self.dim_bufman.read_u32_with_cursor(cursor)?;
+                if child_offset != u32::MAX {
+                    pending.push(child_offset);
+                }
+            }
+            // Same layout as read by the node's deserialization
+            let data_file_idx = (dim_index % self.data_file_parts as u32) as u8;
+            let data = self.get_data(FileOffset(node_offset + 5), data_file_idx)?;
+            let data = unsafe { &*data }.try_get_data(self, dim_index)?;
+            let mut insert = |vector_id| {
+                docs.insert(vector_id);
+            };
+            data.map.for_each(|_, list| match &visible {
+                Some(visible) => list.for_each_id_in(visible, &mut insert),
+                None => list.for_each_id(&mut insert),
+            });
+        }
+        self.dim_bufman.close_cursor(cursor)?;
+        Ok(docs.len())
+    }
+
+    // Follows the children offsets in the dim file from the root down to
+    // the node of `dim_index`, returning its offset and quantization value
+    fn find_node_offset(&self, dim_index: u32) -> Result<Option<(u32, u32)>, BufIoError> {
+        let cursor = self.dim_bufman.open_cursor()?;
+        let mut node_offset = 0;
+        let mut qv = self.read_node_qv(cursor, node_offset)?;
+        for child_index in calculate_path(dim_index, 0) {
+            self.dim_bufman.seek_with_cursor(
+                cursor,
+                (node_offset + 5 + qv * 4 + child_index as u32 * 4) as u64,
+            )?;
+            node_offset = self.dim_bufman.read_u32_with_cursor(cursor)?;
+            if node_offset == u32::MAX {
+                self.dim_bufman.close_cursor(cursor)?;
+                return Ok(None);
+            }
+            qv = self.read_node_qv(cursor, node_offset)?;
+        }
+        self.dim_bufman.close_cursor(cursor)?;
+        Ok(Some((node_offset, qv)))
+    }
+
+    fn read_node_qv(&self, cursor: u64, node_offset: u32) -> Result<u32, BufIoError> {
+        self.dim_bufman
+            .seek_with_cursor(cursor, node_offset as u64 + 4)?;
+        let quantization_and_implicit = self.dim_bufman.read_u8_with_cursor(cursor)?;
+        let quantization_bits = (quantization_and_implicit << 1) >> 1;
+        Ok(1u32 << quantization_bits)
+    }
+
+    /// Counts the postings stored for the node data at `file_offset`,
+    /// reading only the length of each page instead of loading the data
+    pub fn count_postings(
+        &self,
+        file_offset: FileOffset,
+        data_file_idx: u8,
+    ) -> Result<usize, BufIoError> {
+        let dim_cursor = self.dim_bufman.open_cursor()?;
+        let qv = self.read_node_qv(dim_cursor, file_offset.0 - 5)?;
+        let mut pool_offsets = Vec::with_capacity(qv as usize);
+        for _ in 0..qv {
+            let offset = self.dim_bufman.read_u32_with_cursor(dim_cursor)?;
+            if offset != u32::MAX {
+                pool_offsets.push(offset);
+            }
+        }
+        self.dim_bufman.close_cursor(dim_cursor)?;
+
+        let bufman = self.data_bufmans.get(data_file_idx)?;
+        let cursor = bufman.open_cursor()?;
+        let mut count = 0;
+        for mut pool_offset in pool_offsets {
+            // Each versioned pagepool links to the next version's, and
+            // each page to the next page of the same pool
+            while pool_offset != u32::MAX {
+                bufman.seek_with_cursor(cursor, pool_offset as u64 + 4)?;
+                let mut page_offset = bufman.read_u32_with_cursor(cursor)?;
+                pool_offset = bufman.read_u32_with_cursor(cursor)?;
+                while page_offset != u32::MAX {
+                    bufman.seek_with_cursor(cursor, page_offset as u64)?;
+                    page_offset = bufman.read_u32_with_cursor(cursor)?;
+                    count += bufman.read_u32_with_cursor(cursor)? as usize;
+                }
+            }
+        }
+        bufman.close_cursor(cursor)?;
+        Ok(count)
+    }
+
+    /// Packs the data file index above the 32-bit offset.
+    pub fn combine_index(file_offset: FileOffset, data_file_idx: u8) -> u64 {
+        (u64::from(data_file_idx) << 32) | u64::from(file_offset.0)
+    }
+
+    /// Drops the data and sets of a node from the registries and leaves
+    /// them pending, as if they had never been loaded
+    #[cfg(test)]
+    pub(crate) fn unload(
+        &self,
+        data: &ProbLazyItem<InvertedIndexSparseAnnNodeBasicTSHashmapData>,
+        sets: &ProbLazyItem<VersionedInvertedFixedSetIndex>,
+    ) {
+        let file_index = data.get_file_index();
+        self.data_registry
+            .remove(&Self::combine_index(file_index.get_offset().unwrap(), 0));
+        data.set_state(ProbLazyItemState::Pending(file_index));
+        let file_index = sets.get_file_index();
+        self.sets_registry
+            .remove(&Self::combine_index(file_index.get_offset().unwrap(), 0));
+        sets.set_state(ProbLazyItemState::Pending(file_index));
+    }
+
+    pub fn get_prop_key(file_offset: FileOffset, length: BytesToRead) -> u64 {
+        DenseIndexCache::get_prop_key(file_offset, length)
+    }
+
+    pub fn load_item<T: InvertedIndexSerialize>(
+        &self,
+        file_offset: FileOffset,
+        data_file_idx: u8,
+    ) -> Result<T, BufIoError> {
+        T::deserialize(
+            &self.dim_bufman,
+            &self.data_bufmans,
+            file_offset,
+            data_file_idx,
+            self.data_file_parts,
+            self,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distance::cosine::CosineSimilarity;
+    use crate::models::file_persist::{write_node_to_file, write_prop_to_file};
+    use crate::models::lru_cache::EvictStrategy;
+    use crate::quantization::{scalar::ScalarQuantization, Quantization, StorageType};
+    use std::fs::OpenOptions;
+    use std::ptr;
+    use std::thread;
+    use tempfile::{tempdir, TempDir};
+
+    fn setup_cache() -> (Arc<DenseIndexCache>, TempDir) {
+        let dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            ProbNode::get_serialized_size(8),
+        ));
+        let level_0_bufmans = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}_0.index", **ver)),
+            ProbNode::get_serialized_size(16),
+        ));
+        let prop_file = Arc::new(RwLock::new(
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(dir.as_ref().join("prop.data"))
+                .unwrap(),
+        ));
+        let cache = Arc::new(DenseIndexCache::new(bufmans, level_0_bufmans, prop_file));
+        (cache, dir)
+    }
+
+    fn create_prop(cache: &DenseIndexCache, id: u64) -> Arc<NodeProp> {
+        let id = VectorId(id);
+        let value = Arc::new(Storage::UnsignedByte {
+            mag: 10,
+            quant_vec: vec![1, 2, 3],
+        });
+        let location =
+            write_prop_to_file(&id, value.clone(), &mut *cache.prop_file.write().unwrap()).unwrap();
+        Arc::new(NodeProp {
+            id,
+            value,
+            location,
+        })
+    }
+
+    fn create_node(
+        cache: &DenseIndexCache,
+        id: u64,
+        level: HNSWLevel,
+        neighbors_count: usize,
+    ) -> ProbNode {
+        ProbNode::new(
+            level,
+            create_prop(cache, id),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            neighbors_count,
+        )
+    }
+
+    #[test]
+    fn test_list_versions() {
+        let (cache, dir) = setup_cache();
+
+        cache.bufmans.get(Hash::from(42)).unwrap();
+        cache.level_0_bufmans.get(Hash::from(42)).unwrap();
+        cache.level_0_bufmans.get(Hash::from(7)).unwrap();
+        cache.bufmans.get(Hash::from(1000)).unwrap();
+        File::create(dir.as_ref().join("junk.index")).unwrap();
+        File::create(dir.as_ref().join("12.vec_raw")).unwrap();
+
+        // Unregistered versions are ordered by their hash
+        let versions = cache.list_versions().unwrap();
+        assert_eq!(
+            vec![Hash::from(7), Hash::from(42), Hash::from(1000)],
+            versions
+        );
+
+        // Registered versions come first, by version number
+        cache.register_version(0, Hash::from(1000)).unwrap();
+        cache.register_version(1, Hash::from(7)).unwrap();
+        let versions = cache.list_versions().unwrap();
+        assert_eq!(
+            vec![Hash::from(1000), Hash::from(7), Hash::from(42)],
+            versions
+        );
+    }
+
+    #[test]
+    fn test_evict_single_node() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let location = node.prop.location;
+
+        let bufman = cache.bufmans.get(version_id).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+        let offset = node.serialize(&cache.bufmans, version_id, cursor).unwrap();
+        bufman.close_cursor(cursor).unwrap();
+
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id,
+        };
+        let prop_key = DenseIndexCache::get_prop_key(location.0, location.1);
+
+        let loaded = cache.get_object(file_index, false).unwrap();
+        assert_eq!(loaded, cache.get_object(file_index, false).unwrap());
+        assert!(cache.props_registry.contains_key(&prop_key));
+
+        assert!(cache.evict(file_index, false));
+        assert!(!cache.evict(file_index, false));
+        assert!(!cache.props_registry.contains_key(&prop_key));
+
+        let reloaded = cache.get_object(file_index, false).unwrap();
+        assert_ne!(loaded, reloaded);
+        let reloaded_node = unsafe { &*reloaded }.get_lazy_data().unwrap();
+        assert_eq!(reloaded_node.prop.id.0, 0);
+        assert_eq!(reloaded_node.hnsw_level, HNSWLevel(1));
+    }
+
+    #[test]
+    fn test_file_level() {
+        let (cache, _dir) = setup_cache();
+        let level_0_version = Hash::from(1);
+        let higher_version = Hash::from(2);
+
+        for id in 0..3 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item = ProbLazyItem::new(node, level_0_version, 0, true, FileOffset(u32::MAX));
+            unsafe { cache.append_node(level_0_version, item, true) }.unwrap();
+        }
+        for (i, level) in [1, 2, 1].into_iter().enumerate() {
+            let node = create_node(&cache, i as u64 + 3, HNSWLevel(level), 8);
+            let item = ProbLazyItem::new(node, higher_version, 0, false, FileOffset(u32::MAX));
+            unsafe { cache.append_node(higher_version, item, false) }.unwrap();
+        }
+        // The range is read from the headers, not from the nodes
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+
+        let level_0_info = cache.file_level(level_0_version).unwrap();
+        assert_eq!(
+            level_0_info,
+            LevelInfo {
+                min_level: HNSWLevel(0),
+                max_level: HNSWLevel(0),
+            }
+        );
+        assert!(level_0_info.is_level_0());
+
+        let higher_info = cache.file_level(higher_version).unwrap();
+        assert_eq!(
+            higher_info,
+            LevelInfo {
+                min_level: HNSWLevel(1),
+                max_level: HNSWLevel(2),
+            }
+        );
+        assert!(!higher_info.is_level_0());
+
+        assert!(cache.file_level(Hash::from(3)).is_err());
+    }
+
+    #[test]
+    fn test_long_version_chains() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let new_item = |id, version: u16| {
+            let node = create_node(&cache, id, HNSWLevel(1), 8);
+            ProbLazyItem::new(
+                node,
+                Hash::from(version as u32),
+                version,
+                false,
+                FileOffset(id as u32 * node_size),
+            )
+        };
+        let root = new_item(0, 0);
+        let short = new_item(1, 0);
+        for version in 1..=20 {
+            ProbLazyItem::add_version(root, new_item(0, version), &cache)
+                .unwrap()
+                .unwrap();
+        }
+        ProbLazyItem::add_version(short, new_item(1, 1), &cache)
+            .unwrap()
+            .unwrap();
+        assert_eq!(unsafe { &*root }.version_chain_len(), 20);
+        for (id, item) in [root, short].into_iter().enumerate() {
+            unsafe { cache.insert_lazy_object(Hash::from(0), id as u32 * node_size, item) };
+        }
+
+        let chains = cache.long_version_chains(1);
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].0, unsafe { &*root }.get_file_index());
+        assert_eq!(chains[0].2, 20);
+
+        assert_eq!(cache.long_version_chains(0).len(), 2);
+        assert!(cache.long_version_chains(20).is_empty());
+    }
+
+    #[test]
+    fn test_warm_by_degree() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let items: Vec<_> = (0..6)
+            .map(|id| {
+                let node = create_node(&cache, id, HNSWLevel(1), 8);
+                ProbLazyItem::new(
+                    node,
+                    version_id,
+                    0,
+                    false,
+                    FileOffset(id as u32 * node_size),
+                )
+            })
+            .collect();
+        let add_neighbor = |node: usize, neighbor: usize| {
+            let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+            unsafe { &*items[node] }
+                .get_lazy_data()
+                .unwrap()
+                .add_neighbor(neighbor as u32, items[neighbor], dist, &cache);
+        };
+        // 0 -> {1, 2}, the low degree 1 -> {3} is discovered before the
+        // hub 2 -> {3, 4, 5}
+        add_neighbor(0, 1);
+        add_neighbor(0, 2);
+        add_neighbor(1, 3);
+        for neighbor in 3..6 {
+            add_neighbor(2, neighbor);
+        }
+        for &item in &items {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        // Start with a cold cache
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let file_indices: Vec<_> = items
+            .iter()
+            .map(|&item| unsafe { &*item }.get_file_index())
+            .collect();
+        let is_resident = |id: usize| {
+            let combined_index = DenseIndexCache::combine_index(&file_indices[id], false);
+            cache.registry.get(&combined_index).is_some()
+        };
+
+        let loaded = cache
+            .warm_by_degree(&[(file_indices[0], false)], 3)
+            .unwrap();
+
+        assert_eq!(
+            loaded,
+            vec![file_indices[0], file_indices[2], file_indices[1]]
+        );
+        assert!((0..3).all(is_resident));
+        assert!(!(3..6).any(is_resident));
+
+        // Nodes already seen aren't loaded twice
+        let loaded = cache
+            .warm_by_degree(&[(file_indices[0], false)], 10)
+            .unwrap();
+        assert_eq!(loaded.len(), 6);
+        assert!((0..6).all(is_resident));
+    }
+
+    #[test]
+    fn test_warm_entry_points() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let level_0_node_size = ProbNode::get_serialized_size(16) as u32;
+        let new_item = |id, level: u8, child, offset| {
+            let is_level_0 = level == 0;
+            let neighbors_count = if is_level_0 { 16 } else { 8 };
+            let node = ProbNode::new(
+                HNSWLevel(level),
+                create_prop(&cache, id),
+                ptr::null_mut(),
+                child,
+                neighbors_count,
+            );
+            ProbLazyItem::new(node, version_id, 0, is_level_0, FileOffset(offset))
+        };
+        let add_neighbor = |node: SharedNode, id: u32, neighbor: SharedNode| {
+            let dist = MetricResult::CosineSimilarity(CosineSimilarity(id as f32 / 10.0));
+            unsafe { &*node }
+                .get_lazy_data()
+                .unwrap()
+                .add_neighbor(id, neighbor, dist, &cache);
+        };
+
+        // level 0: entry with neighbors 1 and 2, node 3 is only a
+        // neighbor of 1 and shouldn't be loaded
+        let entry_0 = new_item(0, 0, ptr::null_mut(), 0);
+        let level_0_neighbors: Vec<_> = (1..4)
+            .map(|i| new_item(i, 0, ptr::null_mut(), level_0_node_size * i as u32))
+            .collect();
+        add_neighbor(entry_0, 1, level_0_neighbors[0]);
+        add_neighbor(entry_0, 2, level_0_neighbors[1]);
+        add_neighbor(level_0_neighbors[0], 3, level_0_neighbors[2]);
+
+        // level 1: entry with neighbor 4
+        let entry_1 = new_item(0, 1, entry_0, 0);
+        let level_1_neighbor = new_item(4, 1, ptr::null_mut(), node_size);
+        add_neighbor(entry_1, 4, level_1_neighbor);
+
+        for node in [entry_0, entry_1, level_1_neighbor]
+            .into_iter()
+            .chain(level_0_neighbors.iter().copied())
+        {
+            write_node_to_file(node, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        // Start with a cold cache
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let is_resident = |node: SharedNode| {
+            let node = unsafe { &*node };
+            let combined_index =
+                DenseIndexCache::combine_index(&node.get_file_index(), node.is_level_0);
+            cache.registry.get(&combined_index).is_some()
+        };
+        assert!(!is_resident(entry_1));
+
+        cache
+            .warm_entry_points(unsafe { &*entry_1 }.get_file_index())
+            .unwrap();
+
+        assert!(is_resident(entry_1));
+        assert!(is_resident(level_1_neighbor));
+        assert!(is_resident(entry_0));
+        assert!(is_resident(level_0_neighbors[0]));
+        assert!(is_resident(level_0_neighbors[1]));
+        assert!(!is_resident(level_0_neighbors[2]));
+    }
+
+    #[test]
+    fn test_merged_node_heap_size_grows_with_neighbors() {
+        let version = Hash::from(1);
+        let node_with_neighbors = |neighbors_count: u32| {
+            let node = MergedNode::new(HNSWLevel(0));
+            for i in 0..neighbors_count {
+                let neighbor = MergedNode::new(HNSWLevel(0));
+                neighbor.set_prop_pending((FileOffset(i * 100), BytesToRead(100)));
+                node.add_ready_neighbor(
+                    LazyItem::new(version, 0, neighbor),
+                    MetricResult::CosineSimilarity(CosineSimilarity(0.5)),
+                );
+            }
+            LazyItem::new(version, 0, node)
+        };
+
+        let mut sizes = Vec::new();
+        for neighbors_count in [0, 1, 4, 16] {
+            let item = node_with_neighbors(neighbors_count);
+            let size = <MergedNode as Cacheable>::heap_size(&item);
+            assert_eq!(MergedNode::into_cache_item(item.clone()).heap_size(), size);
+            sizes.push(size);
+        }
+
+        assert!(sizes.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(sizes[0], size_of::<MergedNode>());
+        assert_eq!(
+            sizes[3] - sizes[2],
+            12 * size_of::<EagerLazyItem<MergedNode, MetricResult>>()
+        );
+        assert_eq!(
+            <MergedNode as Cacheable>::heap_size(&LazyItem::new_invalid()),
+            0
+        );
+    }
+
+    #[test]
+    fn test_locate_newest_version() {
+        let (cache, _dir) = setup_cache();
+        let v1 = Hash::from(1);
+        let v2 = Hash::from(2);
+
+        let insert = |version_id: Hash, version_number: u16| {
+            let node = create_node(&cache, 7, HNSWLevel(0), 16);
+            let item =
+                ProbLazyItem::new(node, version_id, version_number, true, FileOffset(u32::MAX));
+            let offset = unsafe { cache.append_node(version_id, item, true) }.unwrap();
+            FileIndex::Valid {
+                offset,
+                version_number,
+                version_id,
+            }
+        };
+
+        assert_eq!(cache.locate(VectorId(7)).unwrap(), None);
+
+        let old = insert(v1, 1);
+        assert_eq!(cache.locate(VectorId(7)).unwrap(), Some(old));
+
+        let new = insert(v2, 2);
+        assert_eq!(cache.locate(VectorId(7)).unwrap(), Some(new));
+
+        // Appending an older version again doesn't shadow the newer one
+        insert(v1, 1);
+        assert_eq!(cache.locate(VectorId(7)).unwrap(), Some(new));
+
+        // Nodes that are only cached aren't indexed
+        let node = create_node(&cache, 9, HNSWLevel(0), 16);
+        let item = ProbLazyItem::new(node, v2, 2, true, FileOffset(0));
+        unsafe { cache.insert_lazy_object(v2, 0, item) };
+        assert_eq!(cache.locate(VectorId(9)).unwrap(), None);
+
+        // Locations are known after a restart
+        let cold_cache = || {
+            DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            )
+        };
+        assert_eq!(cold_cache().locate(VectorId(7)).unwrap(), Some(new));
+
+        // Upper level nodes are not indexed
+        let node = create_node(&cache, 8, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, v2, 2, false, FileOffset(u32::MAX));
+        unsafe { cache.append_node(v2, item, false) }.unwrap();
+        assert_eq!(cache.locate(VectorId(8)).unwrap(), None);
+
+        assert!(cache.remove_location(&VectorId(7)).unwrap());
+        assert!(!cache.remove_location(&VectorId(7)).unwrap());
+        assert_eq!(cache.locate(VectorId(7)).unwrap(), None);
+        assert_eq!(cold_cache().locate(VectorId(7)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_query_guard_spares_version() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let (queried, other) = (Hash::from(1), Hash::from(2));
+        let file_index = |version_id, i: u32| FileIndex::Valid {
+            offset: FileOffset(i * node_size),
+            version_number: 0,
+            version_id,
+        };
+        for (version_id, count) in [(queried, 3), (other, 8)] {
+            for i in 0..count {
+                let node = create_node(&cache, i, HNSWLevel(1), 8);
+                let item =
+                    ProbLazyItem::new(node, version_id, 0, false, FileOffset(i as u32 * node_size));
+                write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id)
+                    .unwrap();
+            }
+        }
+
+        // Cold cache that evicts as soon as it holds more than 4 nodes
+        let mut cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        cache.registry = ShardedLRUCache::new(4, 1, EvictStrategy::Immediate);
+        cache.registry.set_evict_on_insert(true);
+        DenseIndexCache::spare_active_versions(&mut cache.registry, &cache.active_versions);
+        let load = |cache: &DenseIndexCache, file_index| {
+            cache.get_object(file_index, false).unwrap();
+        };
+        let is_resident = |cache: &DenseIndexCache, i| {
+            let key = DenseIndexCache::combine_index(&file_index(queried, i), false);
+            cache.registry.get(&key).is_some()
+        };
+
+        let guard = cache.begin_query(queried);
+        let overlapping = cache.begin_query(queried);
+        for i in 0..3 {
+            load(&cache, file_index(queried, i));
+        }
+        for i in 0..8 {
+            load(&cache, file_index(other, i));
+        }
+        assert!((0..3).all(|i| is_resident(&cache, i)));
+        assert_eq!(cache.registry.len(), 4);
+
+        // Still active while the overlapping query runs
+        drop(guard);
+        for i in 0..8 {
+            load(&cache, file_index(other, i));
+        }
+        assert!((0..3).all(|i| is_resident(&cache, i)));
+
+        drop(overlapping);
+        assert!(cache.active_versions.is_empty());
+        for i in 0..8 {
+            load(&cache, file_index(other, i));
+        }
+        assert!(!(0..3).any(|i| is_resident(&cache, i)));
+    }
+
+    #[test]
+    fn test_prob_lazy_item_is_resolved() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(0));
+        write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        let file_index = unsafe { &*item }.get_file_index();
+        assert!(unsafe { &*item }.is_resolved());
+
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        // With `max_loads` exhausted, a placeholder is returned
+        let pending = cache
+            .get_lazy_object(file_index, 0, usize::MAX, &mut HashSet::new(), false)
+            .unwrap();
+        let pending = unsafe { &*pending };
+        assert!(!pending.is_resolved());
+        assert!(pending.get_lazy_data().is_none());
+
+        let ready = unsafe { &*cache.get_object(file_index, false).unwrap() };
+        assert!(ready.is_resolved());
+        assert!(ready.get_lazy_data().is_some());
+    }
+
+    #[test]
+    fn test_force_load_invalid_file_index() {
+        let (cache, _dir) = setup_cache();
+        for is_level_0 in [false, true] {
+            let error = cache
+                .force_load_single_object(FileIndex::Invalid, is_level_0)
+                .unwrap_err();
+            assert!(
+                matches!(&error, BufIoError::Io(error) if error.kind() == io::ErrorKind::InvalidInput),
+                "{}",
+                error
+            );
+        }
+        assert!(cache.registry.is_empty());
+
+        // The cache is still usable afterwards
+        let version_id = Hash::from(0);
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(0));
+        write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        let file_index = unsafe { &*item }.get_file_index();
+        let loaded = cache.force_load_single_object(file_index, false).unwrap();
+        assert_eq!(unsafe { &*loaded }.get_file_index(), file_index);
+    }
+
+    #[test]
+    fn test_adaptive_max_loads() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let file_indices: Vec<_> = (0..32)
+            .map(|id| {
+                let node = create_node(&cache, id, HNSWLevel(1), 8);
+                let item = ProbLazyItem::new(
+                    node,
+                    version_id,
+                    0,
+                    false,
+                    FileOffset(id as u32 * node_size),
+                );
+                write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id)
+                    .unwrap();
+                unsafe { &*item }.get_file_index()
+            })
+            .collect();
+
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        )
+        .with_adaptive_max_loads(1, 1000);
+        assert_eq!(cache.effective_max_loads(), 1);
+
+        // Every node is cold, the batch size grows with the misses
+        let mut last = cache.effective_max_loads();
+        for file_index in &file_indices {
+            cache.get_object(file_index.clone(), false).unwrap();
+            let current = cache.effective_max_loads();
+            assert!(current > last);
+            last = current;
+        }
+        assert!(last > 800);
+
+        // The working set is cached now, the batch size shrinks with the hits
+        for _ in 0..4 {
+            for file_index in &file_indices {
+                cache.get_object(file_index.clone(), false).unwrap();
+                let current = cache.effective_max_loads();
+                assert!(current <= last);
+                last = current;
+            }
+        }
+        assert!(last < 10);
+
+        // Without the policy the batch size stays fixed
+        let fixed = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        fixed.get_object(file_indices[0].clone(), false).unwrap();
+        assert_eq!(fixed.effective_max_loads(), 1000);
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let (primary, replica) = (Hash::from(1), Hash::from(2));
+        for version_id in [primary, replica] {
+            for id in 0..5 {
+                let node = create_node(&cache, id, HNSWLevel(1), 8);
+                let item = ProbLazyItem::new(
+                    node,
+                    version_id,
+                    0,
+                    false,
+                    FileOffset(id as u32 * node_size),
+                );
+                write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id)
+                    .unwrap();
+            }
+        }
+        // The props are at different locations, give the replica's nodes
+        // those of the primary's
+        for id in 0..5 {
+            let file_index = |version_id| FileIndex::Valid {
+                offset: FileOffset(id * node_size),
+                version_number: 0,
+                version_id,
+            };
+            let bytes = cache.read_node_bytes(file_index(primary), false).unwrap();
+            cache
+                .write_node_bytes(file_index(replica), false, &bytes)
+                .unwrap();
+        }
+
+        let root = cache.merkle_root(primary, false).unwrap();
+        assert_eq!(cache.merkle_root(replica, false).unwrap(), root);
+        for id in 0..5 {
+            let proof = cache
+                .merkle_proof(primary, false, FileOffset(id * node_size))
+                .unwrap();
+            assert!(proof.verify(&root));
+        }
+        assert!(cache
+            .merkle_proof(primary, false, FileOffset(node_size / 2))
+            .is_err());
+
+        // Flip a byte of the replica's fourth node
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(3 * node_size),
+            version_number: 0,
+            version_id: replica,
+        };
+        let mut bytes = cache.read_node_bytes(file_index, false).unwrap();
+        bytes[5] ^= 1;
+        cache.write_node_bytes(file_index, false, &bytes).unwrap();
+
+        let replica_root = cache.merkle_root(replica, false).unwrap();
+        assert_ne!(replica_root, root);
+        let proof = |version_id, id| {
+            cache
+                .merkle_proof(version_id, false, FileOffset(id * node_size))
+                .unwrap()
+        };
+        assert!(proof(replica, 3).verify(&replica_root));
+        assert!(!proof(replica, 3).verify(&root));
+        assert_ne!(proof(replica, 3).leaf, proof(primary, 3).leaf);
+        // The other nodes are the same on both sides
+        assert_eq!(proof(replica, 0).leaf, proof(primary, 0).leaf);
+    }
+
+    #[test]
+    fn test_get_norm() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let vectors = [vec![3.0, 4.0, 12.0], vec![0.1, -0.7, 0.25, 0.5]];
+        let storage_types = [StorageType::FullPrecisionFP, StorageType::UnsignedByte];
+        let mut file_indices = Vec::new();
+        let mut expected = Vec::new();
+        for (id, (vector, &storage_type)) in vectors.iter().zip(&storage_types).enumerate() {
+            let value = ScalarQuantization
+                .quantize(vector, storage_type, (-1.0, 1.0))
+                .unwrap();
+            // Computed from what the scores are computed from
+            expected.push(match &value {
+                Storage::UnsignedByte { quant_vec, .. } => quant_vec
+                    .iter()
+                    .map(|&x| (x as f32) * (x as f32))
+                    .sum::<f32>()
+                    .sqrt(),
+                Storage::FullPrecisionFP { vec, .. } => {
+                    vec.iter().map(|x| x * x).sum::<f32>().sqrt()
+                }
+                _ => unreachable!(),
+            });
+            let value = Arc::new(value);
+            let location = write_prop_to_file(
+                &VectorId(id as u64),
+                value.clone(),
+                &mut *cache.prop_file.write().unwrap(),
+            )
+            .unwrap();
+            let prop = Arc::new(NodeProp {
+                id: VectorId(id as u64),
+                value,
+                location,
+            });
+            let node = ProbNode::new(HNSWLevel(1), prop, ptr::null_mut(), ptr::null_mut(), 8);
+            let item = ProbLazyItem::new(
+                node,
+                version_id,
+                0,
+                false,
+                FileOffset(id as u32 * node_size),
+            );
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+            file_indices.push(unsafe { &*item }.get_file_index());
+        }
+        assert!((expected[0] - 13.0).abs() < 1e-6);
+
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        for (file_index, expected) in file_indices.iter().zip(&expected) {
+            let norm = cold_cache.get_norm(*file_index, false).unwrap();
+            assert!((norm - expected).abs() <= expected * f32::EPSILON * 4.0);
+        }
+        assert!(cold_cache.registry.is_empty());
+
+        // Cached nodes answer from their own prop
+        cold_cache.get_object(file_indices[1], false).unwrap();
+        let norm = cold_cache.get_norm(file_indices[1], false).unwrap();
+        assert!((norm - expected[1]).abs() <= expected[1] * f32::EPSILON * 4.0);
+    }
+
+    #[test]
+    fn test_export_edges() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+        let create_items = |ids: &[u64], level: u8, neighbors_count: usize| -> Vec<_> {
+            let node_size = ProbNode::get_serialized_size(neighbors_count) as u32;
+            ids.iter()
+                .enumerate()
+                .map(|(i, &id)| {
+                    let node = create_node(&cache, id, HNSWLevel(level), neighbors_count);
+                    ProbLazyItem::new(
+                        node,
+                        version_id,
+                        0,
+                        level == 0,
+                        FileOffset(i as u32 * node_size),
+                    )
+                })
+                .collect()
+        };
+        // Links the nodes at positions `from` and `to` of `items`
+        let link = |items: &[SharedNode], from: usize, to: usize| {
+            let data = |i: usize| unsafe { &*items[i] }.get_lazy_data().unwrap();
+            let to_id = data(to).get_id().0 as u32;
+            data(from).add_neighbor(to_id, items[to], dist, &cache);
+        };
+
+        // 0 and 1 link each other, 0 and 2 only one way, and so do 2 and
+        // 3, which has no neighbors
+        let upper = create_items(&[0, 1, 2, 3], 1, 8);
+        link(&upper, 0, 1);
+        link(&upper, 1, 0);
+        link(&upper, 0, 2);
+        link(&upper, 2, 3);
+        // 0 -> 1 again on level 0, along with an edge of its own
+        let level_0 = create_items(&[0, 1, 4], 0, 16);
+        link(&level_0, 0, 1);
+        link(&level_0, 2, 0);
+        for &item in upper.iter().chain(&level_0) {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        assert_eq!(
+            cache.export_edges(version_id).unwrap(),
+            vec![(0, 1), (0, 2), (1, 0), (2, 3), (4, 0)]
+        );
+        // Nothing was added to the registry
+        assert!(cache.registry.is_empty());
+        assert!(cache.export_edges(Hash::from(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let upper_size = ProbNode::get_serialized_size(8) as u32;
+        let level_0_size = ProbNode::get_serialized_size(16) as u32;
+        let item = |id, level, offset| {
+            let neighbors_count = if level == 0 { 16 } else { 8 };
+            let node = create_node(&cache, id, HNSWLevel(level), neighbors_count);
+            ProbLazyItem::new(node, version_id, 0, level == 0, FileOffset(offset))
+        };
+        let data = |item: SharedNode| unsafe { &*item }.get_lazy_data().unwrap();
+
+        // Node 0 on levels 1 and 0 and its neighbor 1 are live, the nodes
+        // between and after them are dead
+        let top = item(0, 1, 0);
+        let bottom = item(0, 0, 0);
+        let neighbor = item(1, 1, 2 * upper_size);
+        data(top).set_child(bottom);
+        data(bottom).set_parent(top);
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+        data(top).add_neighbor(1, neighbor, dist, &cache);
+        let dead = [
+            item(2, 1, upper_size),
+            item(3, 1, 3 * upper_size),
+            item(4, 0, level_0_size),
+        ];
+        for item in [top, bottom, neighbor].into_iter().chain(dead) {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        let root = unsafe { &*top }.get_file_index();
+        let live = 2.0 * upper_size as f64 + level_0_size as f64;
+        let total = 4.0 * upper_size as f64 + 2.0 * level_0_size as f64;
+        let ratio = cache.fragmentation(version_id, &[root]).unwrap();
+        assert!((ratio - live / total).abs() < 1e-9);
+        // Nothing was added to the registry
+        assert!(cache.registry.is_empty());
+
+        assert_eq!(cache.fragmentation(Hash::from(1), &[]).unwrap(), 1.0);
+        assert_eq!(cache.fragmentation(version_id, &[]).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let upper_size = ProbNode::get_serialized_size(8) as u32;
+        let level_0_size = ProbNode::get_serialized_size(16) as u32;
+        let item = |id, level, offset| {
+            let neighbors_count = if level == 0 { 16 } else { 8 };
+            let node = create_node(&cache, id, HNSWLevel(level), neighbors_count);
+            ProbLazyItem::new(node, version_id, 0, level == 0, FileOffset(offset))
+        };
+        let data = |item: SharedNode| unsafe { &*item }.get_lazy_data().unwrap();
+
+        // Node 0 on levels 1 and 0 linked to its neighbor 1 on both levels,
+        // and node 2, written on both levels but never linked
+        let top = item(0, 1, 0);
+        let bottom = item(0, 0, 0);
+        let orphan_top = item(2, 1, upper_size);
+        let neighbor_top = item(1, 1, 2 * upper_size);
+        let neighbor_bottom = item(1, 0, level_0_size);
+        let orphan_bottom = item(2, 0, 2 * level_0_size);
+        data(top).set_child(bottom);
+        data(bottom).set_parent(top);
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+        data(top).add_neighbor(1, neighbor_top, dist, &cache);
+        data(bottom).add_neighbor(1, neighbor_bottom, dist, &cache);
+        for item in [
+            top,
+            bottom,
+            orphan_top,
+            neighbor_top,
+            neighbor_bottom,
+            orphan_bottom,
+        ] {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        let root = unsafe { &*top }.get_file_index();
+        assert_eq!(
+            cache.find_orphans(version_id, false, &[root]).unwrap(),
+            vec![FileOffset(upper_size)]
+        );
+        assert_eq!(
+            cache.find_orphans(version_id, true, &[root]).unwrap(),
+            vec![FileOffset(2 * level_0_size)]
+        );
+        // Without roots, every node is unreachable
+        assert_eq!(
+            cache.find_orphans(version_id, true, &[]).unwrap(),
+            vec![
+                FileOffset(0),
+                FileOffset(level_0_size),
+                FileOffset(2 * level_0_size)
+            ]
+        );
+        assert!(cache
+            .find_orphans(Hash::from(1), false, &[root])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_object_levels() {
+        let (cache, dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let upper_size = ProbNode::get_serialized_size(8) as u32;
+        let level_0_size = ProbNode::get_serialized_size(16) as u32;
+        let item = |id, level, offset| {
+            let neighbors_count = if level == 0 { 16 } else { 8 };
+            let node = create_node(&cache, id, HNSWLevel(level), neighbors_count);
+            ProbLazyItem::new(node, version_id, 0, level == 0, FileOffset(offset))
+        };
+        let data = |item: SharedNode| unsafe { &*item }.get_lazy_data().unwrap();
+        let link = |parent: SharedNode, child: SharedNode| {
+            data(parent).set_child(child);
+            data(child).set_parent(parent);
+        };
+
+        // Node 0 on levels 2 to 0, and node 1 on levels 1 and 0, which is
+        // a neighbor of node 0 on level 1
+        let top = item(0, 2, 0);
+        let middle = item(0, 1, upper_size);
+        let bottom = item(0, 0, 0);
+        let neighbor = item(1, 1, 2 * upper_size);
+        let neighbor_bottom = item(1, 0, level_0_size);
+        link(top, middle);
+        link(middle, bottom);
+        link(neighbor, neighbor_bottom);
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+        data(middle).add_neighbor(1, neighbor, dist, &cache);
+        for item in [top, middle, bottom, neighbor, neighbor_bottom] {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        cache.bufmans.flush_all().unwrap();
+        cache.level_0_bufmans.flush_all().unwrap();
+        let file_index = unsafe { &*middle }.get_file_index();
+
+        // Caches over their own level-0 buffer managers, which start
+        // without any region of the files read
+        let cold_cache = || {
+            let level_0_bufmans = Arc::new(BufferManagerFactory::new(
+                dir.as_ref().into(),
+                |root, ver: &Hash| root.join(format!("{}_0.index", **ver)),
+                ProbNode::get_serialized_size(16),
+            ));
+            let cache = DenseIndexCache::new(
+                cache.bufmans.clone(),
+                level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            );
+            (cache, level_0_bufmans.get(version_id).unwrap())
         };
-        let file_size = bufman.file_size();
-        if region_start as u64 > file_size {
-            return Ok(Vec::new());
+
+        let (levels_cache, level_0_bufman) = cold_cache();
+        let loaded = levels_cache
+            .get_object_levels(file_index, false, 1..=2)
+            .unwrap();
+        assert_eq!(level_0_bufman.read_count(), 0);
+        let loaded = data(loaded);
+        assert!(unsafe { &*loaded.get_parent() }.is_resolved());
+        assert!(!unsafe { &*loaded.get_child() }.is_resolved());
+        let loaded_neighbor = data(loaded.get_neighbors()[0]);
+        assert_eq!(loaded_neighbor.get_id(), &VectorId(1));
+        assert!(!unsafe { &*loaded_neighbor.get_child() }.is_resolved());
+
+        // The children are read from the level-0 file otherwise
+        let (full_cache, level_0_bufman) = cold_cache();
+        let loaded = data(full_cache.get_object(file_index, false).unwrap());
+        assert!(level_0_bufman.read_count() > 0);
+        assert!(unsafe { &*loaded.get_child() }.is_resolved());
+
+        let level_0_index = unsafe { &*bottom }.get_file_index();
+        assert!(levels_cache
+            .get_object_levels(level_0_index, true, 1..=2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_get_node_header() {
+        let (cache, dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let items: Vec<_> = (0..9)
+            .map(|id| {
+                let node = create_node(&cache, id, HNSWLevel(1), 8);
+                ProbLazyItem::new(
+                    node,
+                    version_id,
+                    0,
+                    false,
+                    FileOffset(id as u32 * node_size),
+                )
+            })
+            .collect();
+        let node = unsafe { &*items[0] }.get_lazy_data().unwrap();
+        for neighbor in 1..9 {
+            let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+            node.add_neighbor(neighbor as u32, items[neighbor], dist, &cache);
         }
-        println!(
-            "Loading region: {}, version: {}, is_level_0: {}",
-            region_start, version_number, is_level_0
+        for &item in &items {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        let prop_location = node.prop.location;
+        let file_index = unsafe { &*items[0] }.get_file_index();
+        cache.bufmans.flush_all().unwrap();
+
+        // Caches over their own buffer managers, which start without any
+        // region of the file read
+        let cold_cache = || {
+            let bufmans = Arc::new(BufferManagerFactory::new(
+                dir.as_ref().into(),
+                |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+                ProbNode::get_serialized_size(8),
+            ));
+            let cache = DenseIndexCache::new(
+                bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            );
+            (cache, bufmans.get(version_id).unwrap())
+        };
+
+        let (header_cache, bufman) = cold_cache();
+        let header = header_cache.get_node_header(file_index, false).unwrap();
+        let header_reads = bufman.read_count();
+        assert_eq!(
+            header,
+            NodeHeader {
+                hnsw_level: HNSWLevel(1),
+                prop_location,
+                version_id,
+                version_number: 0,
+                parent: FileIndex::Invalid,
+                child: FileIndex::Invalid,
+                root_version: FileIndex::Invalid,
+                neighbors_count: 8,
+                neighbor_encoding: NeighborEncoding::Indexed,
+            }
         );
-        let cap = ((file_size - region_start as u64) / node_size as u64).min(1000) as usize;
-        let mut nodes = Vec::with_capacity(cap);
-        for i in 0..1000 {
-            let offset = FileOffset(i * node_size + region_start);
-            if offset.0 as u64 >= file_size {
-                break;
+        // Neither the node nor its prop were loaded
+        assert!(header_cache.registry.is_empty());
+        assert!(header_cache
+            .try_get_prop(prop_location.0, prop_location.1)
+            .is_none());
+
+        // A full load also reads the regions holding the neighbors
+        let (full_cache, bufman) = cold_cache();
+        full_cache.get_object(file_index, false).unwrap();
+        let full_reads = bufman.read_count();
+        assert_eq!(header_reads, 1);
+        assert!(full_reads >= 9 * header_reads);
+
+        assert!(cache.get_node_header(FileIndex::Invalid, false).is_err());
+    }
+
+    #[test]
+    fn test_eviction_sink() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let version_id = Hash::from(1);
+        let file_index = |i: u32| FileIndex::Valid {
+            offset: FileOffset(i * node_size),
+            version_number: 0,
+            version_id,
+        };
+        for i in 0..6 {
+            let node = create_node(&cache, i, HNSWLevel(1), 8);
+            let item =
+                ProbLazyItem::new(node, version_id, 0, false, FileOffset(i as u32 * node_size));
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        // Cold cache that evicts as soon as it holds more than 4 nodes
+        let mut cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        cache.registry = ShardedLRUCache::new(4, 1, EvictStrategy::Immediate);
+        for i in 0..6 {
+            cache.get_object(file_index(i), false).unwrap();
+        }
+        // Nothing is evicted without a sink
+        for i in 0..6 {
+            let key = DenseIndexCache::combine_index(&file_index(i), false);
+            assert!(cache.registry.peek(&key).is_some());
+        }
+        cache.registry = ShardedLRUCache::new(4, 1, EvictStrategy::Immediate);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let cache = cache.with_eviction_sink({
+            let evicted = evicted.clone();
+            move |key, node| {
+                let file_index = unsafe { &*node }.get_file_index();
+                evicted.lock().unwrap().push((key, file_index));
             }
+        });
+        for i in 0..6 {
+            cache.get_object(file_index(i), false).unwrap();
+        }
+
+        // The least recently used nodes, as keyed in the registry
+        let evicted = evicted.lock().unwrap();
+        assert_eq!(
+            *evicted,
+            (0..2)
+                .map(|i| {
+                    let key = DenseIndexCache::combine_index(&file_index(i), false);
+                    (key, file_index(i))
+                })
+                .collect::<Vec<_>>()
+        );
+        for (key, _) in evicted.iter() {
+            assert!(cache.registry.peek(key).is_none());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_survives_compaction() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let (old_version, dst_version) = (Hash::from(1), Hash::from(2));
+        for i in 0..3 {
+            let node = create_node(&cache, i, HNSWLevel(1), 8);
+            let item = ProbLazyItem::new(
+                node,
+                old_version,
+                0,
+                false,
+                FileOffset(i as u32 * node_size),
+            );
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, old_version).unwrap();
+        }
+        let old_path = cache.bufmans.file_path(&old_version);
+
+        let snapshot = cache.snapshot(&[old_version]).unwrap();
+        let snapshot_clone = snapshot.clone();
+        cache.compact(&[old_version], dst_version).unwrap();
+        assert!(cache.bufmans.file_path(&dst_version).exists());
+
+        // The pinned file is still there and readable
+        assert!(old_path.exists());
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        for i in 0..3 {
             let file_index = FileIndex::Valid {
-                offset,
-                version_number,
+                offset: FileOffset(i * node_size),
+                version_number: 0,
+                version_id: old_version,
+            };
+            let node = cold_cache.get_object(file_index, false).unwrap();
+            assert_eq!(unsafe { &*node }.get_current_version_id(), old_version);
+        }
+
+        // Removed once the last snapshot pinning it is dropped
+        drop(snapshot);
+        assert!(old_path.exists());
+        drop(snapshot_clone);
+        assert!(!old_path.exists());
+        assert!(!cache.bufmans.is_open(&old_version));
+
+        // Unpinned files are removed right away
+        cache.compact(&[dst_version], Hash::from(3)).unwrap();
+        assert!(!cache.bufmans.file_path(&dst_version).exists());
+    }
+
+    #[test]
+    fn test_compact_retires_unlinked_files() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(16) as u64;
+        let new_cache = || {
+            DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            )
+        };
+        let writer = new_cache().with_max_version_file_bytes(2 * node_size);
+        let (source, dst) = (Hash::from(1), Hash::from(2));
+        for id in 0..5 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item = ProbLazyItem::new(node, source, 0, true, FileOffset(u32::MAX));
+            unsafe { writer.append_node(source, item, true) }.unwrap();
+        }
+        let chain = writer.version_chain(source, true).unwrap();
+        assert_eq!(chain.len(), 3);
+
+        // Nodes resident in the cache keep their files
+        let resident = Hash::from(3);
+        let node = create_node(&cache, 5, HNSWLevel(0), 16);
+        let item = ProbLazyItem::new(node, resident, 0, true, FileOffset(u32::MAX));
+        unsafe { writer.append_node(resident, item, true) }.unwrap();
+        writer.compact(&[resident], Hash::from(4)).unwrap();
+        assert!(cache.level_0_bufmans.file_path(&resident).exists());
+
+        // So do files that other versions link into
+        let (parent_version, child_version) = (Hash::from(5), Hash::from(6));
+        let parent = ProbLazyItem::new(
+            create_node(&cache, 6, HNSWLevel(2), 8),
+            parent_version,
+            0,
+            false,
+            FileOffset(0),
+        );
+        write_node_to_file(
+            parent,
+            &cache.bufmans,
+            &cache.level_0_bufmans,
+            parent_version,
+        )
+        .unwrap();
+        let child = ProbNode::new(
+            HNSWLevel(1),
+            create_prop(&cache, 7),
+            parent,
+            ptr::null_mut(),
+            8,
+        );
+        let child = ProbLazyItem::new(child, child_version, 0, false, FileOffset(0));
+        write_node_to_file(child, &cache.bufmans, &cache.level_0_bufmans, child_version).unwrap();
+        new_cache()
+            .compact(&[parent_version], Hash::from(7))
+            .unwrap();
+        assert!(cache.bufmans.file_path(&parent_version).exists());
+
+        // The others are removed, along with their continuations and headers
+        let compactor = new_cache();
+        compactor.compact(&[source], dst).unwrap();
+        for version_id in &chain {
+            assert!(!cache.level_0_bufmans.file_path(version_id).exists());
+            assert!(!compactor.header_path(*version_id, true).exists());
+        }
+        let nodes = compactor
+            .load_region(0, 0, dst, node_size as u32, true)
+            .unwrap();
+        let ids: Vec<_> = nodes
+            .iter()
+            .map(|&node| unsafe { &*node }.get_lazy_data().unwrap().prop.id.0)
+            .collect();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+        assert!(compactor.file_level(dst).unwrap().is_level_0());
+    }
+
+    #[test]
+    fn test_get_object_opt() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(1);
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(0));
+        write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+
+        let file_index = |version_id| FileIndex::Valid {
+            offset: FileOffset(0),
+            version_number: 0,
+            version_id,
+        };
+        let node = cache.get_object_opt(file_index(version_id), false).unwrap();
+        assert!(node.is_some());
+
+        let missing_version = Hash::from(7);
+        let node = cache
+            .get_object_opt(file_index(missing_version), false)
+            .unwrap();
+        assert!(node.is_none());
+        // The missing file isn't created by the lookup
+        assert!(!cache.bufmans.file_path(&missing_version).exists());
+    }
+
+    #[test]
+    fn test_peek_object_scan() {
+        let (cache, _dir) = setup_cache();
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let version_id = Hash::from(1);
+        let file_index = |i: u32| FileIndex::Valid {
+            offset: FileOffset(i * node_size),
+            version_number: 0,
+            version_id,
+        };
+        for i in 0..12 {
+            let node = create_node(&cache, i, HNSWLevel(1), 8);
+            let item =
+                ProbLazyItem::new(node, version_id, 0, false, FileOffset(i as u32 * node_size));
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        // Cold cache that evicts as soon as it holds more than 4 nodes
+        let mut cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        cache.registry = ShardedLRUCache::new(4, 1, EvictStrategy::Immediate);
+        cache.registry.set_evict_on_insert(true);
+        let is_resident = |cache: &DenseIndexCache, i| {
+            let key = DenseIndexCache::combine_index(&file_index(i), false);
+            cache.registry.peek(&key).is_some()
+        };
+        let scan = |cache: &DenseIndexCache, range: std::ops::Range<u32>, peek: bool| {
+            for i in range {
+                let node = if peek {
+                    cache.peek_object(file_index(i), false)
+                } else {
+                    cache.get_object(file_index(i), false)
+                };
+                assert_eq!(unsafe { &*node.unwrap() }.get_file_index(), file_index(i));
+            }
+        };
+
+        scan(&cache, 0..4, false);
+        let hot: Vec<_> = (0..4)
+            .map(|i| cache.peek_object(file_index(i), false).unwrap())
+            .collect();
+        assert!((0..4).all(|i| is_resident(&cache, i)));
+
+        scan(&cache, 4..12, true);
+        assert_eq!(cache.registry.len(), 4);
+        assert!((0..4).all(|i| is_resident(&cache, i)));
+        for (i, item) in hot.iter().enumerate() {
+            let node = cache.peek_object(file_index(i as u32), false).unwrap();
+            assert!(std::ptr::eq(node, *item));
+        }
+
+        // Peeking didn't promote node 0, so it's still the first to go
+        scan(&cache, 4..5, false);
+        assert!(!is_resident(&cache, 0));
+        assert!((1..5).all(|i| is_resident(&cache, i)));
+
+        // A normal scan pushes out the rest of the hot set
+        scan(&cache, 5..12, false);
+        assert!(!(0..4).any(|i| is_resident(&cache, i)));
+        assert_eq!(cache.registry.len(), 4);
+    }
+
+    #[test]
+    fn test_node_alignment() {
+        let (cache, _dir) = setup_cache();
+        let aligned_cache = || {
+            DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            )
+            .with_node_alignment(64)
+        };
+        let version_id = Hash::from(3);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let writer = aligned_cache();
+        let node_stride = writer.node_stride(node_size);
+        assert_eq!(node_stride, 320);
+
+        for id in 0..5 {
+            let node = create_node(&cache, id, HNSWLevel(1), 8);
+            let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(u32::MAX));
+            let offset = unsafe { writer.append_node(version_id, item, false) }.unwrap();
+            assert_eq!(offset.0 % 64, 0);
+            assert_eq!(offset.0, id as u32 * node_stride);
+        }
+        // The layout is recorded in the file's header
+        let header = aligned_cache().read_header(version_id, false).unwrap();
+        assert_eq!(header.format_version, VersionFileHeader::FORMAT_VERSION);
+        assert_eq!(header.node_size, node_size);
+        assert_eq!(header.node_alignment, 64);
+        assert_eq!(
+            header.level_info,
+            Some(LevelInfo {
+                min_level: HNSWLevel(1),
+                max_level: HNSWLevel(1),
+            })
+        );
+
+        // and read back from there, so caches that don't configure the
+        // alignment keep to it when appending and reading
+        let reader = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let node = create_node(&cache, 5, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(u32::MAX));
+        let offset = unsafe { reader.append_node(version_id, item, false) }.unwrap();
+        assert_eq!(offset.0, 5 * node_stride);
+        let nodes = reader
+            .load_region(0, 0, version_id, node_size, false)
+            .unwrap();
+        assert_eq!(nodes.len(), 6);
+        for (id, &node) in nodes.iter().enumerate() {
+            let node = unsafe { &*node };
+            assert_eq!(
+                node.get_file_index().get_offset(),
+                Some(FileOffset(id as u32 * node_stride))
+            );
+            let data = node.get_lazy_data().unwrap();
+            assert_eq!(data.prop.id, VectorId(id as u64));
+            assert_eq!(data.hnsw_level, HNSWLevel(1));
+        }
+    }
+
+    #[test]
+    fn test_put_and_cache() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(3);
+        let node = create_node(&cache, 1, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(u32::MAX));
+        let offset = unsafe { cache.put_and_cache(version_id, item, false) }.unwrap();
+
+        let bufman = cache.bufmans.get(version_id).unwrap();
+        let reads = bufman.read_count();
+        let file_index = FileIndex::Valid {
+            offset,
+            version_number: 0,
+            version_id,
+        };
+        assert_eq!(cache.get_object(file_index, false).unwrap(), item);
+        assert_eq!(bufman.read_count(), reads);
+    }
+
+    #[test]
+    fn test_append_node() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(3);
+        let node_size = ProbNode::get_serialized_size(16) as u32;
+
+        // Existing content of the version file
+        let existing = ProbLazyItem::new(
+            create_node(&cache, 0, HNSWLevel(0), 16),
+            version_id,
+            0,
+            true,
+            FileOffset(0),
+        );
+        write_node_to_file(existing, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+
+        let mut offsets = Vec::new();
+        for id in 1..5 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item = ProbLazyItem::new(node, Hash::from(99), 0, true, FileOffset(u32::MAX));
+            // Taken before the node is moved, stays readable after
+            let data = unsafe { &*item }.get_lazy_data().unwrap();
+            let offset = unsafe { cache.append_node(version_id, item, true) }.unwrap();
+            assert_eq!(unsafe { &*item }.get_current_version_id(), version_id);
+            assert_eq!(data.prop.id.0, id as u64);
+            offsets.push(offset.0);
+        }
+        assert_eq!(
+            offsets,
+            vec![node_size, 2 * node_size, 3 * node_size, 4 * node_size]
+        );
+
+        // Concurrent appends get distinct aligned offsets
+        let handles: Vec<_> = (5..13)
+            .map(|id| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    let node = create_node(&cache, id, HNSWLevel(0), 16);
+                    let item = ProbLazyItem::new(node, version_id, 0, true, FileOffset(0));
+                    unsafe { cache.append_node(version_id, item, true) }
+                        .unwrap()
+                        .0
+                })
+            })
+            .collect();
+        offsets.extend(handles.into_iter().map(|h| h.join().unwrap()));
+        let mut sorted = offsets.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 12);
+        assert!(sorted.iter().all(|offset| offset % node_size == 0));
+
+        // Read every node back from disk
+        for (i, offset) in offsets.into_iter().enumerate() {
+            let file_index = FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_number: 0,
                 version_id,
             };
-            let node = self.force_load_single_object(file_index, is_level_0)?;
-            nodes.push(node);
+            assert!(cache.evict(file_index, true));
+            let loaded = cache.get_object(file_index, true).unwrap();
+            let node = unsafe { &*loaded }.get_lazy_data().unwrap();
+            assert_eq!(node.prop.id.0, i as u64 + 1);
+            assert_eq!(node.hnsw_level, HNSWLevel(0));
         }
-        Ok(nodes)
     }
 
-    // Retrieves an object from the cache, attempting to batch load if possible, based on the state of the batch load lock.
-    //
-    // This function first attempts to acquire the `batch_load_lock` using a non-blocking `try_lock`. If successful,
-    // it sets a high `max_loads` value (1000), allowing for a larger batch load. This is the preferred scenario where
-    // the system is capable of performing a more efficient batch load, loading multiple nodes at once. If the lock is
-    // already held (i.e., another thread is performing a large batch load), the function falls back to a lower `max_loads`
-    // value (1), effectively loading nodes one at a time to avoid blocking or deadlocking.
-    //
-    // The key idea here is to **always attempt to load as many nodes as possible** (with `max_loads = 1000`) unless
-    // another thread is already performing a large load, in which case the function resorts to a smaller load size.
-    // This dynamic loading strategy balances efficient batch loading with the need to avoid blocking or deadlocks in high-concurrency situations.
-    //
-    // After determining the appropriate `max_loads`, the function proceeds by calling `get_lazy_object`, which handles
-    // the actual loading process, and retrieves the lazy-loaded data.
-    pub fn get_object(
-        &self,
-        file_index: FileIndex,
-        is_level_0: bool,
-    ) -> Result<SharedNode, BufIoError> {
-        let (_lock, max_loads) = match self.batch_load_lock.try_lock() {
-            Ok(lock) => (Some(lock), 1000),
-            Err(TryLockError::Poisoned(poison_err)) => panic!("lock error: {}", poison_err),
-            Err(TryLockError::WouldBlock) => (None, 1),
-        };
-        self.get_lazy_object(file_index, max_loads, &mut HashSet::new(), is_level_0)
+    #[test]
+    fn test_append_node_rollover() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(3);
+        let node_size = ProbNode::get_serialized_size(16) as u64;
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        )
+        .with_max_version_file_bytes(3 * node_size);
+
+        let file_indices: Vec<_> = (0..8)
+            .map(|id| {
+                let node = create_node(&cache, id, HNSWLevel(0), 16);
+                let item = ProbLazyItem::new(node, version_id, 0, true, FileOffset(0));
+                unsafe { cache.append_node(version_id, item, true) }.unwrap();
+                unsafe { &*item }.get_file_index()
+            })
+            .collect();
+
+        let chain = cache.version_chain(version_id, true).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0], version_id);
+        for (i, file_index) in file_indices.iter().enumerate() {
+            let FileIndex::Valid {
+                offset, version_id, ..
+            } = file_index
+            else {
+                unreachable!()
+            };
+            assert_eq!(*version_id, chain[i / 3]);
+            assert_eq!(offset.0 as u64, (i % 3) as u64 * node_size);
+        }
+        for version_id in &chain {
+            let bufman = cache.level_0_bufmans.get(*version_id).unwrap();
+            assert!(bufman.file_size() <= 3 * node_size);
+        }
+
+        // The chain is recorded on disk, and every node is still readable
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        assert_eq!(cold_cache.version_chain(version_id, true).unwrap(), chain);
+        assert_eq!(
+            cold_cache
+                .read_header(version_id, true)
+                .unwrap()
+                .continuation,
+            Some(chain[1])
+        );
+        assert_eq!(
+            cold_cache.read_header(chain[1], true).unwrap().continues,
+            Some(version_id)
+        );
+        for (id, file_index) in file_indices.into_iter().enumerate() {
+            let loaded = cold_cache.get_object(file_index, true).unwrap();
+            let node = unsafe { &*loaded }.get_lazy_data().unwrap();
+            assert_eq!(node.prop.id, VectorId(id as u64));
+        }
+        assert_eq!(
+            cold_cache.file_level(version_id).unwrap(),
+            LevelInfo {
+                min_level: HNSWLevel(0),
+                max_level: HNSWLevel(0),
+            }
+        );
+
+        // The continuation ids can't be used for versions of their own
+        assert!(cache.register_version(1, chain[1]).is_err());
+        let node = create_node(&cache, 8, HNSWLevel(0), 16);
+        let item = ProbLazyItem::new(node, chain[2], 1, true, FileOffset(0));
+        assert!(unsafe { cache.append_node(chain[2], item, true) }.is_err());
+        assert_eq!(cache.resolve_version(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_replicate_node_bytes() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let items: Vec<_> = (0..2)
+            .map(|id| {
+                let node = create_node(&cache, id, HNSWLevel(1), 8);
+                ProbLazyItem::new(
+                    node,
+                    version_id,
+                    0,
+                    false,
+                    FileOffset(id as u32 * node_size),
+                )
+            })
+            .collect();
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity(0.5));
+        unsafe { &*items[0] }
+            .get_lazy_data()
+            .unwrap()
+            .add_neighbor(1, items[1], dist, &cache);
+        for &item in &items {
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        // Fresh index files, props are replicated separately
+        let (replica, _replica_dir) = setup_cache();
+        let replica = DenseIndexCache::new(
+            replica.bufmans.clone(),
+            replica.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let file_indices: Vec<_> = items
+            .iter()
+            .map(|&item| unsafe { &*item }.get_file_index())
+            .collect();
+        for &file_index in &file_indices {
+            let bytes = cache.read_node_bytes(file_index, false).unwrap();
+            assert_eq!(bytes.len(), node_size as usize);
+            replica.write_node_bytes(file_index, false, &bytes).unwrap();
+            assert_eq!(replica.read_node_bytes(file_index, false).unwrap(), bytes);
+        }
+
+        let original = unsafe { &*items[0] }.get_lazy_data().unwrap();
+        let loaded = replica.get_object(file_indices[0], false).unwrap();
+        let loaded = unsafe { &*loaded }.get_lazy_data().unwrap();
+        assert_eq!(loaded.hnsw_level, original.hnsw_level);
+        assert_eq!(loaded.prop.id, original.prop.id);
+        assert_eq!(loaded.prop.location, original.prop.location);
+        let neighbors = |node: &ProbNode| -> Vec<_> {
+            node.get_neighbors_raw()
+                .iter()
+                .filter_map(|neighbor| unsafe { neighbor.load(Ordering::SeqCst).as_ref() })
+                .map(|(id, neighbor, dist)| (*id, unsafe { &**neighbor }.get_file_index(), *dist))
+                .collect()
+        };
+        assert_eq!(neighbors(loaded), neighbors(original));
+        assert_eq!(neighbors(loaded).len(), 1);
+
+        assert!(replica
+            .write_node_bytes(file_indices[0], false, &[0; 10])
+            .is_err());
+    }
+
+    #[test]
+    fn test_merge_versions() {
+        let (cache, _dir) = setup_cache();
+        let level_0_node_size = ProbNode::get_serialized_size(16) as u32;
+        let new_item = |id, level: u8, child, version: u32, index: u32| {
+            let is_level_0 = level == 0;
+            let (neighbors_count, node_size) = if is_level_0 {
+                (16, level_0_node_size)
+            } else {
+                (8, ProbNode::get_serialized_size(8) as u32)
+            };
+            let node = ProbNode::new(
+                HNSWLevel(level),
+                create_prop(&cache, id),
+                ptr::null_mut(),
+                child,
+                neighbors_count,
+            );
+            ProbLazyItem::new(
+                node,
+                Hash::from(version),
+                version as u16,
+                is_level_0,
+                FileOffset(index * node_size),
+            )
+        };
+        let add_neighbor = |node: SharedNode, id: u32, neighbor: SharedNode| {
+            let dist = MetricResult::CosineSimilarity(CosineSimilarity(id as f32 / 10.0));
+            unsafe { &*node }
+                .get_lazy_data()
+                .unwrap()
+                .add_neighbor(id, neighbor, dist, &cache);
+        };
+
+        // Level 0 nodes spread over the three versions, linked across them
+        let level_0 = [
+            new_item(0, 0, ptr::null_mut(), 2, 0),
+            new_item(1, 0, ptr::null_mut(), 2, 1),
+            new_item(2, 0, ptr::null_mut(), 3, 0),
+            new_item(3, 0, ptr::null_mut(), 3, 1),
+            new_item(4, 0, ptr::null_mut(), 1, 0),
+        ];
+        add_neighbor(level_0[0], 1, level_0[1]);
+        add_neighbor(level_0[0], 2, level_0[2]);
+        add_neighbor(level_0[1], 3, level_0[3]);
+        add_neighbor(level_0[2], 0, level_0[0]);
+        add_neighbor(level_0[3], 4, level_0[4]);
+        // Level 1 entry in version 1, its neighbor in version 3
+        let entry = new_item(0, 1, level_0[0], 1, 0);
+        let level_1_neighbor = new_item(5, 1, ptr::null_mut(), 3, 0);
+        add_neighbor(entry, 5, level_1_neighbor);
+
+        for node in level_0.into_iter().chain([entry, level_1_neighbor]) {
+            let version_id = unsafe { &*node }.get_current_version_id();
+            write_node_to_file(node, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        unsafe { cache.insert_lazy_object(Hash::from(3), level_0_node_size, level_0[3]) };
+        // As `append_node` would have
+        cache
+            .record_location(
+                &VectorId(3),
+                Hash::from(3),
+                unsafe { &*level_0[3] }.get_current_version_number(),
+                FileOffset(level_0_node_size),
+            )
+            .unwrap();
+
+        // Collects every node reachable from the entry, with the ids of its
+        // neighbors and child, and the versions they were loaded from
+        let traverse = |entry: FileIndex| {
+            let cache = DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            );
+            let mut nodes = Vec::new();
+            let mut versions = HashSet::new();
+            let mut visited = HashSet::new();
+            let mut stack = vec![(entry, false)];
+            while let Some((file_index, is_level_0)) = stack.pop() {
+                if !visited.insert(DenseIndexCache::combine_index(&file_index, is_level_0)) {
+                    continue;
+                }
+                if let FileIndex::Valid { version_id, .. } = file_index {
+                    versions.insert(version_id);
+                }
+                let node = cache.get_object(file_index, is_level_0).unwrap();
+                let data = unsafe { &*node }.try_get_data(&cache).unwrap();
+                let mut neighbor_ids = Vec::new();
+                for neighbor in data.get_neighbors_raw().iter() {
+                    let Some((id, neighbor, _)) =
+                        (unsafe { neighbor.load(Ordering::SeqCst).as_ref() })
+                    else {
+                        continue;
+                    };
+                    neighbor_ids.push(*id);
+                    stack.push((unsafe { &**neighbor }.get_file_index(), is_level_0));
+                }
+                neighbor_ids.sort();
+                let child = unsafe { data.get_child().as_ref() }.map(|child| {
+                    let child_data = child.try_get_data(&cache).unwrap();
+                    stack.push((child.get_file_index(), child.is_level_0));
+                    child_data.prop.id.0
+                });
+                nodes.push((data.prop.id.0, data.hnsw_level.0, neighbor_ids, child));
+            }
+            nodes.sort();
+            (nodes, versions)
+        };
+
+        let (before, versions) = traverse(unsafe { &*entry }.get_file_index());
+        assert_eq!(before.len(), 7);
+        assert_eq!(versions.len(), 3);
+
+        let dst = Hash::from(10);
+        cache
+            .merge_versions(&[Hash::from(1), Hash::from(2), Hash::from(3)], dst)
+            .unwrap();
+
+        // The entry comes first in the merged higher level file
+        let (after, versions) = traverse(FileIndex::Valid {
+            offset: FileOffset(0),
+            version_number: 1,
+            version_id: dst,
+        });
+        assert_eq!(after, before);
+        assert_eq!(versions, HashSet::from([dst]));
+
+        // Version 1's level 0 node goes first, then versions 2 and 3
+        assert_eq!(
+            cache.locate(VectorId(3)).unwrap(),
+            Some(FileIndex::Valid {
+                offset: FileOffset(4 * level_0_node_size),
+                version_number: 3,
+                version_id: dst,
+            })
+        );
+
+        assert!(cache.merge_versions(&[dst], dst).is_err());
+    }
+
+    #[test]
+    fn test_prefetch_neighbor_props() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(16) as u32;
+        let items: Vec<_> = (0..4)
+            .map(|id| {
+                let node = create_node(&cache, id, HNSWLevel(0), 16);
+                ProbLazyItem::new(node, version_id, 0, true, FileOffset(id as u32 * node_size))
+            })
+            .collect();
+        let node = unsafe { &*items[0] }.get_lazy_data().unwrap();
+        for (i, neighbor) in items.iter().enumerate().skip(1) {
+            let dist = MetricResult::CosineSimilarity(CosineSimilarity(i as f32 / 10.0));
+            node.add_neighbor(i as u32, *neighbor, dist, &cache);
+        }
+        let neighbor_locations: Vec<_> = items[1..]
+            .iter()
+            .map(|item| unsafe { &**item }.get_lazy_data().unwrap().prop.location)
+            .collect();
+        for item in &items {
+            write_node_to_file(*item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        // Start with a cold cache, loading only the node itself
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let file_index = unsafe { &*items[0] }.get_file_index();
+        let loaded = cache.force_load_single_object(file_index, true).unwrap();
+        for (offset, length) in &neighbor_locations {
+            assert!(cache.try_get_prop(*offset, *length).is_none());
+        }
+
+        cache.prefetch_neighbor_props(&loaded).unwrap();
+
+        for (i, (offset, length)) in neighbor_locations.iter().enumerate() {
+            let prop = cache.try_get_prop(*offset, *length).unwrap();
+            assert_eq!(prop.id.0, i as u64 + 1);
+        }
+
+        // Loading a neighbor takes over the prefetched prop
+        let prop = cache.try_get_prop(neighbor_locations[0].0, neighbor_locations[0].1);
+        let neighbor = cache
+            .get_object(unsafe { &*items[1] }.get_file_index(), true)
+            .unwrap();
+        let neighbor_prop = &unsafe { &*neighbor }.get_lazy_data().unwrap().prop;
+        assert!(Arc::ptr_eq(&prop.unwrap(), neighbor_prop));
+        let prefetched_bytes = cache.prefetched_props.lock().unwrap().bytes;
+        assert_eq!(cache.prefetched_props.lock().unwrap().props.len(), 2);
+
+        // Props that aren't loaded don't pile up beyond the cap
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        )
+        .with_prefetched_prop_bytes(prefetched_bytes / 2);
+        let loaded = cache.force_load_single_object(file_index, true).unwrap();
+        cache.prefetch_neighbor_props(&loaded).unwrap();
+        let prefetched = cache.prefetched_props.lock().unwrap();
+        assert!(prefetched.bytes <= prefetched_bytes / 2);
+        assert!(prefetched.props.len() < 3);
+        let resident = neighbor_locations
+            .iter()
+            .filter(|(offset, length)| {
+                cache
+                    .props_registry
+                    .contains_key(&DenseIndexCache::get_prop_key(*offset, *length))
+            })
+            .count();
+        assert_eq!(resident, prefetched.props.len());
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let level_0_size = ProbNode::get_serialized_size(16) as u32;
+        let level_1_size = ProbNode::get_serialized_size(8) as u32;
+        for id in 0..10 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item = ProbLazyItem::new(
+                node,
+                version_id,
+                0,
+                true,
+                FileOffset(id as u32 * level_0_size),
+            );
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        for id in 0..4 {
+            let node = create_node(&cache, id + 10, HNSWLevel(1), 8);
+            let item = ProbLazyItem::new(
+                node,
+                version_id,
+                0,
+                false,
+                FileOffset(id as u32 * level_1_size),
+            );
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        let cold_cache = || {
+            DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            )
+        };
+        let file_index = |offset| FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id,
+        };
+
+        let old_cache = cold_cache();
+        for id in [1, 4, 7] {
+            old_cache
+                .get_object(file_index(id * level_0_size), true)
+                .unwrap();
+        }
+        old_cache
+            .get_object(file_index(2 * level_1_size), false)
+            .unwrap();
+        let manifest = old_cache.export_manifest();
+        let mut expected = vec![
+            (version_id, level_0_size, true),
+            (version_id, 4 * level_0_size, true),
+            (version_id, 7 * level_0_size, true),
+            (version_id, 2 * level_1_size, false),
+        ];
+        expected.sort_unstable_by_key(|(_, offset, is_level_0)| (*offset, *is_level_0));
+        assert_eq!(manifest, expected);
+
+        let new_cache = cold_cache();
+        assert!(new_cache.export_manifest().is_empty());
+        new_cache.warm_from_manifest(&manifest, |_| 0).unwrap();
+        assert_eq!(new_cache.export_manifest(), manifest);
+        for &(_, offset, is_level_0) in &manifest {
+            let node = new_cache
+                .registry
+                .get(&DenseIndexCache::combine_index(
+                    &file_index(offset),
+                    is_level_0,
+                ))
+                .unwrap();
+            assert!(unsafe { &*node.load() }.is_ready());
+        }
+    }
+
+    #[test]
+    fn test_resolve_pending() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        for id in 0..3 {
+            let node = create_node(&cache, id, HNSWLevel(1), 8);
+            let item = ProbLazyItem::new(
+                node,
+                version_id,
+                0,
+                false,
+                FileOffset(id as u32 * node_size),
+            );
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(node_size),
+            version_number: 0,
+            version_id,
+        };
+
+        let pending = cold_cache
+            .get_lazy_object(
+                file_index.clone(),
+                0,
+                usize::MAX,
+                &mut HashSet::new(),
+                false,
+            )
+            .unwrap();
+        assert!(unsafe { &*pending }.is_pending());
+
+        let resolved = unsafe { cold_cache.resolve_pending(pending) }.unwrap();
+        assert_eq!(resolved, pending);
+        let resolved = unsafe { &*resolved };
+        assert!(resolved.is_ready());
+        assert_eq!(resolved.get_file_index(), file_index);
+        let data = resolved.try_get_data(&cold_cache).unwrap();
+        assert_eq!(data.hnsw_level, HNSWLevel(1));
+        assert_eq!(data.get_id(), &VectorId(1));
+        assert_eq!(
+            cold_cache
+                .registry
+                .get(&DenseIndexCache::combine_index(&file_index, false))
+                .map(|node| node.load()),
+            Some(pending)
+        );
+
+        // Already ready, nothing to do
+        assert_eq!(
+            unsafe { cold_cache.resolve_pending(pending) }.unwrap(),
+            pending
+        );
+
+        // Another pending item for the same node resolves to the cached
+        // one, rather than to a second copy
+        let other = ProbLazyItem::new_pending(file_index, false);
+        assert_eq!(
+            unsafe { cold_cache.resolve_pending(other) }.unwrap(),
+            pending
+        );
+        assert!(unsafe { &*other }.is_pending());
+    }
+
+    #[test]
+    fn test_empty_cache() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        assert!(cache.is_empty().unwrap());
+        assert!(cache
+            .load_region(0, 0, version_id, node_size, false)
+            .unwrap()
+            .is_empty());
+        // Loading the region opens an empty index file, still nothing to query
+        assert!(cache.is_empty().unwrap());
+
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(0));
+        write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        cache.bufmans.flush_all().unwrap();
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        assert!(!cold_cache.is_empty().unwrap());
+
+        // The files aren't scanned again once nodes were found
+        fs::remove_file(cache.bufmans.file_path(&version_id)).unwrap();
+        assert!(!cold_cache.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_concurrent_get_lazy_object() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DenseIndexCache>();
+        assert_send_sync::<InvertedIndexCache>();
+
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        let file_indices: Vec<_> = (0..16)
+            .map(|id| {
+                let offset = FileOffset(id as u32 * node_size);
+                let node = create_node(&cache, id, HNSWLevel(1), 8);
+                let item = ProbLazyItem::new(node, version_id, 0, false, offset);
+                write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id)
+                    .unwrap();
+                FileIndex::Valid {
+                    offset,
+                    version_number: 0,
+                    version_id,
+                }
+            })
+            .collect();
+
+        for _ in 0..20 {
+            let cold_cache = Arc::new(DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            ));
+            // Every thread loads all the nodes, starting at a different one
+            let handles: Vec<_> = (0..8)
+                .map(|start| {
+                    let cache = cold_cache.clone();
+                    let file_indices = file_indices.clone();
+                    thread::spawn(move || {
+                        let mut loaded: Vec<_> = (0..file_indices.len())
+                            .map(|i| {
+                                let i = (start * 2 + i) % file_indices.len();
+                                let node = cache
+                                    .get_lazy_object(
+                                        file_indices[i],
+                                        1,
+                                        usize::MAX,
+                                        &mut HashSet::new(),
+                                        false,
+                                    )
+                                    .unwrap();
+                                (i, node as usize)
+                            })
+                            .collect();
+                        loaded.sort();
+                        loaded
+                    })
+                })
+                .collect();
+            let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+            // All threads got the single registered copy of each node
+            for (i, file_index) in file_indices.iter().enumerate() {
+                let node = cold_cache
+                    .registry
+                    .get(&DenseIndexCache::combine_index(file_index, false))
+                    .unwrap()
+                    .load();
+                assert!(results.iter().all(|loaded| loaded[i] == (i, node as usize)));
+                let data = unsafe { &*node }.get_lazy_data().unwrap();
+                assert_eq!(data.prop.id, VectorId(i as u64));
+            }
+        }
+    }
+
+    #[test]
+    fn test_scrubber_detects_divergence() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(8) as u32;
+        for id in 0..3 {
+            let node = create_node(&cache, id, HNSWLevel(1), 8);
+            let item = ProbLazyItem::new(
+                node,
+                version_id,
+                0,
+                false,
+                FileOffset(id as u32 * node_size),
+            );
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        let cold_cache = Arc::new(DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        ));
+        for id in 0..3 {
+            cold_cache
+                .get_lazy_object(
+                    FileIndex::Valid {
+                        offset: FileOffset(id * node_size),
+                        version_number: 0,
+                        version_id,
+                    },
+                    1,
+                    usize::MAX,
+                    &mut HashSet::new(),
+                    false,
+                )
+                .unwrap();
+        }
+        assert_eq!(cold_cache.scrub().unwrap(), 0);
+        assert_eq!(
+            cold_cache.stats(),
+            DenseIndexCacheStats {
+                scrubbed_nodes: 3,
+                scrub_mismatches: 0,
+            }
+        );
+
+        // Flip the level of the second node on disk
+        let bufman = cache.bufmans.get(version_id).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+        bufman.seek_with_cursor(cursor, node_size as u64).unwrap();
+        bufman.update_u8_with_cursor(cursor, 2).unwrap();
+        bufman.close_cursor(cursor).unwrap();
+
+        let token = cold_cache.spawn_scrubber(Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while cold_cache.stats().scrub_mismatches == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        token.cancel();
+        let stats = cold_cache.stats();
+        assert!(stats.scrub_mismatches >= 1);
+        assert!(stats.scrubbed_nodes >= 6);
+    }
+
+    #[test]
+    fn test_key_packing_boundaries() {
+        let file_index = |offset, version_id: u32| FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: Hash::from(version_id),
+        };
+
+        let key = DenseIndexCache::get_prop_key(FileOffset(u32::MAX), BytesToRead(u32::MAX));
+        assert_eq!(key, u64::MAX);
+        let offset_only = DenseIndexCache::get_prop_key(FileOffset(u32::MAX), BytesToRead(0));
+        let length_only = DenseIndexCache::get_prop_key(FileOffset(0), BytesToRead(u32::MAX));
+        assert_eq!(offset_only, (u32::MAX as u64) << 32);
+        assert_eq!(length_only, u32::MAX as u64);
+
+        // All bits set is reserved for `FileIndex::Invalid`
+        let max_offset = (1 << 31) - 1;
+        let max_version_id = u32::MAX - 1;
+        let level_0 = DenseIndexCache::combine_index(&file_index(max_offset, max_version_id), true);
+        let upper = DenseIndexCache::combine_index(&file_index(max_offset, max_version_id), false);
+        assert_ne!(level_0, upper);
+        assert_eq!(level_0 >> 63, 1);
+        assert_eq!((upper >> 32) as u32, max_offset);
+        assert_eq!(upper as u32, max_version_id);
+        assert_eq!(
+            DenseIndexCache::combine_index(&file_index(max_offset, u32::MAX), false),
+            u64::MAX >> 1
+        );
+
+        let key = NodeRegistry::combine_index(&file_index(u32::MAX, 0));
+        assert_eq!(key >> 32, u32::MAX as u64);
+        assert_ne!(key, NodeRegistry::combine_index(&FileIndex::Invalid));
+
+        assert_eq!(
+            InvertedIndexCache::combine_index(FileOffset(u32::MAX), u8::MAX),
+            (u8::MAX as u64) << 32 | u32::MAX as u64
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overlaps the level-0 bit")]
+    fn test_combine_index_offset_overflow() {
+        DenseIndexCache::combine_index(
+            &FileIndex::Valid {
+                offset: FileOffset(1 << 31),
+                version_number: 0,
+                version_id: Hash::from(0),
+            },
+            false,
+        );
+    }
+
+    #[test]
+    fn test_hashed_key_scheme() {
+        let file_index = |offset| FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: Hash::from(7),
+        };
+        // The level-0 node at offset 64 and the higher level node at
+        // offset 2^31 + 64 only differ in the bit that the packed scheme
+        // reuses for the level-0 flag
+        let level_0 = file_index(64);
+        let upper = file_index((1 << 31) | 64);
+        #[cfg(not(debug_assertions))]
+        assert_eq!(
+            DenseIndexCache::combine_index(&level_0, true),
+            DenseIndexCache::combine_index(&upper, false)
+        );
+        assert_ne!(
+            DenseIndexCache::hash_index(&level_0, true),
+            DenseIndexCache::hash_index(&upper, false)
+        );
+        assert_ne!(
+            DenseIndexCache::hash_index(&level_0, true),
+            DenseIndexCache::hash_index(&level_0, false)
+        );
+        assert_eq!(
+            DenseIndexCache::hash_index(&FileIndex::Invalid, false),
+            u64::MAX
+        );
+
+        // Nodes load and evict the same under either scheme
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(0));
+        write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        )
+        .with_key_scheme(RegistryKeyScheme::Hashed);
+        let file_index = unsafe { &*item }.get_file_index();
+        let loaded = cache.get_object(file_index, false).unwrap();
+        assert_eq!(unsafe { &*loaded }.get_file_index(), file_index);
+        assert!(cache
+            .registry
+            .get(&DenseIndexCache::hash_index(&file_index, false))
+            .is_some());
+        assert_eq!(cache.get_object(file_index, false).unwrap(), loaded);
+        assert!(cache.evict(file_index, false));
+        assert!(!cache.evict(file_index, false));
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct CustomItem {
+        name: String,
     }
 
-    pub fn combine_index(file_index: &FileIndex, is_level_0: bool) -> u64 {
-        let level_bit = if is_level_0 { 1u64 << 63 } else { 0 };
-        match file_index {
-            FileIndex::Valid {
-                offset, version_id, ..
-            } => ((offset.0 as u64) << 32) | (**version_id as u64) | level_bit,
-            FileIndex::Invalid => u64::MAX, // Use max u64 value for Invalid
+    impl Cacheable for CustomItem {
+        fn from_cache_item(cache_item: CacheItem) -> Option<LazyItem<Self>> {
+            cache_item.downcast()
         }
-    }
 
-    pub fn get_prop_key(
-        FileOffset(file_offset): FileOffset,
-        BytesToRead(length): BytesToRead,
-    ) -> u64 {
-        (file_offset as u64) << 32 | (length as u64)
+        fn into_cache_item(item: LazyItem<Self>) -> CacheItem {
+            CacheItem::dynamic(item)
+        }
+
+        fn heap_size(_item: &LazyItem<Self>) -> usize {
+            0
+        }
     }
 
-    pub fn load_item<T: DenseSerialize>(
-        &self,
-        file_index: FileIndex,
-        is_level_0: bool,
-    ) -> Result<T, BufIoError> {
-        let mut skipm: HashSet<u64> = HashSet::new();
+    #[test]
+    fn test_register_custom_cacheable() {
+        let dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            8192,
+        ));
+        let registry = Arc::new(NodeRegistry::new(1000, bufmans));
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(64),
+            version_number: 0,
+            version_id: Hash::from(1),
+        };
+        let item = CustomItem {
+            name: "custom".to_string(),
+        };
+        registry.register(&file_index, LazyItem::new(Hash::from(1), 0, item.clone()));
 
-        if file_index == FileIndex::Invalid {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Cannot deserialize with an invalid FileIndex",
+        let cached: LazyItem<CustomItem> = registry
+            .clone()
+            .get_object(
+                file_index.clone(),
+                |_, _, _, _, _| panic!("Cached item shouldn't be loaded"),
+                1000,
+                &mut HashSet::new(),
             )
-            .into());
+            .unwrap();
+        let data = cached
+            .get_lazy_data()
+            .unwrap()
+            .shared_get()
+            .clone()
+            .unwrap();
+        assert_eq!(*data, item);
+
+        // The item doesn't downcast to other types
+        let cache_item = CustomItem::into_cache_item(cached);
+        assert!(f32::from_cache_item(cache_item.clone()).is_none());
+        assert!(cache_item.clone().downcast::<u64>().is_none());
+        assert!(CustomItem::from_cache_item(cache_item).is_some());
+    }
+
+    #[test]
+    fn test_save_and_load_filter() {
+        let dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            8192,
+        ));
+        let registry = NodeRegistry::new(1000, bufmans.clone());
+        {
+            let mut cuckoo_filter = registry.cuckoo_filter().unwrap().write().unwrap();
+            for key in (0..2000u64).step_by(7) {
+                cuckoo_filter.insert(&key);
+            }
+        }
+        let mut saved = Vec::new();
+        registry.save_filter(&mut saved).unwrap();
+
+        let restored = NodeRegistry::new(1000, bufmans.clone());
+        restored.load_filter(&mut saved.as_slice()).unwrap();
+        let contains = |registry: &NodeRegistry, key: u64| {
+            registry
+                .cuckoo_filter()
+                .unwrap()
+                .read()
+                .unwrap()
+                .contains(&key)
         };
+        for key in 0..2000u64 {
+            assert_eq!(contains(&restored, key), contains(&registry, key));
+        }
+        assert!(contains(&restored, 7));
 
-        let bufmans = if is_level_0 {
-            &self.level_0_bufmans
-        } else {
-            &self.bufmans
+        let larger = NodeRegistry::new(100_000, bufmans.clone());
+        let error = larger.load_filter(&mut saved.as_slice()).unwrap_err();
+        assert!(error.to_string().contains("capacity"), "{}", error);
+        assert!(!contains(&larger, 7));
+
+        let without_filter = NodeRegistry::with_cuckoo_filter(1000, bufmans, false);
+        assert!(without_filter.save_filter(&mut Vec::new()).is_err());
+        assert!(without_filter.load_filter(&mut saved.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_registry_without_cuckoo_filter() {
+        let dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            8192,
+        ));
+        let file_index = |offset| FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: Hash::from(1),
+        };
+        let loads = AtomicUsize::new(0);
+        let lookup = |registry: &Arc<NodeRegistry>, offset: u32| {
+            let item: LazyItem<CustomItem> = registry
+                .clone()
+                .get_object(
+                    file_index(offset),
+                    |_, file_index, _, _, _| {
+                        let FileIndex::Valid { offset, .. } = file_index else {
+                            unreachable!()
+                        };
+                        loads.fetch_add(1, Ordering::Relaxed);
+                        Ok(LazyItem::new(
+                            Hash::from(1),
+                            0,
+                            CustomItem {
+                                name: format!("loaded {}", offset.0),
+                            },
+                        ))
+                    },
+                    1000,
+                    &mut HashSet::new(),
+                )
+                .unwrap();
+            item.get_lazy_data()
+                .unwrap()
+                .shared_get()
+                .clone()
+                .unwrap()
+                .name
+                .clone()
         };
 
-        T::deserialize(bufmans, file_index, self, 1000, &mut skipm, is_level_0)
+        let mut results = Vec::new();
+        for use_cuckoo_filter in [true, false] {
+            let registry = Arc::new(NodeRegistry::with_cuckoo_filter(
+                1000,
+                bufmans.clone(),
+                use_cuckoo_filter,
+            ));
+            assert_eq!(registry.cuckoo_filter.is_some(), use_cuckoo_filter);
+            for offset in [0, 64] {
+                let item = CustomItem {
+                    name: format!("registered {}", offset),
+                };
+                registry.register(&file_index(offset), LazyItem::new(Hash::from(1), 0, item));
+            }
+            // Registered, loaded on a miss, then cached after the load
+            let names: Vec<_> = [0, 64, 128, 128]
+                .into_iter()
+                .map(|offset| lookup(&registry, offset))
+                .collect();
+            results.push(names);
+            assert_eq!(loads.swap(0, Ordering::Relaxed), 1);
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(
+            results[0],
+            vec!["registered 0", "registered 64", "loaded 128", "loaded 128"]
+        );
     }
-}
 
-pub struct InvertedIndexCache {
-    data_registry: LRUCache<u64, *mut ProbLazyItem<InvertedIndexSparseAnnNodeBasicTSHashmapData>>,
-    sets_registry: LRUCache<u64, *mut ProbLazyItem<VersionedInvertedFixedSetIndex>>,
-    pub dim_bufman: Arc<BufferManager>,
-    pub data_bufmans: Arc<BufferManagerFactory<u8>>,
-    loading_data: TSHashTable<u64, Arc<Mutex<bool>>>,
-    loading_sets: TSHashTable<u64, Arc<Mutex<bool>>>,
-    pub data_file_parts: u8,
-}
+    #[test]
+    fn test_load_region_cancellable() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node_size = ProbNode::get_serialized_size(16) as u32;
+        for id in 0..300 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item =
+                ProbLazyItem::new(node, version_id, 0, true, FileOffset(id as u32 * node_size));
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+        let cold_cache = || {
+            DenseIndexCache::new(
+                cache.bufmans.clone(),
+                cache.level_0_bufmans.clone(),
+                cache.prop_file.clone(),
+            )
+        };
+        let offsets = |nodes: &[SharedNode]| -> Vec<_> {
+            nodes
+                .iter()
+                .map(|node| unsafe { &**node }.get_file_index().get_offset().unwrap())
+                .collect()
+        };
 
-unsafe impl Send for InvertedIndexCache {}
-unsafe impl Sync for InvertedIndexCache {}
+        let full = cold_cache()
+            .load_region(0, 0, version_id, node_size, true)
+            .unwrap();
+        assert_eq!(full.len(), 300);
+        let full = offsets(&full);
 
-impl InvertedIndexCache {
-    pub fn new(
-        dim_bufman: Arc<BufferManager>,
-        data_bufmans: Arc<BufferManagerFactory<u8>>,
-        data_file_parts: u8,
-    ) -> Self {
-        let data_registry = LRUCache::with_prob_eviction(100_000_000, 0.03125);
-        let sets_registry = LRUCache::with_prob_eviction(100_000_000, 0.03125);
+        let token = CancellationToken::new();
+        token.cancel();
+        let nodes = cold_cache()
+            .load_region_cancellable(0, 0, version_id, node_size, true, &token)
+            .unwrap();
+        assert!(nodes.is_empty());
 
-        Self {
-            data_registry,
-            sets_registry,
-            dim_bufman,
-            data_bufmans,
-            loading_data: TSHashTable::new(16),
-            loading_sets: TSHashTable::new(16),
-            data_file_parts,
-        }
+        // Cancel from another thread once a few nodes are loaded
+        let cache = Arc::new(cold_cache());
+        let token = CancellationToken::new();
+        let canceller = {
+            let cache = cache.clone();
+            let token = token.clone();
+            thread::spawn(move || {
+                while cache.registry.len() < 5 {
+                    thread::yield_now();
+                }
+                token.cancel();
+            })
+        };
+        let nodes = cache
+            .load_region_cancellable(0, 0, version_id, node_size, true, &token)
+            .unwrap();
+        canceller.join().unwrap();
+        let partial = offsets(&nodes);
+        assert!(partial.len() >= 5);
+        assert_eq!(partial[..], full[..partial.len()]);
+
+        let token = CancellationToken::with_timeout(Duration::ZERO);
+        assert!(token.is_cancelled());
     }
 
-    pub fn get_data(
-        &self,
-        file_offset: FileOffset,
-        data_file_idx: u8,
-    ) -> Result<*mut ProbLazyItem<InvertedIndexSparseAnnNodeBasicTSHashmapData>, BufIoError> {
-        let combined_index = Self::combine_index(file_offset, 0);
+    #[test]
+    fn test_defrag_props() {
+        let (cache, dir) = setup_cache();
+        let locations: Vec<_> = (0..6)
+            .map(|id| {
+                let value = Arc::new(Storage::UnsignedByte {
+                    mag: id,
+                    quant_vec: vec![id as u8; 100 + id as usize],
+                });
+                write_prop_to_file(
+                    &VectorId(id as u64),
+                    value,
+                    &mut *cache.prop_file.write().unwrap(),
+                )
+                .unwrap()
+            })
+            .collect();
+        // Props 0, 2 and 3 are dead, prop 5 is referenced twice
+        let live = [locations[4], locations[1], locations[5], locations[5]];
+        let before: Vec<_> = live
+            .iter()
+            .map(|&(offset, length)| cache.get_prop(offset, length).unwrap())
+            .collect();
 
-        if let Some(item) = self.data_registry.get(&combined_index) {
-            return Ok(item);
+        let prop_path = dir.as_ref().join("prop.data");
+        let relocations = cache.defrag_props(&live).unwrap();
+        assert_eq!(relocations.len(), 3);
+        let live_bytes: u32 = live[..3].iter().map(|(_, length)| length.0).sum();
+        let file_size = cache.prop_file.read().unwrap().metadata().unwrap().len();
+        assert_eq!(file_size, live_bytes as u64);
+
+        // Packed in the order of the old offsets
+        assert_eq!(relocations[&locations[1]].0, FileOffset(0));
+        assert_eq!(relocations[&locations[4]].0, FileOffset(locations[1].1 .0));
+        for (old, prop) in live.iter().zip(&before) {
+            let (offset, length) = relocations[old];
+            assert_eq!(length, old.1);
+            let moved = cache.get_prop(offset, length).unwrap();
+            assert!(!Arc::ptr_eq(&moved, prop));
+            assert_eq!(moved.id, prop.id);
+            assert_eq!(moved.value, prop.value);
+            assert_eq!(moved.location, (offset, length));
         }
+        assert_eq!(fs::metadata(&prop_path).unwrap().len(), live_bytes as u64);
 
-        let mut mutex = self
-            .loading_data
-            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
-        let mut load_complete = mutex.lock().unwrap();
+        // Props are appended to the new file
+        let appended = write_prop_to_file(
+            &VectorId(6),
+            Arc::new(Storage::UnsignedByte {
+                mag: 6,
+                quant_vec: vec![6; 10],
+            }),
+            &mut *cache.prop_file.write().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(appended.0, FileOffset(live_bytes));
 
-        loop {
-            // check again
-            if let Some(item) = self.data_registry.get(&combined_index) {
-                return Ok(item);
-            }
+        // Empty props don't need moving
+        let relocations = cache
+            .defrag_props(&[(FileOffset(7), BytesToRead(0))])
+            .unwrap();
+        assert!(relocations.is_empty());
+        assert_eq!(cache.prop_file.read().unwrap().metadata().unwrap().len(), 0);
+        assert!(!dir.as_ref().join("prop.data.defrag").exists());
+    }
 
-            // another thread loaded the data but its not in the registry (got evicted), retry
-            if *load_complete {
-                drop(load_complete);
-                mutex = self
-                    .loading_data
-                    .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
-                load_complete = mutex.lock().unwrap();
-                continue;
-            }
+    #[test]
+    fn test_prop_pool_bytes() {
+        let (cache, _dir) = setup_cache();
+        let locations: Vec<_> = (0..20)
+            .map(|id| {
+                let value = Arc::new(Storage::UnsignedByte {
+                    mag: 10,
+                    quant_vec: vec![id as u8; 4096],
+                });
+                write_prop_to_file(&VectorId(id), value, &mut *cache.prop_file.write().unwrap())
+                    .unwrap()
+            })
+            .collect();
+        let prop_size = size_of::<Storage>() + 4096;
 
-            break;
+        // Without a pool props are only kept alive by their users
+        for &(offset, length) in &locations {
+            cache.get_prop(offset, length).unwrap();
         }
+        assert_eq!(cache.resident_prop_bytes(), 0);
 
-        let data = InvertedIndexSparseAnnNodeBasicTSHashmapData::deserialize(
-            &self.dim_bufman,
-            &self.data_bufmans,
-            file_offset,
-            data_file_idx,
-            self.data_file_parts,
-            self,
-        )?;
-        let state = ProbLazyItemState::Ready(ReadyState {
-            data,
-            file_offset,
-            version_id: 0.into(),
-            version_number: 0,
-        });
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        )
+        .with_prop_pool_bytes(4 * prop_size);
+        let held = cache.get_prop(locations[0].0, locations[0].1).unwrap();
+        for &(offset, length) in &locations[1..] {
+            cache.get_prop(offset, length).unwrap();
+            assert!(cache.pooled_prop_bytes() <= 4 * prop_size);
+            assert!(cache.resident_prop_bytes() <= 5 * prop_size);
+        }
+        // The last 4 props are pooled, the first one is dropped by the pool
+        // but still held here, so it counts as resident but not pooled
+        assert_eq!(cache.pooled_prop_bytes(), 4 * prop_size);
+        assert_eq!(cache.resident_prop_bytes(), 5 * prop_size);
+        for &(offset, length) in &locations[16..] {
+            assert!(cache.try_get_prop(offset, length).is_some());
+        }
+        for &(offset, length) in &locations[1..16] {
+            assert!(cache.try_get_prop(offset, length).is_none());
+        }
+        assert!(cache.try_get_prop(locations[0].0, locations[0].1).is_some());
 
-        let item = ProbLazyItem::new_from_state(state, false);
+        // Dropped props are read again when needed
+        let prop = cache.get_prop(locations[1].0, locations[1].1).unwrap();
+        assert_eq!(prop.id, VectorId(1));
+        drop(held);
+        assert_eq!(cache.resident_prop_bytes(), 4 * prop_size);
+    }
 
-        self.data_registry
-            .insert(combined_index.clone(), item.clone());
+    #[test]
+    fn test_get_prop_out_of_range() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(0);
+        let node = create_node(&cache, 0, HNSWLevel(1), 8);
+        let item = ProbLazyItem::new(node, version_id, 0, false, FileOffset(0));
+        write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        let prop_file_size = cache.prop_file.read().unwrap().metadata().unwrap().len() as u32;
 
-        *load_complete = true;
-        self.loading_data.delete(&combined_index);
+        // Point the node's prop past the end of the prop file
+        let bufman = cache.bufmans.get(version_id).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+        bufman.seek_with_cursor(cursor, 1).unwrap();
+        bufman
+            .update_u32_with_cursor(cursor, prop_file_size - 2)
+            .unwrap();
+        bufman.close_cursor(cursor).unwrap();
 
-        Ok(item)
+        let cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        let error = cache
+            .get_object(unsafe { &*item }.get_file_index(), false)
+            .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                BufIoError::Corrupt {
+                    context: "prop out of range",
+                    offset,
+                } if offset == prop_file_size as u64 - 2
+            ),
+            "{}",
+            error
+        );
+        assert!(matches!(
+            cache.get_prop(FileOffset(prop_file_size), BytesToRead(1)),
+            Err(BufIoError::Corrupt { .. })
+        ));
     }
 
-    pub fn get_sets(
-        &self,
-        file_offset: FileOffset,
-        data_file_idx: u8,
-    ) -> Result<*mut ProbLazyItem<VersionedInvertedFixedSetIndex>, BufIoError> {
-        let combined_index = Self::combine_index(file_offset, 0);
+    #[test]
+    fn test_get_prop_zero_length() {
+        let (cache, _dir) = setup_cache();
 
-        if let Some(item) = self.sets_registry.get(&combined_index) {
-            return Ok(item);
-        }
+        // Holding a read lock makes any attempt to take the write lock
+        // block, so this only returns if the prop file isn't touched
+        let prop_file_guard = cache.prop_file.read().unwrap();
+        let prop = cache.get_prop(FileOffset(128), BytesToRead(0)).unwrap();
+        let other = cache.get_prop(FileOffset(0), BytesToRead(0)).unwrap();
+        assert!(cache.prop_file.try_write().is_err());
+        drop(prop_file_guard);
 
-        let mut mutex = self
-            .loading_data
-            .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
-        let mut load_complete = mutex.lock().unwrap();
+        assert!(Arc::ptr_eq(&prop, &other));
+        assert!(Arc::ptr_eq(&prop, &DenseIndexCache::empty_prop()));
+        assert_eq!(prop.location.1, BytesToRead(0));
+        assert!(cache.props_registry.is_empty());
+        assert!(cache
+            .try_get_prop(FileOffset(64), BytesToRead(0))
+            .is_some_and(|p| Arc::ptr_eq(&p, &prop)));
+    }
 
-        loop {
-            // check again
-            if let Some(item) = self.sets_registry.get(&combined_index) {
-                return Ok(item);
-            }
+    #[test]
+    fn test_version_file_header() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(5);
+        assert!(cache.read_header(version_id, true).is_err());
 
-            // another thread loaded the data but its not in the registry (got evicted), retry
-            if *load_complete {
-                drop(load_complete);
-                mutex = self
-                    .loading_data
-                    .get_or_create(combined_index, || Arc::new(Mutex::new(false)));
-                load_complete = mutex.lock().unwrap();
-                continue;
-            }
+        let header = VersionFileHeader {
+            format_version: 3,
+            node_size: ProbNode::get_serialized_size(16) as u32,
+            level_info: Some(LevelInfo {
+                min_level: HNSWLevel(0),
+                max_level: HNSWLevel(4),
+            }),
+            checksum: Some(0x0123_4567_89ab_cdef),
+            continuation: Some(Hash::from(u32::MAX - 1)),
+            node_alignment: 64,
+            continues: Some(Hash::from(7)),
+        };
+        cache.write_header(version_id, true, &header).unwrap();
+        assert_eq!(cache.read_header(version_id, true).unwrap(), header);
+        // Level-0 and higher level files have separate headers
+        assert!(cache.read_header(version_id, false).is_err());
 
-            break;
-        }
+        let empty = VersionFileHeader {
+            level_info: None,
+            checksum: None,
+            continuation: None,
+            continues: None,
+            ..header
+        };
+        cache.write_header(version_id, false, &empty).unwrap();
+        assert_eq!(cache.read_header(version_id, false).unwrap(), empty);
 
-        let dim_cursor = self.dim_bufman.open_cursor()?;
-        self.dim_bufman
-            .seek_with_cursor(dim_cursor, file_offset.0 as u64)?;
-        let data_offset = self.dim_bufman.read_u32_with_cursor(dim_cursor)?;
-        self.dim_bufman.close_cursor(dim_cursor)?;
+        // Headers written before continuations were linked back
+        let path = cache.header_path(version_id, true);
+        let mut bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..31]).unwrap();
+        let unlinked = cache.read_header(version_id, true).unwrap();
+        assert_eq!(unlinked.node_alignment, header.node_alignment);
+        assert_eq!(unlinked.continues, None);
 
-        let data = VersionedInvertedFixedSetIndex::deserialize(
-            &self.dim_bufman,
-            &self.data_bufmans,
-            FileOffset(data_offset),
-            data_file_idx,
-            self.data_file_parts,
-            self,
-        )?;
-        let state = ProbLazyItemState::Ready(ReadyState {
-            data,
-            file_offset,
-            version_id: 0.into(),
-            version_number: 0,
-        });
+        // Headers written before the node alignment was added describe
+        // unpadded nodes
+        fs::write(&path, &bytes[..27]).unwrap();
+        let unpadded = cache.read_header(version_id, true).unwrap();
+        assert_eq!(unpadded.checksum, header.checksum);
+        assert_eq!(unpadded.node_alignment, 1);
 
-        let item = ProbLazyItem::new_from_state(state, false);
+        bytes[0] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let error = cache.read_header(version_id, true).unwrap_err();
+        assert!(error.to_string().contains("magic"), "{}", error);
+    }
 
-        self.sets_registry
-            .insert(combined_index.clone(), item.clone());
+    #[test]
+    fn test_verify_file_checksum() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(5);
+        let node_size = ProbNode::get_serialized_size(16) as u32;
+        for id in 0..3 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item =
+                ProbLazyItem::new(node, version_id, 0, true, FileOffset(id as u32 * node_size));
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
 
-        *load_complete = true;
-        self.loading_sets.delete(&combined_index);
+        // No digest written yet
+        assert!(cache.verify_file_checksum(version_id, true).is_err());
 
-        Ok(item)
+        cache.write_file_checksum(version_id, true).unwrap();
+        assert!(cache.verify_file_checksum(version_id, true).unwrap());
+        // The digest is kept in the file's header
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        assert!(cold_cache.verify_file_checksum(version_id, true).unwrap());
+
+        // Flip a single byte of the second node
+        let path = cache.level_0_bufmans.file_path(&version_id);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[node_size as usize + 1] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        assert!(!cache.verify_file_checksum(version_id, true).unwrap());
+
+        // The version has no higher level file, which isn't created
+        cache.write_file_checksum(version_id, false).unwrap();
+        assert!(!cache.bufmans.file_path(&version_id).exists());
+        assert!(cache.verify_file_checksum(version_id, false).is_err());
     }
 
-    pub fn combine_index(file_offset: FileOffset, data_file_idx: u8) -> u64 {
-        (data_file_idx as u64) << 32 | file_offset.0 as u64
+    #[test]
+    fn test_backup_version() {
+        let (cache, _dir) = setup_cache();
+        let version_id = Hash::from(5);
+        let node_size = ProbNode::get_serialized_size(16) as u32;
+        for id in 0..3 {
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item =
+                ProbLazyItem::new(node, version_id, 0, true, FileOffset(id as u32 * node_size));
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+        }
+
+        let mut backup = Vec::new();
+        let copied = cache.backup_version(version_id, true, &mut backup).unwrap();
+        let bytes = fs::read(cache.level_0_bufmans.file_path(&version_id)).unwrap();
+        assert_eq!(copied, 3 * node_size as u64);
+        assert_eq!(backup, bytes);
+
+        // Checked against the stored digest
+        cache.write_file_checksum(version_id, true).unwrap();
+        let mut backup = Vec::new();
+        cache.backup_version(version_id, true, &mut backup).unwrap();
+        assert_eq!(backup, bytes);
+        let header = cache.read_header(version_id, true).unwrap();
+        cache
+            .write_header(
+                version_id,
+                true,
+                &VersionFileHeader {
+                    checksum: Some(0),
+                    ..header
+                },
+            )
+            .unwrap();
+        assert!(cache
+            .backup_version(version_id, true, &mut Vec::new())
+            .is_err());
+
+        assert!(cache
+            .backup_version(Hash::from(6), true, &mut Vec::new())
+            .is_err());
+        assert!(!cache.level_0_bufmans.file_path(&Hash::from(6)).exists());
+
+        // The cursor is closed when writing the backup fails, so the
+        // thread's next cursor reuses it
+        let bufman = cache.level_0_bufmans.get(version_id).unwrap();
+        let cursor = bufman.open_cursor().unwrap();
+        bufman.close_cursor(cursor).unwrap();
+        let mut short = [0u8; 16];
+        assert!(cache
+            .backup_version(version_id, true, &mut &mut short[..])
+            .is_err());
+        assert_eq!(bufman.open_cursor().unwrap(), cursor);
     }
 
-    pub fn get_prop_key(
-        FileOffset(file_offset): FileOffset,
-        BytesToRead(length): BytesToRead,
-    ) -> u64 {
-        (file_offset as u64) << 32 | (length as u64)
+    #[test]
+    fn test_get_object_as_of() {
+        let (cache, dir) = setup_cache();
+        // Resolving before anything is registered doesn't create the log
+        assert_eq!(cache.resolve_version(1).unwrap(), None);
+        assert!(!dir.as_ref().join("version_ids.log").exists());
+
+        // The same node, rewritten at the same offset in version 2 with a
+        // new prop
+        for (version_number, id) in [(1u16, 10u64), (2, 20)] {
+            let version_id = Hash::from(100 + version_number as u32);
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item = ProbLazyItem::new(node, version_id, version_number, true, FileOffset(0));
+            write_node_to_file(item, &cache.bufmans, &cache.level_0_bufmans, version_id).unwrap();
+            cache.register_version(version_number, version_id).unwrap();
+        }
+        assert_eq!(cache.resolve_version(1).unwrap(), Some(Hash::from(101)));
+
+        let v1 = unsafe { &*cache.get_object_as_of(FileOffset(0), 1, true).unwrap() };
+        assert_eq!(v1.get_current_version_id(), Hash::from(101));
+        assert_eq!(v1.get_current_version_number(), 1);
+        assert_eq!(v1.get_lazy_data().unwrap().prop.id, VectorId(10));
+
+        let v2 = unsafe { &*cache.get_object_as_of(FileOffset(0), 2, true).unwrap() };
+        assert_eq!(v2.get_current_version_id(), Hash::from(102));
+        assert_eq!(v2.get_lazy_data().unwrap().prop.id, VectorId(20));
+
+        assert!(cache.get_object_as_of(FileOffset(0), 3, true).is_err());
+
+        // The registered versions are read back after a restart
+        let cold_cache = DenseIndexCache::new(
+            cache.bufmans.clone(),
+            cache.level_0_bufmans.clone(),
+            cache.prop_file.clone(),
+        );
+        assert_eq!(
+            cold_cache.resolve_version(2).unwrap(),
+            Some(Hash::from(102))
+        );
+        let v1 = unsafe { &*cold_cache.get_object_as_of(FileOffset(0), 1, true).unwrap() };
+        assert_eq!(v1.get_lazy_data().unwrap().prop.id, VectorId(10));
     }
 
-    pub fn load_item<T: InvertedIndexSerialize>(
-        &self,
-        file_offset: FileOffset,
-        data_file_idx: u8,
-    ) -> Result<T, BufIoError> {
-        T::deserialize(
-            &self.dim_bufman,
-            &self.data_bufmans,
-            file_offset,
-            data_file_idx,
-            self.data_file_parts,
-            self,
-        )
+    #[test]
+    fn test_min_resident_version() {
+        let (cache, _dir) = setup_cache();
+        assert_eq!(cache.min_resident_version(), None);
+
+        for (id, version) in [(0, 5), (1, 3), (2, 7)] {
+            let version_id = Hash::from(version);
+            let node = create_node(&cache, id, HNSWLevel(0), 16);
+            let item = ProbLazyItem::new(node, version_id, 0, true, FileOffset(0));
+            unsafe { cache.insert_lazy_object(version_id, 0, item) };
+        }
+        assert_eq!(cache.min_resident_version(), Some(Hash::from(3)));
+
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(0),
+            version_number: 0,
+            version_id: Hash::from(3),
+        };
+        assert!(cache.evict(file_index, true));
+        assert_eq!(cache.min_resident_version(), Some(Hash::from(5)));
     }
 }
@@ -744,6 +744,19 @@ impl<K: Eq + Hash, V> TSHashTable<K, V> {
         ht.get(k).map(f)
     }
 
+    /// Calls `f` with each entry in turn, without cloning them
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &V),
+    {
+        for ht in &self.hash_table_list {
+            let ht = ht.lock().unwrap();
+            for (k, v) in ht.iter() {
+                f(k, v);
+            }
+        }
+    }
+
     pub fn map_m<F>(&self, f: F)
     where
         F: Fn(&K, &V) + Send + Sync + 'static,
@@ -787,6 +800,12 @@ impl<K: Eq + Hash, V> TSHashTable<K, V> {
             .collect()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.hash_table_list
+            .iter()
+            .all(|ht| ht.lock().unwrap().is_empty())
+    }
+
     pub fn from_list(size: u8, kv: Vec<(K, V)>) -> Self {
         let tsh = Self::new(size);
         for (k, v) in kv {
@@ -56,19 +56,32 @@ pub fn write_prop_to_file(
         .seek(SeekFrom::End(0))
         .map_err(|e| WaCustomError::FsError(e.to_string()))?;
 
+    // Prop locations are stored as 32-bit offsets and lengths, check them
+    // before writing anything that couldn't be referenced
+    let offset = u32::try_from(offset)
+        .map_err(|_| WaCustomError::FsError(format!("Prop offset {} exceeds 32 bits", offset)))?;
+    let length = u32::try_from(prop_bytes.len()).map_err(|_| {
+        WaCustomError::FsError(format!("Prop length {} exceeds 32 bits", prop_bytes.len()))
+    })?;
+
     file.write_all(&prop_bytes)
         .map_err(|e| WaCustomError::FsError(e.to_string()))?;
 
-    Ok((
-        FileOffset(offset as u32),
-        BytesToRead(prop_bytes.len() as u32),
-    ))
+    Ok((FileOffset(offset), BytesToRead(length)))
 }
 
 pub fn read_prop_from_file(
     (offset, bytes_to_read): (FileOffset, BytesToRead),
     file: &mut File,
 ) -> Result<NodeProp, BufIoError> {
+    // A corrupt location would otherwise fail with an obscure EOF or
+    // deserialization error, or not at all
+    if offset.0 as u64 + bytes_to_read.0 as u64 > file.metadata()?.len() {
+        return Err(BufIoError::Corrupt {
+            context: "prop out of range",
+            offset: offset.0 as u64,
+        });
+    }
     let mut bytes = vec![0u8; bytes_to_read.0 as usize];
     file.seek(SeekFrom::Start(offset.0 as u64))?;
     file.read_exact(&mut bytes)?;
@@ -1,5 +1,7 @@
+use rand::Rng;
+use siphasher::sip::SipHasher24;
 use std::collections::HashMap;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash};
 
 pub trait Identifiable {
     type Id: Eq + Hash;
@@ -59,22 +61,66 @@ pub enum IdentityMapKey {
     Int(u32),
 }
 
+/// Keys of the SipHash hashing the keys of an `IdentityMap`
+///
+/// Keys may come from untrusted input, maps are seeded randomly so that
+/// keys colliding in a map can't be crafted ahead of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashSeed {
+    k0: u64,
+    k1: u64,
+}
+
+impl HashSeed {
+    pub fn new(k0: u64, k1: u64) -> Self {
+        Self { k0, k1 }
+    }
+
+    pub fn random() -> Self {
+        let mut rng = rand::thread_rng();
+        Self::new(rng.gen(), rng.gen())
+    }
+}
+
+impl BuildHasher for HashSeed {
+    type Hasher = SipHasher24;
+
+    fn build_hasher(&self) -> SipHasher24 {
+        SipHasher24::new_with_keys(self.k0, self.k1)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IdentityMap<T> {
-    map: HashMap<IdentityMapKey, T>,
+    map: HashMap<IdentityMapKey, T, HashSeed>,
 }
 
 impl<T> IdentityMap<T> {
     pub fn new() -> Self {
+        Self::with_seed(HashSeed::random())
+    }
+
+    pub fn with_seed(seed: HashSeed) -> Self {
         Self {
-            map: HashMap::new(),
+            map: HashMap::with_hasher(seed),
         }
     }
 
     pub fn from_iter(iter: impl Iterator<Item = (IdentityMapKey, T)>) -> Self {
-        Self {
-            map: iter.collect(),
-        }
+        Self::from_iter_with_seed(iter, HashSeed::random())
+    }
+
+    pub fn from_iter_with_seed(
+        iter: impl Iterator<Item = (IdentityMapKey, T)>,
+        seed: HashSeed,
+    ) -> Self {
+        let mut map = HashMap::with_hasher(seed);
+        map.extend(iter);
+        Self { map }
+    }
+
+    pub fn seed(&self) -> HashSeed {
+        *self.map.hasher()
     }
 
     pub fn insert(&mut self, key: IdentityMapKey, value: T) -> Option<T> {
@@ -109,3 +155,40 @@ impl<T> IdentityMap<T> {
         self.map.remove(key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_map_seed() {
+        let key = |i: u32| {
+            if i % 2 == 0 {
+                IdentityMapKey::Int(i)
+            } else {
+                IdentityMapKey::String(format!("key-{}", i))
+            }
+        };
+        let (seed_a, seed_b) = (HashSeed::new(1, 2), HashSeed::new(3, 4));
+        let map_a = IdentityMap::from_iter_with_seed((0..100).map(|i| (key(i), i)), seed_a);
+        let map_b = IdentityMap::from_iter_with_seed((0..100).map(|i| (key(i), i)), seed_b);
+        assert_eq!(map_a.seed(), seed_a);
+        assert_eq!(map_b.seed(), seed_b);
+
+        // Buckets the keys fall in, in a table of 128
+        let buckets = |seed: HashSeed| -> Vec<u64> {
+            (0..100).map(|i| seed.hash_one(key(i)) % 128).collect()
+        };
+        assert_ne!(buckets(seed_a), buckets(seed_b));
+
+        for i in 0..100 {
+            assert_eq!(map_a.get(&key(i)), Some(&i));
+            assert_eq!(map_b.get(&key(i)), Some(&i));
+        }
+        assert_eq!(map_a.get(&key(100)), None);
+        assert_ne!(
+            IdentityMap::<u32>::new().seed(),
+            IdentityMap::<u32>::new().seed()
+        );
+    }
+}
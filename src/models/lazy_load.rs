@@ -10,7 +10,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fmt;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
 
@@ -86,8 +86,12 @@ pub enum LazyItem<T: Clone + 'static> {
         // Pointer to the file offset where the data is stored. Used for lazy loading the data when
         // needed. If the data is not loaded, this index retrieves it from persistent storage.
         file_index: ArcShift<Option<FileIndex>>,
-        // Tracks the lifespan the item.
-        decay_counter: usize,
+        // Tracks how "hot" the item is. Every access through the cache bumps it (see
+        // `LazyItem::touch`), and each time the item is picked as an eviction candidate it is
+        // halved (see `LazyItem::decay`). An item whose counter is still non-zero gets a second
+        // chance instead of being evicted, so frequently accessed items outlive cold ones even if
+        // they weren't accessed most recently. Shared between clones of the same item.
+        decay_counter: Arc<AtomicUsize>,
         // Prevents infinite serialization loops when handling cyclic data. This flag indicates
         // whether the `LazyItem` has already been serialized during the current cycle, ensuring
         // the data isn't serialized multiple times. It must be reset after the whole serialization
@@ -302,7 +306,7 @@ impl<T: Clone + 'static> LazyItem<T> {
         Self::Valid {
             data: ArcShift::new(Some(Arc::new(item))),
             file_index: ArcShift::new(None),
-            decay_counter: 0,
+            decay_counter: Arc::new(AtomicUsize::new(0)),
             persist_flag: Arc::new(AtomicBool::new(true)),
             versions: LazyItemVec::new(),
             version_id,
@@ -319,7 +323,7 @@ impl<T: Clone + 'static> LazyItem<T> {
         LazyItem::Valid {
             data: ArcShift::new(Some(Arc::new(data))),
             file_index: ArcShift::new(None),
-            decay_counter: 0,
+            decay_counter: Arc::new(AtomicUsize::new(0)),
             persist_flag: Arc::new(AtomicBool::new(true)),
             versions: LazyItemVec::new(),
             version_id,
@@ -332,7 +336,7 @@ impl<T: Clone + 'static> LazyItem<T> {
         Self::Valid {
             data: ArcShift::new(Some(item)),
             file_index: ArcShift::new(None),
-            decay_counter: 0,
+            decay_counter: Arc::new(AtomicUsize::new(0)),
             persist_flag: Arc::new(AtomicBool::new(true)),
             versions: LazyItemVec::new(),
             version_id,
@@ -349,6 +353,16 @@ impl<T: Clone + 'static> LazyItem<T> {
         matches!(self, Self::Invalid)
     }
 
+    /// Whether the item holds its data, as opposed to only the location
+    /// to load it from, e.g. when returned by `NodeRegistry::get_object`
+    /// after `max_loads` ran out. Invalid items aren't resolved.
+    pub fn is_resolved(&self) -> bool {
+        if let Self::Valid { data, .. } = self {
+            return data.clone().get().is_some();
+        }
+        false
+    }
+
     pub fn get_lazy_data(&self) -> Option<ArcShift<Option<Arc<T>>>> {
         if let Self::Valid { data, .. } = self {
             return Some(data.clone());
@@ -377,6 +391,52 @@ impl<T: Clone + 'static> LazyItem<T> {
         }
     }
 
+    /// Counts the versions linked through `versions`, recursively, i.e.
+    /// the number of versions reachable from this item excluding itself.
+    ///
+    /// Only the versions that are loaded are counted.
+    pub fn version_chain_len(&self) -> usize {
+        let Some(versions) = self.get_versions() else {
+            return 0;
+        };
+        let mut items_arc = versions.items.clone();
+        items_arc
+            .get()
+            .iter()
+            .map(|version| 1 + version.version_chain_len())
+            .sum()
+    }
+
+    /// Records an access to the item by incrementing its decay counter.
+    pub fn touch(&self) {
+        if let Self::Valid { decay_counter, .. } = self {
+            decay_counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Ages the item by halving its decay counter.
+    ///
+    /// Returns `true` if the item had been touched since it was last
+    /// aged, i.e. it should be spared from eviction this time around.
+    pub fn decay(&self) -> bool {
+        if let Self::Valid { decay_counter, .. } = self {
+            let prev = decay_counter
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| Some(c / 2))
+                .unwrap();
+            prev > 0
+        } else {
+            false
+        }
+    }
+
+    pub fn get_decay_counter(&self) -> usize {
+        if let Self::Valid { decay_counter, .. } = self {
+            decay_counter.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+
     pub fn set_versions_persistence(&self, flag: bool) {
         self.set_persistence(flag);
         if let Some(versions) = self.get_versions() {
@@ -447,6 +507,51 @@ impl<T: Clone + CustomSerialize + Cacheable + 'static> LazyItem<T> {
         }
     }
 
+    /// Drops all but the `keep_latest` most recent versions linked from this
+    /// item, relinking the kept ones as if they were added right after it.
+    /// The latest version is always kept, so a `keep_latest` of 0 is treated
+    /// as 1.
+    ///
+    /// Versions that aren't loaded are loaded first, so that loading them
+    /// later doesn't restore the dropped links.
+    pub fn compact_versions(
+        &self,
+        cache: Arc<NodeRegistry>,
+        keep_latest: usize,
+    ) -> Result<(), WaCustomError> {
+        let Self::Valid { versions, .. } = self else {
+            return Ok(());
+        };
+        self.try_get_data(cache.clone())?;
+
+        let mut chain = Vec::new();
+        let mut stack: Vec<_> = versions.iter().collect();
+        while let Some(version) = stack.pop() {
+            version.try_get_data(cache.clone())?;
+            if let Some(links) = version.get_versions() {
+                stack.extend(links.iter());
+            }
+            chain.push(version);
+        }
+        let keep_latest = keep_latest.max(1);
+        if chain.len() <= keep_latest {
+            return Ok(());
+        }
+        chain.sort_by_key(|version| version.get_current_version_number());
+        let kept = chain.split_off(chain.len() - keep_latest);
+
+        versions.clear();
+        for version in &kept {
+            if let Some(links) = version.get_versions() {
+                links.clear();
+            }
+        }
+        for (i, version) in kept.into_iter().enumerate() {
+            self.add_version_inner(cache.clone(), version, 0, i as u16 + 1);
+        }
+        Ok(())
+    }
+
     pub fn get_version(&self, cache: Arc<NodeRegistry>, version: u16) -> Option<LazyItem<T>> {
         match self {
             Self::Valid {
@@ -665,7 +770,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
             item: ArcShift::new(LazyItem::Valid {
                 data: ArcShift::new(Some(Arc::new(item))),
                 file_index: ArcShift::new(None),
-                decay_counter: 0,
+                decay_counter: Arc::new(AtomicUsize::new(0)),
                 persist_flag: Arc::new(AtomicBool::new(true)),
                 versions: LazyItemVec::new(),
                 version_id,
@@ -686,7 +791,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
             item: ArcShift::new(LazyItem::Valid {
                 data: ArcShift::new(Some(item)),
                 file_index: ArcShift::new(None),
-                decay_counter: 0,
+                decay_counter: Arc::new(AtomicUsize::new(0)),
                 persist_flag: Arc::new(AtomicBool::new(true)),
                 versions: LazyItemVec::new(),
                 version_id,
@@ -745,7 +850,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
             {
                 (
                     file_index.clone(),
-                    *decay_counter,
+                    decay_counter.clone(),
                     persist_flag.clone(),
                     *version_id,
                     *version_number,
@@ -755,7 +860,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
             } else {
                 (
                     ArcShift::new(None),
-                    0,
+                    Arc::new(AtomicUsize::new(0)),
                     Arc::new(AtomicBool::new(true)),
                     0.into(),
                     0,
@@ -802,7 +907,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
             {
                 (
                     data.clone(),
-                    *decay_counter,
+                    decay_counter.clone(),
                     persist_flag.clone(),
                     *version_id,
                     *version_number,
@@ -812,7 +917,7 @@ impl<T: Clone + 'static> LazyItemRef<T> {
             } else {
                 (
                     ArcShift::new(None),
-                    0,
+                    Arc::new(AtomicUsize::new(0)),
                     Arc::new(AtomicBool::new(true)),
                     0.into(),
                     0,
@@ -1112,6 +1217,13 @@ impl<T: Clone + 'static> LazyItemVec<T> {
         let mut items = self.items.clone();
         items.transactional_update(|_| Vec::new()).unwrap();
     }
+
+    pub fn retain(&self, mut f: impl FnMut(&LazyItem<T>) -> bool) {
+        let mut items = self.items.clone();
+        items
+            .transactional_update(|old| old.iter().filter(|item| f(item)).cloned().collect())
+            .unwrap();
+    }
 }
 
 impl<T: Clone + 'static, const N: usize> LazyItemArray<T, N> {
@@ -1246,17 +1358,14 @@ impl IncrementalSerializableGrowableData {
     pub fn insert(&mut self, vec_id: u32) {
         let insert_dimension = (vec_id % 64) as usize;
         let insert_index = (vec_id / 64) as usize;
-        let items_len = self.items.len();
 
-        if items_len <= insert_index {
-            self.items.resize(
-                insert_index + 1,
-                LazyItem::new(
-                    Hash::from(u32::MAX),
-                    u16::MAX,
-                    STM::new(VectorData::new(), 1, true),
-                ),
-            );
+        // Each chunk gets its own data, a resize would share a single one
+        while self.items.len() <= insert_index {
+            self.items.push(LazyItem::new(
+                Hash::from(u32::MAX),
+                u16::MAX,
+                STM::new(VectorData::new(), 1, true),
+            ));
         }
 
         let mut vector_data_arcshift = self
@@ -1330,6 +1439,102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lazy_item_is_resolved() {
+        let temp_dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            temp_dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            8192,
+        ));
+        let cache = Arc::new(NodeRegistry::new(1000, bufmans));
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(0),
+            version_number: 0,
+            version_id: Hash::from(0),
+        };
+
+        // No loads left, so only the location is returned
+        let pending: LazyItem<f32> = cache
+            .get_object(
+                file_index,
+                |_, _, _, _, _| unreachable!(),
+                0,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(pending.is_valid());
+        assert!(!pending.is_resolved());
+
+        assert!(LazyItem::new(Hash::from(0), 0, 1.0).is_resolved());
+        assert!(!LazyItem::<f32>::new_invalid().is_resolved());
+    }
+
+    #[test]
+    fn test_lazy_item_version_chain_len() {
+        let temp_dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            temp_dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            8192,
+        ));
+        let cache = Arc::new(NodeRegistry::new(1000, bufmans));
+        let root = LazyItem::new(Hash::from(0), 0, 0.0);
+        assert_eq!(root.version_chain_len(), 0);
+
+        for i in 1..=100 {
+            let version = LazyItem::new(Hash::from(0), i, 0.0);
+            root.add_version(cache.clone(), version);
+        }
+
+        assert_eq!(root.version_chain_len(), 100);
+        assert_eq!(LazyItem::<f32>::Invalid.version_chain_len(), 0);
+    }
+
+    #[test]
+    fn test_lazy_item_compact_versions() {
+        let temp_dir = tempdir().unwrap();
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            temp_dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            8192,
+        ));
+        let cache = Arc::new(NodeRegistry::new(1000, bufmans));
+        let root = LazyItem::new(Hash::from(0), 0, 0.0);
+
+        for i in 1..=10 {
+            let version = LazyItem::new(Hash::from(0), i, i as f32);
+            root.add_version(cache.clone(), version);
+        }
+        assert_eq!(root.version_chain_len(), 10);
+
+        root.compact_versions(cache.clone(), 3).unwrap();
+        assert_eq!(root.version_chain_len(), 3);
+        let (latest, _) = root.get_latest_version(cache.clone());
+        assert_eq!(latest.get_current_version_number(), 10);
+        assert_eq!(*latest.get_data(cache.clone()), 10.0);
+        for i in 8..=10 {
+            assert_eq!(
+                root.get_version(cache.clone(), i)
+                    .unwrap()
+                    .get_current_version_number(),
+                i
+            );
+        }
+        assert!(root.get_version(cache.clone(), 7).is_none());
+
+        // Versions keep being added after the kept ones
+        root.add_version(cache.clone(), LazyItem::new(Hash::from(0), 11, 11.0));
+        assert_eq!(root.version_chain_len(), 4);
+        let (latest, _) = root.get_latest_version(cache.clone());
+        assert_eq!(latest.get_current_version_number(), 11);
+
+        root.compact_versions(cache.clone(), 1).unwrap();
+        assert_eq!(root.version_chain_len(), 1);
+        let (latest, _) = root.get_latest_version(cache.clone());
+        assert_eq!(latest.get_current_version_number(), 11);
+    }
+
     #[test]
     fn test_lazy_item_versions_add_and_get_with_skipped_items() {
         let temp_dir = tempdir().unwrap();
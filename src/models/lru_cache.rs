@@ -1,8 +1,12 @@
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use half::f16;
 use rand::Rng;
+use std::collections::BTreeMap;
 use std::iter::Iterator;
+use std::ops::Bound;
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
 
 // Calculates counter age, while considering a possibility of
 // wraparound (with the assumption that wraparound will happen at most
@@ -64,6 +68,13 @@ impl EvictionIndex {
         }
     }
 
+    fn on_remove(&self, counter: u32, key: u64) {
+        let i = Self::idx(counter);
+        if self.inner[i].load(Ordering::SeqCst) == key {
+            self.clear(i);
+        }
+    }
+
     fn get_keys(&self, max: u8) -> Vec<(u8, u64)> {
         let mut result = vec![];
         for (i, x) in self.inner.iter().enumerate() {
@@ -83,6 +94,7 @@ impl EvictionIndex {
     }
 }
 
+#[derive(Clone)]
 pub struct ProbEviction {
     // Probability of eviction per call. E.g. A value of 0.1 means
     // eviction will be randomly triggered with 10% probability on each call
@@ -118,14 +130,26 @@ impl ProbEviction {
 }
 
 #[allow(unused)]
+#[derive(Clone)]
 pub enum EvictStrategy {
-    // Eviction will happen immediately after insertion
+    // The least recently used entries are evicted as soon as eviction
+    // is triggered, see `LRUCache::set_evict_on_insert`
     Immediate,
     // All extra items will be evicted together at a probabilistically
     // calculated frequency
     Probabilistic(ProbEviction),
+    // The least recently used entry is evicted on every insert that
+    // goes over capacity, as per a precise recency list. Slower than
+    // the other strategies, but deterministic
+    Exact,
 }
 
+/// See `LRUCache::set_retain_hook`
+type RetainHook<V> = Arc<dyn Fn(&V) -> bool + Send + Sync>;
+
+/// See `LRUCache::set_eviction_sink`
+type EvictionSink<K, V> = Arc<dyn Fn(K, V) + Send + Sync>;
+
 pub struct LRUCache<K, V>
 where
     K: Eq + std::hash::Hash + Clone + Into<u64> + From<u64>,
@@ -137,8 +161,23 @@ where
     // Global counter
     counter: AtomicU32,
     evict_strategy: EvictStrategy,
+    // Max number of entries removed each time eviction is triggered
+    evict_batch_size: usize,
     index: EvictionIndex,
     evict_hook: Option<fn(&V)>,
+    // Consulted for every eviction candidate. If it returns `true`,
+    // the entry is spared for the current eviction round.
+    retain_hook: Option<RetainHook<V>>,
+    // Receives the evicted entries, once they're out of the map
+    eviction_sink: Option<EvictionSink<K, V>>,
+    // Keys ordered by their counter values, only maintained for
+    // `EvictStrategy::Exact`
+    recency: Option<Mutex<BTreeMap<u32, K>>>,
+    // Whether inserts evict entries over capacity, see
+    // `set_evict_on_insert`
+    evict_on_insert: bool,
+    // Times `recency` was found locked by another thread
+    contended_locks: AtomicU64,
 }
 
 /// Wrapper for the value that's returned from the LRUCache when
@@ -164,13 +203,43 @@ where
     V: Clone,
 {
     pub fn new(capacity: usize, evict_strategy: EvictStrategy) -> Self {
+        // Defaults to evicting a single entry at a time for immediate
+        // eviction, and `1 / prob` entries for probabilistic eviction
+        let evict_batch_size = match &evict_strategy {
+            EvictStrategy::Immediate | EvictStrategy::Exact => 1,
+            EvictStrategy::Probabilistic(prob) => (1.0_f32 / prob.prob.to_f32()) as usize,
+        };
+        Self::with_evict_batch_size(capacity, evict_strategy, evict_batch_size)
+    }
+
+    /// Constructs a new LRUCache that evicts up to `evict_batch_size`
+    /// entries at once whenever eviction is triggered
+    ///
+    /// Larger batches amortize the cost of eviction over more inserts,
+    /// at the expense of evicting entries a bit earlier than needed.
+    pub fn with_evict_batch_size(
+        capacity: usize,
+        evict_strategy: EvictStrategy,
+        evict_batch_size: usize,
+    ) -> Self {
+        let recency = match &evict_strategy {
+            EvictStrategy::Exact => Some(Mutex::new(BTreeMap::new())),
+            _ => None,
+        };
+        let evict_on_insert = matches!(evict_strategy, EvictStrategy::Exact);
         LRUCache {
             map: DashMap::new(),
+            recency,
+            evict_on_insert,
+            contended_locks: AtomicU64::new(0),
             counter: AtomicU32::new(0),
             index: EvictionIndex::new(),
             evict_hook: None,
+            retain_hook: None,
+            eviction_sink: None,
             capacity,
             evict_strategy,
+            evict_batch_size,
         }
     }
 
@@ -180,10 +249,83 @@ where
         Self::new(capacity, strategy)
     }
 
+    /// Constructs a new LRUCache that evicts exactly the least
+    /// recently used entry whenever an insert goes over capacity
+    ///
+    /// Meant for benchmarks and tests that need reproducible cache
+    /// behavior. Every access updates a shared recency list, so this
+    /// is slower than the other strategies under contention.
+    pub fn with_exact_eviction(capacity: usize) -> Self {
+        Self::new(capacity, EvictStrategy::Exact)
+    }
+
     pub fn set_evict_hook(&mut self, hook: Option<fn(&V)>) {
         self.evict_hook = hook;
     }
 
+    /// Sets whether inserts going over capacity trigger eviction, as per
+    /// the eviction strategy. Always the case for `EvictStrategy::Exact`,
+    /// off by default for the others, whose caches then only shrink when
+    /// `evict` is called.
+    ///
+    /// The retain hook and the eviction sink only ever see entries that
+    /// are evicted, so caches relying on them need this.
+    pub fn set_evict_on_insert(&mut self, evict_on_insert: bool) {
+        self.evict_on_insert =
+            evict_on_insert || matches!(self.evict_strategy, EvictStrategy::Exact);
+    }
+
+    /// Sets a hook that can veto eviction of an entry
+    ///
+    /// The hook is called for each entry that's about to be evicted;
+    /// returning `true` keeps the entry in the cache for this round
+    /// and the next candidate is considered instead. Useful for
+    /// layering frequency-aware aging on top of the LRU policy.
+    pub fn set_retain_hook<F>(&mut self, hook: Option<F>)
+    where
+        F: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        self.retain_hook = hook.map(|hook| Arc::new(hook) as RetainHook<V>);
+    }
+
+    /// Sets a callback receiving the key and value of every evicted
+    /// entry, e.g. to spill it to a secondary store
+    ///
+    /// Unlike the evict hook, the sink is called once the entry has been
+    /// removed and no lock of the cache is held anymore, so it may access
+    /// the cache itself. Entries removed explicitly aren't passed to it.
+    pub fn set_eviction_sink<F>(&mut self, sink: Option<F>)
+    where
+        F: Fn(K, V) + Send + Sync + 'static,
+    {
+        self.eviction_sink = sink.map(|sink| Arc::new(sink) as EvictionSink<K, V>);
+    }
+
+    fn sink_evicted(&self, key: K, value: V) {
+        if let Some(eviction_sink) = &self.eviction_sink {
+            eviction_sink(key, value);
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Fraction of the capacity that's currently in use
+    ///
+    /// Eviction is lazy, so this can briefly go above 1.0.
+    pub fn fill_ratio(&self) -> f64 {
+        self.len() as f64 / self.capacity as f64
+    }
+
     /// Returns an entry from the cache
     ///
     /// None will be returned if the cache doesn't contain the key
@@ -195,21 +337,39 @@ where
             *counter_val = new_counter;
             self.index
                 .on_cache_hit(old_counter, new_counter, key.clone().into());
+            self.touch_recency(Some(old_counter), new_counter, key);
             Some(value.clone())
         } else {
             None
         }
     }
 
+    /// Returns an entry from the cache without marking it as recently
+    /// used, e.g. for one-off accesses that shouldn't keep it cached
+    pub fn peek(&self, key: &K) -> Option<V> {
+        self.map.get(key).map(|entry| entry.value().0.clone())
+    }
+
     /// Inserts an entry into the cache
     ///
     /// Note that if the entry is already present in cache, it will be
     /// overwritten
     pub fn insert(&self, key: K, value: V) {
         let counter = self.increment_counter();
-        self.map.insert(key.clone(), (value, counter));
+        match self.map.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let (_, old_counter) = entry.insert((value, counter));
+                self.touch_recency(Some(old_counter), counter, &key);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((value, counter));
+                self.touch_recency(None, counter, &key);
+            }
+        }
         self.index.on_cache_miss(counter, key.into());
-        // self.evict();
+        if self.evict_on_insert {
+            self.evict();
+        }
     }
 
     /// Gets the value from the cache if it exists, else tries to
@@ -229,14 +389,17 @@ where
             .and_modify(|(_, counter)| {
                 let old_counter = counter.clone();
                 let new_counter = self.increment_counter();
+                self.touch_recency(Some(old_counter), new_counter, &k1);
                 self.index.on_cache_hit(old_counter, new_counter, k1.into());
                 *counter = new_counter;
             })
             .or_try_insert_with(|| {
                 inserted = true;
                 let counter = self.increment_counter();
-                self.index.on_cache_miss(counter, k2.into());
-                f().map(|v| (v, counter))
+                self.index.on_cache_miss(counter, k2.clone().into());
+                let value = f()?;
+                self.touch_recency(None, counter, &k2);
+                Ok((value, counter))
             })
             .map(|v| v.0.clone());
         // @NOTE: We need to clone the value before calling
@@ -245,7 +408,9 @@ where
         match res {
             Ok(v) => {
                 if inserted {
-                    // self.evict();
+                    if self.evict_on_insert {
+                        self.evict();
+                    }
                     Ok(CachedValue::Miss(v))
                 } else {
                     Ok(CachedValue::Hit(v))
@@ -255,53 +420,121 @@ where
         }
     }
 
+    /// Removes an entry from the cache, returning its value if it
+    /// was present
+    ///
+    /// Unlike eviction, the evict hook is not called for entries
+    /// removed explicitly
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let (key, (value, counter)) = self.map.remove(key)?;
+        if let Some(recency) = &self.recency {
+            self.lock_recency(recency).remove(&counter);
+        }
+        self.index.on_remove(counter, key.into());
+        Some(value)
+    }
+
     fn evict(&self) {
         if self.map.len() > self.capacity {
             match &self.evict_strategy {
-                EvictStrategy::Immediate => self.evict_lru(),
+                EvictStrategy::Immediate => self.evict_lru(self.evict_batch_size),
                 EvictStrategy::Probabilistic(prob) => {
                     if self.map.len() > self.capacity && prob.should_trigger() {
                         self.evict_lru_probabilistic(&prob);
                     }
                 }
+                EvictStrategy::Exact => self.evict_lru_exact(),
             }
         }
     }
 
-    fn evict_lru(&self) {
-        let mut oldest_pair = None;
-        let mut oldest_counter = u32::MAX;
+    // Evicts up to `count` of the least recently used entries, in a single
+    // pass over the map
+    fn evict_lru(&self, count: usize) {
+        let mut candidates: Vec<(u32, K)> = self
+            .map
+            .iter()
+            .map(|entry| {
+                let (key, (_, counter_val)) = entry.pair();
+                (*counter_val, key.clone())
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|(counter, _)| *counter);
 
-        for entry in self.map.iter() {
-            let (key, (value, counter_val)) = entry.pair();
-            if *counter_val < oldest_counter {
-                oldest_counter = *counter_val;
-                oldest_pair = Some((key.clone(), value.clone()));
+        let mut evicted = 0;
+        for (counter, key) in candidates {
+            if evicted == count {
+                return;
             }
+            // Candidates spared by the retain hook are skipped, as are the
+            // ones accessed or evicted by another thread since the pass
+            let removed = self.map.remove_if(&key, |_, (value, counter_val)| {
+                *counter_val == counter
+                    && !self
+                        .retain_hook
+                        .as_ref()
+                        .is_some_and(|retain_hook| retain_hook(value))
+            });
+            let Some((key, (value, _))) = removed else {
+                continue;
+            };
+            self.index.on_remove(counter, key.clone().into());
+            if let Some(evict_hook) = self.evict_hook {
+                evict_hook(&value);
+            }
+            self.sink_evicted(key, value);
+            evicted += 1;
         }
+    }
 
-        if let Some((key, value)) = oldest_pair {
-            // If item didn't exist it will return None. This can
-            // happen if another thread finds the same item to evict
-            // and "wins". This implies for temporarily the dashmap
-            // size could exceed max capacity. It's fine for now but
-            // needs to be fixed.
+    fn evict_lru_exact(&self) {
+        let Some(recency) = &self.recency else {
+            return;
+        };
+        // Counter of the last candidate that couldn't be evicted,
+        // either because it was spared by the retain hook or accessed
+        // concurrently
+        let mut skipped_until = None;
+        while self.map.len() > self.capacity {
+            // The recency lock must not be held while accessing the
+            // map, as `get` takes the two locks in the opposite order
+            let candidate = {
+                let recency = self.lock_recency(recency);
+                let lower = skipped_until.map_or(Bound::Unbounded, Bound::Excluded);
+                recency
+                    .range((lower, Bound::Unbounded))
+                    .next()
+                    .map(|(counter, key)| (*counter, key.clone()))
+            };
+            let Some((counter, key)) = candidate else {
+                return;
+            };
+            let removed = self.map.remove_if(&key, |_, (value, counter_val)| {
+                *counter_val == counter
+                    && !self
+                        .retain_hook
+                        .as_ref()
+                        .is_some_and(|retain_hook| retain_hook(value))
+            });
+            let Some((key, (value, _))) = removed else {
+                skipped_until = Some(counter);
+                continue;
+            };
+            self.lock_recency(recency).remove(&counter);
+            self.index.on_remove(counter, key.clone().into());
             if let Some(evict_hook) = self.evict_hook {
                 evict_hook(&value);
             }
-            let removed = self.map.remove(&key);
-            if removed.is_none() {
-                log::warn!("Item already evicted by another thread");
-            }
+            self.sink_evicted(key, value);
         }
     }
 
     fn evict_lru_probabilistic(&self, strategy: &ProbEviction) {
-        let num_to_evict = (1.0_f32 / strategy.prob.to_f32()) as u8;
+        // The eviction index only has 256 slots to pick candidates from
+        let num_to_evict = self.evict_batch_size.min(u8::MAX as usize) as u8;
         if num_to_evict > 0 {
             let global_counter = self.counter.load(Ordering::SeqCst);
             let mut pairs_to_evict = Vec::with_capacity(num_to_evict as usize);
-            // @TODO: What if num_to_evict is > 256?
             for (idx, key) in self.index.get_keys(num_to_evict as u8) {
                 if pairs_to_evict.len() as u8 >= num_to_evict {
                     break;
@@ -322,11 +555,19 @@ where
                 }
             }
             for (idx, key, value) in pairs_to_evict {
+                if let Some(retain_hook) = &self.retain_hook {
+                    if retain_hook(&value) {
+                        continue;
+                    }
+                }
                 if let Some(evict_hook) = self.evict_hook {
                     evict_hook(&value)
                 }
-                self.map.remove(&key);
+                let removed = self.map.remove(&key);
                 self.index.remove(idx);
+                if let Some((key, (value, _))) = removed {
+                    self.sink_evicted(key, value);
+                }
             }
         }
     }
@@ -343,11 +584,225 @@ where
         }
     }
 
+    /// Returns the keys ordered from the most to the least recently
+    /// used, for inspecting eviction behavior
+    ///
+    /// The order is derived from the counter values at the time each
+    /// entry is visited, so it's only best-effort while other threads
+    /// are accessing the cache.
+    pub fn recency_snapshot(&self) -> Vec<K> {
+        let global_counter = self.counter.load(Ordering::SeqCst);
+        let mut entries: Vec<(u32, K)> = self
+            .map
+            .iter()
+            .map(|entry| {
+                let (key, (_, counter_val)) = entry.pair();
+                (counter_age(global_counter, *counter_val), key.clone())
+            })
+            .collect();
+        entries.sort_by_key(|(age, _)| *age);
+        entries.into_iter().map(|(_, key)| key).collect()
+    }
+
+    fn touch_recency(&self, old_counter: Option<u32>, new_counter: u32, key: &K) {
+        if let Some(recency) = &self.recency {
+            let mut recency = self.lock_recency(recency);
+            if let Some(old_counter) = old_counter {
+                recency.remove(&old_counter);
+            }
+            recency.insert(new_counter, key.clone());
+        }
+    }
+
+    // Locks the recency list, counting whether another thread held it
+    fn lock_recency<'a>(
+        &self,
+        recency: &'a Mutex<BTreeMap<u32, K>>,
+    ) -> MutexGuard<'a, BTreeMap<u32, K>> {
+        match recency.try_lock() {
+            Ok(recency) => recency,
+            Err(err) => {
+                if let TryLockError::WouldBlock = err {
+                    self.contended_locks.fetch_add(1, Ordering::Relaxed);
+                }
+                recency.lock().unwrap()
+            }
+        }
+    }
+
+    /// Number of times an access had to wait for another thread holding
+    /// the cache's lock. Only `EvictStrategy::Exact` takes a lock, the
+    /// other strategies never wait.
+    pub fn contended_locks(&self) -> u64 {
+        self.contended_locks.load(Ordering::Relaxed)
+    }
+
     fn increment_counter(&self) -> u32 {
         self.counter.fetch_add(1, Ordering::SeqCst)
     }
 }
 
+/// An `LRUCache` split into independent shards to reduce contention
+/// between concurrent inserts
+///
+/// Each shard has its own eviction index, counter and capacity, so
+/// entries in different shards never contend with each other.
+/// Capacity and eviction are applied per shard, with the total
+/// capacity split evenly between them.
+pub struct ShardedLRUCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone + Into<u64> + From<u64>,
+    V: Clone,
+{
+    shards: Box<[LRUCache<K, V>]>,
+    // Maps a key to the value picking its shard, modulo the shard count
+    shard_key: fn(u64) -> u64,
+}
+
+impl<K, V> ShardedLRUCache<K, V>
+where
+    K: Eq + std::hash::Hash + Clone + Into<u64> + From<u64>,
+    V: Clone,
+{
+    pub fn new(capacity: usize, shard_count: usize, evict_strategy: EvictStrategy) -> Self {
+        let shard_count = shard_count.max(1);
+        let shard_capacity = capacity.div_ceil(shard_count);
+        let shards = (0..shard_count)
+            .map(|_| LRUCache::new(shard_capacity, evict_strategy.clone()))
+            .collect();
+        Self {
+            shards,
+            shard_key: Self::fold_key,
+        }
+    }
+
+    // Constructs a new ShardedLRUCache with probabilistic eviction strategy
+    pub fn with_prob_eviction(capacity: usize, prob: f32, shard_count: usize) -> Self {
+        let strategy = EvictStrategy::Probabilistic(ProbEviction::new(f16::from_f32_const(prob)));
+        Self::new(capacity, shard_count, strategy)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    // Keys are expected to be combined indexes with the file offset in
+    // the upper and the version in the lower half by default, which are
+    // folded together so that entries of a single version spread across
+    // shards instead of all landing in the same one.
+    fn fold_key(key: u64) -> u64 {
+        (key >> 32) ^ key
+    }
+
+    /// Sets the function mapping keys to the value their shard is picked
+    /// by, modulo the shard count, for keys laid out differently than
+    /// combined indexes. The entries are only ever looked up in the shard
+    /// the function picks, so it must be set before any is inserted.
+    pub fn set_shard_key(&mut self, shard_key: fn(u64) -> u64) {
+        debug_assert!(self.is_empty(), "entries would be left in other shards");
+        self.shard_key = shard_key;
+    }
+
+    fn shard(&self, key: &K) -> &LRUCache<K, V> {
+        let idx = (self.shard_key)(key.clone().into()) % self.shards.len() as u64;
+        &self.shards[idx as usize]
+    }
+
+    /// Number of times an access had to wait for another thread holding
+    /// the lock of its shard, see `LRUCache::contended_locks`
+    pub fn contended_locks(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| shard.contended_locks())
+            .sum()
+    }
+
+    pub fn set_evict_hook(&mut self, hook: Option<fn(&V)>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_evict_hook(hook);
+        }
+    }
+
+    pub fn set_evict_on_insert(&mut self, evict_on_insert: bool) {
+        for shard in self.shards.iter_mut() {
+            shard.set_evict_on_insert(evict_on_insert);
+        }
+    }
+
+    pub fn set_retain_hook<F>(&mut self, hook: Option<F>)
+    where
+        F: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        let hook = hook.map(|hook| Arc::new(hook) as RetainHook<V>);
+        for shard in self.shards.iter_mut() {
+            shard.retain_hook = hook.clone();
+        }
+    }
+
+    pub fn set_eviction_sink<F>(&mut self, sink: Option<F>)
+    where
+        F: Fn(K, V) + Send + Sync + 'static,
+    {
+        let sink = sink.map(|sink| Arc::new(sink) as EvictionSink<K, V>);
+        for shard in self.shards.iter_mut() {
+            shard.eviction_sink = sink.clone();
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).get(key)
+    }
+
+    pub fn peek(&self, key: &K) -> Option<V> {
+        self.shard(key).peek(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.shard(&key).insert(key, value)
+    }
+
+    pub fn get_or_insert<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<CachedValue<V>, E> {
+        self.shard(&key).get_or_insert(key, f)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.map.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.map.is_empty())
+    }
+
+    /// Total capacity across all shards
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(|shard| shard.capacity).sum()
+    }
+
+    /// Fraction of the total capacity that's currently in use
+    pub fn fill_ratio(&self) -> f64 {
+        self.len() as f64 / self.capacity() as f64
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.shards.iter().flat_map(|shard| shard.values())
+    }
+
+    #[allow(unused)]
+    pub(crate) fn evict(&self) {
+        for shard in self.shards.iter() {
+            shard.evict();
+        }
+    }
+}
+
 pub struct Values<'a, K: 'a, V: 'a> {
     iter: dashmap::iter::Iter<'a, K, (V, u32), std::hash::RandomState, DashMap<K, (V, u32)>>,
 }
@@ -368,9 +823,14 @@ where
 #[cfg(test)]
 mod tests {
 
-    use std::{collections::HashMap, sync::Arc, thread};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Barrier},
+        thread,
+    };
 
     use super::*;
+    use crate::models::{lazy_load::LazyItem, versioning::Hash};
 
     // #[test]
     // fn test_basic_usage() {
@@ -534,6 +994,42 @@ mod tests {
         assert_eq!(vec!["value1", "value2", "value3", "value4"], values);
     }
 
+    #[test]
+    fn test_fill_ratio() {
+        let cache: LRUCache<u64, u64> = LRUCache::new(200, EvictStrategy::Immediate);
+        assert_eq!(0.0, cache.fill_ratio());
+        for i in 0..50 {
+            cache.insert(i, i);
+        }
+        assert!((cache.fill_ratio() - 0.25).abs() < 1e-9);
+
+        let cache: ShardedLRUCache<u64, u64> =
+            ShardedLRUCache::new(400, 4, EvictStrategy::Immediate);
+        assert_eq!(400, cache.capacity());
+        for i in 0..300 {
+            cache.insert(i << 32, i);
+        }
+        assert!((cache.fill_ratio() - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_recency_snapshot() {
+        let cache: LRUCache<u64, u64> = LRUCache::new(10, EvictStrategy::Immediate);
+        for i in 1..=5 {
+            cache.insert(i, i);
+        }
+        assert_eq!(vec![5, 4, 3, 2, 1], cache.recency_snapshot());
+
+        cache.get(&2);
+        cache.get(&4);
+        cache.get(&2);
+        assert_eq!(vec![2, 4, 5, 3, 1], cache.recency_snapshot());
+
+        cache.remove(&5);
+        let _ = cache.get_or_insert::<()>(1, || unreachable!());
+        assert_eq!(vec![1, 2, 4, 3], cache.recency_snapshot());
+    }
+
     fn gen_rand_nums(rng: &mut rand::rngs::ThreadRng, n: u64, min: u32, max: u32) -> Vec<u32> {
         (0..n).map(|_| rng.gen_range(min..max)).collect()
     }
@@ -669,6 +1165,193 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_retain_hook_frequency_aging() {
+        let mut cache: LRUCache<u64, LazyItem<u32>> = LRUCache::new(2, EvictStrategy::Immediate);
+        cache.set_retain_hook(Some(LazyItem::decay));
+        cache.set_evict_on_insert(true);
+
+        let hot = LazyItem::new(Hash::from(0), 0, 1);
+        let cold = LazyItem::new(Hash::from(0), 0, 2);
+        cache.insert(1, hot.clone());
+        cache.insert(2, cold);
+        for _ in 0..3 {
+            hot.touch();
+        }
+
+        // Key 1 is the least recently used entry, but it's hot so the
+        // cold entry gets evicted instead
+        cache.insert(3, LazyItem::new(Hash::from(0), 0, 3));
+        assert!(cache.map.contains_key(&1));
+        assert!(!cache.map.contains_key(&2));
+        assert_eq!(1, hot.get_decay_counter());
+
+        // Still surviving, but its counter keeps decaying
+        cache.insert(4, LazyItem::new(Hash::from(0), 0, 4));
+        assert!(cache.map.contains_key(&1));
+        assert!(!cache.map.contains_key(&3));
+        assert_eq!(0, hot.get_decay_counter());
+
+        // Once fully decayed, it's evicted like any other entry
+        cache.insert(5, LazyItem::new(Hash::from(0), 0, 5));
+        assert!(!cache.map.contains_key(&1));
+        assert_eq!(2, cache.map.len());
+    }
+
+    #[test]
+    fn test_evict_batch_size() {
+        let cache: LRUCache<u64, u64> =
+            LRUCache::with_evict_batch_size(10, EvictStrategy::Immediate, 4);
+        for i in 0..10 {
+            cache.insert(i, i);
+        }
+
+        // Going over capacity evicts a whole batch of the least
+        // recently used entries
+        cache.insert(10, 10);
+        cache.evict();
+        assert_eq!(7, cache.map.len());
+        for i in 0..4 {
+            assert!(!cache.map.contains_key(&i));
+        }
+
+        for i in 11..100 {
+            cache.insert(i, i);
+            let len_before = cache.map.len();
+            cache.evict();
+            assert!(len_before - cache.map.len() <= 4);
+            assert!(cache.map.len() <= 10);
+        }
+
+        // Probabilistic eviction removes at most a batch per trigger
+        let strategy = EvictStrategy::Probabilistic(ProbEviction::new(f16::from_f32_const(1.0)));
+        let cache: LRUCache<u64, u64> = LRUCache::with_evict_batch_size(10, strategy, 4);
+        for i in 0..1000 {
+            cache.insert(i, i);
+        }
+        cache.evict();
+        let len = cache.map.len();
+        assert!(len < 1000);
+        assert!(len >= 996);
+    }
+
+    #[test]
+    fn test_exact_eviction() {
+        let cache: LRUCache<u64, u64> = LRUCache::with_exact_eviction(3);
+        for i in 0..3 {
+            cache.insert(i, i);
+        }
+        assert_eq!(3, cache.len());
+
+        // Touching 0 makes 1 the least recently used entry
+        assert_eq!(Some(0), cache.get(&0));
+        cache.insert(3, 3);
+        assert_eq!(3, cache.len());
+        assert!(!cache.map.contains_key(&1));
+
+        // Overwriting counts as a use as well
+        cache.insert(2, 20);
+        cache.insert(4, 4);
+        assert!(!cache.map.contains_key(&0));
+
+        let hit = cache.get_or_insert::<()>(3, || Ok(30)).unwrap();
+        assert!(matches!(hit, CachedValue::Hit(3)));
+        cache.get_or_insert::<()>(5, || Ok(5)).unwrap();
+        assert!(!cache.map.contains_key(&2));
+
+        // A failed insert doesn't count towards capacity
+        assert!(cache.get_or_insert(6, || Err(())).is_err());
+        assert_eq!(vec![5, 3, 4], cache.recency_snapshot());
+
+        // Keys are evicted in exactly the order they were last used
+        let mut expected = cache.recency_snapshot();
+        for i in 10..20 {
+            cache.insert(i, i);
+            let lru = expected.pop().unwrap();
+            assert!(!cache.map.contains_key(&lru));
+            expected.insert(0, i);
+            assert_eq!(expected, cache.recency_snapshot());
+        }
+        assert_eq!(3, cache.recency.as_ref().unwrap().lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_sharded_conc_inserts() {
+        let cache: Arc<ShardedLRUCache<u64, u64>> =
+            Arc::new(ShardedLRUCache::new(1000, 8, EvictStrategy::Immediate));
+        assert_eq!(8, cache.shard_count());
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|t| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    for offset in 0..500u64 {
+                        // Combined index of a node in version `t`
+                        let key = (offset << 32) | t;
+                        cache.insert(key, key);
+                        assert_eq!(Some(key), cache.get(&key));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(4000, cache.len());
+
+        // Entries of a single version are spread across all shards
+        for shard in cache.shards.iter() {
+            assert!(shard.map.len() >= 250);
+        }
+
+        // Capacity is applied per shard, which bounds the total
+        while cache.shards.iter().any(|shard| shard.map.len() > 125) {
+            cache.evict();
+        }
+        assert_eq!(1000, cache.len());
+
+        let key = (1 << 32) | 3;
+        cache.insert(key, 42);
+        assert_eq!(Some(42), cache.remove(&key));
+        assert_eq!(None, cache.get(&key));
+    }
+
+    #[test]
+    fn test_sharded_contention() {
+        let contended_locks = |shard_count, shard_key: fn(u64) -> u64| {
+            let mut cache = ShardedLRUCache::new(1_000_000, shard_count, EvictStrategy::Exact);
+            cache.set_shard_key(shard_key);
+            let cache: Arc<ShardedLRUCache<u64, u64>> = Arc::new(cache);
+            let barrier = Arc::new(Barrier::new(8));
+            let handles: Vec<_> = (0..8u64)
+                .map(|t| {
+                    let cache = cache.clone();
+                    let barrier = barrier.clone();
+                    thread::spawn(move || {
+                        barrier.wait();
+                        for i in 0..20_000u64 {
+                            let key = i * 8 + t;
+                            cache.insert(key, key);
+                            assert_eq!(Some(key), cache.get(&key));
+                        }
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            assert_eq!(160_000, cache.len());
+            cache.contended_locks()
+        };
+
+        // The keys of each thread all fall in a shard of its own, so the
+        // threads never wait for each other
+        assert_eq!(0, contended_locks(8, |key| key));
+        // Sharing a single shard, they do, even on a single core as they
+        // get preempted while holding the lock
+        assert!(contended_locks(1, |key| key) > 0);
+    }
+
     // #[test]
     // fn test_evict_hook() {
     //     let mut cache: LRUCache<u64, &'static str> = LRUCache::new(2, EvictStrategy::Immediate);
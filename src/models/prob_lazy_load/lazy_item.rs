@@ -1,5 +1,7 @@
 use std::{
     fmt::Debug,
+    mem::ManuallyDrop,
+    ptr,
     sync::atomic::{AtomicPtr, Ordering},
 };
 
@@ -13,7 +15,10 @@ use crate::{
         types::FileOffset,
         versioning::Hash,
     },
-    storage::inverted_index_sparse_ann_basic::InvertedIndexSparseAnnNodeBasicTSHashmapData,
+    storage::{
+        inverted_index_sparse_ann_basic::InvertedIndexSparseAnnNodeBasicTSHashmapData,
+        sparse_ann_query_basic::QueryStats,
+    },
 };
 
 use super::lazy_item_array::ProbLazyItemArray;
@@ -54,6 +59,61 @@ pub struct ProbLazyItem<T> {
     pub is_level_0: bool,
 }
 
+/// State replaced by `ProbLazyItem::relocate`, whose data was moved to the
+/// new state. Threads that loaded the old state before the move may still
+/// be reading it, so it stays allocated until this is dropped, which frees
+/// it without dropping the data a second time.
+pub struct RetiredState<T>(*mut ProbLazyItemState<T>);
+
+// SAFETY: the state is only ever freed through this, never accessed
+unsafe impl<T: Send> Send for RetiredState<T> {}
+unsafe impl<T: Sync> Sync for RetiredState<T> {}
+
+impl<T> Drop for RetiredState<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // SAFETY: the state was allocated by a `Box`, and `ManuallyDrop`
+            // has the same layout as the state
+            drop(Box::from_raw(
+                self.0 as *mut ManuallyDrop<ProbLazyItemState<T>>,
+            ));
+        }
+    }
+}
+
+/// A `*mut ProbLazyItem<T>` as held by the cache registries, which share
+/// it between the threads loading and querying the index.
+///
+/// Sending and sharing it across threads is sound as long as `T` is, since:
+/// - the registries only hold pointers returned by the `ProbLazyItem`
+///   constructors, which are never freed while the index is alive
+/// - the wrapped pointer is written once, by the thread that created or
+///   loaded the item (holding the loading lock of its key in the latter
+///   case), and only loaded afterwards; registry entries are replaced as
+///   a whole under the registry's own locks
+/// - the item itself is only mutated by atomically swapping its state
+pub struct AtomicSharedNode<T>(AtomicPtr<ProbLazyItem<T>>);
+
+// SAFETY: see the invariants above, the pointee is `Send + Sync` if `T` is
+unsafe impl<T: Send + Sync> Send for AtomicSharedNode<T> {}
+unsafe impl<T: Send + Sync> Sync for AtomicSharedNode<T> {}
+
+impl<T> AtomicSharedNode<T> {
+    pub fn new(item: *mut ProbLazyItem<T>) -> Self {
+        Self(AtomicPtr::new(item))
+    }
+
+    pub fn load(&self) -> *mut ProbLazyItem<T> {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for AtomicSharedNode<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.load())
+    }
+}
+
 impl<T: PartialEq> PartialEq for ProbLazyItem<T> {
     fn eq(&self, other: &Self) -> bool {
         self.is_level_0 == other.is_level_0
@@ -124,6 +184,45 @@ impl<T> ProbLazyItem<T> {
         }
     }
 
+    /// Moves a ready item to `file_offset` in the `version_id` file, keeping
+    /// its data. The data is moved to a new state, and the old one is
+    /// returned rather than freed, for the caller to keep until no thread
+    /// can still be reading it.
+    ///
+    /// # Safety
+    ///
+    /// No other thread may change the item's state meanwhile. Threads that
+    /// hold references to the data taken before the move keep reading the
+    /// old state's copy of it, so they must not update it, as the updates
+    /// would be lost.
+    pub unsafe fn relocate(&self, version_id: Hash, file_offset: FileOffset) -> RetiredState<T> {
+        let old_state = self.state.load(Ordering::Acquire);
+        // SAFETY: the state is a valid pointer. The copy takes over the
+        // data, which `RetiredState` doesn't drop.
+        let new_state = match unsafe { ptr::read(old_state) } {
+            ProbLazyItemState::Ready(ReadyState {
+                data,
+                version_number,
+                ..
+            }) => ProbLazyItemState::Ready(ReadyState {
+                data,
+                file_offset,
+                version_id,
+                version_number,
+            }),
+            ProbLazyItemState::Pending(file_index) => {
+                ProbLazyItemState::Pending(FileIndex::Valid {
+                    offset: file_offset,
+                    version_number: file_index.get_version_number().unwrap_or(0),
+                    version_id,
+                })
+            }
+        };
+        self.state
+            .store(Box::into_raw(Box::new(new_state)), Ordering::SeqCst);
+        RetiredState(old_state)
+    }
+
     pub fn is_ready(&self) -> bool {
         unsafe {
             matches!(
@@ -142,6 +241,13 @@ impl<T> ProbLazyItem<T> {
         }
     }
 
+    /// Whether the item is a loaded node rather than a placeholder from
+    /// `new_pending`, whose data is `None`. Same as `is_ready`, matching
+    /// `LazyItem::is_resolved`.
+    pub fn is_resolved(&self) -> bool {
+        self.is_ready()
+    }
+
     pub fn get_lazy_data<'a>(&self) -> Option<&'a T> {
         unsafe {
             match &*self.state.load(Ordering::Acquire) {
@@ -185,6 +291,19 @@ impl ProbLazyItem<ProbNode> {
         }
     }
 
+    /// Counts the versions linked through the node's `versions`,
+    /// recursively, excluding the node itself. Versions that aren't loaded
+    /// aren't counted.
+    pub fn version_chain_len(&self) -> usize {
+        let Some(data) = self.get_lazy_data() else {
+            return 0;
+        };
+        (0..data.versions.len())
+            .filter_map(|i| data.versions.get(i))
+            .map(|version| 1 + unsafe { &*version }.version_chain_len())
+            .sum()
+    }
+
     pub fn add_version(
         this: *mut Self,
         version: *mut Self,
@@ -319,6 +438,32 @@ impl ProbLazyItem<InvertedIndexSparseAnnNodeBasicTSHashmapData> {
             }
         }
     }
+
+    /// Same as `try_get_data`, counting the access into `stats`
+    pub fn try_get_data_with_stats<'a>(
+        &self,
+        cache: &InvertedIndexCache,
+        dim: u32,
+        stats: &mut QueryStats,
+    ) -> Result<&'a InvertedIndexSparseAnnNodeBasicTSHashmapData, BufIoError> {
+        unsafe {
+            match &*self.state.load(Ordering::Relaxed) {
+                ProbLazyItemState::Ready(state) => {
+                    stats.nodes_from_cache += 1;
+                    Ok(&state.data)
+                }
+                ProbLazyItemState::Pending(file_index) => {
+                    let offset = file_index.get_offset().unwrap();
+                    (*(cache.get_data_with_stats(
+                        offset,
+                        (dim % cache.data_file_parts as u32) as u8,
+                        Some(stats),
+                    )?))
+                    .try_get_data(cache, dim)
+                }
+            }
+        }
+    }
 }
 
 impl ProbLazyItem<VersionedInvertedFixedSetIndex> {
@@ -338,6 +483,32 @@ impl ProbLazyItem<VersionedInvertedFixedSetIndex> {
             }
         }
     }
+
+    /// Same as `try_get_data`, counting the access into `stats`
+    pub fn try_get_data_with_stats<'a>(
+        &self,
+        cache: &InvertedIndexCache,
+        dim: u32,
+        stats: &mut QueryStats,
+    ) -> Result<&'a VersionedInvertedFixedSetIndex, BufIoError> {
+        unsafe {
+            match &*self.state.load(Ordering::Relaxed) {
+                ProbLazyItemState::Ready(state) => {
+                    stats.nodes_from_cache += 1;
+                    Ok(&state.data)
+                }
+                ProbLazyItemState::Pending(file_index) => {
+                    let offset = file_index.get_offset().unwrap();
+                    (*(cache.get_sets_with_stats(
+                        offset,
+                        (dim % cache.data_file_parts as u32) as u8,
+                        Some(stats),
+                    )?))
+                    .try_get_data(cache, dim)
+                }
+            }
+        }
+    }
 }
 
 impl<T> Drop for ProbLazyItem<T> {
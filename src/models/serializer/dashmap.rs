@@ -1,6 +1,6 @@
 use dashmap::DashMap;
 
-use super::CustomSerialize;
+use super::{ChunkGeometry, CustomSerialize};
 use crate::models::buffered_io::{BufIoError, BufferManagerFactory};
 use crate::models::cache_loader::Cacheable;
 use crate::models::identity_collections::IdentityMapKey;
@@ -14,6 +14,26 @@ use crate::models::{
 use std::collections::HashSet;
 use std::sync::Arc;
 
+// @SERIALIZED_SIZE:
+//   Header (see `ChunkGeometry`, absent from maps written before it was
+//   added, whose chunks have `CHUNK_SIZE` slots of 14 bytes):
+//     4 bytes for `CHUNK_HEADER_MAGIC` +
+//     2 bytes for chunk size +
+//     2 bytes for slot stride
+//
+//   Chunks:
+//     chunk size * (
+//       4 bytes for key offset +
+//       4 bytes for item offset +
+//       2 bytes for version number +
+//       4 bytes for version hash
+//     ) = chunk size * 14 +
+//     4 bytes for next chunk link
+
+/// Size of the slots written for the map entries, readers skip any bytes
+/// past these in slots of a larger recorded stride
+const SLOT_STRIDE: u64 = 14;
+
 impl<T> CustomSerialize for DashMap<IdentityMapKey, LazyItem<T>>
 where
     T: Cacheable + CustomSerialize + Clone + 'static,
@@ -24,63 +44,7 @@ where
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
-        if self.is_empty() {
-            return Ok(u32::MAX);
-        };
-        let bufman = bufmans.get(version)?;
-        let start_offset = bufman.cursor_position(cursor)? as u32;
-        let items: Vec<_> = self
-            .iter()
-            .map(|item| (item.key().clone(), item.value().clone()))
-            .collect();
-        let total_items = items.len();
-
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
-            let is_last_chunk = chunk_end == total_items;
-
-            // Write placeholders for item offsets
-            let placeholder_start = bufman.cursor_position(cursor)? as u32;
-            for _ in 0..CHUNK_SIZE {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                bufman.update_u16_with_cursor(cursor, u16::MAX)?;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-            }
-            // Write placeholder for next chunk link
-            let next_chunk_placeholder = bufman.cursor_position(cursor)? as u32;
-            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-
-            // Serialize items and update placeholders
-            for i in chunk_start..chunk_end {
-                let key_offset = items[i].0.serialize(bufmans.clone(), version, cursor)?;
-                let item_offset = items[i].1.serialize(bufmans.clone(), version, cursor)?;
-
-                let placeholder_pos = placeholder_start as u64 + ((i - chunk_start) as u64 * 14);
-                let current_pos = bufman.cursor_position(cursor)?;
-
-                // Write entry offset
-                bufman.seek_with_cursor(cursor, placeholder_pos)?;
-                bufman.update_u32_with_cursor(cursor, key_offset)?;
-                bufman.update_u32_with_cursor(cursor, item_offset)?;
-                bufman.update_u16_with_cursor(cursor, items[i].1.get_current_version_number())?;
-                bufman.update_u32_with_cursor(cursor, *items[i].1.get_current_version())?;
-
-                // Return to the current position
-                bufman.seek_with_cursor(cursor, current_pos)?;
-            }
-
-            // Write next chunk link
-            let next_chunk_start = bufman.cursor_position(cursor)? as u32;
-            bufman.seek_with_cursor(cursor, next_chunk_placeholder as u64)?;
-            if is_last_chunk {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
-            } else {
-                bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
-            }
-            bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
-        }
-        Ok(start_offset)
+        serialize_with_chunk_size(self, bufmans, version, cursor, CHUNK_SIZE)
     }
 
     fn deserialize(
@@ -102,12 +66,20 @@ where
                 }
                 let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
-                bufman.seek_with_cursor(cursor, offset as u64)?;
+                // Chunk geometry recorded by the writer, rather than the
+                // `CHUNK_SIZE` this binary was built with
+                let (geometry, first_chunk) = ChunkGeometry::read(
+                    &bufman,
+                    cursor,
+                    offset as u64,
+                    ChunkGeometry::new(CHUNK_SIZE as u64, SLOT_STRIDE),
+                    SLOT_STRIDE,
+                )?;
                 let mut items = Vec::new();
-                let mut current_chunk = offset;
+                let mut current_chunk = first_chunk as u32;
                 loop {
-                    for i in 0..CHUNK_SIZE {
-                        bufman.seek_with_cursor(cursor, current_chunk as u64 + (i as u64 * 14))?;
+                    for i in 0..geometry.chunk_size {
+                        bufman.seek_with_cursor(cursor, geometry.slot(current_chunk as u64, i))?;
                         let key_offset = bufman.read_u32_with_cursor(cursor)?;
                         let item_offset = bufman.read_u32_with_cursor(cursor)?;
                         let item_version_number = bufman.read_u16_with_cursor(cursor)?;
@@ -141,8 +113,7 @@ where
                         )?;
                         items.push((key, item));
                     }
-                    bufman
-                        .seek_with_cursor(cursor, current_chunk as u64 + CHUNK_SIZE as u64 * 14)?;
+                    bufman.seek_with_cursor(cursor, geometry.next_link(current_chunk as u64))?;
                     // Read next chunk link
                     current_chunk = bufman.read_u32_with_cursor(cursor)?;
                     if current_chunk == u32::MAX {
@@ -155,3 +126,71 @@ where
         }
     }
 }
+
+/// Serializes the map in chunks of `chunk_size` entries, recording the chunk
+/// size and the slot stride in a `ChunkGeometry` header so that the map
+/// stays readable by a binary built with a different `CHUNK_SIZE`.
+pub(crate) fn serialize_with_chunk_size<T>(
+    map: &DashMap<IdentityMapKey, LazyItem<T>>,
+    bufmans: Arc<BufferManagerFactory<Hash>>,
+    version: Hash,
+    cursor: u64,
+    chunk_size: usize,
+) -> Result<u32, BufIoError>
+where
+    T: Cacheable + CustomSerialize + Clone + 'static,
+{
+    if map.is_empty() {
+        return Ok(u32::MAX);
+    };
+    let bufman = bufmans.get(version)?;
+    let start_offset = bufman.cursor_position(cursor)? as u32;
+    let items: Vec<_> = map
+        .iter()
+        .map(|item| (item.key().clone(), item.value().clone()))
+        .collect();
+    let total_items = items.len();
+
+    let geometry = ChunkGeometry::new(chunk_size as u64, SLOT_STRIDE);
+    geometry.write_header(&bufman, cursor)?;
+
+    for chunk_start in (0..total_items).step_by(chunk_size) {
+        let chunk_end = std::cmp::min(chunk_start + chunk_size, total_items);
+        let is_last_chunk = chunk_end == total_items;
+
+        // Write placeholders for item offsets and the next chunk link
+        let placeholder_start = bufman.cursor_position(cursor)?;
+        bufman.update_with_cursor(cursor, &vec![u8::MAX; geometry.chunk_bytes() as usize])?;
+        let next_chunk_placeholder = geometry.next_link(placeholder_start);
+
+        // Serialize items and update placeholders
+        for (i, (key, item)) in items[chunk_start..chunk_end].iter().enumerate() {
+            let key_offset = key.serialize(bufmans.clone(), version, cursor)?;
+            let item_offset = item.serialize(bufmans.clone(), version, cursor)?;
+
+            let placeholder_pos = geometry.slot(placeholder_start, i as u64);
+            let current_pos = bufman.cursor_position(cursor)?;
+
+            // Write entry offset
+            bufman.seek_with_cursor(cursor, placeholder_pos)?;
+            bufman.update_u32_with_cursor(cursor, key_offset)?;
+            bufman.update_u32_with_cursor(cursor, item_offset)?;
+            bufman.update_u16_with_cursor(cursor, item.get_current_version_number())?;
+            bufman.update_u32_with_cursor(cursor, *item.get_current_version())?;
+
+            // Return to the current position
+            bufman.seek_with_cursor(cursor, current_pos)?;
+        }
+
+        // Write next chunk link
+        let next_chunk_start = bufman.cursor_position(cursor)? as u32;
+        bufman.seek_with_cursor(cursor, next_chunk_placeholder)?;
+        if is_last_chunk {
+            bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
+        } else {
+            bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
+        }
+        bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
+    }
+    Ok(start_offset)
+}
@@ -1,4 +1,5 @@
 use std::collections::HashSet;
+use std::ops::RangeInclusive;
 
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
@@ -53,9 +54,18 @@ impl DenseSerialize for SharedNode {
         file_index: FileIndex,
         cache: &DenseIndexCache,
         max_loads: u16,
+        neighbor_fanout: usize,
+        levels: &RangeInclusive<u8>,
         skipm: &mut HashSet<u64>,
         is_level_0: bool,
     ) -> Result<Self, BufIoError> {
-        cache.get_lazy_object(file_index, max_loads, skipm, is_level_0)
+        cache.get_lazy_object_in_levels(
+            file_index,
+            max_loads,
+            neighbor_fanout,
+            levels,
+            skipm,
+            is_level_0,
+        )
     }
 }
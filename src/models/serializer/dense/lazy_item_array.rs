@@ -1,4 +1,4 @@
-use std::{collections::HashSet, io};
+use std::{collections::HashSet, io, ops::RangeInclusive};
 
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
@@ -49,6 +49,9 @@ impl<const N: usize> DenseSerialize for ProbLazyItemArray<ProbNode, N> {
             bufman.update_u16_with_cursor(cursor, version_number)?;
             bufman.update_u32_with_cursor(cursor, *version_id)?;
         }
+        // Leave the cursor past the empty slots too, at the end of the
+        // array
+        bufman.seek_with_cursor(cursor, start_offset + 10 * N as u64)?;
 
         Ok(start_offset as u32)
     }
@@ -58,6 +61,8 @@ impl<const N: usize> DenseSerialize for ProbLazyItemArray<ProbNode, N> {
         file_index: FileIndex,
         cache: &DenseIndexCache,
         max_loads: u16,
+        neighbor_fanout: usize,
+        levels: &RangeInclusive<u8>,
         skipm: &mut HashSet<u64>,
         is_level_0: bool,
     ) -> Result<Self, BufIoError> {
@@ -92,7 +97,14 @@ impl<const N: usize> DenseSerialize for ProbLazyItemArray<ProbNode, N> {
                         version_id,
                     };
                     let item = SharedNode::deserialize(
-                        bufmans, file_index, cache, max_loads, skipm, is_level_0,
+                        bufmans,
+                        file_index,
+                        cache,
+                        max_loads,
+                        neighbor_fanout,
+                        levels,
+                        skipm,
+                        is_level_0,
                     )?;
                     array.push(item);
                 }
@@ -6,6 +6,7 @@ mod node;
 mod tests;
 
 use std::collections::HashSet;
+use std::ops::RangeInclusive;
 
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
@@ -14,6 +15,49 @@ use crate::models::{
     versioning::Hash,
 };
 
+/// Bit of the serialized neighbors length of a `ProbNode` set when its
+/// neighbors are written with `NeighborEncoding::Inline`
+pub const INLINE_NEIGHBORS_FLAG: u16 = 1 << 15;
+
+/// How the neighbors of a serialized `ProbNode` refer to the neighbor nodes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NeighborEncoding {
+    /// id (u32), offset (u32), version number (u16), version id (u32),
+    /// distance (5 bytes)
+    #[default]
+    Indexed,
+    /// id (u32), distance (5 bytes)
+    ///
+    /// The neighbor nodes are found by id in the location index of the
+    /// cache when loading, see `DenseIndexCache::locate`, which only
+    /// indexes level 0 nodes, so only those can be written this way.
+    ///
+    /// The index only knows the newest location of each id, so a node
+    /// loaded from an older version would link to neighbors of newer
+    /// versions. Inline neighbors are meant for files that aren't
+    /// versioned, and nodes with versions are rejected.
+    Inline,
+}
+
+impl NeighborEncoding {
+    /// Splits a serialized neighbors length into the encoding it's
+    /// flagged with and the neighbors count
+    pub fn from_len(len: u16) -> (Self, usize) {
+        if len & INLINE_NEIGHBORS_FLAG != 0 {
+            (Self::Inline, (len & !INLINE_NEIGHBORS_FLAG) as usize)
+        } else {
+            (Self::Indexed, len as usize)
+        }
+    }
+
+    pub fn slot_size(self) -> u32 {
+        match self {
+            Self::Indexed => 19,
+            Self::Inline => 9,
+        }
+    }
+}
+
 pub trait DenseSerialize: Sized {
     fn serialize(
         &self,
@@ -22,11 +66,14 @@ pub trait DenseSerialize: Sized {
         cursor: u64,
     ) -> Result<u32, BufIoError>;
 
+    #[allow(clippy::too_many_arguments)]
     fn deserialize(
         bufmans: &BufferManagerFactory<Hash>,
         file_index: FileIndex,
         cache: &DenseIndexCache,
         max_loads: u16,
+        neighbor_fanout: usize,
+        levels: &RangeInclusive<u8>,
         skipm: &mut HashSet<u64>,
         is_level_0: bool,
     ) -> Result<Self, BufIoError>;
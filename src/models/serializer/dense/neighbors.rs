@@ -1,6 +1,8 @@
 use std::{
     collections::HashSet,
-    io, ptr,
+    io,
+    ops::RangeInclusive,
+    ptr,
     sync::atomic::{AtomicPtr, Ordering},
 };
 
@@ -9,11 +11,11 @@ use crate::models::{
     cache_loader::DenseIndexCache,
     lazy_load::FileIndex,
     prob_node::SharedNode,
-    types::{FileOffset, MetricResult},
+    types::{FileOffset, MetricResult, VectorId},
     versioning::Hash,
 };
 
-use super::DenseSerialize;
+use super::{DenseSerialize, NeighborEncoding, INLINE_NEIGHBORS_FLAG};
 
 // @SERIALIZED_SIZE:
 //   2 bytes for length +
@@ -22,6 +24,9 @@ use super::DenseSerialize;
 //     10 bytes offset & version +
 //     5 bytes for distance/similarity
 //   ) = 2 + len * 19
+//
+//   or, with `NeighborEncoding::Inline`, 2 + len * 9 without the offsets
+//   and versions
 impl DenseSerialize for Box<[AtomicPtr<(u32, SharedNode, MetricResult)>]> {
     fn serialize(
         &self,
@@ -29,47 +34,7 @@ impl DenseSerialize for Box<[AtomicPtr<(u32, SharedNode, MetricResult)>]> {
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
-        let bufman = bufmans.get(version)?;
-        let start = bufman.cursor_position(cursor)?;
-        debug_assert_eq!(
-            (start - 39) % (self.len() as u64 * 19 + 121),
-            0,
-            "offset: {}",
-            start
-        );
-        bufman.update_u16_with_cursor(cursor, self.len() as u16)?;
-
-        for neighbor in self.iter() {
-            let (node_id, node_ptr, dist) = unsafe {
-                if let Some(neighbor) = neighbor.load(Ordering::SeqCst).as_ref() {
-                    neighbor.clone()
-                } else {
-                    bufman.update_with_cursor(cursor, &[u8::MAX; 19])?;
-                    continue;
-                }
-            };
-
-            let node = unsafe { &*node_ptr };
-
-            let (node_offset, node_version_number, node_version_id) = match node.get_file_index() {
-                FileIndex::Valid {
-                    offset,
-                    version_number,
-                    version_id,
-                } => (offset.0, version_number, version_id),
-                _ => unreachable!(),
-            };
-            let mut buf = Vec::with_capacity(19);
-            buf.extend(node_id.to_le_bytes());
-            buf.extend(node_offset.to_le_bytes());
-            buf.extend(node_version_number.to_le_bytes());
-            buf.extend(node_version_id.to_le_bytes());
-            let (tag, value) = dist.get_tag_and_value();
-            buf.push(tag);
-            buf.extend(value.to_le_bytes());
-            bufman.update_with_cursor(cursor, &buf)?;
-        }
-        Ok(start as u32)
+        serialize_neighbors(self, bufmans, version, cursor, NeighborEncoding::Indexed, 1)
     }
 
     fn deserialize(
@@ -77,6 +42,8 @@ impl DenseSerialize for Box<[AtomicPtr<(u32, SharedNode, MetricResult)>]> {
         file_index: FileIndex,
         cache: &DenseIndexCache,
         max_loads: u16,
+        neighbor_fanout: usize,
+        levels: &RangeInclusive<u8>,
         skipm: &mut HashSet<u64>,
         is_level_0: bool,
     ) -> Result<Self, BufIoError> {
@@ -94,39 +61,65 @@ impl DenseSerialize for Box<[AtomicPtr<(u32, SharedNode, MetricResult)>]> {
                 let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
                 bufman.seek_with_cursor(cursor, offset as u64)?;
-                let len = bufman.read_u16_with_cursor(cursor)? as usize;
+                let (encoding, len) =
+                    NeighborEncoding::from_len(bufman.read_u16_with_cursor(cursor)?);
                 let mut neighbors = Vec::with_capacity(len);
                 let placeholder_start = offset as u64 + 2;
 
                 for i in 0..len {
-                    let placeholder_offset = placeholder_start as u64 + i as u64 * 19;
+                    let placeholder_offset =
+                        placeholder_start + i as u64 * encoding.slot_size() as u64;
                     bufman.seek_with_cursor(cursor, placeholder_offset)?;
                     let node_id = bufman.read_u32_with_cursor(cursor)?;
-                    let node_offset = bufman.read_u32_with_cursor(cursor)?;
-                    if node_offset == u32::MAX {
-                        neighbors.push(AtomicPtr::new(ptr::null_mut()));
-                        continue;
-                    }
-                    let node_version_number = bufman.read_u16_with_cursor(cursor)?;
-                    let node_version_id = bufman.read_u32_with_cursor(cursor)?;
+                    let (node_file_index, dist_offset) = match encoding {
+                        NeighborEncoding::Indexed => {
+                            let node_offset = bufman.read_u32_with_cursor(cursor)?;
+                            if node_offset == u32::MAX {
+                                neighbors.push(AtomicPtr::new(ptr::null_mut()));
+                                continue;
+                            }
+                            let node_version_number = bufman.read_u16_with_cursor(cursor)?;
+                            let node_version_id = bufman.read_u32_with_cursor(cursor)?;
+                            let node_file_index = FileIndex::Valid {
+                                offset: FileOffset(node_offset),
+                                version_number: node_version_number,
+                                version_id: Hash::from(node_version_id),
+                            };
+                            (node_file_index, placeholder_offset + 14)
+                        }
+                        NeighborEncoding::Inline => {
+                            if node_id == u32::MAX {
+                                neighbors.push(AtomicPtr::new(ptr::null_mut()));
+                                continue;
+                            }
+                            let Some(node_file_index) = cache.locate(VectorId(node_id as u64))?
+                            else {
+                                bufman.close_cursor(cursor)?;
+                                return Err(io::Error::new(
+                                    io::ErrorKind::NotFound,
+                                    format!("No location indexed for neighbor {}", node_id),
+                                )
+                                .into());
+                            };
+                            (node_file_index, placeholder_offset + 4)
+                        }
+                    };
 
                     let dist: MetricResult =
                         crate::models::serializer::SimpleSerialize::deserialize(
                             &bufman,
-                            FileOffset(placeholder_offset as u32 + 14),
+                            FileOffset(dist_offset as u32),
                         )?;
 
-                    let node_file_index = FileIndex::Valid {
-                        offset: FileOffset(node_offset),
-                        version_number: node_version_number,
-                        version_id: Hash::from(node_version_id),
-                    };
-
+                    // Neighbors past the fanout are left pending, unless
+                    // they are already in the cache
                     let node = SharedNode::deserialize(
                         bufmans,
                         node_file_index,
                         cache,
-                        max_loads,
+                        if i < neighbor_fanout { max_loads } else { 0 },
+                        neighbor_fanout,
+                        levels,
                         skipm,
                         is_level_0,
                     )?;
@@ -141,3 +134,61 @@ impl DenseSerialize for Box<[AtomicPtr<(u32, SharedNode, MetricResult)>]> {
         }
     }
 }
+
+/// Writes the neighbors in `encoding`, returning the offset they start
+/// at. The neighbors of an inline encoded node must be level 0 nodes,
+/// see `NeighborEncoding::Inline`.
+pub(super) fn serialize_neighbors(
+    neighbors: &[AtomicPtr<(u32, SharedNode, MetricResult)>],
+    bufmans: &BufferManagerFactory<Hash>,
+    version: Hash,
+    cursor: u64,
+    encoding: NeighborEncoding,
+    alignment: u32,
+) -> Result<u32, BufIoError> {
+    let bufman = bufmans.get(version)?;
+    let start = bufman.cursor_position(cursor)?;
+    let slot_size = encoding.slot_size() as usize;
+    let node_stride =
+        (neighbors.len() as u64 * slot_size as u64 + 121).next_multiple_of(alignment as u64);
+    debug_assert_eq!((start - 39) % node_stride, 0, "offset: {}", start);
+    let len = match encoding {
+        NeighborEncoding::Indexed => neighbors.len() as u16,
+        NeighborEncoding::Inline => neighbors.len() as u16 | INLINE_NEIGHBORS_FLAG,
+    };
+    bufman.update_u16_with_cursor(cursor, len)?;
+
+    for neighbor in neighbors.iter() {
+        let (node_id, node_ptr, dist) = unsafe {
+            if let Some(neighbor) = neighbor.load(Ordering::SeqCst).as_ref() {
+                *neighbor
+            } else {
+                bufman.update_with_cursor(cursor, &vec![u8::MAX; slot_size])?;
+                continue;
+            }
+        };
+
+        let mut buf = Vec::with_capacity(slot_size);
+        buf.extend(node_id.to_le_bytes());
+        if encoding == NeighborEncoding::Indexed {
+            let node = unsafe { &*node_ptr };
+
+            let (node_offset, node_version_number, node_version_id) = match node.get_file_index() {
+                FileIndex::Valid {
+                    offset,
+                    version_number,
+                    version_id,
+                } => (offset.0, version_number, version_id),
+                _ => unreachable!(),
+            };
+            buf.extend(node_offset.to_le_bytes());
+            buf.extend(node_version_number.to_le_bytes());
+            buf.extend(node_version_id.to_le_bytes());
+        }
+        let (tag, value) = dist.get_tag_and_value();
+        buf.push(tag);
+        buf.extend(value.to_le_bytes());
+        bufman.update_with_cursor(cursor, &buf)?;
+    }
+    Ok(start as u32)
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashSet, io, ptr, sync::atomic::AtomicPtr};
+use std::{collections::HashSet, io, ops::RangeInclusive, ptr, sync::atomic::AtomicPtr};
 
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
@@ -10,7 +10,7 @@ use crate::models::{
     versioning::Hash,
 };
 
-use super::DenseSerialize;
+use super::{neighbors::serialize_neighbors, DenseSerialize, NeighborEncoding};
 
 // @SERIALIZED_SIZE:
 //   Properties:
@@ -25,7 +25,8 @@ use super::DenseSerialize;
 //     neighbors length * 19 bytes for neighbor link +  | nb * 19 + 32
 //     8 * 10 bytes for version link                    | nb * 19 + 112
 //
-//   Total = nb * 19 + 121 (where `nb` is the neighbors count)
+//   Total = nb * 19 + 121 (where `nb` is the neighbors count), or
+//   nb * 9 + 121 with inline neighbors, see `NeighborEncoding`
 impl DenseSerialize for ProbNode {
     fn serialize(
         &self,
@@ -33,107 +34,7 @@ impl DenseSerialize for ProbNode {
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
-        let is_level_0 = self.hnsw_level.0 == 0;
-        let bufman = bufmans.get(version)?;
-        let start_offset = bufman.cursor_position(cursor)?;
-
-        let neighbors = self.get_neighbors_raw();
-        let size = Self::get_serialized_size(neighbors.len()) as u64;
-
-        debug_assert_eq!(start_offset % size, 0, "offset: {}", start_offset);
-
-        let mut buf = Vec::with_capacity(39);
-
-        // Serialize basic fields
-        buf.push(self.hnsw_level.0);
-
-        // Serialize prop
-        let (FileOffset(offset), BytesToRead(length)) = &self.prop.location;
-        buf.extend(offset.to_le_bytes());
-        buf.extend(length.to_le_bytes());
-
-        let parent_ptr = self.get_parent();
-
-        // Get parent file index
-        let parent_file_index = if let Some(parent) = unsafe { parent_ptr.as_ref() } {
-            debug_assert!(!parent.is_level_0);
-            let file_index = match parent.get_file_index() {
-                FileIndex::Valid {
-                    offset,
-                    version_number,
-                    version_id,
-                } => (offset.0, version_number, version_id),
-                _ => unreachable!(),
-            };
-            Some(file_index)
-        } else {
-            None
-        };
-
-        let child_ptr = self.get_child();
-
-        // Get child file index
-        let child_file_index = if let Some(child) = unsafe { child_ptr.as_ref() } {
-            debug_assert_eq!(child.is_level_0, self.hnsw_level.0 == 1);
-            let file_index = match child.get_file_index() {
-                FileIndex::Valid {
-                    offset,
-                    version_number,
-                    version_id,
-                } => (offset.0, version_number, version_id),
-                _ => unreachable!(),
-            };
-            Some(file_index)
-        } else {
-            None
-        };
-
-        if let Some((offset, version_number, version_id)) = parent_file_index {
-            buf.extend(offset.to_le_bytes());
-            buf.extend(version_number.to_le_bytes());
-            buf.extend(version_id.to_le_bytes());
-        } else {
-            buf.extend([u8::MAX; 10]);
-        }
-
-        if let Some((offset, version_number, version_id)) = child_file_index {
-            buf.extend(offset.to_le_bytes());
-            buf.extend(version_number.to_le_bytes());
-            buf.extend(version_id.to_le_bytes());
-        } else {
-            buf.extend([u8::MAX; 10]);
-        }
-
-        if let Some(root) = unsafe { self.root_version.as_ref() } {
-            debug_assert_eq!(root.is_level_0, is_level_0);
-            let (offset, version_number, version_id) = match root.get_file_index() {
-                FileIndex::Valid {
-                    offset,
-                    version_number,
-                    version_id,
-                } => (offset.0, version_number, version_id),
-                _ => unimplemented!(),
-            };
-            buf.extend(offset.to_le_bytes());
-            buf.extend(version_number.to_le_bytes());
-            buf.extend(version_id.to_le_bytes());
-        } else {
-            buf.extend([u8::MAX; 10]);
-        }
-
-        bufman.update_with_cursor(cursor, &buf)?;
-
-        #[cfg(debug_assertions)]
-        {
-            let current = bufman.cursor_position(cursor)?;
-
-            assert_eq!(current, start_offset + 39);
-        }
-
-        neighbors.serialize(bufmans, version, cursor)?;
-        self.versions.serialize(bufmans, version, cursor)?;
-
-        Ok(start_offset as u32)
+        self.serialize_with_layout(bufmans, version, cursor, NeighborEncoding::Indexed, 1)
     }
 
     fn deserialize(
@@ -141,6 +42,8 @@ impl DenseSerialize for ProbNode {
         file_index: FileIndex,
         cache: &DenseIndexCache,
         max_loads: u16,
+        neighbor_fanout: usize,
+        levels: &RangeInclusive<u8>,
         skipm: &mut HashSet<u64>,
         is_level_0: bool,
     ) -> Result<Self, BufIoError> {
@@ -181,7 +84,15 @@ impl DenseSerialize for ProbNode {
                 let root_version_offset = bufman.read_u32_with_cursor(cursor)?;
                 let root_version_version_number = bufman.read_u16_with_cursor(cursor)?;
                 let root_version_version_id = Hash::from(bufman.read_u32_with_cursor(cursor)?);
+                let (neighbor_encoding, _) =
+                    NeighborEncoding::from_len(bufman.read_u16_with_cursor(cursor)?);
                 bufman.close_cursor(cursor)?;
+                // Parents and children outside of `levels` are left pending,
+                // unless they are already in the cache
+                let link_max_loads = |level: Option<u8>| match level {
+                    Some(level) if levels.contains(&level) => max_loads,
+                    _ => 0,
+                };
                 // Deserialize parent
                 let parent = if parent_offset != u32::MAX {
                     SharedNode::deserialize(
@@ -192,7 +103,9 @@ impl DenseSerialize for ProbNode {
                             version_id: parent_version_id,
                         },
                         cache,
-                        max_loads,
+                        link_max_loads(hnsw_level.0.checked_add(1)),
+                        neighbor_fanout,
+                        levels,
                         skipm,
                         false,
                     )?
@@ -209,7 +122,9 @@ impl DenseSerialize for ProbNode {
                             version_id: child_version_id,
                         },
                         cache,
-                        max_loads,
+                        link_max_loads(hnsw_level.0.checked_sub(1)),
+                        neighbor_fanout,
+                        levels,
                         skipm,
                         hnsw_level.0 == 1,
                     )?
@@ -227,6 +142,8 @@ impl DenseSerialize for ProbNode {
                         },
                         cache,
                         max_loads,
+                        neighbor_fanout,
+                        levels,
                         skipm,
                         hnsw_level.0 == 0,
                     )?
@@ -246,12 +163,16 @@ impl DenseSerialize for ProbNode {
                         neighbors_file_index,
                         cache,
                         max_loads,
+                        neighbor_fanout,
+                        levels,
                         skipm,
                         is_level_0,
                     )?;
 
                 let versions_file_index = FileIndex::Valid {
-                    offset: FileOffset(offset + 41 + neighbors.len() as u32 * 19),
+                    offset: FileOffset(
+                        offset + 41 + neighbors.len() as u32 * neighbor_encoding.slot_size(),
+                    ),
                     version_number,
                     version_id,
                 };
@@ -261,6 +182,8 @@ impl DenseSerialize for ProbNode {
                     versions_file_index,
                     cache,
                     max_loads,
+                    neighbor_fanout,
+                    levels,
                     skipm,
                     is_level_0,
                 )?;
@@ -278,3 +201,138 @@ impl DenseSerialize for ProbNode {
         }
     }
 }
+
+impl ProbNode {
+    /// Serializes the node like `serialize`, writing its neighbors in
+    /// `encoding`, into a file whose nodes are padded up to multiples of
+    /// `alignment` bytes, see `DenseIndexCache::with_node_alignment`. Only
+    /// level 0 nodes that aren't part of a version chain can have inline
+    /// neighbors, see `NeighborEncoding::Inline`.
+    pub fn serialize_with_layout(
+        &self,
+        bufmans: &BufferManagerFactory<Hash>,
+        version: Hash,
+        cursor: u64,
+        encoding: NeighborEncoding,
+        alignment: u32,
+    ) -> Result<u32, BufIoError> {
+        let is_level_0 = self.hnsw_level.0 == 0;
+        if encoding == NeighborEncoding::Inline && !is_level_0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only level 0 nodes can have inline neighbors",
+            )
+            .into());
+        }
+        if encoding == NeighborEncoding::Inline
+            && (!self.versions.is_empty() || !self.root_version.is_null())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Versioned nodes can't have inline neighbors",
+            )
+            .into());
+        }
+        let bufman = bufmans.get(version)?;
+        let start_offset = bufman.cursor_position(cursor)?;
+
+        let neighbors = self.get_neighbors_raw();
+        let size = neighbors.len() as u64 * encoding.slot_size() as u64 + 121;
+        let stride = size.next_multiple_of(alignment as u64);
+
+        debug_assert_eq!(start_offset % stride, 0, "offset: {}", start_offset);
+
+        let mut buf = Vec::with_capacity(39);
+
+        // Serialize basic fields
+        buf.push(self.hnsw_level.0);
+
+        // Serialize prop
+        let (FileOffset(offset), BytesToRead(length)) = &self.prop.location;
+        buf.extend(offset.to_le_bytes());
+        buf.extend(length.to_le_bytes());
+
+        let parent_ptr = self.get_parent();
+
+        // Get parent file index
+        let parent_file_index = if let Some(parent) = unsafe { parent_ptr.as_ref() } {
+            debug_assert!(!parent.is_level_0);
+            let file_index = match parent.get_file_index() {
+                FileIndex::Valid {
+                    offset,
+                    version_number,
+                    version_id,
+                } => (offset.0, version_number, version_id),
+                _ => unreachable!(),
+            };
+            Some(file_index)
+        } else {
+            None
+        };
+
+        let child_ptr = self.get_child();
+
+        // Get child file index
+        let child_file_index = if let Some(child) = unsafe { child_ptr.as_ref() } {
+            debug_assert_eq!(child.is_level_0, self.hnsw_level.0 == 1);
+            let file_index = match child.get_file_index() {
+                FileIndex::Valid {
+                    offset,
+                    version_number,
+                    version_id,
+                } => (offset.0, version_number, version_id),
+                _ => unreachable!(),
+            };
+            Some(file_index)
+        } else {
+            None
+        };
+
+        if let Some((offset, version_number, version_id)) = parent_file_index {
+            buf.extend(offset.to_le_bytes());
+            buf.extend(version_number.to_le_bytes());
+            buf.extend(version_id.to_le_bytes());
+        } else {
+            buf.extend([u8::MAX; 10]);
+        }
+
+        if let Some((offset, version_number, version_id)) = child_file_index {
+            buf.extend(offset.to_le_bytes());
+            buf.extend(version_number.to_le_bytes());
+            buf.extend(version_id.to_le_bytes());
+        } else {
+            buf.extend([u8::MAX; 10]);
+        }
+
+        if let Some(root) = unsafe { self.root_version.as_ref() } {
+            debug_assert_eq!(root.is_level_0, is_level_0);
+            let (offset, version_number, version_id) = match root.get_file_index() {
+                FileIndex::Valid {
+                    offset,
+                    version_number,
+                    version_id,
+                } => (offset.0, version_number, version_id),
+                _ => unimplemented!(),
+            };
+            buf.extend(offset.to_le_bytes());
+            buf.extend(version_number.to_le_bytes());
+            buf.extend(version_id.to_le_bytes());
+        } else {
+            buf.extend([u8::MAX; 10]);
+        }
+
+        bufman.update_with_cursor(cursor, &buf)?;
+
+        #[cfg(debug_assertions)]
+        {
+            let current = bufman.cursor_position(cursor)?;
+
+            assert_eq!(current, start_offset + 39);
+        }
+
+        serialize_neighbors(neighbors, bufmans, version, cursor, encoding, alignment)?;
+        self.versions.serialize(bufmans, version, cursor)?;
+
+        Ok(start_offset as u32)
+    }
+}
@@ -1,4 +1,4 @@
-use super::DenseSerialize;
+use super::{DenseSerialize, NeighborEncoding};
 use crate::{
     distance::cosine::CosineSimilarity,
     models::{
@@ -130,6 +130,15 @@ fn get_cache(
 }
 
 fn create_prob_node(id: u64, prop_file: &RwLock<File>) -> ProbNode {
+    create_prob_node_at_level(id, HNSWLevel(2), 8, prop_file)
+}
+
+fn create_prob_node_at_level(
+    id: u64,
+    level: HNSWLevel,
+    neighbors_count: usize,
+    prop_file: &RwLock<File>,
+) -> ProbNode {
     let id = VectorId(id);
     let value = Arc::new(Storage::UnsignedByte {
         mag: 10,
@@ -144,11 +153,11 @@ fn create_prob_node(id: u64, prop_file: &RwLock<File>) -> ProbNode {
         location,
     });
     ProbNode::new(
-        HNSWLevel(2),
+        level,
         prop.clone(),
         ptr::null_mut(),
         ptr::null_mut(),
-        8,
+        neighbors_count,
     )
 }
 
@@ -337,6 +346,71 @@ fn test_prob_node_serialization_with_neighbors() {
     lazy_node.assert_eq(&deserialized, &mut tester);
 }
 
+#[test]
+fn test_prob_node_deserialization_with_neighbor_fanout() {
+    let root_version_id = Hash::from(0);
+    let root_version_number = 0;
+    let (bufmans, cache, bufman, cursor, prop_file, _temp_dir) = setup_test(root_version_id);
+
+    let mut nodes = Vec::new();
+
+    let node = create_prob_node(0, &prop_file);
+    let lazy_node = ProbLazyItem::new(
+        node,
+        root_version_id,
+        root_version_number,
+        false,
+        FileOffset(0),
+    );
+    let node_size = ProbNode::get_serialized_size(8) as u32;
+
+    nodes.push(lazy_node);
+
+    for i in 1..9 {
+        let neighbor_node = create_prob_node(i, &prop_file);
+
+        let lazy_item = ProbLazyItem::new(
+            neighbor_node,
+            root_version_id,
+            root_version_number,
+            false,
+            FileOffset(node_size * i as u32),
+        );
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity((i as f32) / 10.0));
+        unsafe { &*lazy_node }
+            .get_lazy_data()
+            .unwrap()
+            .add_neighbor(i as u32, lazy_item, dist, &cache);
+        nodes.push(lazy_item);
+    }
+
+    for node in nodes {
+        node.serialize(&bufmans, root_version_id, cursor).unwrap();
+    }
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(0),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    // Load into a fresh cache so nothing is resident yet
+    let cache = get_cache(bufmans, prop_file);
+    let deserialized = cache.get_object_with_fanout(file_index, false, 3).unwrap();
+    let neighbors = unsafe { &*deserialized }
+        .get_lazy_data()
+        .unwrap()
+        .get_neighbors_raw();
+
+    assert_eq!(neighbors.len(), 8);
+    for (i, neighbor) in neighbors.iter().enumerate() {
+        let (_, neighbor_node, _) = unsafe { neighbor.load(Ordering::SeqCst).as_ref() }.unwrap();
+        let is_resident = unsafe { &**neighbor_node }.get_lazy_data().is_some();
+        assert_eq!(is_resident, i < 3, "neighbor {}", i);
+    }
+}
+
 #[test]
 fn test_prob_lazy_item_cyclic_serialization() {
     let root_version_id = Hash::from(0);
@@ -485,3 +559,141 @@ fn test_prob_lazy_item_with_versions_serialization_and_validation() {
 
     root.assert_eq(&deserialized, &mut tester);
 }
+
+#[test]
+fn test_prob_node_inline_neighbors() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, prop_file, _temp_dir) = setup_test(root_version_id);
+    let node_size = ProbNode::get_serialized_size(16) as u32;
+
+    let node = create_prob_node_at_level(0, HNSWLevel(0), 16, &prop_file);
+    for i in 1..=16 {
+        // The neighbors are appended through the cache, which indexes
+        // their location
+        let neighbor = ProbLazyItem::new(
+            create_prob_node_at_level(i, HNSWLevel(0), 16, &prop_file),
+            root_version_id,
+            0,
+            true,
+            FileOffset(u32::MAX),
+        );
+        unsafe { cache.append_node(root_version_id, neighbor, true) }.unwrap();
+        let dist = MetricResult::CosineSimilarity(CosineSimilarity((i as f32) / 20.0));
+        node.add_neighbor(i as u32, neighbor, dist, &cache);
+    }
+
+    // Two copies of the node are written after the neighbors
+    bufman.seek_with_cursor(cursor, bufman.file_size()).unwrap();
+    let indexed_offset = node
+        .serialize_with_layout(
+            &bufmans,
+            root_version_id,
+            cursor,
+            NeighborEncoding::Indexed,
+            1,
+        )
+        .unwrap();
+    let indexed_size = bufman.cursor_position(cursor).unwrap() as u32 - indexed_offset;
+    // Nodes start at multiples of their size
+    let inline_node_size = ProbNode::get_serialized_size(16) as u32 - 16 * 10;
+    bufman
+        .seek_with_cursor(
+            cursor,
+            (indexed_offset + node_size).next_multiple_of(inline_node_size) as u64,
+        )
+        .unwrap();
+    let inline_offset = node
+        .serialize_with_layout(
+            &bufmans,
+            root_version_id,
+            cursor,
+            NeighborEncoding::Inline,
+            1,
+        )
+        .unwrap();
+    let inline_size = bufman.cursor_position(cursor).unwrap() as u32 - inline_offset;
+    bufman.close_cursor(cursor).unwrap();
+    assert_eq!(indexed_size, node_size);
+    assert_eq!(inline_size, inline_node_size);
+    assert!(inline_size < indexed_size);
+
+    let load = |offset| -> ProbNode {
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: root_version_id,
+        };
+        cache.load_item(file_index, true).unwrap()
+    };
+    let indexed = load(indexed_offset);
+    let inline = load(inline_offset);
+    let adjacency = |node: &ProbNode| -> Vec<(u32, SharedNode, f32)> {
+        node.get_neighbors_raw()
+            .iter()
+            .map(|neighbor| {
+                let (id, node, dist) = unsafe { neighbor.load(Ordering::SeqCst).as_ref() }.unwrap();
+                (*id, *node, dist.get_value())
+            })
+            .collect()
+    };
+    assert_eq!(adjacency(&indexed).len(), 16);
+    assert_eq!(adjacency(&indexed), adjacency(&inline));
+    assert_eq!(adjacency(&node), adjacency(&inline));
+
+    // The neighbor locations are persisted, so a fresh cache resolves the
+    // inline neighbors as well
+    let cold_cache = get_cache(bufmans.clone(), prop_file.clone());
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(inline_offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let cold_inline: ProbNode = cold_cache.load_item(file_index, true).unwrap();
+    let neighbor_locations = |node: &ProbNode| -> Vec<(u32, FileIndex)> {
+        node.get_neighbors_raw()
+            .iter()
+            .map(|neighbor| {
+                let (id, node, _) = unsafe { neighbor.load(Ordering::SeqCst).as_ref() }.unwrap();
+                (*id, unsafe { &**node }.get_file_index())
+            })
+            .collect()
+    };
+    assert_eq!(neighbor_locations(&cold_inline).len(), 16);
+    assert_eq!(neighbor_locations(&cold_inline), neighbor_locations(&node));
+
+    // Higher level nodes have no indexed location to resolve
+    let node = create_prob_node(17, &prop_file);
+    let bufman = bufmans.get(root_version_id).unwrap();
+    let cursor = bufman.open_cursor().unwrap();
+    assert!(node
+        .serialize_with_layout(
+            &bufmans,
+            root_version_id,
+            cursor,
+            NeighborEncoding::Inline,
+            1,
+        )
+        .is_err());
+
+    // Nor are nodes with versions, whose neighbors would resolve to the
+    // newest version of each id
+    let node = create_prob_node_at_level(18, HNSWLevel(0), 16, &prop_file);
+    let version = ProbLazyItem::new(
+        create_prob_node_at_level(18, HNSWLevel(0), 16, &prop_file),
+        Hash::from(1),
+        1,
+        true,
+        FileOffset(0),
+    );
+    node.versions.push(version);
+    assert!(node
+        .serialize_with_layout(
+            &bufmans,
+            root_version_id,
+            cursor,
+            NeighborEncoding::Inline,
+            1,
+        )
+        .is_err());
+    bufman.close_cursor(cursor).unwrap();
+}
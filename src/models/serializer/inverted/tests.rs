@@ -1,11 +1,15 @@
-use std::{fs::OpenOptions, sync::Arc};
+use std::{
+    fs::{self, OpenOptions},
+    sync::Arc,
+    thread,
+};
 
 use rand::Rng;
 use tempfile::{tempdir, TempDir};
 
 use crate::{
     models::{
-        buffered_io::{BufferManager, BufferManagerFactory},
+        buffered_io::{BufIoError, BufferManager, BufferManagerFactory},
         cache_loader::InvertedIndexCache,
         fixedset::VersionedInvertedFixedSetIndex,
         serializer::inverted::InvertedIndexSerialize,
@@ -18,6 +22,7 @@ use crate::{
             InvertedIndexSparseAnnNodeBasicTSHashmapData,
         },
         page::{Pagepool, VersionedPagepool},
+        sparse_ann_query_basic::QueryStats,
     },
 };
 
@@ -423,6 +428,105 @@ fn test_inverted_index_data_incremental_serialization_with_new_entries() {
     assert_eq!(table.max_key, deserialized.max_key);
 }
 
+#[test]
+fn test_inverted_index_data_bounded_concurrent_loads() {
+    let mut rng = rand::thread_rng();
+    let (dim_bufman, data_bufmans, _cache, _data_bufman, dim_cursor, _data_cursor, _temp_dir) =
+        setup_test(0);
+
+    let mut tables = Vec::new();
+    for _ in 0..32 {
+        let table = InvertedIndexSparseAnnNodeBasicTSHashmapData::new(6);
+        for i in 0..8 {
+            table
+                .map
+                .insert(i * 8, get_random_versioned_pagepool(&mut rng, 0.into()));
+        }
+        dim_bufman.update_u8_with_cursor(dim_cursor, 6).unwrap();
+        let offset = table
+            .serialize(&dim_bufman, &data_bufmans, 0, 8, dim_cursor)
+            .unwrap();
+        tables.push((offset, table));
+    }
+    dim_bufman.close_cursor(dim_cursor).unwrap();
+
+    let cache = Arc::new(InvertedIndexCache::with_max_in_flight_loads(
+        dim_bufman,
+        data_bufmans,
+        8,
+        2,
+    ));
+    let handles: Vec<_> = tables
+        .iter()
+        .map(|(offset, _)| {
+            let cache = cache.clone();
+            let offset = *offset;
+            thread::spawn(move || {
+                let item = cache.get_data(FileOffset(offset), 0).unwrap();
+                let mut list = unsafe { &*item }.get_lazy_data().unwrap().map.to_list();
+                list.sort_by_key(|(k, _)| *k);
+                list
+            })
+        })
+        .collect();
+
+    for (handle, (_, table)) in handles.into_iter().zip(&tables) {
+        let mut table_list = table.map.to_list();
+        table_list.sort_by_key(|(k, _)| *k);
+        assert_eq!(handle.join().unwrap(), table_list);
+    }
+    assert!(cache.peak_in_flight_loads() >= 1);
+    assert!(cache.peak_in_flight_loads() <= 2);
+}
+
+#[test]
+fn test_inverted_index_data_missing_shard() {
+    let mut rng = rand::thread_rng();
+    let (dim_bufman, data_bufmans, _cache, _data_bufman, dim_cursor, _data_cursor, temp_dir) =
+        setup_test(3);
+
+    let table = InvertedIndexSparseAnnNodeBasicTSHashmapData::new(6);
+    for i in 0..8 {
+        table
+            .map
+            .insert(i * 8, get_random_versioned_pagepool(&mut rng, 0.into()));
+    }
+    dim_bufman.update_u8_with_cursor(dim_cursor, 6).unwrap();
+    let offset = table
+        .serialize(&dim_bufman, &data_bufmans, 3, 8, dim_cursor)
+        .unwrap();
+    dim_bufman.close_cursor(dim_cursor).unwrap();
+    data_bufmans.flush_all().unwrap();
+
+    // Reopen the index after its data file got lost
+    std::fs::remove_file(data_bufmans.file_path(&3)).unwrap();
+    let data_bufmans = Arc::new(BufferManagerFactory::new(
+        temp_dir.as_ref().into(),
+        |root, idx: &u8| root.join(format!("{}.idat", idx)),
+        InvertedIndexSparseAnnNodeBasicTSHashmap::get_serialized_size(6) as usize,
+    ));
+    let cache = get_cache(dim_bufman, data_bufmans.clone());
+
+    let error = cache.get_data(FileOffset(offset), 3).unwrap_err();
+    assert!(matches!(error, BufIoError::MissingShard { idx: 3 }));
+    assert_eq!(error.to_string(), "Missing data file of shard 3");
+    assert!(matches!(
+        cache.get_sets(FileOffset(offset), 3),
+        Err(BufIoError::MissingShard { idx: 3 })
+    ));
+    // Reading didn't create an empty file in place of the lost one
+    assert!(!data_bufmans.file_path(&3).exists());
+
+    assert!(cache.repair_shard(8).is_err());
+    cache.repair_shard(3).unwrap();
+    assert_eq!(
+        std::fs::metadata(data_bufmans.file_path(&3)).unwrap().len(),
+        0
+    );
+    // The other shards are untouched
+    assert!(!data_bufmans.file_path(&4).exists());
+}
+
 #[test]
 fn test_fixedset_serialization() {
     let mut rng = rand::thread_rng();
@@ -895,3 +999,150 @@ fn test_inverted_index_incremental_serialization_with_multiple_versions() {
 
     assert_eq!(inverted_index, deserialized);
 }
+
+#[test]
+fn test_inverted_index_cache_warm_dimensions() {
+    let temp_dir = tempdir().unwrap();
+    let mut rng = rand::thread_rng();
+    let inverted_index =
+        InvertedIndexSparseAnnBasicTSHashmap::new(temp_dir.as_ref().into(), 6, 0.into(), 8)
+            .unwrap();
+
+    for dim_index in 0..100 {
+        for _ in 0..100 {
+            inverted_index
+                .insert(
+                    dim_index,
+                    rng.gen_range(0.0..1.0),
+                    rng.gen_range(0..u32::MAX),
+                    0.into(),
+                    1.0,
+                )
+                .unwrap();
+        }
+    }
+    inverted_index.serialize().unwrap();
+
+    // A fresh cache over the same files starts out with empty registries
+    let cache = get_cache(
+        inverted_index.cache.dim_bufman.clone(),
+        inverted_index.cache.data_bufmans.clone(),
+    );
+    let dims = [0, 3, 17, 42, 99];
+    cache.warm_dimensions(&dims).unwrap();
+    // Dimensions without a node are skipped
+    cache.warm_dimensions(&[5000]).unwrap();
+
+    let mut stats = QueryStats::default();
+    for dim_index in dims {
+        let node = inverted_index.find_node(dim_index).unwrap();
+        let data_file_idx = (dim_index % 8) as u8;
+        cache
+            .get_data_with_stats(
+                FileOffset(node.file_offset.0 + 5),
+                data_file_idx,
+                Some(&mut stats),
+            )
+            .unwrap();
+    }
+    assert_eq!(stats.nodes_from_cache, dims.len());
+    assert_eq!(stats.nodes_loaded, 0);
+}
+
+#[test]
+fn test_inverted_index_cache_doc_count_at() {
+    let temp_dir = tempdir().unwrap();
+    let inverted_index =
+        InvertedIndexSparseAnnBasicTSHashmap::new(temp_dir.as_ref().into(), 6, 0.into(), 8)
+            .unwrap();
+    // A fresh cache, as the index's own registries hold the sets as they
+    // were first loaded
+    let doc_count_at = |version: u32| {
+        get_cache(
+            inverted_index.cache.dim_bufman.clone(),
+            inverted_index.cache.data_bufmans.clone(),
+        )
+        .doc_count_at(version.into())
+        .unwrap()
+    };
+    assert_eq!(doc_count_at(0), 0);
+
+    // Version 0 puts every document in two dimensions, version 1 adds to
+    // some of them, version 2 to new ones only
+    for vector_id in 0..40 {
+        for dim_index in [vector_id % 7, 10 + vector_id % 5] {
+            inverted_index
+                .insert(dim_index, 0.5, vector_id, 0.into(), 1.0)
+                .unwrap();
+        }
+    }
+    inverted_index.serialize().unwrap();
+    let mut counts = vec![doc_count_at(0)];
+
+    for vector_id in 40..100 {
+        inverted_index
+            .insert(vector_id % 7, 0.25, vector_id, 1.into(), 1.0)
+            .unwrap();
+    }
+    inverted_index.serialize().unwrap();
+    counts.push(doc_count_at(1));
+
+    for vector_id in 100..150 {
+        inverted_index
+            .insert(20 + vector_id % 3, 0.75, vector_id, 2.into(), 1.0)
+            .unwrap();
+    }
+    inverted_index.serialize().unwrap();
+    counts.push(doc_count_at(2));
+
+    assert_eq!(counts, [40, 100, 150]);
+    // Documents added later don't count for older versions
+    assert_eq!(doc_count_at(0), 40);
+    assert_eq!(doc_count_at(1), 100);
+}
+
+#[test]
+fn test_inverted_index_cache_doc_count_at_colliding_ids() {
+    let temp_dir = tempdir().unwrap();
+    let inverted_index =
+        InvertedIndexSparseAnnBasicTSHashmap::new(temp_dir.as_ref().into(), 6, 0.into(), 8)
+            .unwrap();
+    let doc_count_at = |version: u32| {
+        get_cache(
+            inverted_index.cache.dim_bufman.clone(),
+            inverted_index.cache.data_bufmans.clone(),
+        )
+        .doc_count_at(version.into())
+        .unwrap()
+    };
+
+    // All the ids are equal modulo 512, so they would share a bit of the
+    // fixed sets
+    for i in 0..600 {
+        inverted_index
+            .insert(i % 7, 0.5, i * 512, 0.into(), 1.0)
+            .unwrap();
+    }
+    inverted_index.serialize().unwrap();
+    for i in 600..700 {
+        inverted_index
+            .insert(i % 7, 0.25, i * 512, 1.into(), 1.0)
+            .unwrap();
+    }
+    inverted_index.serialize().unwrap();
+
+    assert_eq!(doc_count_at(0), 600);
+    assert_eq!(doc_count_at(1), 700);
+    assert!(get_cache(
+        inverted_index.cache.dim_bufman.clone(),
+        inverted_index.cache.data_bufmans.clone(),
+    )
+    .doc_count_at(2.into())
+    .is_err());
+
+    // Without a versions log, as written before versions were recorded,
+    // every posting counts
+    fs::remove_file(temp_dir.as_ref().join("versions.log")).unwrap();
+    assert_eq!(doc_count_at(0), 700);
+    assert_eq!(doc_count_at(2), 700);
+}
@@ -1,9 +1,9 @@
-use super::CustomSerialize;
+use super::{lazy_item_vec::GEOMETRY, ChunkGeometry, CustomSerialize, NONE_OFFSET};
 use crate::models::buffered_io::{BufIoError, BufferManagerFactory};
 use crate::models::cache_loader::Cacheable;
+use crate::models::lazy_load::FileIndex;
 use crate::models::lazy_load::LazyItemVec;
 use crate::models::lazy_load::SyncPersist;
-use crate::models::lazy_load::{FileIndex, CHUNK_SIZE};
 use crate::models::types::FileOffset;
 use crate::models::versioning::Hash;
 use crate::models::{
@@ -16,7 +16,7 @@ use std::collections::HashSet;
 use std::{
     io,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc,
     },
 };
@@ -37,17 +37,24 @@ where
         bufman.seek_with_cursor(cursor, bufman.file_size())?;
         map.serialize(bufmans, version, cursor)?
     } else {
-        bufman.seek_with_cursor(cursor, offset as u64)?;
         let items = map.items.clone().get().clone();
+        let (geometry, first_chunk) = ChunkGeometry::read(
+            &bufman,
+            cursor,
+            offset as u64,
+            GEOMETRY,
+            GEOMETRY.slot_stride,
+        )?;
+        let chunk_size = geometry.chunk_size as usize;
 
-        let mut current_chunk = offset;
+        let mut current_chunk = first_chunk;
 
         let mut i = 0;
 
         // remove the items from `items` which have already been serialized
         let last_chunk = loop {
-            for j in 0..CHUNK_SIZE {
-                bufman.seek_with_cursor(cursor, current_chunk as u64 + (j as u64 * 10))?;
+            for j in 0..geometry.chunk_size {
+                bufman.seek_with_cursor(cursor, geometry.slot(current_chunk, j))?;
                 let item_offset = bufman.read_u32_with_cursor(cursor)?;
                 if item_offset == u32::MAX {
                     continue;
@@ -59,21 +66,22 @@ where
                 i += 1;
             }
             let prev_chunk = current_chunk;
-            bufman.seek_with_cursor(cursor, current_chunk as u64 + CHUNK_SIZE as u64 * 10)?;
-            current_chunk = bufman.read_u32_with_cursor(cursor)?;
-            if current_chunk == u32::MAX {
+            bufman.seek_with_cursor(cursor, geometry.next_link(current_chunk))?;
+            let next_chunk = bufman.read_u32_with_cursor(cursor)?;
+            if next_chunk == u32::MAX {
                 break prev_chunk;
             }
+            current_chunk = next_chunk as u64;
         };
 
         bufman.seek_with_cursor(cursor, bufman.file_size())?;
         // fill last chunk
-        for j in 0..CHUNK_SIZE {
+        for j in 0..geometry.chunk_size {
             if i >= items.len() {
                 break;
             }
             let current_pos = bufman.cursor_position(cursor)?;
-            let placeholder_pos = last_chunk as u64 + (j as u64 * 10);
+            let placeholder_pos = geometry.slot(last_chunk, j);
             bufman.seek_with_cursor(cursor, placeholder_pos)?;
             let item_offset = bufman.read_u32_with_cursor(cursor)?;
             if item_offset != u32::MAX {
@@ -98,30 +106,25 @@ where
             let total_items = items.len() - i;
 
             let current_pos = bufman.cursor_position(cursor)?;
-            bufman.seek_with_cursor(cursor, last_chunk as u64 + (CHUNK_SIZE as u64 * 10))?;
+            bufman.seek_with_cursor(cursor, geometry.next_link(last_chunk))?;
             bufman.update_u32_with_cursor(cursor, current_pos as u32)?;
             bufman.seek_with_cursor(cursor, current_pos)?;
 
-            for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-                let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
+            for chunk_start in (0..total_items).step_by(chunk_size) {
+                let chunk_end = std::cmp::min(chunk_start + chunk_size, total_items);
                 let is_last_chunk = chunk_end == total_items;
 
-                // Write placeholders for item offsets
-                let placeholder_start = bufman.cursor_position(cursor)? as u32;
-                for _ in 0..CHUNK_SIZE {
-                    bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                    bufman.update_u16_with_cursor(cursor, u16::MAX)?;
-                    bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                }
-                // Write placeholder for next chunk link
-                let next_chunk_placeholder = bufman.cursor_position(cursor)? as u32;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+                // Write placeholders for item offsets and the next chunk link
+                let placeholder_start = bufman.cursor_position(cursor)?;
+                bufman
+                    .update_with_cursor(cursor, &vec![u8::MAX; geometry.chunk_bytes() as usize])?;
+                let next_chunk_placeholder = geometry.next_link(placeholder_start);
 
                 // Serialize items and update placeholders
                 for j in chunk_start..chunk_end {
                     let item_offset = items[i].serialize(bufmans.clone(), version, cursor)?;
                     let placeholder_pos =
-                        placeholder_start as u64 + ((j - chunk_start) as u64 * 10);
+                        geometry.slot(placeholder_start, (j - chunk_start) as u64);
                     let current_pos = bufman.cursor_position(cursor)?;
 
                     // Write entry offset
@@ -137,7 +140,7 @@ where
 
                 // Write next chunk link
                 let next_chunk_start = bufman.cursor_position(cursor)? as u32;
-                bufman.seek_with_cursor(cursor, next_chunk_placeholder as u64)?;
+                bufman.seek_with_cursor(cursor, next_chunk_placeholder)?;
                 if is_last_chunk {
                     bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
                 } else {
@@ -243,7 +246,7 @@ fn lazy_item_deserialize_impl<T: Cacheable + CustomSerialize + Clone>(
             Ok(LazyItem::Valid {
                 data: ArcShift::new(Some(Arc::new(data))),
                 file_index: ArcShift::new(Some(file_index)),
-                decay_counter: 0,
+                decay_counter: Arc::new(AtomicUsize::new(0)),
                 persist_flag: Arc::new(AtomicBool::new(true)),
                 versions,
                 version_id,
@@ -361,6 +364,33 @@ impl<T: Cacheable + CustomSerialize> CustomSerialize for LazyItem<T> {
     }
 }
 
+impl<T: Cacheable + CustomSerialize> CustomSerialize for Option<LazyItem<T>> {
+    fn serialize(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+    ) -> Result<u32, BufIoError> {
+        match self {
+            Some(item) => item.serialize(bufmans, version, cursor),
+            None => Ok(NONE_OFFSET),
+        }
+    }
+
+    fn deserialize(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+        skipm: &mut HashSet<u64>,
+    ) -> Result<Self, BufIoError> {
+        if file_index.get_offset() == Some(FileOffset(NONE_OFFSET)) {
+            return Ok(None);
+        }
+        LazyItem::deserialize(bufmans, file_index, cache, max_loads, skipm).map(Some)
+    }
+}
+
 impl CustomSerialize for LazyItemRef<MergedNode> {
     fn serialize(
         &self,
@@ -1,5 +1,6 @@
-use super::CustomSerialize;
-use crate::models::buffered_io::{BufIoError, BufferManagerFactory};
+use super::scratch::with_scratch_buffer;
+use super::{ChunkLayout, CustomSerialize, DuplicateKeyPolicy};
+use crate::models::buffered_io::{BufIoError, BufferManager, BufferManagerFactory};
 use crate::models::cache_loader::{Cacheable, NodeRegistry};
 use crate::models::identity_collections::{IdentityMap, IdentityMapKey};
 use crate::models::lazy_load::{FileIndex, LazyItem, LazyItemMap, SyncPersist, CHUNK_SIZE};
@@ -10,10 +11,32 @@ use std::{io, sync::Arc};
 
 const MSB: u32 = 1 << 31;
 
+/// Layout version of the serialized map header block.
+///
+/// Version 1 was the linked-chunk layout where entry headers were
+/// interleaved with the keys and values they point to. It carries no
+/// version, a map in that layout starts with the key offset of its first
+/// entry instead, which points past the first chunk, so is never this
+/// value.
+const MAP_LAYOUT_VERSION: u32 = 2;
+
+/// Size of a single entry in the header block:
+/// key offset (u32), item offset (u32), version number (u16), version id (u32)
+const MAP_ENTRY_SIZE: u64 = 14;
+
 impl<T> CustomSerialize for LazyItemMap<T>
 where
     T: Cacheable + Clone + CustomSerialize + 'static,
 {
+    // Layout:
+    //
+    // [key 0][item 0][key 1][item 1]...[key n-1][item n-1]
+    // [layout version: u32][len: u32][entry 0]...[entry n-1]
+    //
+    // Keys and items are written sequentially first, and the header block
+    // with all the entries is written contiguously after them, so no seeking
+    // back to patch placeholders is needed. The returned offset points to the
+    // header block.
     fn serialize(
         &self,
         bufmans: Arc<BufferManagerFactory<Hash>>,
@@ -24,61 +47,42 @@ where
             return Ok(u32::MAX);
         };
         let bufman = bufmans.get(version)?;
-        let start_offset = bufman.cursor_position(cursor)? as u32;
         let mut items_arc = self.items.clone();
         let items: Vec<_> = items_arc
             .get()
             .iter()
             .map(|(key, value)| (key.clone(), value.clone()))
             .collect();
-        let total_items = items.len();
-
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
-            let is_last_chunk = chunk_end == total_items;
-
-            // Write placeholders for item offsets
-            let placeholder_start = bufman.cursor_position(cursor)? as u32;
-            for _ in 0..CHUNK_SIZE {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                bufman.update_u16_with_cursor(cursor, u16::MAX)?;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-            }
-            // Write placeholder for next chunk link
-            let next_chunk_placeholder = bufman.cursor_position(cursor)? as u32;
-            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
 
-            // Serialize items and update placeholders
-            for i in chunk_start..chunk_end {
-                let key_offset = items[i].0.serialize(bufmans.clone(), version, cursor)?;
-                let item_offset = items[i].1.serialize(bufmans.clone(), version, cursor)?;
-
-                let placeholder_pos = placeholder_start as u64 + ((i - chunk_start) as u64 * 14);
-                let current_pos = bufman.cursor_position(cursor)?;
-
-                // Write entry offset
-                bufman.seek_with_cursor(cursor, placeholder_pos)?;
-                bufman.update_u32_with_cursor(cursor, key_offset)?;
-                bufman.update_u32_with_cursor(cursor, item_offset)?;
-                bufman.update_u16_with_cursor(cursor, items[i].1.get_current_version_number())?;
-                bufman.update_u32_with_cursor(cursor, *items[i].1.get_current_version())?;
+        // First pass: serialize keys and values, collecting their offsets
+        let mut entries = Vec::with_capacity(items.len());
+        for (key, item) in &items {
+            let key_offset = key.serialize(bufmans.clone(), version, cursor)?;
+            let item_offset = item.serialize(bufmans.clone(), version, cursor)?;
+            entries.push((
+                key_offset,
+                item_offset,
+                item.get_current_version_number(),
+                *item.get_current_version(),
+            ));
+        }
 
-                // Return to the current position
-                bufman.seek_with_cursor(cursor, current_pos)?;
+        // Second pass: write the header block in one go
+        let header_offset = bufman.cursor_position(cursor)? as u32;
+        with_scratch_buffer(|header| {
+            header.reserve(8 + entries.len() * MAP_ENTRY_SIZE as usize);
+            header.extend_from_slice(&MAP_LAYOUT_VERSION.to_le_bytes());
+            header.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+            for (key_offset, item_offset, version_number, version_id) in entries {
+                header.extend_from_slice(&key_offset.to_le_bytes());
+                header.extend_from_slice(&item_offset.to_le_bytes());
+                header.extend_from_slice(&version_number.to_le_bytes());
+                header.extend_from_slice(&version_id.to_le_bytes());
             }
+            bufman.update_with_cursor(cursor, header)
+        })?;
 
-            // Write next chunk link
-            let next_chunk_start = bufman.cursor_position(cursor)? as u32;
-            bufman.seek_with_cursor(cursor, next_chunk_placeholder as u64)?;
-            if is_last_chunk {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
-            } else {
-                bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
-            }
-            bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
-        }
-        Ok(start_offset)
+        Ok(header_offset)
     }
 
     fn deserialize(
@@ -87,6 +91,32 @@ where
         cache: Arc<NodeRegistry>,
         max_loads: u16,
         skipm: &mut HashSet<u64>,
+    ) -> Result<Self, BufIoError> {
+        Self::deserialize_with_policy(
+            bufmans,
+            file_index,
+            cache,
+            max_loads,
+            skipm,
+            DuplicateKeyPolicy::default(),
+        )
+    }
+}
+
+impl<T> LazyItemMap<T>
+where
+    T: Cacheable + Clone + CustomSerialize + 'static,
+{
+    /// Deserializes a map, resolving keys listed in more than one entry
+    /// according to `policy`. Maps written in the version 1 layout are
+    /// detected and read as well.
+    pub fn deserialize_with_policy(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+        skipm: &mut HashSet<u64>,
+        policy: DuplicateKeyPolicy,
     ) -> Result<Self, BufIoError> {
         match file_index {
             FileIndex::Invalid => Ok(LazyItemMap::new()),
@@ -101,61 +131,133 @@ where
                 let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
                 bufman.seek_with_cursor(cursor, offset as u64)?;
-                let mut items = Vec::new();
-                let mut current_chunk = offset;
-                loop {
-                    for i in 0..CHUNK_SIZE {
-                        bufman.seek_with_cursor(cursor, current_chunk as u64 + (i as u64 * 14))?;
-                        let key_offset = bufman.read_u32_with_cursor(cursor)?;
-                        let item_offset = bufman.read_u32_with_cursor(cursor)?;
-                        let item_version_number = bufman.read_u16_with_cursor(cursor)?;
-                        let item_version_id = bufman.read_u32_with_cursor(cursor)?.into();
-                        if key_offset == u32::MAX {
-                            continue;
+                let layout_version = bufman.read_u32_with_cursor(cursor)?;
+                let entries = if layout_version == MAP_LAYOUT_VERSION {
+                    let len = bufman.read_u32_with_cursor(cursor)?;
+                    let mut entries = Vec::with_capacity(len as usize);
+                    for _ in 0..len {
+                        entries.push(read_entry(&bufman, cursor)?);
+                    }
+                    entries
+                } else if layout_version != u32::MAX && layout_version > offset {
+                    match read_chunked_entries(&bufman, cursor, offset, version_number) {
+                        Ok(entries) => entries,
+                        Err(err) => {
+                            bufman.close_cursor(cursor)?;
+                            return Err(err);
                         }
-                        let key_file_index = FileIndex::Valid {
-                            offset: FileOffset(key_offset),
-                            version_number,
-                            version_id,
-                        };
-                        let key = IdentityMapKey::deserialize(
-                            bufmans.clone(),
-                            key_file_index,
-                            cache.clone(),
-                            max_loads,
-                            skipm,
-                        )?;
-                        let item_file_index = FileIndex::Valid {
-                            offset: FileOffset(item_offset),
-                            version_number: item_version_number,
-                            version_id: item_version_id,
-                        };
-                        let item = LazyItem::deserialize(
-                            bufmans.clone(),
-                            item_file_index,
-                            cache.clone(),
-                            max_loads,
-                            skipm,
-                        )?;
-                        items.push((key, item));
                     }
-                    bufman
-                        .seek_with_cursor(cursor, current_chunk as u64 + CHUNK_SIZE as u64 * 14)?;
-                    // Read next chunk link
-                    current_chunk = bufman.read_u32_with_cursor(cursor)?;
-                    if current_chunk == u32::MAX {
-                        break;
+                } else {
+                    bufman.close_cursor(cursor)?;
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unsupported LazyItemMap layout version: {}", layout_version),
+                    )
+                    .into());
+                };
+                bufman.close_cursor(cursor)?;
+
+                let mut items = IdentityMap::new();
+                for (key_offset, item_offset, item_version_number, item_version_id) in entries {
+                    let key_file_index = FileIndex::Valid {
+                        offset: FileOffset(key_offset),
+                        version_number,
+                        version_id,
+                    };
+                    let key = IdentityMapKey::deserialize(
+                        bufmans.clone(),
+                        key_file_index,
+                        cache.clone(),
+                        max_loads,
+                        skipm,
+                    )?;
+                    if items.get(&key).is_some() {
+                        match policy {
+                            DuplicateKeyPolicy::LastWins => {}
+                            DuplicateKeyPolicy::FirstWins => continue,
+                            DuplicateKeyPolicy::Error => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("Duplicate LazyItemMap key: {:?}", key),
+                                )
+                                .into());
+                            }
+                        }
                     }
+                    let item_file_index = FileIndex::Valid {
+                        offset: FileOffset(item_offset),
+                        version_number: item_version_number,
+                        version_id: item_version_id,
+                    };
+                    let item = LazyItem::deserialize(
+                        bufmans.clone(),
+                        item_file_index,
+                        cache.clone(),
+                        max_loads,
+                        skipm,
+                    )?;
+                    items.insert(key, item);
                 }
-                bufman.close_cursor(cursor)?;
-                Ok(LazyItemMap::from_map(IdentityMap::from_iter(
-                    items.into_iter(),
-                )))
+                Ok(LazyItemMap::from_map(items))
             }
         }
     }
 }
 
+/// Reads the entry at the cursor: key offset, item offset, version number
+/// and version id
+fn read_entry(bufman: &BufferManager, cursor: u64) -> Result<(u32, u32, u16, Hash), BufIoError> {
+    Ok((
+        bufman.read_u32_with_cursor(cursor)?,
+        bufman.read_u32_with_cursor(cursor)?,
+        bufman.read_u16_with_cursor(cursor)?,
+        bufman.read_u32_with_cursor(cursor)?.into(),
+    ))
+}
+
+/// Reads the entries of a map in the version 1 layout, linked chunks of
+/// `CHUNK_SIZE` entry slots starting at `offset`, each followed by the
+/// offset of the next chunk.
+///
+/// Maps written before the version number was added to the entries are
+/// detected, their entries take the map's `version_number`.
+fn read_chunked_entries(
+    bufman: &BufferManager,
+    cursor: u64,
+    offset: u32,
+    version_number: u16,
+) -> Result<Vec<(u32, u32, u16, Hash)>, BufIoError> {
+    let layout = ChunkLayout::detect(bufman, cursor, offset as u64, CHUNK_SIZE as u64, 4)?;
+    let mut entries = Vec::new();
+    let mut visited_chunks = HashSet::new();
+    let mut current_chunk = offset;
+    while current_chunk != u32::MAX {
+        if !visited_chunks.insert(current_chunk) {
+            return Err(BufIoError::Corrupt {
+                context: "chunk link cycle",
+                offset: current_chunk as u64,
+            });
+        }
+        bufman.seek_with_cursor(cursor, current_chunk as u64)?;
+        for _ in 0..CHUNK_SIZE {
+            let entry = match layout {
+                ChunkLayout::Legacy => (
+                    bufman.read_u32_with_cursor(cursor)?,
+                    bufman.read_u32_with_cursor(cursor)?,
+                    version_number,
+                    bufman.read_u32_with_cursor(cursor)?.into(),
+                ),
+                ChunkLayout::Versioned => read_entry(bufman, cursor)?,
+            };
+            if entry.0 != u32::MAX {
+                entries.push(entry);
+            }
+        }
+        current_chunk = bufman.read_u32_with_cursor(cursor)?;
+    }
+    Ok(entries)
+}
+
 impl CustomSerialize for IdentityMapKey {
     fn serialize(
         &self,
@@ -167,10 +269,11 @@ impl CustomSerialize for IdentityMapKey {
         let start = bufman.cursor_position(cursor)? as u32;
         match self {
             Self::String(str) => {
-                let bytes = str.clone().into_bytes();
-                let len = bytes.len() as u32;
-                bufman.update_u32_with_cursor(cursor, MSB | len)?;
-                bufman.update_with_cursor(cursor, &bytes)?;
+                with_scratch_buffer(|buf| {
+                    buf.extend_from_slice(&(MSB | str.len() as u32).to_le_bytes());
+                    buf.extend_from_slice(str.as_bytes());
+                    bufman.update_with_cursor(cursor, buf)
+                })?;
             }
             Self::Int(int) => {
                 bufman.update_u32_with_cursor(cursor, *int)?;
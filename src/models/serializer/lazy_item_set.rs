@@ -1,9 +1,9 @@
-use super::CustomSerialize;
+use super::{lazy_item_vec::GEOMETRY, ChunkGeometry, CustomSerialize};
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
     cache_loader::{Cacheable, NodeRegistry},
     identity_collections::{Identifiable, IdentitySet},
-    lazy_load::{FileIndex, LazyItem, LazyItemSet, SyncPersist, CHUNK_SIZE},
+    lazy_load::{FileIndex, LazyItem, LazyItemSet, SyncPersist},
     types::FileOffset,
     versioning::Hash,
 };
@@ -20,53 +20,7 @@ where
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
-        if self.is_empty() {
-            return Ok(u32::MAX);
-        };
-        let bufman = bufmans.get(version)?;
-        let start_offset = bufman.cursor_position(cursor)? as u32;
-        let mut items_arc = self.items.clone();
-        let items: Vec<_> = items_arc.get().iter().map(Clone::clone).collect();
-        let total_items = items.len();
-
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
-            let is_last_chunk = chunk_end == total_items;
-
-            // Write placeholders for item offsets
-            let placeholder_start = bufman.cursor_position(cursor)? as u32;
-            for _ in 0..CHUNK_SIZE {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                bufman.update_u16_with_cursor(cursor, u16::MAX)?;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-            }
-            // Write placeholder for next chunk link
-            let next_chunk_placeholder = bufman.cursor_position(cursor)? as u32;
-            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-
-            // Serialize items and update placeholders
-            for i in chunk_start..chunk_end {
-                let item_offset = items[i].serialize(bufmans.clone(), version, cursor)?;
-                let placeholder_pos = placeholder_start as u64 + ((i - chunk_start) as u64 * 10);
-                let current_pos = bufman.cursor_position(cursor)?;
-                bufman.seek_with_cursor(cursor, placeholder_pos)?;
-                bufman.update_u32_with_cursor(cursor, item_offset)?;
-                bufman.update_u16_with_cursor(cursor, items[i].get_current_version_number())?;
-                bufman.update_u32_with_cursor(cursor, *items[i].get_current_version())?;
-                bufman.seek_with_cursor(cursor, current_pos)?;
-            }
-
-            // Write next chunk link
-            let next_chunk_start = bufman.cursor_position(cursor)? as u32;
-            bufman.seek_with_cursor(cursor, next_chunk_placeholder as u64)?;
-            if is_last_chunk {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
-            } else {
-                bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
-            }
-            bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
-        }
-        Ok(start_offset)
+        self.serialize_with_empty_placeholder(bufmans, version, cursor, false)
     }
     fn deserialize(
         bufmans: Arc<BufferManagerFactory<Hash>>,
@@ -87,12 +41,19 @@ where
                 }
                 let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
-                bufman.seek_with_cursor(cursor, offset as u64)?;
+                // Sets share the chunk format of `LazyItemVec`
+                let (geometry, first_chunk) = ChunkGeometry::read(
+                    &bufman,
+                    cursor,
+                    offset as u64,
+                    GEOMETRY,
+                    GEOMETRY.slot_stride,
+                )?;
                 let mut items = Vec::new();
-                let mut current_chunk = offset;
+                let mut current_chunk = first_chunk;
                 loop {
-                    for i in 0..CHUNK_SIZE {
-                        bufman.seek_with_cursor(cursor, current_chunk as u64 + (i as u64 * 10))?;
+                    for i in 0..geometry.chunk_size {
+                        bufman.seek_with_cursor(cursor, geometry.slot(current_chunk, i))?;
                         let item_offset = bufman.read_u32_with_cursor(cursor)?;
                         let item_version_number = bufman.read_u16_with_cursor(cursor)?;
                         let item_version_id = bufman.read_u32_with_cursor(cursor)?.into();
@@ -113,13 +74,13 @@ where
                         )?;
                         items.push(item);
                     }
-                    bufman
-                        .seek_with_cursor(cursor, current_chunk as u64 + CHUNK_SIZE as u64 * 10)?;
+                    bufman.seek_with_cursor(cursor, geometry.next_link(current_chunk))?;
                     // Read next chunk link
-                    current_chunk = bufman.read_u32_with_cursor(cursor)?;
-                    if current_chunk == u32::MAX {
+                    let next_chunk = bufman.read_u32_with_cursor(cursor)?;
+                    if next_chunk == u32::MAX {
                         break;
                     }
+                    current_chunk = next_chunk as u64;
                 }
                 bufman.close_cursor(cursor)?;
                 Ok(LazyItemSet::from_set(IdentitySet::from_iter(
@@ -129,3 +90,77 @@ where
         }
     }
 }
+
+impl<T> LazyItemSet<T>
+where
+    T: Cacheable + Clone + Identifiable<Id = u64> + CustomSerialize + 'static,
+{
+    /// Serializes the collection, like `serialize`, except that if
+    /// `empty_placeholder` is set, an empty collection is written as a
+    /// chunk of empty slots with a terminating link, instead of not being
+    /// written at all. Its offset is then a real one, that can be patched
+    /// later, rather than `u32::MAX`.
+    ///
+    /// As for `LazyItemVec`, the chunks are preceded by a header recording
+    /// their geometry, see `ChunkGeometry`.
+    pub fn serialize_with_empty_placeholder(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+        empty_placeholder: bool,
+    ) -> Result<u32, BufIoError> {
+        if self.is_empty() && !empty_placeholder {
+            return Ok(u32::MAX);
+        };
+        let bufman = bufmans.get(version)?;
+        let start_offset = bufman.cursor_position(cursor)? as u32;
+        GEOMETRY.write_header(&bufman, cursor)?;
+        let chunk_size = GEOMETRY.chunk_size as usize;
+        let mut items_arc = self.items.clone();
+        let items: Vec<_> = items_arc.get().iter().map(Clone::clone).collect();
+        let total_items = items.len();
+
+        // An empty collection still gets a chunk when a placeholder is
+        // requested
+        let chunk_count = total_items.div_ceil(chunk_size).max(1);
+        for chunk_start in (0..chunk_count).map(|chunk| chunk * chunk_size) {
+            let chunk_end = std::cmp::min(chunk_start + chunk_size, total_items);
+            let is_last_chunk = chunk_end == total_items;
+
+            // Write placeholders for item offsets
+            let placeholder_start = bufman.cursor_position(cursor)?;
+            for _ in 0..chunk_size {
+                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+                bufman.update_u16_with_cursor(cursor, u16::MAX)?;
+                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+            }
+            // Write placeholder for next chunk link
+            let next_chunk_placeholder = bufman.cursor_position(cursor)?;
+            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+
+            // Serialize items and update placeholders
+            for (slot, item) in items[chunk_start..chunk_end].iter().enumerate() {
+                let item_offset = item.serialize(bufmans.clone(), version, cursor)?;
+                let placeholder_pos = GEOMETRY.slot(placeholder_start, slot as u64);
+                let current_pos = bufman.cursor_position(cursor)?;
+                bufman.seek_with_cursor(cursor, placeholder_pos)?;
+                bufman.update_u32_with_cursor(cursor, item_offset)?;
+                bufman.update_u16_with_cursor(cursor, item.get_current_version_number())?;
+                bufman.update_u32_with_cursor(cursor, *item.get_current_version())?;
+                bufman.seek_with_cursor(cursor, current_pos)?;
+            }
+
+            // Write next chunk link
+            let next_chunk_start = bufman.cursor_position(cursor)? as u32;
+            bufman.seek_with_cursor(cursor, next_chunk_placeholder)?;
+            if is_last_chunk {
+                bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
+            } else {
+                bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
+            }
+            bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
+        }
+        Ok(start_offset)
+    }
+}
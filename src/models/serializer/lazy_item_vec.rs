@@ -1,4 +1,7 @@
-use super::CustomSerialize;
+use super::{
+    ChunkGeometry, ChunkLayout, CustomSerialize, DeserializeWarning, LoadCost,
+    CHUNK_DIRECTORY_FLAG, INDEXED_LAYOUT_FLAG,
+};
 use crate::models::{
     buffered_io::{BufIoError, BufferManagerFactory},
     cache_loader::{Cacheable, NodeRegistry},
@@ -6,9 +9,121 @@ use crate::models::{
     types::FileOffset,
     versioning::Hash,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::sync::Arc;
 
+/// Identifies the data held by a loaded item, along with the version
+/// whose file it's written to. Items that aren't loaded are not written
+/// by `serialize` and have no identity.
+fn data_identity<T: Clone + 'static>(item: &LazyItem<T>) -> Option<(usize, Hash)> {
+    let data = item.get_lazy_data()?;
+    let ptr = Arc::as_ptr(data.shared_get().as_ref()?) as usize;
+    Some((ptr, item.get_current_version()))
+}
+
+/// Size of the header of the indexed layout: flag (u8), item count (u32)
+const INDEXED_HEADER_SIZE: u64 = 5;
+
+/// Size of the header preceding the chunk directory: flag (u8), chunk
+/// count (u32), chunk size (u16) and slot stride (u16)
+const DIRECTORY_HEADER_SIZE: u64 = 9;
+
+/// Chunks written by this binary, with slots in the versioned layout
+pub(super) const GEOMETRY: ChunkGeometry = ChunkGeometry::new(CHUNK_SIZE as u64, 10);
+
+/// Writes `item` unless an item sharing its data was already written in
+/// this pass, returning the offset of its data either way
+fn write_item<T>(
+    item: &LazyItem<T>,
+    bufmans: Arc<BufferManagerFactory<Hash>>,
+    version: Hash,
+    cursor: u64,
+    written_offsets: &mut HashMap<(usize, Hash), u32>,
+) -> Result<u32, BufIoError>
+where
+    T: Cacheable + CustomSerialize + Clone + 'static,
+{
+    let identity = data_identity(item);
+    match identity.and_then(|id| written_offsets.get(&id)) {
+        Some(&offset) => {
+            item.set_file_index(Some(FileIndex::Valid {
+                offset: FileOffset(offset),
+                version_number: item.get_current_version_number(),
+                version_id: item.get_current_version(),
+            }));
+            item.set_persistence(false);
+            Ok(offset)
+        }
+        None => {
+            let offset = item.serialize(bufmans, version, cursor)?;
+            if let Some(id) = identity {
+                written_offsets.insert(id, offset);
+            }
+            Ok(offset)
+        }
+    }
+}
+
+/// Writes `items` in linked chunks of the given geometry at the cursor,
+/// returning the offsets of the chunks. At least one chunk is written,
+/// even if there are no items.
+fn write_chunks<T>(
+    items: &[LazyItem<T>],
+    bufmans: Arc<BufferManagerFactory<Hash>>,
+    version: Hash,
+    cursor: u64,
+    geometry: ChunkGeometry,
+) -> Result<Vec<u32>, BufIoError>
+where
+    T: Cacheable + CustomSerialize + Clone + 'static,
+{
+    let bufman = bufmans.get(version)?;
+    let total_items = items.len();
+    // Offsets of the items written in this pass, so items sharing the
+    // same underlying data are only written once
+    let mut written_offsets: HashMap<(usize, Hash), u32> = HashMap::new();
+
+    let chunk_size = geometry.chunk_size as usize;
+    let chunk_count = total_items.div_ceil(chunk_size).max(1);
+    let mut chunk_offsets = Vec::with_capacity(chunk_count);
+    let placeholders = vec![u8::MAX; geometry.chunk_bytes() as usize];
+    for chunk_start in (0..chunk_count).map(|chunk| chunk * chunk_size) {
+        let chunk_end = std::cmp::min(chunk_start + chunk_size, total_items);
+        let is_last_chunk = chunk_end == total_items;
+
+        // Write placeholders for item offsets and the next chunk link
+        let placeholder_start = bufman.cursor_position(cursor)?;
+        chunk_offsets.push(placeholder_start as u32);
+        bufman.update_with_cursor(cursor, &placeholders)?;
+        let next_chunk_placeholder = geometry.next_link(placeholder_start);
+
+        // Serialize items and update placeholders
+        for (slot, item) in items[chunk_start..chunk_end].iter().enumerate() {
+            let item_offset =
+                write_item(item, bufmans.clone(), version, cursor, &mut written_offsets)?;
+            let placeholder_pos = geometry.slot(placeholder_start, slot as u64);
+            let current_pos = bufman.cursor_position(cursor)?;
+            bufman.seek_with_cursor(cursor, placeholder_pos)?;
+            bufman.update_u32_with_cursor(cursor, item_offset)?;
+            bufman.update_u16_with_cursor(cursor, item.get_current_version_number())?;
+            bufman.update_u32_with_cursor(cursor, *item.get_current_version())?;
+            bufman.seek_with_cursor(cursor, current_pos)?;
+        }
+
+        // Write next chunk link
+        let next_chunk_start = bufman.cursor_position(cursor)? as u32;
+        bufman.seek_with_cursor(cursor, next_chunk_placeholder)?;
+        if is_last_chunk {
+            bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
+        } else {
+            bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
+        }
+        bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
+    }
+    Ok(chunk_offsets)
+}
+
 impl<T> CustomSerialize for LazyItemVec<T>
 where
     T: Cacheable + CustomSerialize + Clone + CustomSerialize + 'static,
@@ -19,52 +134,7 @@ where
         version: Hash,
         cursor: u64,
     ) -> Result<u32, BufIoError> {
-        if self.is_empty() {
-            return Ok(u32::MAX);
-        };
-        let bufman = bufmans.get(version)?;
-        let start_offset = bufman.cursor_position(cursor)? as u32;
-        let items: Vec<_> = self.iter().collect();
-        let total_items = items.len();
-
-        for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
-            let chunk_end = std::cmp::min(chunk_start + CHUNK_SIZE, total_items);
-            let is_last_chunk = chunk_end == total_items;
-
-            // Write placeholders for item offsets
-            let placeholder_start = bufman.cursor_position(cursor)? as u32;
-            for _ in 0..CHUNK_SIZE {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-                bufman.update_u16_with_cursor(cursor, u16::MAX)?;
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-            }
-            // Write placeholder for next chunk link
-            let next_chunk_placeholder = bufman.cursor_position(cursor)? as u32;
-            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
-
-            // Serialize items and update placeholders
-            for i in chunk_start..chunk_end {
-                let item_offset = items[i].serialize(bufmans.clone(), version, cursor)?;
-                let placeholder_pos = placeholder_start as u64 + ((i - chunk_start) as u64 * 10);
-                let current_pos = bufman.cursor_position(cursor)?;
-                bufman.seek_with_cursor(cursor, placeholder_pos)?;
-                bufman.update_u32_with_cursor(cursor, item_offset)?;
-                bufman.update_u16_with_cursor(cursor, items[i].get_current_version_number())?;
-                bufman.update_u32_with_cursor(cursor, *items[i].get_current_version())?;
-                bufman.seek_with_cursor(cursor, current_pos)?;
-            }
-
-            // Write next chunk link
-            let next_chunk_start = bufman.cursor_position(cursor)? as u32;
-            bufman.seek_with_cursor(cursor, next_chunk_placeholder as u64)?;
-            if is_last_chunk {
-                bufman.update_u32_with_cursor(cursor, u32::MAX)?; // Last chunk
-            } else {
-                bufman.update_u32_with_cursor(cursor, next_chunk_start)?;
-            }
-            bufman.seek_with_cursor(cursor, next_chunk_start as u64)?;
-        }
-        Ok(start_offset)
+        self.serialize_with_empty_placeholder(bufmans, version, cursor, false)
     }
     fn deserialize(
         bufmans: Arc<BufferManagerFactory<Hash>>,
@@ -73,26 +143,57 @@ where
         max_loads: u16,
         skipm: &mut HashSet<u64>,
     ) -> Result<Self, BufIoError> {
+        // Vectors written before the version number was added to the slots
+        // have no header, their layout is detected and the items are
+        // upconverted by taking the version number from `file_index`
         match file_index {
             FileIndex::Invalid => Ok(LazyItemVec::new()),
             FileIndex::Valid {
                 offset: FileOffset(offset),
                 version_id,
-                ..
+                version_number,
             } => {
                 if offset == u32::MAX {
                     return Ok(LazyItemVec::new());
                 }
                 let bufman = bufmans.get(version_id)?;
                 let cursor = bufman.open_cursor()?;
-                bufman.seek_with_cursor(cursor, offset as u64)?;
+                let (mut geometry, first_chunk) = ChunkGeometry::read(
+                    &bufman,
+                    cursor,
+                    offset as u64,
+                    GEOMETRY,
+                    GEOMETRY.slot_stride,
+                )?;
+                // Only versioned slots are written along with a header
+                let layout = if first_chunk == offset as u64 {
+                    ChunkLayout::detect(&bufman, cursor, first_chunk, CHUNK_SIZE as u64, 0)?
+                } else {
+                    ChunkLayout::Versioned
+                };
+                if layout == ChunkLayout::Legacy {
+                    geometry = ChunkGeometry::new(CHUNK_SIZE as u64, layout.slot_size());
+                }
                 let mut items = Vec::new();
-                let mut current_chunk = offset;
+                let mut current_chunk = first_chunk as u32;
+                // A corrupt next chunk link could point back to an earlier
+                // chunk and loop forever
+                let mut visited_chunks = HashSet::new();
                 loop {
-                    for i in 0..CHUNK_SIZE {
-                        bufman.seek_with_cursor(cursor, current_chunk as u64 + (i as u64 * 10))?;
+                    if !visited_chunks.insert(current_chunk) {
+                        bufman.close_cursor(cursor)?;
+                        return Err(BufIoError::Corrupt {
+                            context: "chunk link cycle",
+                            offset: current_chunk as u64,
+                        });
+                    }
+                    for i in 0..geometry.chunk_size {
+                        bufman.seek_with_cursor(cursor, geometry.slot(current_chunk as u64, i))?;
                         let item_offset = bufman.read_u32_with_cursor(cursor)?;
-                        let item_version_number = bufman.read_u16_with_cursor(cursor)?;
+                        let item_version_number = match layout {
+                            ChunkLayout::Legacy => version_number,
+                            ChunkLayout::Versioned => bufman.read_u16_with_cursor(cursor)?,
+                        };
                         let item_version_id = bufman.read_u32_with_cursor(cursor)?.into();
                         if item_offset == u32::MAX {
                             continue;
@@ -111,8 +212,7 @@ where
                         )?;
                         items.push(item);
                     }
-                    bufman
-                        .seek_with_cursor(cursor, current_chunk as u64 + CHUNK_SIZE as u64 * 10)?;
+                    bufman.seek_with_cursor(cursor, geometry.next_link(current_chunk as u64))?;
                     // Read next chunk link
                     current_chunk = bufman.read_u32_with_cursor(cursor)?;
                     if current_chunk == u32::MAX {
@@ -125,3 +225,537 @@ where
         }
     }
 }
+
+impl<T> LazyItemVec<T>
+where
+    T: Cacheable + CustomSerialize + Clone + 'static,
+{
+    /// Serializes the collection, like `serialize`, except that if
+    /// `empty_placeholder` is set, an empty collection is written as a
+    /// chunk of empty slots with a terminating link, instead of not being
+    /// written at all. Its offset is then a real one, that
+    /// `serialize_append` can add items to later, rather than `u32::MAX`.
+    ///
+    /// The chunks are preceded by a header recording their geometry, see
+    /// `ChunkGeometry`.
+    pub fn serialize_with_empty_placeholder(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+        empty_placeholder: bool,
+    ) -> Result<u32, BufIoError> {
+        if self.is_empty() && !empty_placeholder {
+            return Ok(u32::MAX);
+        };
+        self.serialize_with_chunk_size(bufmans, version, cursor, CHUNK_SIZE)
+    }
+
+    /// Serializes the vector in chunks of `chunk_size` items, as a binary
+    /// built with that `CHUNK_SIZE` does
+    pub(crate) fn serialize_with_chunk_size(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+        chunk_size: usize,
+    ) -> Result<u32, BufIoError> {
+        let bufman = bufmans.get(version)?;
+        let start_offset = bufman.cursor_position(cursor)? as u32;
+        let geometry = ChunkGeometry::new(chunk_size as u64, GEOMETRY.slot_stride);
+        geometry.write_header(&bufman, cursor)?;
+        let items: Vec<_> = self.iter().collect();
+        write_chunks(&items, bufmans, version, cursor, geometry)?;
+        Ok(start_offset)
+    }
+
+    /// Serializes only the items pushed since the vector was last
+    /// serialized at `prev_offset`, leaving the existing chunks intact.
+    ///
+    /// The existing chunk chain is walked to count the items already on
+    /// disk; the remaining items are written at the end of the file and
+    /// placed into the free slots of the last chunk, with new chunks
+    /// linked in as needed. Returns the (unchanged) start offset.
+    pub fn serialize_append(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+        prev_offset: u32,
+    ) -> Result<u32, BufIoError> {
+        if prev_offset == u32::MAX {
+            return self.serialize(bufmans, version, cursor);
+        }
+        let bufman = bufmans.get(version)?;
+        let items: Vec<_> = self.iter().collect();
+        let (geometry, first_chunk) = ChunkGeometry::read(
+            &bufman,
+            cursor,
+            prev_offset as u64,
+            GEOMETRY,
+            GEOMETRY.slot_stride,
+        )?;
+
+        // Walk the existing chunks, only the last one can have free slots
+        let mut serialized_items = 0;
+        let mut last_chunk = first_chunk;
+        let mut used_slots;
+        loop {
+            used_slots = 0;
+            for i in 0..geometry.chunk_size {
+                bufman.seek_with_cursor(cursor, geometry.slot(last_chunk, i))?;
+                if bufman.read_u32_with_cursor(cursor)? != u32::MAX {
+                    used_slots += 1;
+                }
+            }
+            serialized_items += used_slots as usize;
+            bufman.seek_with_cursor(cursor, geometry.next_link(last_chunk))?;
+            let next_chunk = bufman.read_u32_with_cursor(cursor)?;
+            if next_chunk == u32::MAX {
+                break;
+            }
+            last_chunk = next_chunk as u64;
+        }
+
+        for item in items.iter().skip(serialized_items) {
+            if used_slots == geometry.chunk_size {
+                // Allocate a new chunk at the end of the file and link it
+                let new_chunk = bufman.file_size();
+                let placeholders = vec![u8::MAX; geometry.chunk_bytes() as usize];
+                bufman.write_to_end_of_file(cursor, &placeholders)?;
+
+                bufman.seek_with_cursor(cursor, geometry.next_link(last_chunk))?;
+                bufman.update_u32_with_cursor(cursor, new_chunk as u32)?;
+                last_chunk = new_chunk;
+                used_slots = 0;
+            }
+
+            bufman.seek_with_cursor(cursor, bufman.file_size())?;
+            let item_offset = item.serialize(bufmans.clone(), version, cursor)?;
+
+            bufman.seek_with_cursor(cursor, geometry.slot(last_chunk, used_slots))?;
+            bufman.update_u32_with_cursor(cursor, item_offset)?;
+            bufman.update_u16_with_cursor(cursor, item.get_current_version_number())?;
+            bufman.update_u32_with_cursor(cursor, *item.get_current_version())?;
+            used_slots += 1;
+        }
+
+        bufman.seek_with_cursor(cursor, bufman.file_size())?;
+        Ok(prev_offset)
+    }
+
+    /// Serializes the vector with a fixed stride layout instead of linked
+    /// chunks, so that any item can be read by `deserialize_indexed_at`
+    /// with a single seek.
+    ///
+    /// A header of `INDEXED_LAYOUT_FLAG` (u8) and the item count (u32) is
+    /// followed by the slots, item `i`'s at `start + 5 + i * 10`, each
+    /// holding the item offset (u32), version number (u16) and version id
+    /// (u32). Returns the start offset.
+    pub fn serialize_indexed(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+    ) -> Result<u32, BufIoError> {
+        let bufman = bufmans.get(version)?;
+        let start_offset = bufman.cursor_position(cursor)? as u32;
+        let items: Vec<_> = self.iter().collect();
+
+        bufman.update_u8_with_cursor(cursor, INDEXED_LAYOUT_FLAG)?;
+        bufman.update_u32_with_cursor(cursor, items.len() as u32)?;
+        for _ in 0..items.len() {
+            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+            bufman.update_u16_with_cursor(cursor, u16::MAX)?;
+            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+        }
+
+        let mut written_offsets: HashMap<(usize, Hash), u32> = HashMap::new();
+        for (i, item) in items.iter().enumerate() {
+            let item_offset =
+                write_item(item, bufmans.clone(), version, cursor, &mut written_offsets)?;
+            let slot_pos = start_offset as u64 + INDEXED_HEADER_SIZE + i as u64 * 10;
+            let current_pos = bufman.cursor_position(cursor)?;
+            bufman.seek_with_cursor(cursor, slot_pos)?;
+            bufman.update_u32_with_cursor(cursor, item_offset)?;
+            bufman.update_u16_with_cursor(cursor, item.get_current_version_number())?;
+            bufman.update_u32_with_cursor(cursor, *item.get_current_version())?;
+            bufman.seek_with_cursor(cursor, current_pos)?;
+        }
+        Ok(start_offset)
+    }
+
+    /// Deserializes only the item at `index` of a vector written by
+    /// `serialize_indexed`, reading the header, its slot and the item
+    /// itself.
+    pub fn deserialize_indexed_at(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        index: usize,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+        skipm: &mut HashSet<u64>,
+    ) -> Result<LazyItem<T>, BufIoError> {
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            return Ok(LazyItem::Invalid);
+        };
+        let bufman = bufmans.get(version_id)?;
+        let cursor = bufman.open_cursor()?;
+        bufman.seek_with_cursor(cursor, offset as u64)?;
+        if bufman.read_u8_with_cursor(cursor)? != INDEXED_LAYOUT_FLAG {
+            bufman.close_cursor(cursor)?;
+            return Err(BufIoError::Corrupt {
+                context: "missing indexed layout flag",
+                offset: offset as u64,
+            });
+        }
+        let len = bufman.read_u32_with_cursor(cursor)? as usize;
+        if index >= len {
+            bufman.close_cursor(cursor)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Index {} out of range, the vector has {} items", index, len),
+            )
+            .into());
+        }
+        bufman.seek_with_cursor(
+            cursor,
+            offset as u64 + INDEXED_HEADER_SIZE + index as u64 * 10,
+        )?;
+        let item_offset = bufman.read_u32_with_cursor(cursor)?;
+        let item_version_number = bufman.read_u16_with_cursor(cursor)?;
+        let item_version_id = bufman.read_u32_with_cursor(cursor)?.into();
+        bufman.close_cursor(cursor)?;
+
+        LazyItem::deserialize(
+            bufmans,
+            FileIndex::Valid {
+                offset: FileOffset(item_offset),
+                version_number: item_version_number,
+                version_id: item_version_id,
+            },
+            cache,
+            max_loads,
+            skipm,
+        )
+    }
+
+    /// Serializes the vector in linked chunks preceded by a directory of
+    /// their offsets, so that `read_chunk` can read any chunk without
+    /// walking the ones before it.
+    ///
+    /// A header of `CHUNK_DIRECTORY_FLAG` (u8), the chunk count (u32),
+    /// the chunk size (u16) and the slot stride (u16) is followed by the
+    /// offset (u32) of each chunk, then by the chunks, linked as usual.
+    /// Returns the start offset.
+    pub fn serialize_with_chunk_directory(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+    ) -> Result<u32, BufIoError> {
+        let bufman = bufmans.get(version)?;
+        let start_offset = bufman.cursor_position(cursor)? as u32;
+        let items: Vec<_> = self.iter().collect();
+        let chunk_count = items.len().div_ceil(GEOMETRY.chunk_size as usize).max(1);
+
+        bufman.update_u8_with_cursor(cursor, CHUNK_DIRECTORY_FLAG)?;
+        bufman.update_u32_with_cursor(cursor, chunk_count as u32)?;
+        bufman.update_u16_with_cursor(cursor, GEOMETRY.chunk_size as u16)?;
+        bufman.update_u16_with_cursor(cursor, GEOMETRY.slot_stride as u16)?;
+        for _ in 0..chunk_count {
+            bufman.update_u32_with_cursor(cursor, u32::MAX)?;
+        }
+
+        let chunk_offsets = write_chunks(&items, bufmans.clone(), version, cursor, GEOMETRY)?;
+        let current_pos = bufman.cursor_position(cursor)?;
+        bufman.seek_with_cursor(cursor, start_offset as u64 + DIRECTORY_HEADER_SIZE)?;
+        for chunk_offset in chunk_offsets {
+            bufman.update_u32_with_cursor(cursor, chunk_offset)?;
+        }
+        bufman.seek_with_cursor(cursor, current_pos)?;
+        Ok(start_offset)
+    }
+
+    /// Deserializes only the items of chunk `n` of a vector written by
+    /// `serialize_with_chunk_directory`, reading the header, the chunk's
+    /// directory entry and the chunk itself.
+    pub fn read_chunk(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        n: usize,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+        skipm: &mut HashSet<u64>,
+    ) -> Result<Vec<LazyItem<T>>, BufIoError> {
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            return Ok(Vec::new());
+        };
+        let bufman = bufmans.get(version_id)?;
+        let cursor = bufman.open_cursor()?;
+        bufman.seek_with_cursor(cursor, offset as u64)?;
+        if bufman.read_u8_with_cursor(cursor)? != CHUNK_DIRECTORY_FLAG {
+            bufman.close_cursor(cursor)?;
+            return Err(BufIoError::Corrupt {
+                context: "missing chunk directory flag",
+                offset: offset as u64,
+            });
+        }
+        let chunk_count = bufman.read_u32_with_cursor(cursor)? as usize;
+        let geometry = ChunkGeometry::new(
+            bufman.read_u16_with_cursor(cursor)? as u64,
+            bufman.read_u16_with_cursor(cursor)? as u64,
+        );
+        if geometry.chunk_size == 0 || geometry.slot_stride < GEOMETRY.slot_stride {
+            bufman.close_cursor(cursor)?;
+            return Err(BufIoError::Corrupt {
+                context: "invalid chunk size or slot stride",
+                offset: offset as u64,
+            });
+        }
+        if n >= chunk_count {
+            bufman.close_cursor(cursor)?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Chunk {} out of range, the vector has {} chunks",
+                    n, chunk_count
+                ),
+            )
+            .into());
+        }
+        bufman.seek_with_cursor(cursor, offset as u64 + DIRECTORY_HEADER_SIZE + n as u64 * 4)?;
+        let chunk_offset = bufman.read_u32_with_cursor(cursor)? as u64;
+
+        let mut slots = Vec::with_capacity(geometry.chunk_size as usize);
+        for i in 0..geometry.chunk_size {
+            bufman.seek_with_cursor(cursor, geometry.slot(chunk_offset, i))?;
+            let item_offset = bufman.read_u32_with_cursor(cursor)?;
+            let item_version_number = bufman.read_u16_with_cursor(cursor)?;
+            let item_version_id = bufman.read_u32_with_cursor(cursor)?;
+            if item_offset != u32::MAX {
+                slots.push((item_offset, item_version_number, item_version_id));
+            }
+        }
+        bufman.close_cursor(cursor)?;
+
+        slots
+            .into_iter()
+            .map(|(item_offset, version_number, version_id)| {
+                LazyItem::deserialize(
+                    bufmans.clone(),
+                    FileIndex::Valid {
+                        offset: FileOffset(item_offset),
+                        version_number,
+                        version_id: version_id.into(),
+                    },
+                    cache.clone(),
+                    max_loads,
+                    skipm,
+                )
+            })
+            .collect()
+    }
+
+    /// Estimates the cost of deserializing the vector at `file_index` by
+    /// walking its chunks, reading only the item offsets of their slots,
+    /// without deserializing any of the items.
+    pub fn estimate_load_cost(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+    ) -> Result<LoadCost, BufIoError> {
+        let mut cost = LoadCost::default();
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            return Ok(cost);
+        };
+        if offset == u32::MAX {
+            return Ok(cost);
+        }
+        let bufman = bufmans.get(version_id)?;
+        let cursor = bufman.open_cursor()?;
+        let (geometry, first_chunk) = ChunkGeometry::read(
+            &bufman,
+            cursor,
+            offset as u64,
+            GEOMETRY,
+            GEOMETRY.slot_stride,
+        )?;
+        let mut current_chunk = first_chunk as u32;
+        let mut visited_chunks = HashSet::new();
+        while current_chunk != u32::MAX {
+            if !visited_chunks.insert(current_chunk) {
+                bufman.close_cursor(cursor)?;
+                return Err(BufIoError::Corrupt {
+                    context: "chunk link cycle",
+                    offset: current_chunk as u64,
+                });
+            }
+            cost.chunks += 1;
+            for i in 0..geometry.chunk_size {
+                bufman.seek_with_cursor(cursor, geometry.slot(current_chunk as u64, i))?;
+                if bufman.read_u32_with_cursor(cursor)? != u32::MAX {
+                    cost.items += 1;
+                }
+            }
+            bufman.seek_with_cursor(cursor, geometry.next_link(current_chunk as u64))?;
+            current_chunk = bufman.read_u32_with_cursor(cursor)?;
+        }
+        bufman.close_cursor(cursor)?;
+        Ok(cost)
+    }
+
+    /// Like `deserialize`, but salvages the readable prefix of the vector
+    /// instead of failing the whole load.
+    ///
+    /// Reading stops at the first corrupt chunk or item; the items read
+    /// up to that point are returned along with warnings describing what
+    /// was skipped.
+    pub fn deserialize_lenient(
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        file_index: FileIndex,
+        cache: Arc<NodeRegistry>,
+        max_loads: u16,
+    ) -> (Self, Vec<DeserializeWarning>) {
+        let mut warnings = Vec::new();
+        let FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_id,
+            ..
+        } = file_index
+        else {
+            return (LazyItemVec::new(), warnings);
+        };
+        if offset == u32::MAX {
+            return (LazyItemVec::new(), warnings);
+        }
+        let (bufman, cursor) = match bufmans
+            .get(version_id)
+            .and_then(|bufman| bufman.open_cursor().map(|cursor| (bufman, cursor)))
+        {
+            Ok(res) => res,
+            Err(error) => {
+                warnings.push(DeserializeWarning::ChunkUnreadable { offset, error });
+                return (LazyItemVec::new(), warnings);
+            }
+        };
+        let file_size = bufman.file_size();
+        let (geometry, first_chunk) = match ChunkGeometry::read(
+            &bufman,
+            cursor,
+            offset as u64,
+            GEOMETRY,
+            GEOMETRY.slot_stride,
+        ) {
+            Ok(res) => res,
+            Err(error) => {
+                warnings.push(DeserializeWarning::ChunkUnreadable { offset, error });
+                let _ = bufman.close_cursor(cursor);
+                return (LazyItemVec::new(), warnings);
+            }
+        };
+        let mut skipm = HashSet::new();
+        let mut items = Vec::new();
+        let mut current_chunk = first_chunk as u32;
+        let mut visited_chunks = HashSet::new();
+
+        'chunks: loop {
+            if !visited_chunks.insert(current_chunk) {
+                warnings.push(DeserializeWarning::ChunkCycle {
+                    offset: current_chunk,
+                });
+                break;
+            }
+            if current_chunk as u64 + geometry.chunk_bytes() > file_size {
+                warnings.push(DeserializeWarning::InvalidChunkOffset {
+                    offset: current_chunk,
+                });
+                break;
+            }
+            let read_slot = |i: usize| -> Result<(u32, u16, Hash), BufIoError> {
+                bufman.seek_with_cursor(cursor, geometry.slot(current_chunk as u64, i as u64))?;
+                Ok((
+                    bufman.read_u32_with_cursor(cursor)?,
+                    bufman.read_u16_with_cursor(cursor)?,
+                    bufman.read_u32_with_cursor(cursor)?.into(),
+                ))
+            };
+            for i in 0..geometry.chunk_size as usize {
+                let (item_offset, item_version_number, item_version_id) = match read_slot(i) {
+                    Ok(slot) => slot,
+                    Err(error) => {
+                        warnings.push(DeserializeWarning::ChunkUnreadable {
+                            offset: current_chunk,
+                            error,
+                        });
+                        break 'chunks;
+                    }
+                };
+                if item_offset == u32::MAX {
+                    continue;
+                }
+                if item_version_id == version_id && item_offset as u64 >= file_size {
+                    warnings.push(DeserializeWarning::InvalidItemOffset {
+                        chunk_offset: current_chunk,
+                        slot: i,
+                        offset: item_offset,
+                    });
+                    break 'chunks;
+                }
+                let item_file_index = FileIndex::Valid {
+                    offset: FileOffset(item_offset),
+                    version_number: item_version_number,
+                    version_id: item_version_id,
+                };
+                match LazyItem::deserialize(
+                    bufmans.clone(),
+                    item_file_index,
+                    cache.clone(),
+                    max_loads,
+                    &mut skipm,
+                ) {
+                    Ok(item) => items.push(item),
+                    Err(error) => {
+                        warnings.push(DeserializeWarning::ItemFailed {
+                            chunk_offset: current_chunk,
+                            slot: i,
+                            error,
+                        });
+                        break 'chunks;
+                    }
+                }
+            }
+            // Read next chunk link
+            let next_chunk = bufman
+                .seek_with_cursor(cursor, geometry.next_link(current_chunk as u64))
+                .and_then(|_| bufman.read_u32_with_cursor(cursor));
+            match next_chunk {
+                Ok(u32::MAX) => break,
+                Ok(next_chunk) => current_chunk = next_chunk,
+                Err(error) => {
+                    warnings.push(DeserializeWarning::ChunkUnreadable {
+                        offset: current_chunk,
+                        error,
+                    });
+                    break;
+                }
+            }
+        }
+        let _ = bufman.close_cursor(cursor);
+        (LazyItemVec::from_vec(items), warnings)
+    }
+}
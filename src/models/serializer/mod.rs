@@ -16,6 +16,7 @@ mod lazy_item_vec;
 mod metric_distance;
 mod neighbour;
 mod node;
+pub mod scratch;
 mod storage;
 
 #[cfg(test)]
@@ -47,6 +48,286 @@ pub trait CustomSerialize: Sized {
     ) -> Result<Self, BufIoError>;
 }
 
+/// Offset written for an explicitly absent (`None`) item, to tell it apart
+/// from an empty one, which is written as `u32::MAX`.
+pub const NONE_OFFSET: u32 = u32::MAX - 1;
+
+/// Flag starting the header of a `LazyItemVec` written by
+/// `serialize_indexed`, telling its fixed stride layout apart from the
+/// chunked one.
+pub const INDEXED_LAYOUT_FLAG: u8 = 0xA5;
+
+/// Flag starting the header of a `LazyItemVec` written by
+/// `serialize_with_chunk_directory`, whose chunks are listed in the header.
+pub const CHUNK_DIRECTORY_FLAG: u8 = 0xA6;
+
+/// Layout of the item slots in the chunks of a serialized `LazyItemVec`
+/// or `LazyItemMap`, whose slots start with the key offset (u32).
+///
+/// Chunks written with a header always use the versioned layout. Data
+/// written before the version number was added to the slots predates the
+/// headers too, its layout is told apart by `detect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkLayout {
+    /// item offset (u32), version id (u32)
+    Legacy,
+    /// item offset (u32), version number (u16), version id (u32)
+    Versioned,
+}
+
+impl ChunkLayout {
+    pub fn slot_size(self) -> u64 {
+        match self {
+            Self::Legacy => 8,
+            Self::Versioned => 10,
+        }
+    }
+
+    /// Detects the layout of the headerless chunk at `chunk`, of
+    /// `chunk_size` slots each starting with `key_size` bytes of key
+    /// offset. The chunk is read as each layout in turn, and the first
+    /// one it's a valid chunk of is returned, see `ChunkGeometry::fits`.
+    /// Chunks valid in neither are taken to be versioned, and fail to read
+    /// as such.
+    pub fn detect(
+        bufman: &BufferManager,
+        cursor: u64,
+        chunk: u64,
+        chunk_size: u64,
+        key_size: u64,
+    ) -> Result<Self, BufIoError> {
+        for layout in [Self::Versioned, Self::Legacy] {
+            let geometry = ChunkGeometry::new(chunk_size, key_size + layout.slot_size());
+            if geometry.fits(bufman, cursor, chunk)? {
+                return Ok(layout);
+            }
+        }
+        Ok(Self::Versioned)
+    }
+}
+
+/// Starts the header recording the `ChunkGeometry` of a chunked
+/// `LazyItemVec` or `DashMap`. Data written before the header was added
+/// starts with the item (respectively key) offset of its first slot
+/// instead, which points past the chunk and is never this value.
+pub const CHUNK_HEADER_MAGIC: u32 = u32::MAX - 2;
+
+/// Number of slots per chunk and size of each slot of a chunked
+/// collection, recorded in its header so that it stays readable by a
+/// binary built with a different `CHUNK_SIZE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkGeometry {
+    pub chunk_size: u64,
+    pub slot_stride: u64,
+}
+
+impl ChunkGeometry {
+    /// Size of the header: `CHUNK_HEADER_MAGIC` (u32), chunk size (u16)
+    /// and slot stride (u16)
+    pub const HEADER_SIZE: u64 = 8;
+
+    pub const fn new(chunk_size: u64, slot_stride: u64) -> Self {
+        Self {
+            chunk_size,
+            slot_stride,
+        }
+    }
+
+    /// Offset of slot `i` of the chunk at `chunk`
+    pub fn slot(self, chunk: u64, i: u64) -> u64 {
+        chunk + i * self.slot_stride
+    }
+
+    /// Offset of the link to the next chunk of the chunk at `chunk`
+    pub fn next_link(self, chunk: u64) -> u64 {
+        self.slot(chunk, self.chunk_size)
+    }
+
+    /// Size of a chunk, its slots followed by the next chunk link
+    pub fn chunk_bytes(self) -> u64 {
+        self.chunk_size * self.slot_stride + 4
+    }
+
+    /// Writes the header at the cursor. Fails if the geometry doesn't fit
+    /// in it.
+    pub fn write_header(self, bufman: &BufferManager, cursor: u64) -> Result<(), BufIoError> {
+        let (Ok(chunk_size @ 1..), Ok(slot_stride)) = (
+            u16::try_from(self.chunk_size),
+            u16::try_from(self.slot_stride),
+        ) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk geometry {:?} doesn't fit in a header", self),
+            )
+            .into());
+        };
+        let mut header = [0u8; Self::HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&CHUNK_HEADER_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&chunk_size.to_le_bytes());
+        header[6..8].copy_from_slice(&slot_stride.to_le_bytes());
+        bufman.update_with_cursor(cursor, &header)?;
+        Ok(())
+    }
+
+    /// Reads the header of the collection at `offset`, returning the
+    /// geometry it records and the offset of the first chunk. Collections
+    /// written before the header was added have the `headerless` geometry
+    /// and start with their first chunk.
+    ///
+    /// Fails if the recorded slots are smaller than `min_slot_stride`, the
+    /// size of the fields read from each.
+    pub fn read(
+        bufman: &BufferManager,
+        cursor: u64,
+        offset: u64,
+        headerless: Self,
+        min_slot_stride: u64,
+    ) -> Result<(Self, u64), BufIoError> {
+        bufman.seek_with_cursor(cursor, offset)?;
+        if bufman.read_u32_with_cursor(cursor)? != CHUNK_HEADER_MAGIC {
+            return Ok((headerless, offset));
+        }
+        let geometry = Self::new(
+            bufman.read_u16_with_cursor(cursor)? as u64,
+            bufman.read_u16_with_cursor(cursor)? as u64,
+        );
+        if geometry.chunk_size == 0 || geometry.slot_stride < min_slot_stride {
+            return Err(BufIoError::Corrupt {
+                context: "invalid chunk size or slot stride",
+                offset,
+            });
+        }
+        Ok((geometry, offset + Self::HEADER_SIZE))
+    }
+
+    /// Whether the chunk at `chunk` is a valid chunk of this geometry: it
+    /// lies within the file, its used slots come before the blank ones and
+    /// point into the file, and its next chunk link is either unset or
+    /// points past it, as writers leave them.
+    pub fn fits(self, bufman: &BufferManager, cursor: u64, chunk: u64) -> Result<bool, BufIoError> {
+        let file_size = bufman.file_size();
+        let chunk_bytes = self.chunk_bytes();
+        if chunk + chunk_bytes > file_size {
+            return Ok(false);
+        }
+        let mut bytes = vec![0u8; chunk_bytes as usize];
+        bufman.seek_with_cursor(cursor, chunk)?;
+        if bufman.read_with_cursor(cursor, &mut bytes)? != bytes.len() {
+            return Ok(false);
+        }
+        let (slots, link) = bytes.split_at((chunk_bytes - 4) as usize);
+        let mut free = false;
+        for slot in slots.chunks_exact(self.slot_stride as usize) {
+            let offset = u32::from_le_bytes(slot[0..4].try_into().unwrap());
+            if slot.iter().all(|byte| *byte == u8::MAX) {
+                free = true;
+            } else if free || (offset != u32::MAX && offset as u64 >= file_size) {
+                // Invalid items are written with a `u32::MAX` offset
+                return Ok(false);
+            }
+        }
+        let link = u32::from_le_bytes(link.try_into().unwrap());
+        Ok(link == u32::MAX || (link as u64 >= chunk + chunk_bytes && (link as u64) < file_size))
+    }
+}
+
+/// What deserializing a `LazyItemMap` does with a key that's listed more
+/// than once, which a correct writer never produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateKeyPolicy {
+    /// Keep the item of the last entry with the key
+    #[default]
+    LastWins,
+    /// Keep the item of the first entry with the key
+    FirstWins,
+    /// Fail the deserialization
+    Error,
+}
+
+/// Describes a part of a structure that was skipped by a lenient
+/// deserialization because it could not be read.
+#[derive(Debug)]
+pub enum DeserializeWarning {
+    /// A chunk link pointing outside of the file
+    InvalidChunkOffset { offset: u32 },
+    /// An item slot whose offset points outside of the file
+    InvalidItemOffset {
+        chunk_offset: u32,
+        slot: usize,
+        offset: u32,
+    },
+    /// An item that failed to deserialize
+    ItemFailed {
+        chunk_offset: u32,
+        slot: usize,
+        error: BufIoError,
+    },
+    /// The chunk itself could not be read
+    ChunkUnreadable { offset: u32, error: BufIoError },
+    /// A chunk link pointing back to a chunk that was already read
+    ChunkCycle { offset: u32 },
+}
+
+/// Estimated cost of deserializing a chunked structure, counted from its
+/// chunks without loading any of its items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadCost {
+    /// Chunks in the chain
+    pub chunks: usize,
+    /// Occupied item slots, each a sub-load when deserializing
+    pub items: usize,
+}
+
+type SerializeFn<'a> =
+    Box<dyn Fn(Arc<BufferManagerFactory<Hash>>, Hash, u64) -> Result<u32, BufIoError> + 'a>;
+
+/// Independent items serialized one after another with a single cursor,
+/// e.g. to lay out a whole file in one pass.
+#[derive(Default)]
+pub struct SerializeBatch<'a> {
+    items: Vec<SerializeFn<'a>>,
+}
+
+impl<'a> SerializeBatch<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_item<T: CustomSerialize>(mut self, item: &'a T) -> Self {
+        self.push(item);
+        self
+    }
+
+    pub fn push<T: CustomSerialize>(&mut self, item: &'a T) {
+        self.items.push(Box::new(move |bufmans, version, cursor| {
+            item.serialize(bufmans, version, cursor)
+        }));
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Serializes the items in order, each starting where the previous
+    /// one left the cursor, and returns the offsets their `serialize`
+    /// returned, to deserialize them from. Stops at the first failure.
+    pub fn serialize(
+        &self,
+        bufmans: Arc<BufferManagerFactory<Hash>>,
+        version: Hash,
+        cursor: u64,
+    ) -> Result<Vec<u32>, BufIoError> {
+        self.items
+            .iter()
+            .map(|serialize| serialize(bufmans.clone(), version, cursor))
+            .collect()
+    }
+}
+
 trait SimpleSerialize: Sized {
     fn serialize(&self, bufman: &BufferManager, cursor: u64) -> Result<u32, BufIoError>;
 
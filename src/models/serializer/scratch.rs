@@ -0,0 +1,52 @@
+//! Scratch buffers reused across `serialize` calls, so that serializing
+//! many small items, e.g. the keys of a large map, doesn't allocate a
+//! temporary buffer per item.
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Buffers kept per thread, enough for the nesting of the serializers
+const MAX_POOLED_BUFFERS: usize = 4;
+
+static MAX_RETAINED_BYTES: AtomicUsize = AtomicUsize::new(1 << 20);
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Caps the capacity of the buffers kept for reuse, larger ones are freed
+/// after use. 0 disables pooling, every call then starts from a new
+/// buffer.
+pub fn set_max_retained_bytes(bytes: usize) {
+    MAX_RETAINED_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+/// Number of times a scratch buffer was allocated or grown on the current
+/// thread
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+/// Calls `f` with an empty buffer, taken from the current thread's pool
+/// and returned to it afterwards
+pub fn with_scratch_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    let mut buf = POOL
+        .with(|pool| pool.borrow_mut().pop())
+        .unwrap_or_default();
+    let capacity = buf.capacity();
+    let result = f(&mut buf);
+    if buf.capacity() > capacity {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+    }
+    if buf.capacity() <= MAX_RETAINED_BYTES.load(Ordering::Relaxed) {
+        buf.clear();
+        POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED_BUFFERS {
+                pool.push(buf);
+            }
+        });
+    }
+    result
+}
@@ -68,50 +68,90 @@ impl SimpleSerialize for Storage {
         bufman: &BufferManager,
         FileOffset(offset): FileOffset,
     ) -> Result<Self, BufIoError> {
+        let mut storage = Self::UnsignedByte {
+            mag: 0,
+            quant_vec: Vec::new(),
+        };
+        storage.deserialize_into(bufman, FileOffset(offset))?;
+        Ok(storage)
+    }
+}
+
+impl Storage {
+    /// Same as `SimpleSerialize::deserialize`, but reads into `self`,
+    /// reusing its buffers if it's already of the stored variant. Loading
+    /// many vectors into one storage only allocates when a vector is
+    /// longer than the ones loaded before.
+    pub fn deserialize_into(
+        &mut self,
+        bufman: &BufferManager,
+        FileOffset(offset): FileOffset,
+    ) -> Result<(), BufIoError> {
         let cursor = bufman.open_cursor()?;
         bufman.seek_with_cursor(cursor, offset as u64)?;
 
         let variant_index = bufman.read_u8_with_cursor(cursor)?;
 
-        let storage = match variant_index {
+        match variant_index {
             0 => {
-                let mag = bufman.read_u32_with_cursor(cursor)?;
-                let len = bufman.read_u32_with_cursor(cursor)? as usize;
-                let mut quant_vec = Vec::with_capacity(len);
-
-                for _ in 0..len {
-                    let el = bufman.read_u8_with_cursor(cursor)?;
-                    quant_vec.push(el);
+                if !matches!(self, Self::UnsignedByte { .. }) {
+                    *self = Self::UnsignedByte {
+                        mag: 0,
+                        quant_vec: Vec::new(),
+                    };
                 }
-
-                Self::UnsignedByte { mag, quant_vec }
+                let Self::UnsignedByte { mag, quant_vec } = self else {
+                    unreachable!()
+                };
+                *mag = bufman.read_u32_with_cursor(cursor)?;
+                let len = bufman.read_u32_with_cursor(cursor)? as usize;
+                quant_vec.clear();
+                quant_vec.resize(len, 0);
+                bufman.read_with_cursor(cursor, quant_vec)?;
             }
             1 => {
-                let resolution = bufman.read_u8_with_cursor(cursor)?;
-                let mag = bufman.read_f32_with_cursor(cursor)?;
-                let len = bufman.read_u32_with_cursor(cursor)? as usize;
-                let mut quant_vec = Vec::with_capacity(len);
-
-                for _ in 0..len {
-                    let len = bufman.read_u32_with_cursor(cursor)? as usize;
-                    let mut vec = Vec::with_capacity(len);
-                    for _ in 0..len {
-                        let el = bufman.read_u8_with_cursor(cursor)?;
-                        vec.push(el);
-                    }
-                    quant_vec.push(vec);
+                if !matches!(self, Self::SubByte { .. }) {
+                    *self = Self::SubByte {
+                        mag: 0.0,
+                        quant_vec: Vec::new(),
+                        resolution: 0,
+                    };
                 }
-
-                Self::SubByte {
+                let Self::SubByte {
                     mag,
                     quant_vec,
                     resolution,
+                } = self
+                else {
+                    unreachable!()
+                };
+                *resolution = bufman.read_u8_with_cursor(cursor)?;
+                *mag = bufman.read_f32_with_cursor(cursor)?;
+                let len = bufman.read_u32_with_cursor(cursor)? as usize;
+                quant_vec.truncate(len);
+                quant_vec.resize_with(len, Vec::new);
+
+                for vec in quant_vec {
+                    let len = bufman.read_u32_with_cursor(cursor)? as usize;
+                    vec.clear();
+                    vec.resize(len, 0);
+                    bufman.read_with_cursor(cursor, vec)?;
                 }
             }
             2 => {
-                let mag = bufman.read_f32_with_cursor(cursor)?;
+                if !matches!(self, Self::HalfPrecisionFP { .. }) {
+                    *self = Self::HalfPrecisionFP {
+                        mag: 0.0,
+                        quant_vec: Vec::new(),
+                    };
+                }
+                let Self::HalfPrecisionFP { mag, quant_vec } = self else {
+                    unreachable!()
+                };
+                *mag = bufman.read_f32_with_cursor(cursor)?;
                 let len = bufman.read_u32_with_cursor(cursor)? as usize;
-                let mut quant_vec = Vec::with_capacity(len);
+                quant_vec.clear();
+                quant_vec.reserve(len);
 
                 for _ in 0..len {
                     let mut bytes = [0; 2];
@@ -119,29 +159,35 @@ impl SimpleSerialize for Storage {
                     let el = f16::from_le_bytes(bytes);
                     quant_vec.push(el);
                 }
-
-                Self::HalfPrecisionFP { mag, quant_vec }
             }
             3 => {
-                let mag = bufman.read_f32_with_cursor(cursor)?;
+                if !matches!(self, Self::FullPrecisionFP { .. }) {
+                    *self = Self::FullPrecisionFP {
+                        mag: 0.0,
+                        vec: Vec::new(),
+                    };
+                }
+                let Self::FullPrecisionFP { mag, vec } = self else {
+                    unreachable!()
+                };
+                *mag = bufman.read_f32_with_cursor(cursor)?;
                 let len = bufman.read_u32_with_cursor(cursor)? as usize;
-                let mut vec = Vec::with_capacity(len);
+                vec.clear();
+                vec.reserve(len);
 
                 for _ in 0..len {
                     let el = bufman.read_f32_with_cursor(cursor)?;
                     vec.push(el);
                 }
-
-                Self::FullPrecisionFP { mag, vec }
             }
             _ => {
                 return Err(
                     io::Error::new(io::ErrorKind::InvalidData, "Invalid Storage variant").into(),
                 );
             }
-        };
+        }
 
         bufman.close_cursor(cursor)?;
-        Ok(storage)
+        Ok(())
     }
 }
@@ -1,5 +1,6 @@
 use crate::distance::cosine::CosineSimilarity;
-use crate::models::buffered_io::BufferManager;
+use crate::models::buffered_io::{BufIoError, BufferManager};
+use crate::models::identity_collections::{HashSeed, IdentityMap, IdentityMapKey};
 use crate::models::lazy_load::*;
 use crate::models::serializer::*;
 use crate::models::types::*;
@@ -929,6 +930,54 @@ fn test_storage_serialization() {
     }
 }
 
+#[test]
+fn test_storage_deserialize_into() {
+    let (_bufmans, _cache, bufman, cursor, _dir) = setup_test(0.into());
+    let storages: Vec<_> = (0..50)
+        .map(|i| Storage::FullPrecisionFP {
+            mag: i as f32,
+            vec: (0..16 - i % 8).map(|j| (i * j) as f32 / 10.0).collect(),
+        })
+        .collect();
+    let offsets: Vec<_> = storages
+        .iter()
+        .map(|storage| SimpleSerialize::serialize(storage, &bufman, cursor).unwrap())
+        .collect();
+    let unsigned_byte = Storage::UnsignedByte {
+        mag: 10,
+        quant_vec: vec![0, 1, 4],
+    };
+    let unsigned_byte_offset = SimpleSerialize::serialize(&unsigned_byte, &bufman, cursor).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let mut buffer = Storage::FullPrecisionFP {
+        mag: 0.0,
+        vec: Vec::with_capacity(16),
+    };
+    let Storage::FullPrecisionFP { vec, .. } = &buffer else {
+        unreachable!()
+    };
+    let allocation = (vec.as_ptr(), vec.capacity());
+
+    for (storage, offset) in storages.iter().zip(offsets) {
+        buffer
+            .deserialize_into(&bufman, FileOffset(offset))
+            .unwrap();
+        assert_eq!(&buffer, storage);
+        // Same allocation as before, nothing was reallocated
+        let Storage::FullPrecisionFP { vec, .. } = &buffer else {
+            unreachable!()
+        };
+        assert_eq!((vec.as_ptr(), vec.capacity()), allocation);
+    }
+
+    // Other variants replace the buffer
+    buffer
+        .deserialize_into(&bufman, FileOffset(unsigned_byte_offset))
+        .unwrap();
+    assert_eq!(buffer, unsigned_byte);
+}
+
 #[test]
 fn test_lazy_item_vec_serialization() {
     let root_version_id = Hash::from(0);
@@ -1074,3 +1123,1238 @@ fn test_eager_lazy_item_multiple_serialization() {
 
     assert_eq!(set.len(), deserialized.len());
 }
+
+#[test]
+fn test_lazy_item_map_serialization() {
+    let root_version_id = Hash::from(0);
+    let total_items = 12;
+    // Fixed hash seed so the entries are written in the same order every run
+    let map = LazyItemMap::from_map(IdentityMap::from_iter_with_seed(
+        (0..total_items).map(|i| {
+            let key = if i % 2 == 0 {
+                IdentityMapKey::Int(i)
+            } else {
+                IdentityMapKey::String(format!("key-{}", i))
+            };
+            (
+                key,
+                LazyItem::from_data(root_version_id, i as u16, i as f32 * 0.5),
+            )
+        }),
+        HashSeed::new(1, 2),
+    ));
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let seeks_before = bufman.seek_count();
+    let offset = map
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    let map_seeks = bufman.seek_count() - seeks_before;
+
+    // The chunked layout of the same number of entries, for comparison
+    let seeks_before = bufman.seek_count();
+    write_chunked_map(bufmans.clone(), &bufman, cursor, total_items, true);
+    let chunked_layout_seeks = bufman.seek_count() - seeks_before;
+
+    bufman.seek_with_cursor(cursor, offset as u64).unwrap();
+    let layout_version = bufman.read_u32_with_cursor(cursor).unwrap();
+    let len = bufman.read_u32_with_cursor(cursor).unwrap();
+    let mut entries = Vec::new();
+    for _ in 0..len {
+        let key_offset = bufman.read_u32_with_cursor(cursor).unwrap();
+        let item_offset = bufman.read_u32_with_cursor(cursor).unwrap();
+        let version_number = bufman.read_u16_with_cursor(cursor).unwrap();
+        let version_id = bufman.read_u32_with_cursor(cursor).unwrap();
+        entries.push((key_offset, item_offset, version_number, version_id));
+    }
+    bufman.close_cursor(cursor).unwrap();
+
+    // Each item seeks 3 times on its own, the chunked layout additionally
+    // seeked back and forth twice per entry and twice per chunk link to patch
+    // placeholders, the batched layout doesn't seek at all
+    assert_eq!(map_seeks, 36);
+    assert_eq!(chunked_layout_seeks, 64);
+
+    // Int keys take 4 bytes, string keys 4 bytes of length plus the string,
+    // items 12 bytes, the header block follows the last item
+    assert_eq!(offset, 223);
+    assert_eq!(layout_version, 2);
+    assert_eq!(len, 12);
+    assert_eq!(
+        entries,
+        vec![
+            (0, 4, 8, 0),
+            (16, 20, 10, 0),
+            (32, 36, 0, 0),
+            (48, 52, 6, 0),
+            (64, 68, 4, 0),
+            (80, 89, 9, 0),
+            (101, 110, 5, 0),
+            (122, 131, 7, 0),
+            (143, 152, 1, 0),
+            (164, 168, 2, 0),
+            (180, 189, 3, 0),
+            (201, 211, 11, 0),
+        ]
+    );
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    let deserialized: LazyItemMap<f32> = cache.load_item(file_index).unwrap();
+
+    assert_eq!(map.len(), deserialized.len());
+    for i in 0..total_items {
+        let key = if i % 2 == 0 {
+            IdentityMapKey::Int(i)
+        } else {
+            IdentityMapKey::String(format!("key-{}", i))
+        };
+        match deserialized.get(&key).unwrap() {
+            LazyItem::Valid {
+                data: mut data_arc,
+                version_id,
+                version_number,
+                ..
+            } => {
+                assert_eq!(*data_arc.get().clone().unwrap(), i as f32 * 0.5);
+                assert_eq!(version_id, root_version_id);
+                assert_eq!(version_number, i as u16);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
+
+#[test]
+fn test_lazy_item_map_duplicate_keys() {
+    let root_version_id = Hash::from(0);
+    let map = LazyItemMap::new();
+    for i in 1..=2 {
+        map.insert(
+            IdentityMapKey::Int(i),
+            LazyItem::from_data(root_version_id, 0, i as f32),
+        );
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let offset = map
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+
+    // Point the key of the second entry to the key of the first one,
+    // header: layout version (u32), len (u32), then 14 byte entries
+    bufman.seek_with_cursor(cursor, offset as u64 + 8).unwrap();
+    let first_key_offset = bufman.read_u32_with_cursor(cursor).unwrap();
+    bufman
+        .seek_with_cursor(cursor, offset as u64 + 8 + 14)
+        .unwrap();
+    bufman
+        .update_u32_with_cursor(cursor, first_key_offset)
+        .unwrap();
+    bufman
+        .seek_with_cursor(cursor, first_key_offset as u64)
+        .unwrap();
+    let key = bufman.read_u32_with_cursor(cursor).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let deserialize = |policy| {
+        LazyItemMap::<f32>::deserialize_with_policy(
+            bufmans.clone(),
+            file_index.clone(),
+            cache.clone(),
+            1000,
+            &mut HashSet::new(),
+            policy,
+        )
+    };
+    let value = |map: &LazyItemMap<f32>| match map.get(&IdentityMapKey::Int(key)).unwrap() {
+        LazyItem::Valid { data: mut arc, .. } => *arc.get().clone().unwrap(),
+        LazyItem::Invalid => panic!("Deserialization mismatch"),
+    };
+    // The items of both entries, in the order they're listed
+    let other = 3 - key;
+
+    let last_wins = deserialize(DuplicateKeyPolicy::LastWins).unwrap();
+    assert_eq!(last_wins.len(), 1);
+    assert_eq!(value(&last_wins), other as f32);
+
+    let first_wins = deserialize(DuplicateKeyPolicy::FirstWins).unwrap();
+    assert_eq!(first_wins.len(), 1);
+    assert_eq!(value(&first_wins), key as f32);
+
+    assert!(deserialize(DuplicateKeyPolicy::Error).is_err());
+
+    // Plain deserialization keeps the last item
+    let deserialized: LazyItemMap<f32> = cache.load_item(file_index).unwrap();
+    assert_eq!(value(&deserialized), other as f32);
+}
+
+// Hand-crafts a map in the version 1 layout: linked chunks of entry slots,
+// each followed by the keys and items of its entries. Entries written before
+// the version number was added to them are 12 bytes instead of 14.
+fn write_chunked_map(
+    bufmans: Arc<BufferManagerFactory<Hash>>,
+    bufman: &BufferManager,
+    cursor: u64,
+    total_items: u32,
+    with_version_numbers: bool,
+) -> u32 {
+    let root_version_id = Hash::from(0);
+    let slot_size = if with_version_numbers { 14 } else { 12 };
+    let offset = bufman.cursor_position(cursor).unwrap() as u32;
+    let mut prev_link = None;
+    for chunk_start in (0..total_items).step_by(CHUNK_SIZE) {
+        let chunk = bufman.cursor_position(cursor).unwrap();
+        if let Some(link) = prev_link {
+            bufman.seek_with_cursor(cursor, link).unwrap();
+            bufman.update_u32_with_cursor(cursor, chunk as u32).unwrap();
+            bufman.seek_with_cursor(cursor, chunk).unwrap();
+        }
+        bufman
+            .update_with_cursor(cursor, &vec![u8::MAX; CHUNK_SIZE * slot_size + 4])
+            .unwrap();
+        prev_link = Some(chunk + (CHUNK_SIZE * slot_size) as u64);
+        let chunk_end = (chunk_start + CHUNK_SIZE as u32).min(total_items);
+        for i in chunk_start..chunk_end {
+            let key_offset = IdentityMapKey::Int(i)
+                .serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap();
+            let item_offset = LazyItem::from_data(root_version_id, i as u16, i as f32)
+                .serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap();
+            let end = bufman.cursor_position(cursor).unwrap();
+            bufman
+                .seek_with_cursor(
+                    cursor,
+                    chunk + ((i - chunk_start) as usize * slot_size) as u64,
+                )
+                .unwrap();
+            bufman.update_u32_with_cursor(cursor, key_offset).unwrap();
+            bufman.update_u32_with_cursor(cursor, item_offset).unwrap();
+            if with_version_numbers {
+                bufman.update_u16_with_cursor(cursor, i as u16).unwrap();
+            }
+            bufman
+                .update_u32_with_cursor(cursor, *root_version_id)
+                .unwrap();
+            bufman.seek_with_cursor(cursor, end).unwrap();
+        }
+    }
+    offset
+}
+
+#[test]
+fn test_lazy_item_map_chunked_layout() {
+    let root_version_id = Hash::from(0);
+    let total_items = CHUNK_SIZE as u32 + 2;
+
+    for with_version_numbers in [true, false] {
+        let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+        let offset = write_chunked_map(bufmans, &bufman, cursor, total_items, with_version_numbers);
+        bufman.close_cursor(cursor).unwrap();
+
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 3,
+            version_id: root_version_id,
+        };
+        let deserialized: LazyItemMap<f32> = cache.load_item(file_index).unwrap();
+
+        assert_eq!(deserialized.len(), total_items as usize);
+        for i in 0..total_items {
+            let item = deserialized.get(&IdentityMapKey::Int(i)).unwrap();
+            // Entries without a version number take the map's
+            let version_number = if with_version_numbers { i as u16 } else { 3 };
+            assert_eq!(item.get_current_version_number(), version_number);
+            match item {
+                LazyItem::Valid { data: mut arc, .. } => {
+                    assert_eq!(*arc.get().clone().unwrap(), i as f32);
+                }
+                LazyItem::Invalid => panic!("Deserialization mismatch"),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dashmap_foreign_chunk_size() {
+    let root_version_id = Hash::from(0);
+    let map = ::dashmap::DashMap::new();
+    let total_items = 8;
+    for i in 0..total_items {
+        map.insert(
+            IdentityMapKey::Int(i),
+            LazyItem::from_data(root_version_id, i as u16, i as f32 * 0.5),
+        );
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    // Written by a binary built with a different `CHUNK_SIZE`
+    let chunk_size = CHUNK_SIZE - 2;
+    let offset = super::dashmap::serialize_with_chunk_size(
+        &map,
+        bufmans.clone(),
+        root_version_id,
+        cursor,
+        chunk_size,
+    )
+    .unwrap();
+
+    bufman.seek_with_cursor(cursor, offset as u64).unwrap();
+    assert_eq!(
+        bufman.read_u32_with_cursor(cursor).unwrap(),
+        CHUNK_HEADER_MAGIC
+    );
+    assert_eq!(
+        bufman.read_u16_with_cursor(cursor).unwrap(),
+        chunk_size as u16
+    );
+    assert_eq!(bufman.read_u16_with_cursor(cursor).unwrap(), 14);
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let deserialized = ::dashmap::DashMap::<IdentityMapKey, LazyItem<f32>>::deserialize(
+        bufmans.clone(),
+        file_index,
+        cache.clone(),
+        1000,
+        &mut HashSet::new(),
+    )
+    .unwrap();
+
+    assert_eq!(deserialized.len(), total_items as usize);
+    for i in 0..total_items {
+        match deserialized.get(&IdentityMapKey::Int(i)).unwrap().clone() {
+            LazyItem::Valid {
+                data: mut data_arc,
+                version_id,
+                version_number,
+                ..
+            } => {
+                assert_eq!(*data_arc.get().clone().unwrap(), i as f32 * 0.5);
+                assert_eq!(version_id, root_version_id);
+                assert_eq!(version_number, i as u16);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
+
+#[test]
+fn test_dashmap_headerless() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let entries: Vec<_> = (0..3)
+        .map(|i| {
+            let key_offset = IdentityMapKey::Int(i)
+                .serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap();
+            let item_offset = LazyItem::from_data(root_version_id, i as u16, i as f32)
+                .serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap();
+            (key_offset, item_offset)
+        })
+        .collect();
+
+    // Hand-craft a map as written before the chunk geometry header was
+    // added: a single chunk of `CHUNK_SIZE` 14 byte slots
+    let offset = bufman.cursor_position(cursor).unwrap() as u32;
+    for slot in 0..CHUNK_SIZE {
+        let (key_offset, item_offset) = entries.get(slot).copied().unwrap_or((u32::MAX, u32::MAX));
+        bufman.update_u32_with_cursor(cursor, key_offset).unwrap();
+        bufman.update_u32_with_cursor(cursor, item_offset).unwrap();
+        bufman.update_u16_with_cursor(cursor, slot as u16).unwrap();
+        bufman
+            .update_u32_with_cursor(cursor, *root_version_id)
+            .unwrap();
+    }
+    bufman.update_u32_with_cursor(cursor, u32::MAX).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let deserialized = ::dashmap::DashMap::<IdentityMapKey, LazyItem<f32>>::deserialize(
+        bufmans,
+        file_index,
+        cache,
+        1000,
+        &mut HashSet::new(),
+    )
+    .unwrap();
+
+    assert_eq!(deserialized.len(), 3);
+    for i in 0..3 {
+        let item = deserialized.get(&IdentityMapKey::Int(i)).unwrap().clone();
+        assert_eq!(item.get_current_version_number(), i as u16);
+        match item {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
+
+#[test]
+fn test_lazy_item_vec_foreign_chunk_size() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..8 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    // Written by a binary built with a different `CHUNK_SIZE`
+    let chunk_size = CHUNK_SIZE + 2;
+    let offset = lazy_items
+        .serialize_with_chunk_size(bufmans.clone(), root_version_id, cursor, chunk_size)
+        .unwrap();
+
+    bufman.seek_with_cursor(cursor, offset as u64).unwrap();
+    assert_eq!(
+        bufman.read_u32_with_cursor(cursor).unwrap(),
+        CHUNK_HEADER_MAGIC
+    );
+    assert_eq!(
+        bufman.read_u16_with_cursor(cursor).unwrap(),
+        chunk_size as u16
+    );
+    assert_eq!(bufman.read_u16_with_cursor(cursor).unwrap(), 10);
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let cost = LazyItemVec::<f32>::estimate_load_cost(bufmans.clone(), file_index).unwrap();
+    assert_eq!(cost.chunks, 2);
+    assert_eq!(cost.items, 8);
+
+    // Appending fills the last chunk of the recorded size
+    for i in 8..10 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+    lazy_items
+        .serialize_append(bufmans.clone(), root_version_id, cursor, offset)
+        .unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    let cost = LazyItemVec::<f32>::estimate_load_cost(bufmans.clone(), file_index).unwrap();
+    assert_eq!(cost.chunks, 2);
+    assert_eq!(cost.items, 10);
+
+    let deserialized: LazyItemVec<f32> = cache.clone().load_item(file_index).unwrap();
+    let values: Vec<_> = deserialized
+        .iter()
+        .map(|item| *item.get_lazy_data().unwrap().get().clone().unwrap())
+        .collect();
+    assert_eq!(values, (0..10).map(|i| i as f32).collect::<Vec<_>>());
+
+    let (deserialized, warnings) =
+        LazyItemVec::<f32>::deserialize_lenient(bufmans, file_index, cache, 1000);
+    assert_eq!(deserialized.len(), 10);
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn test_lazy_item_vec_headerless() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let item_offsets: Vec<_> = (0..3)
+        .map(|i| {
+            LazyItem::from_data(root_version_id, i as u16, i as f32)
+                .serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap()
+        })
+        .collect();
+
+    // Hand-craft a vector as written before the chunk geometry header
+    // was added: a single chunk of `CHUNK_SIZE` 10 byte slots
+    let offset = bufman.cursor_position(cursor).unwrap() as u32;
+    for slot in 0..CHUNK_SIZE {
+        let item_offset = item_offsets.get(slot).copied().unwrap_or(u32::MAX);
+        bufman.update_u32_with_cursor(cursor, item_offset).unwrap();
+        bufman.update_u16_with_cursor(cursor, slot as u16).unwrap();
+        bufman
+            .update_u32_with_cursor(cursor, *root_version_id)
+            .unwrap();
+    }
+    bufman.update_u32_with_cursor(cursor, u32::MAX).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let cost = LazyItemVec::<f32>::estimate_load_cost(bufmans.clone(), file_index).unwrap();
+    assert_eq!(cost.chunks, 1);
+    assert_eq!(cost.items, 3);
+
+    let deserialized: LazyItemVec<f32> = cache.load_item(file_index).unwrap();
+    assert_eq!(deserialized.len(), 3);
+    for (i, item) in deserialized.iter().enumerate() {
+        assert_eq!(item.get_current_version_number(), i as u16);
+        match item {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
+
+#[test]
+fn test_scratch_buffer_reuse() {
+    let root_version_id = Hash::from(0);
+    let total_items = 1000;
+    let key = |i: u32| IdentityMapKey::String(format!("scratch-key-{}", i));
+    let map = LazyItemMap::new();
+    for i in 0..total_items {
+        map.insert(key(i), LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let allocations = scratch::allocations();
+    let offset = map
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    // One buffer grown to the longest key, then to the header block,
+    // instead of one per key
+    assert!(scratch::allocations() - allocations <= 4);
+    bufman.close_cursor(cursor).unwrap();
+
+    let deserialized: LazyItemMap<f32> = cache
+        .load_item(FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: root_version_id,
+        })
+        .unwrap();
+    assert_eq!(deserialized.len(), total_items as usize);
+    for i in 0..total_items {
+        let LazyItem::Valid { mut data, .. } = deserialized.get(&key(i)).unwrap() else {
+            panic!("Deserialization mismatch");
+        };
+        assert_eq!(*data.get().clone().unwrap(), i as f32);
+    }
+
+    // Keys are written the same as with a buffer per key
+    let (bufmans, _cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    for i in 0..total_items {
+        key(i)
+            .serialize(bufmans.clone(), root_version_id, cursor)
+            .unwrap();
+    }
+    let mut expected = Vec::new();
+    for i in 0..total_items {
+        let bytes = format!("scratch-key-{}", i).into_bytes();
+        expected.extend_from_slice(&((1u32 << 31) | bytes.len() as u32).to_le_bytes());
+        expected.extend_from_slice(&bytes);
+    }
+    let mut written = vec![0; expected.len()];
+    bufman.seek_with_cursor(cursor, 0).unwrap();
+    bufman.read_with_cursor(cursor, &mut written).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    assert_eq!(written, expected);
+}
+
+#[test]
+fn test_lazy_item_vec_serialize_append() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..1000 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    // Writes performed by a single item, independent of the chunk layout
+    let writes_before = bufman.write_count();
+    LazyItem::from_data(root_version_id, 0, 0.0f32)
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    let writes_per_item = bufman.write_count() - writes_before;
+
+    let offset = lazy_items
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+
+    let mut before = vec![0; bufman.file_size() as usize];
+    bufman.seek_with_cursor(cursor, 0).unwrap();
+    bufman.read_with_cursor(cursor, &mut before).unwrap();
+
+    for i in 1000..1003 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let writes_before = bufman.write_count();
+    let append_offset = lazy_items
+        .serialize_append(bufmans.clone(), root_version_id, cursor, offset)
+        .unwrap();
+    let append_writes = bufman.write_count() - writes_before;
+    assert_eq!(offset, append_offset);
+
+    // 3 items plus their chunk entries, one new chunk and its link
+    assert!(append_writes <= 3 * (writes_per_item + 3) + 2);
+
+    // Earlier chunks are untouched, only the last chunk's link changed
+    let mut after = vec![0; before.len()];
+    bufman.seek_with_cursor(cursor, 0).unwrap();
+    bufman.read_with_cursor(cursor, &mut after).unwrap();
+    let changed_bytes = before.iter().zip(&after).filter(|(a, b)| a != b).count();
+    assert!(changed_bytes <= 4);
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    let deserialized: LazyItemVec<f32> = cache.load_item(file_index).unwrap();
+
+    assert_eq!(lazy_items.len(), deserialized.len());
+    for (i, item) in deserialized.iter().enumerate() {
+        match item {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
+
+#[test]
+fn test_empty_placeholder_serialization() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let file_index = |offset| FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    let empty_vec: LazyItemVec<f32> = LazyItemVec::new();
+    assert_eq!(
+        empty_vec
+            .serialize_with_empty_placeholder(bufmans.clone(), root_version_id, cursor, false)
+            .unwrap(),
+        u32::MAX
+    );
+    let vec_offset = empty_vec
+        .serialize_with_empty_placeholder(bufmans.clone(), root_version_id, cursor, true)
+        .unwrap();
+    assert_ne!(vec_offset, u32::MAX);
+
+    let empty_set: LazyItemSet<MergedNode> = LazyItemSet::new();
+    let set_offset = empty_set
+        .serialize_with_empty_placeholder(bufmans.clone(), root_version_id, cursor, true)
+        .unwrap();
+    assert_ne!(set_offset, u32::MAX);
+    // The header, then a single chunk of empty slots and its terminating
+    // link
+    assert_eq!(
+        (set_offset - vec_offset) as u64,
+        ChunkGeometry::HEADER_SIZE + CHUNK_SIZE as u64 * 10 + 4
+    );
+
+    let deserialized: LazyItemVec<f32> = cache.clone().load_item(file_index(vec_offset)).unwrap();
+    assert!(deserialized.is_empty());
+    let deserialized: LazyItemSet<MergedNode> =
+        cache.clone().load_item(file_index(set_offset)).unwrap();
+    assert!(deserialized.is_empty());
+
+    // The placeholder is filled in place
+    let lazy_items = LazyItemVec::new();
+    for i in 0..3 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+    let offset = lazy_items
+        .serialize_append(bufmans.clone(), root_version_id, cursor, vec_offset)
+        .unwrap();
+    assert_eq!(offset, vec_offset);
+    bufman.close_cursor(cursor).unwrap();
+
+    let deserialized: LazyItemVec<f32> = cache.load_item(file_index(offset)).unwrap();
+    let values: Vec<_> = deserialized
+        .iter()
+        .map(|item| *item.get_lazy_data().unwrap().get().clone().unwrap())
+        .collect();
+    assert_eq!(values, vec![0.0, 1.0, 2.0]);
+}
+
+#[test]
+fn test_lazy_item_vec_estimate_load_cost() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 4 * CHUNK_SIZE - 2] {
+        let lazy_items = LazyItemVec::new();
+        for i in 0..len {
+            lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+        }
+        let offset = lazy_items
+            .serialize(bufmans.clone(), root_version_id, cursor)
+            .unwrap();
+        let file_index = FileIndex::Valid {
+            offset: FileOffset(offset),
+            version_number: 0,
+            version_id: root_version_id,
+        };
+
+        let cost = LazyItemVec::<f32>::estimate_load_cost(bufmans.clone(), file_index).unwrap();
+
+        let deserialized: LazyItemVec<f32> = cache.clone().load_item(file_index).unwrap();
+        assert_eq!(cost.items, deserialized.len());
+        assert_eq!(cost.chunks, len.div_ceil(CHUNK_SIZE));
+    }
+    bufman.close_cursor(cursor).unwrap();
+
+    assert_eq!(
+        LazyItemVec::<f32>::estimate_load_cost(bufmans, FileIndex::Invalid).unwrap(),
+        LoadCost::default()
+    );
+}
+
+#[test]
+fn test_lazy_item_vec_read_chunk() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..5 * CHUNK_SIZE {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let offset = lazy_items
+        .serialize_with_chunk_directory(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    let read_chunk = |n| {
+        let seeks_before = bufman.seek_count();
+        let items = LazyItemVec::<f32>::read_chunk(
+            bufmans.clone(),
+            file_index,
+            n,
+            cache.clone(),
+            1000,
+            &mut HashSet::new(),
+        )?;
+        let values: Vec<_> = items
+            .into_iter()
+            .map(|item| *item.get_lazy_data().unwrap().get().clone().unwrap())
+            .collect();
+        Ok::<_, BufIoError>((values, bufman.seek_count() - seeks_before))
+    };
+
+    let (first_chunk, first_chunk_seeks) = read_chunk(0).unwrap();
+    assert_eq!(
+        first_chunk,
+        (0..CHUNK_SIZE).map(|i| i as f32).collect::<Vec<_>>()
+    );
+    // Chunks 0 to 2 aren't walked, so reading chunk 3 costs as much as
+    // reading the first one
+    let (chunk, chunk_seeks) = read_chunk(3).unwrap();
+    assert_eq!(
+        chunk,
+        (3 * CHUNK_SIZE..4 * CHUNK_SIZE)
+            .map(|i| i as f32)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(chunk_seeks, first_chunk_seeks);
+
+    assert!(read_chunk(5).is_err());
+}
+
+#[test]
+fn test_lazy_item_vec_deserialize_lenient() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..12 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let offset = lazy_items
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+
+    // Corrupt the first item offset of the second chunk
+    let first_chunk = offset as u64 + ChunkGeometry::HEADER_SIZE;
+    bufman
+        .seek_with_cursor(cursor, first_chunk + CHUNK_SIZE as u64 * 10)
+        .unwrap();
+    let second_chunk = bufman.read_u32_with_cursor(cursor).unwrap();
+    bufman
+        .seek_with_cursor(cursor, second_chunk as u64)
+        .unwrap();
+    bufman.update_u32_with_cursor(cursor, 0xFFFF_FFF0).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    let (deserialized, warnings) =
+        LazyItemVec::<f32>::deserialize_lenient(bufmans, file_index, cache, 1000);
+
+    assert_eq!(deserialized.len(), CHUNK_SIZE);
+    for (i, item) in deserialized.iter().enumerate() {
+        match item {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+    assert_eq!(warnings.len(), 1);
+    assert!(matches!(
+        warnings[0],
+        DeserializeWarning::InvalidItemOffset {
+            chunk_offset,
+            slot: 0,
+            offset: 0xFFFF_FFF0,
+        } if chunk_offset == second_chunk
+    ));
+}
+
+#[test]
+fn test_lazy_item_vec_deserialize_chunk_cycle() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..12 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let offset = lazy_items
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+
+    // Point the link of the last chunk back to the first one
+    let first_chunk = offset + ChunkGeometry::HEADER_SIZE as u32;
+    let mut last_chunk = first_chunk;
+    loop {
+        bufman
+            .seek_with_cursor(cursor, last_chunk as u64 + CHUNK_SIZE as u64 * 10)
+            .unwrap();
+        match bufman.read_u32_with_cursor(cursor).unwrap() {
+            u32::MAX => break,
+            next_chunk => last_chunk = next_chunk,
+        }
+    }
+    assert_ne!(last_chunk, first_chunk);
+    bufman
+        .seek_with_cursor(cursor, last_chunk as u64 + CHUNK_SIZE as u64 * 10)
+        .unwrap();
+    bufman.update_u32_with_cursor(cursor, first_chunk).unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    // Deserialize on another thread, so a regression fails the test
+    // instead of hanging it
+    let (tx, rx) = std::sync::mpsc::channel();
+    {
+        let (bufmans, cache) = (bufmans.clone(), cache.clone());
+        std::thread::spawn(move || {
+            let res = <LazyItemVec<f32> as CustomSerialize>::deserialize(
+                bufmans,
+                file_index,
+                cache,
+                1000,
+                &mut std::collections::HashSet::new(),
+            );
+            tx.send(res.map(|items| items.len())).unwrap();
+        });
+    }
+    let res = rx
+        .recv_timeout(std::time::Duration::from_secs(10))
+        .expect("deserialize didn't terminate");
+    assert!(matches!(
+        res,
+        Err(BufIoError::Corrupt { offset: chunk_offset, .. }) if chunk_offset == first_chunk as u64
+    ));
+
+    let (deserialized, warnings) =
+        LazyItemVec::<f32>::deserialize_lenient(bufmans, file_index, cache, 1000);
+    assert_eq!(deserialized.len(), 12);
+    assert_eq!(warnings.len(), 1);
+    assert!(matches!(
+        warnings[0],
+        DeserializeWarning::ChunkCycle { offset: chunk_offset } if chunk_offset == first_chunk
+    ));
+}
+
+#[test]
+fn test_lazy_item_vec_deserialize_read_ahead() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..100 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, _cache, bufman, cursor, temp_dir) = setup_test(root_version_id);
+
+    let offset = lazy_items
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    bufman.close_cursor(cursor).unwrap();
+    bufmans.flush_all().unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    // Reads the vec back through small regions, as a freshly opened file
+    let load = |read_ahead: usize| {
+        let bufmans = Arc::new(BufferManagerFactory::new(
+            temp_dir.as_ref().into(),
+            |root, ver: &Hash| root.join(format!("{}.index", **ver)),
+            64,
+        ));
+        let bufman = bufmans.get(root_version_id).unwrap();
+        bufman.set_read_ahead(read_ahead);
+        let cache = get_cache(bufmans.clone());
+        let deserialized: LazyItemVec<f32> = cache.load_item(file_index.clone()).unwrap();
+        assert_eq!(deserialized.len(), 100);
+        for (i, item) in deserialized.iter().enumerate() {
+            match item {
+                LazyItem::Valid { data: mut arc, .. } => {
+                    assert_eq!(*arc.get().clone().unwrap(), i as f32);
+                }
+                LazyItem::Invalid => panic!("Deserialization mismatch"),
+            }
+        }
+        bufman.read_count()
+    };
+
+    let reads = load(0);
+    let read_ahead_reads = load(4096);
+    assert!(reads > 10);
+    assert!(read_ahead_reads * 4 <= reads);
+}
+
+#[test]
+fn test_lazy_item_vec_dedup_shared_items() {
+    let root_version_id = Hash::from(0);
+    let node = Arc::new(MergedNode::new(HNSWLevel(2)));
+    let lazy_items = LazyItemVec::new();
+    for _ in 0..3 {
+        lazy_items.push(LazyItem::from_arc(root_version_id, 0, node.clone()));
+    }
+    let single_item = LazyItemVec::new();
+    single_item.push(LazyItem::from_arc(root_version_id, 0, node.clone()));
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let offset = lazy_items
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+
+    // All slots point at the same payload
+    let headerless = ChunkGeometry::new(CHUNK_SIZE as u64, 10);
+    let (geometry, first_chunk) =
+        ChunkGeometry::read(&bufman, cursor, offset as u64, headerless, 10).unwrap();
+    let item_offsets: Vec<_> = (0..3)
+        .map(|i| {
+            bufman
+                .seek_with_cursor(cursor, geometry.slot(first_chunk, i))
+                .unwrap();
+            bufman.read_u32_with_cursor(cursor).unwrap()
+        })
+        .collect();
+    assert_eq!(item_offsets, vec![item_offsets[0]; 3]);
+    bufman.close_cursor(cursor).unwrap();
+
+    // The payload is written once, as for a vector holding the item once
+    let (single_bufmans, _, single_bufman, single_cursor, _single_temp_dir) =
+        setup_test(root_version_id);
+    single_item
+        .serialize(single_bufmans, root_version_id, single_cursor)
+        .unwrap();
+    single_bufman.close_cursor(single_cursor).unwrap();
+    assert_eq!(bufman.file_size(), single_bufman.file_size());
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let deserialized: LazyItemVec<MergedNode> = cache.load_item(file_index).unwrap();
+    assert_eq!(deserialized.len(), 3);
+    for item in deserialized.iter() {
+        assert_eq!(
+            item.get_file_index().unwrap().get_offset(),
+            Some(FileOffset(item_offsets[0]))
+        );
+    }
+}
+
+#[test]
+fn test_lazy_item_vec_legacy_layout() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let item_offsets: Vec<_> = (0..7)
+        .map(|i| {
+            LazyItem::from_data(root_version_id, 0, i as f32)
+                .serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap()
+        })
+        .collect();
+
+    // Hand-craft two chunks with 8 byte slots, without version numbers
+    let mut chunk_offsets = Vec::new();
+    for chunk in item_offsets.chunks(CHUNK_SIZE) {
+        chunk_offsets.push(bufman.cursor_position(cursor).unwrap() as u32);
+        for slot in 0..CHUNK_SIZE {
+            let item_offset = chunk.get(slot).copied().unwrap_or(u32::MAX);
+            bufman.update_u32_with_cursor(cursor, item_offset).unwrap();
+            bufman
+                .update_u32_with_cursor(cursor, *root_version_id)
+                .unwrap();
+        }
+        bufman.update_u32_with_cursor(cursor, u32::MAX).unwrap();
+    }
+    bufman
+        .seek_with_cursor(cursor, chunk_offsets[0] as u64 + CHUNK_SIZE as u64 * 8)
+        .unwrap();
+    bufman
+        .update_u32_with_cursor(cursor, chunk_offsets[1])
+        .unwrap();
+    bufman.close_cursor(cursor).unwrap();
+
+    // Items take the version number of the vector they're read from
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(chunk_offsets[0]),
+        version_number: 3,
+        version_id: root_version_id,
+    };
+    let deserialized: LazyItemVec<f32> = cache.load_item(file_index).unwrap();
+
+    assert_eq!(deserialized.len(), 7);
+    for (i, item) in deserialized.iter().enumerate() {
+        assert_eq!(item.get_current_version_number(), 3);
+        match item {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
+
+#[test]
+fn test_lazy_item_vec_indexed_layout() {
+    let root_version_id = Hash::from(0);
+    let lazy_items = LazyItemVec::new();
+    for i in 0..100 {
+        lazy_items.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+
+    let offset = lazy_items
+        .serialize_indexed(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+
+    // Point every slot but the 50th past the end of the file, so reading
+    // any of them fails
+    for i in (0..100).filter(|&i| i != 50) {
+        bufman
+            .seek_with_cursor(cursor, offset as u64 + 5 + i * 10)
+            .unwrap();
+        bufman.update_u32_with_cursor(cursor, 0xFFFF_FFF0).unwrap();
+    }
+    bufman.close_cursor(cursor).unwrap();
+
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    let item = LazyItemVec::<f32>::deserialize_indexed_at(
+        bufmans.clone(),
+        file_index.clone(),
+        50,
+        cache.clone(),
+        1000,
+        &mut HashSet::new(),
+    )
+    .unwrap();
+    match item {
+        LazyItem::Valid { data: mut arc, .. } => {
+            assert_eq!(*arc.get().clone().unwrap(), 50.0);
+        }
+        LazyItem::Invalid => panic!("Deserialization mismatch"),
+    }
+
+    assert!(LazyItemVec::<f32>::deserialize_indexed_at(
+        bufmans.clone(),
+        file_index,
+        100,
+        cache.clone(),
+        1000,
+        &mut HashSet::new(),
+    )
+    .is_err());
+
+    // An offset that isn't the start of an indexed vector has no flag
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(offset + 1),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+    assert!(matches!(
+        LazyItemVec::<f32>::deserialize_indexed_at(
+            bufmans,
+            file_index,
+            0,
+            cache,
+            1000,
+            &mut HashSet::new(),
+        ),
+        Err(BufIoError::Corrupt { .. })
+    ));
+}
+
+#[test]
+fn test_option_lazy_item_serialization() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let items: [Option<LazyItem<MergedNode>>; 3] = [
+        None,
+        Some(LazyItem::Invalid),
+        Some(LazyItem::new(
+            root_version_id,
+            0,
+            MergedNode::new(HNSWLevel(2)),
+        )),
+    ];
+
+    let offsets: Vec<_> = items
+        .iter()
+        .map(|item| {
+            item.serialize(bufmans.clone(), root_version_id, cursor)
+                .unwrap()
+        })
+        .collect();
+    bufman.close_cursor(cursor).unwrap();
+    assert_eq!(offsets[0], NONE_OFFSET);
+    assert_eq!(offsets[1], u32::MAX);
+
+    let deserialized: Vec<Option<LazyItem<MergedNode>>> = offsets
+        .iter()
+        .map(|&offset| {
+            cache
+                .clone()
+                .load_item(FileIndex::Valid {
+                    offset: FileOffset(offset),
+                    version_number: 0,
+                    version_id: root_version_id,
+                })
+                .unwrap()
+        })
+        .collect();
+    assert!(deserialized[0].is_none());
+    assert!(matches!(deserialized[1], Some(LazyItem::Invalid)));
+    match &deserialized[2] {
+        Some(item @ LazyItem::Valid { .. }) => {
+            assert_eq!(item.get_data(cache.clone()).hnsw_level, HNSWLevel(2));
+        }
+        _ => panic!("Expected a valid item"),
+    }
+}
+
+#[test]
+fn test_serialize_batch() {
+    let root_version_id = Hash::from(0);
+    let (bufmans, cache, bufman, cursor, _temp_dir) = setup_test(root_version_id);
+    let file_index = |offset| FileIndex::Valid {
+        offset: FileOffset(offset),
+        version_number: 0,
+        version_id: root_version_id,
+    };
+
+    let vec = LazyItemVec::new();
+    for i in 0..7 {
+        vec.push(LazyItem::from_data(root_version_id, 0, i as f32));
+    }
+    let map = LazyItemMap::new();
+    for i in 0..4 {
+        map.insert(
+            IdentityMapKey::Int(i),
+            LazyItem::from_data(root_version_id, 0, i as f32 * 2.0),
+        );
+    }
+
+    let batch = SerializeBatch::new().with_item(&vec).with_item(&map);
+    assert_eq!(batch.len(), 2);
+    let offsets = batch
+        .serialize(bufmans.clone(), root_version_id, cursor)
+        .unwrap();
+    // Laid out one after the other, the vec starting at the cursor
+    assert_eq!(offsets.len(), 2);
+    assert_eq!(offsets[0], 0);
+    assert!(offsets[1] > offsets[0]);
+    assert_eq!(bufman.cursor_position(cursor).unwrap(), bufman.file_size());
+    bufman.close_cursor(cursor).unwrap();
+
+    let deserialized: LazyItemVec<f32> = cache.clone().load_item(file_index(offsets[0])).unwrap();
+    assert_eq!(deserialized.len(), 7);
+    for (i, item) in deserialized.iter().enumerate() {
+        match item {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+
+    let deserialized: LazyItemMap<f32> = cache.load_item(file_index(offsets[1])).unwrap();
+    assert_eq!(deserialized.len(), 4);
+    for i in 0..4 {
+        match deserialized.get(&IdentityMapKey::Int(i)).unwrap() {
+            LazyItem::Valid { data: mut arc, .. } => {
+                assert_eq!(*arc.get().clone().unwrap(), i as f32 * 2.0);
+            }
+            LazyItem::Invalid => panic!("Deserialization mismatch"),
+        }
+    }
+}
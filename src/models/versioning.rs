@@ -2,6 +2,7 @@ use crate::macros::key;
 use lmdb::{Cursor, Database, Environment, RoTransaction, Transaction, WriteFlags};
 use serde::{Deserialize, Serialize};
 use siphasher::sip::SipHasher24;
+use std::fmt;
 use std::hash::Hasher;
 use std::ops::Deref;
 use std::sync::Arc;
@@ -215,9 +216,54 @@ impl BranchInfo {
     }
 }
 
+/// Highest version number that fits in the 16 bit `version_number` of
+/// the file indices of the index files
+pub const MAX_VERSION_NUMBER: u32 = u16::MAX as u32;
+
+/// What `VersionControl::add_next_version` does once a branch reaches
+/// `MAX_VERSION_NUMBER`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VersionOverflowPolicy {
+    /// Fail with `VersionError::Overflow`, leaving the branch as is
+    #[default]
+    Error,
+    /// Keep adding versions, whose version numbers then wrap around to 0
+    /// in the index files, so their order is lost there
+    Wrap,
+}
+
+#[derive(Debug)]
+pub enum VersionError {
+    Lmdb(lmdb::Error),
+    /// The next version of the branch would be past `MAX_VERSION_NUMBER`
+    Overflow {
+        branch: String,
+    },
+}
+
+impl From<lmdb::Error> for VersionError {
+    fn from(error: lmdb::Error) -> Self {
+        Self::Lmdb(error)
+    }
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Lmdb(error) => write!(f, "LMDB error: {}", error),
+            Self::Overflow { branch } => write!(
+                f,
+                "Branch `{}` has reached the maximum version number {}",
+                branch, MAX_VERSION_NUMBER
+            ),
+        }
+    }
+}
+
 pub struct VersionControl {
     pub env: Arc<Environment>,
     pub db: Arc<Database>,
+    overflow_policy: VersionOverflowPolicy,
 }
 
 impl VersionControl {
@@ -245,11 +291,29 @@ impl VersionControl {
         txn.put(*db, &version_key, &version_bytes, WriteFlags::empty())?;
         txn.commit()?;
 
-        Ok((Self { env, db }, hash))
+        Ok((
+            Self {
+                env,
+                db,
+                overflow_policy: VersionOverflowPolicy::default(),
+            },
+            hash,
+        ))
     }
 
     pub fn from_existing(env: Arc<Environment>, db: Arc<Database>) -> Self {
-        Self { env, db }
+        Self {
+            env,
+            db,
+            overflow_policy: VersionOverflowPolicy::default(),
+        }
+    }
+
+    /// Sets what happens once a branch reaches `MAX_VERSION_NUMBER`,
+    /// failing by default
+    pub fn with_overflow_policy(mut self, overflow_policy: VersionOverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
     }
 
     pub fn generate_hash(&self, branch_name: &str, version: Version) -> lmdb::Result<Hash> {
@@ -266,7 +330,7 @@ impl VersionControl {
         Ok(hash)
     }
 
-    pub fn add_next_version(&self, branch_name: &str) -> lmdb::Result<(Hash, Version)> {
+    pub fn add_next_version(&self, branch_name: &str) -> Result<(Hash, Version), VersionError> {
         let branch_id = BranchId::new(branch_name);
         let branch_key = key!(b:branch_id);
 
@@ -274,6 +338,13 @@ impl VersionControl {
         let bytes = txn.get(*self.db, &branch_key)?;
 
         let mut branch_info: BranchInfo = BranchInfo::deserialize(bytes).unwrap();
+        if *branch_info.current_version >= MAX_VERSION_NUMBER
+            && self.overflow_policy == VersionOverflowPolicy::Error
+        {
+            return Err(VersionError::Overflow {
+                branch: branch_name.to_string(),
+            });
+        }
         let new_version = Version(*branch_info.current_version + 1);
         let version_hash = VersionHash::new(branch_id, new_version);
         let hash = version_hash.calculate_hash();
@@ -478,3 +549,43 @@ impl VersionControl {
         Ok(versions)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lmdb::DatabaseFlags;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_version_number_overflow() {
+        let dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(2)
+                .set_map_size(10485760) // 10MB
+                .open(dir.as_ref())
+                .unwrap(),
+        );
+        let db = Arc::new(env.create_db(None, DatabaseFlags::empty()).unwrap());
+        let vcs = VersionControl::new(env.clone(), db.clone()).unwrap().0;
+
+        let last = Version::from(MAX_VERSION_NUMBER - 1);
+        let hash = vcs.generate_hash("main", last).unwrap();
+        vcs.set_branch_version("main", last, hash).unwrap();
+        let (_, version) = vcs.add_next_version("main").unwrap();
+        assert_eq!(*version, MAX_VERSION_NUMBER);
+
+        // The next version would wrap the version numbers around to 0
+        assert!(matches!(
+            vcs.add_next_version("main"),
+            Err(VersionError::Overflow { .. })
+        ));
+        let branch_info = vcs.get_branch_info("main").unwrap().unwrap();
+        assert_eq!(*branch_info.current_version, MAX_VERSION_NUMBER);
+
+        let vcs = VersionControl::from_existing(env, db)
+            .with_overflow_policy(VersionOverflowPolicy::Wrap);
+        let (_, version) = vcs.add_next_version("main").unwrap();
+        assert_eq!(*version, MAX_VERSION_NUMBER + 1);
+    }
+}
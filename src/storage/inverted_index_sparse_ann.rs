@@ -1,13 +1,17 @@
 use core::array::from_fn;
 use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
 use std::path::Path;
 
 use std::sync::Arc;
 
 use crate::models::{
-    buffered_io::BufferManagerFactory,
-    cache_loader::NodeRegistry,
+    buffered_io::{BufIoError, BufferManagerFactory},
+    cache_loader::{Cacheable, NodeRegistry},
+    common::WaCustomError,
     lazy_load::{LazyItem, LazyItemArray, LazyItemVec},
+    serializer::CustomSerialize,
     types::SparseVector,
     versioning::Hash,
 };
@@ -177,6 +181,112 @@ impl InvertedIndexSparseAnn {
         Some(current_node)
     }
 
+    /// Lists the postings stored at a dimension as (vector id, weight) pairs.
+    /// Entries are returned in stored order, bucket by bucket, with weights
+    /// dequantized from the bucket index. An absent dimension has no postings.
+    pub fn posting_list(&self, dim_index: u32) -> Result<Vec<(u32, f32)>, BufIoError> {
+        let mut current_node = self.root.clone();
+        let path = calculate_path(dim_index, self.root.dim_index);
+        for child_index in path {
+            let Some(child) = current_node.lazy_children.get(child_index) else {
+                return Ok(Vec::new());
+            };
+            current_node = self.load(&child)?;
+        }
+
+        let mut postings = Vec::new();
+        for (index, lazy_item_vec) in current_node.data.iter().enumerate() {
+            let weight = index as f32 / 63.0;
+            for item in lazy_item_vec.iter() {
+                postings.push((*self.load(&item)?, weight));
+            }
+        }
+        Ok(postings)
+    }
+
+    /// Lists every document in the index with its sparse vector, in
+    /// ascending order of vector id. Vectors are reconstructed from the
+    /// postings of all dimensions, in ascending order of dimension, with
+    /// weights dequantized as in `posting_list`.
+    pub fn iter_documents(
+        &self,
+    ) -> impl Iterator<Item = Result<(u32, Vec<(u32, f32)>), BufIoError>> {
+        let (documents, error) = match self.collect_documents() {
+            Ok(documents) => (documents, None),
+            Err(err) => (BTreeMap::new(), Some(err)),
+        };
+        documents.into_iter().map(Ok).chain(error.map(Err))
+    }
+
+    fn collect_documents(&self) -> Result<BTreeMap<u32, Vec<(u32, f32)>>, BufIoError> {
+        let mut documents: BTreeMap<u32, Vec<(u32, f32)>> = BTreeMap::new();
+        let mut stack = vec![self.root.clone()];
+        while let Some(node) = stack.pop() {
+            for (index, lazy_item_vec) in node.data.iter().enumerate() {
+                let weight = index as f32 / 63.0;
+                for item in lazy_item_vec.iter() {
+                    let entries = documents.entry(*self.load(&item)?).or_default();
+                    // A dimension inserted twice for the same vector keeps
+                    // its first posting
+                    if !entries.iter().any(|&(dim, _)| dim == node.dim_index) {
+                        entries.push((node.dim_index, weight));
+                    }
+                }
+            }
+            for child_index in 0..16 {
+                if let Some(child) = node.lazy_children.get(child_index) {
+                    stack.push(self.load(&child)?);
+                }
+            }
+        }
+        for entries in documents.values_mut() {
+            entries.sort_by_key(|&(dim, _)| dim);
+        }
+        Ok(documents)
+    }
+
+    /// Removes all postings of the documents in `doc_ids` in a single pass
+    /// over the nodes of the index, rather than one pass per document.
+    /// Ids that aren't in the index are ignored.
+    pub fn delete_many(&mut self, doc_ids: &HashSet<u32>) -> Result<(), BufIoError> {
+        if doc_ids.is_empty() {
+            return Ok(());
+        }
+        let mut stack = vec![self.root.clone()];
+        while let Some(node) = stack.pop() {
+            for lazy_item_vec in node.data.iter() {
+                // Loading the ids keeps them in memory, so they are matched
+                // again below without any IO
+                let mut matched = false;
+                for item in lazy_item_vec.iter() {
+                    matched |= doc_ids.contains(&*self.load(&item)?);
+                }
+                if matched {
+                    lazy_item_vec
+                        .retain(|item| !doc_ids.contains(&*item.get_data(self.cache.clone())));
+                }
+            }
+            for child_index in 0..16 {
+                if let Some(child) = node.lazy_children.get(child_index) {
+                    stack.push(self.load(&child)?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load<T: Clone + CustomSerialize + Cacheable + 'static>(
+        &self,
+        item: &LazyItem<T>,
+    ) -> Result<Arc<T>, BufIoError> {
+        item.try_get_data(self.cache.clone()).map_err(|e| match e {
+            WaCustomError::BufIo(err) => {
+                Arc::try_unwrap(err).unwrap_or_else(|err| io::Error::other(err.to_string()).into())
+            }
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()).into(),
+        })
+    }
+
     //Fetches quantized u8 value for a dim_index and vector_Id present at respective node in index
     pub fn get(&self, dim_index: u32, vector_id: u32) -> Option<u8> {
         self.root.get(dim_index, vector_id, self.cache.clone())
@@ -205,3 +315,187 @@ impl InvertedIndexSparseAnn {
         Ok(())
     }
 }
+
+/// Assigns documents new ids from a contiguous range, e.g. so that the
+/// ids of indices being concatenated don't collide
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DocIdRemapper;
+
+impl DocIdRemapper {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Copies `index` with its documents renumbered from `base`, in
+    /// ascending order of their current id, and returns the copy along
+    /// with the map of old to new ids. Postings keep their quantized
+    /// weights and their order, so only the ids differ.
+    pub fn remap_inverted_index(
+        &self,
+        index: &InvertedIndexSparseAnn,
+        base: u32,
+    ) -> Result<(InvertedIndexSparseAnn, HashMap<u32, u32>), BufIoError> {
+        // (dimension, quantized weight, vector id) of every posting
+        let mut postings = Vec::new();
+        let mut stack = vec![index.root.clone()];
+        while let Some(node) = stack.pop() {
+            for (quantized_value, lazy_item_vec) in node.data.iter().enumerate() {
+                for item in lazy_item_vec.iter() {
+                    postings.push((node.dim_index, quantized_value, *index.load(&item)?));
+                }
+            }
+            for child_index in 0..16 {
+                if let Some(child) = node.lazy_children.get(child_index) {
+                    stack.push(index.load(&child)?);
+                }
+            }
+        }
+
+        let ids: BTreeSet<u32> = postings.iter().map(|&(_, _, id)| id).collect();
+        if ids.len() as u64 > (u32::MAX - base) as u64 + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} documents don't fit in ids from {}", ids.len(), base),
+            )
+            .into());
+        }
+        let id_map: HashMap<u32, u32> = ids
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| (id, base + i as u32))
+            .collect();
+
+        let remapped = InvertedIndexSparseAnn::new();
+        for (dim_index, quantized_value, id) in postings {
+            let path = calculate_path(dim_index, remapped.root.dim_index);
+            let node = InvertedIndexSparseAnnNode::find_or_create_node(
+                remapped.root.clone(),
+                &path,
+                remapped.cache.clone(),
+            );
+            node.data[quantized_value].push(LazyItem::new(0.into(), 0, id_map[&id]));
+        }
+        Ok((remapped, id_map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posting_list() {
+        let index = InvertedIndexSparseAnn::new();
+        index.insert(5, 1.0, 7);
+        index.insert(5, 0.5, 3);
+        index.insert(5, 1.0, 2);
+        index.insert(9, 0.25, 1);
+
+        assert_eq!(
+            index.posting_list(5).unwrap(),
+            vec![(3, 31.0 / 63.0), (7, 1.0), (2, 1.0)]
+        );
+        assert_eq!(index.posting_list(9).unwrap(), vec![(1, 15.0 / 63.0)]);
+        // Dimension 4 is an implicit node on the path to 5 and 9
+        assert_eq!(index.posting_list(4).unwrap(), Vec::new());
+        assert_eq!(index.posting_list(100).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_iter_documents() {
+        let index = InvertedIndexSparseAnn::new();
+        index
+            .add_sparse_vector(SparseVector::new(4, vec![(1, 1.0), (20, 0.5)]))
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(2, vec![(20, 0.25), (3, 1.0), (7, 0.0)]))
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(9, vec![(0, 0.5)]))
+            .unwrap();
+
+        let documents = index
+            .iter_documents()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            documents,
+            vec![
+                (2, vec![(3, 1.0), (20, 15.0 / 63.0)]),
+                (4, vec![(1, 1.0), (20, 31.0 / 63.0)]),
+                (9, vec![(0, 31.0 / 63.0)]),
+            ]
+        );
+        assert_eq!(InvertedIndexSparseAnn::new().iter_documents().count(), 0);
+    }
+
+    #[test]
+    fn test_delete_many() {
+        let mut index = InvertedIndexSparseAnn::new();
+        index
+            .add_sparse_vector(SparseVector::new(1, vec![(5, 1.0), (20, 0.5)]))
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(2, vec![(5, 0.5), (3, 1.0)]))
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(3, vec![(20, 0.25)]))
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(4, vec![(5, 1.0), (3, 0.5)]))
+            .unwrap();
+
+        index.delete_many(&HashSet::from([1, 4, 99])).unwrap();
+
+        for doc_id in [1, 4] {
+            for dim_index in [3, 5, 20] {
+                assert_eq!(index.get(dim_index, doc_id), None);
+            }
+        }
+        assert_eq!(index.posting_list(5).unwrap(), vec![(2, 31.0 / 63.0)]);
+        assert_eq!(index.posting_list(3).unwrap(), vec![(2, 1.0)]);
+        assert_eq!(index.posting_list(20).unwrap(), vec![(3, 15.0 / 63.0)]);
+        let documents = index
+            .iter_documents()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            documents,
+            vec![
+                (2, vec![(3, 1.0), (5, 31.0 / 63.0)]),
+                (3, vec![(20, 15.0 / 63.0)])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remap_inverted_index() {
+        let index = InvertedIndexSparseAnn::new();
+        index.insert(5, 1.0, 7);
+        index.insert(5, 0.5, 3);
+        index.insert(9, 0.25, 12);
+        index.insert(9, 1.0, 3);
+
+        let (remapped, id_map) = DocIdRemapper::new()
+            .remap_inverted_index(&index, 100)
+            .unwrap();
+        assert_eq!(id_map, HashMap::from([(3, 100), (7, 101), (12, 102)]));
+        assert_eq!(
+            remapped.posting_list(5).unwrap(),
+            vec![(100, 31.0 / 63.0), (101, 1.0)]
+        );
+        assert_eq!(
+            remapped.posting_list(9).unwrap(),
+            vec![(102, 15.0 / 63.0), (100, 1.0)]
+        );
+        // The original index is left as is
+        assert_eq!(
+            index.posting_list(5).unwrap(),
+            vec![(3, 31.0 / 63.0), (7, 1.0)]
+        );
+
+        assert!(DocIdRemapper::new()
+            .remap_inverted_index(&index, u32::MAX - 1)
+            .is_err());
+    }
+}
@@ -4,7 +4,7 @@ use dashmap::DashMap;
 use rayon::prelude::*;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::{path::Path, sync::RwLock};
 
 use std::sync::Arc;
@@ -26,6 +26,7 @@ use crate::models::{
 };
 
 use super::page::VersionedPagepool;
+use super::sparse_ann_query_basic::QueryStats;
 
 // Size of a page in the hash table
 pub const PAGE_SIZE: usize = 32;
@@ -296,6 +297,8 @@ pub struct InvertedIndexSparseAnnBasicTSHashmap {
     pub data_file_parts: u8,
     pub offset_counter: AtomicU32,
     pub node_size: u32,
+    // Bumped on every insert, see `generation`
+    generation: AtomicU64,
 }
 
 #[cfg(test)]
@@ -448,6 +451,18 @@ impl InvertedIndexSparseAnnNodeBasicTSHashmap {
             .search(vector_id))
     }
 
+    /// Same as `find_key_of_id`, counting the fixed sets access into `stats`
+    pub fn find_key_of_id_with_stats(
+        &self,
+        vector_id: u32,
+        cache: &InvertedIndexCache,
+        stats: &mut QueryStats,
+    ) -> Result<Option<u8>, BufIoError> {
+        Ok(unsafe { &*self.fixed_sets }
+            .try_get_data_with_stats(cache, self.dim_index, stats)?
+            .search(vector_id))
+    }
+
     /// See [`crate::models::serializer::inverted::node`] for how its calculated
     pub fn get_serialized_size(quantization_bits: u8) -> u32 {
         let qv = 1u32 << quantization_bits;
@@ -482,6 +497,7 @@ impl InvertedIndexSparseAnnBasicTSHashmap {
             data_bufmans,
             data_file_parts,
         ));
+        cache.record_version(version)?;
 
         Ok(InvertedIndexSparseAnnBasicTSHashmap {
             root: Arc::new(InvertedIndexSparseAnnNodeBasicTSHashmap::new(
@@ -495,6 +511,7 @@ impl InvertedIndexSparseAnnBasicTSHashmap {
             data_file_parts,
             offset_counter,
             node_size,
+            generation: AtomicU64::new(0),
         })
     }
 
@@ -512,6 +529,50 @@ impl InvertedIndexSparseAnnBasicTSHashmap {
         Some(current_node)
     }
 
+    /// Returns true if no value has been inserted into the index yet,
+    /// i.e. the root has neither children nor values of its own
+    pub fn is_empty(&self) -> Result<bool, BufIoError> {
+        if (0..16).any(|i| self.root.children.get(i).is_some()) {
+            return Ok(false);
+        }
+        Ok(unsafe { &*self.root.data }
+            .try_get_data(&self.cache, self.root.dim_index)?
+            .map
+            .is_empty())
+    }
+
+    /// Upper bound on the number of vectors matching `query`, without
+    /// scoring them
+    ///
+    /// Sums the posting list lengths of the query's dimensions, so a
+    /// vector present in several of them is counted once per dimension.
+    /// Dimensions that aren't loaded have their lengths read from disk,
+    /// without loading their postings.
+    pub fn estimate_candidate_count(&self, query: &[(u32, f32)]) -> Result<usize, BufIoError> {
+        let mut dims: Vec<u32> = query.iter().map(|(dim_index, _)| *dim_index).collect();
+        dims.sort_unstable();
+        dims.dedup();
+
+        let mut count = 0;
+        for dim_index in dims {
+            if let Some(node) = self.find_node(dim_index) {
+                let data = unsafe { &*node.data };
+                count += match data.get_lazy_data() {
+                    Some(data) => {
+                        let mut len = 0;
+                        data.map.for_each(|_, list| len += list.len());
+                        len
+                    }
+                    None => self.cache.count_postings(
+                        data.get_file_index().get_offset().unwrap(),
+                        (node.dim_index % self.data_file_parts as u32) as u8,
+                    )?,
+                };
+            }
+        }
+        Ok(count)
+    }
+
     //Inserts vec_id, quantized value u8 at particular node based on path
     pub fn insert(
         &self,
@@ -521,13 +582,23 @@ impl InvertedIndexSparseAnnBasicTSHashmap {
         version: Hash,
         values_upper_bound: f32,
     ) -> Result<(), BufIoError> {
+        self.cache.record_version(version)?;
         let path = calculate_path(dim_index, self.root.dim_index);
         let node = self.root.find_or_create_node(&path, version, || {
             self.offset_counter
                 .fetch_add(self.node_size, Ordering::Relaxed)
         });
         //value will be quantized while being inserted into the Node.
-        node.insert(value, vector_id, &self.cache, version, values_upper_bound)
+        node.insert(value, vector_id, &self.cache, version, values_upper_bound)?;
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Changes whenever a value is inserted into the index, so that data
+    /// derived from the index, such as cached query plans, can tell when
+    /// it's stale
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
     }
 
     /// Adds a sparse vector to the index.
@@ -601,6 +672,7 @@ impl InvertedIndexSparseAnnBasicTSHashmap {
             data_file_parts,
             offset_counter,
             node_size,
+            generation: AtomicU64::new(0),
         })
     }
 }
@@ -3,7 +3,7 @@ use std::array::from_fn;
 use std::path::Path;
 use std::sync::Arc;
 
-use crate::models::buffered_io::BufferManagerFactory;
+use crate::models::buffered_io::{BufIoError, BufferManagerFactory};
 use crate::models::cache_loader::NodeRegistry;
 use crate::models::lazy_load::IncrementalSerializableGrowableData;
 use crate::models::lazy_load::LazyItem;
@@ -12,6 +12,10 @@ use crate::models::types::SparseVector;
 use crate::models::versioning::Hash;
 use arcshift::ArcShift;
 
+use super::inverted_index_sparse_ann_basic::{
+    InvertedIndexSparseAnnBasic, InvertedIndexSparseAnnNodeBasic,
+};
+
 // TODO: Add more powers for larger jumps
 // TODO: Or switch to dynamic calculation of power of max power of 4
 const POWERS_OF_4: [u32; 8] = [1, 4, 16, 64, 256, 1024, 4096, 16384];
@@ -96,8 +100,16 @@ impl InvertedIndexNewDSNode {
         ((value * 63.0).clamp(0.0, 63.0) as u8).min(63)
     }
 
-    pub fn insert(mut node: ArcShift<InvertedIndexNewDSNode>, value: f32, vector_id: u32) {
-        let quantized_value = Self::quantize(value);
+    pub fn insert(node: ArcShift<InvertedIndexNewDSNode>, value: f32, vector_id: u32) {
+        Self::insert_quantized(node, Self::quantize(value), vector_id)
+    }
+
+    /// Inserts the vec_Id into the array at index = quantized_value
+    pub fn insert_quantized(
+        mut node: ArcShift<InvertedIndexNewDSNode>,
+        quantized_value: u8,
+        vector_id: u32,
+    ) {
         let mut data: Arc<[IncrementalSerializableGrowableData; 64]> = node.get().data.clone();
 
         if let Some(growable_data) = Arc::make_mut(&mut data).get_mut(quantized_value as usize) {
@@ -203,3 +215,145 @@ impl InvertedIndexSparseAnnNewDS {
         Ok(())
     }
 }
+
+/// Outcome of [migrate_to_new_ds]
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Number of nodes of the old index that were migrated
+    pub nodes_migrated: usize,
+    /// Number of (dimension, vec_Id) entries written to the new index
+    pub entries_migrated: usize,
+    /// Nodes that couldn't be loaded from the old index, along with
+    /// their subtrees
+    pub skipped_nodes: Vec<SkippedNode>,
+}
+
+#[derive(Debug)]
+pub struct SkippedNode {
+    pub dim_index: u32,
+    pub reason: String,
+}
+
+/// Re-encodes an index in the old [InvertedIndexSparseAnnBasic] format
+/// into the [InvertedIndexSparseAnnNewDS] format.
+///
+/// Every node of `src` is read and its vec_Ids are inserted into the
+/// node at the same dimension of `dst`, under the same quantized value,
+/// so queries on both indexes return the same results. Nodes that
+/// can't be loaded are skipped and reported instead of aborting the
+/// migration.
+pub fn migrate_to_new_ds(
+    src: &InvertedIndexSparseAnnBasic,
+    dst: &InvertedIndexSparseAnnNewDS,
+) -> Result<MigrationReport, BufIoError> {
+    let mut report = MigrationReport::default();
+    let mut stack: Vec<Arc<InvertedIndexSparseAnnNodeBasic>> =
+        vec![Arc::new(src.root.shared_get().clone())];
+
+    while let Some(node) = stack.pop() {
+        let path = calculate_path(node.dim_index, dst.root.dim_index);
+        let dst_node =
+            InvertedIndexNewDSNode::find_or_create_node(dst.root.clone(), &path, dst.cache.clone());
+
+        for (quantized_value, items) in node.data.iter().enumerate() {
+            for item in items.read().unwrap().iter() {
+                match item.try_get_data(src.cache.clone()) {
+                    Ok(vector_id) => {
+                        InvertedIndexNewDSNode::insert_quantized(
+                            dst_node.clone(),
+                            quantized_value as u8,
+                            *vector_id,
+                        );
+                        report.entries_migrated += 1;
+                    }
+                    Err(err) => report.skipped_nodes.push(SkippedNode {
+                        dim_index: node.dim_index,
+                        reason: format!("Failed to load vec_Id: {}", err),
+                    }),
+                }
+            }
+        }
+        report.nodes_migrated += 1;
+
+        for (child_index, pow_4) in POWERS_OF_4.iter().enumerate() {
+            let Some(child) = node.lazy_children.get(child_index) else {
+                continue;
+            };
+            match child.try_get_data(src.cache.clone()) {
+                Ok(child) => stack.push(child),
+                Err(err) => report.skipped_nodes.push(SkippedNode {
+                    dim_index: node.dim_index + pow_4,
+                    reason: err.to_string(),
+                }),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::storage::sparse_ann_query_basic::SparseAnnQueryBasic;
+    use crate::storage::sparse_ann_query_new_ds::SparseAnnQueryNewDS;
+
+    #[test]
+    fn test_migrate_to_new_ds() {
+        let src = InvertedIndexSparseAnnBasic::new();
+        let vectors = [
+            SparseVector::new(1, vec![(0, 0.9), (5, 0.2), (17, 0.5)]),
+            SparseVector::new(2, vec![(5, 0.7), (64, 0.1)]),
+            SparseVector::new(3, vec![(0, 0.3), (17, 0.5), (100, 1.0)]),
+            SparseVector::new(70, vec![(5, 0.4), (100, 0.6)]),
+        ];
+        for vector in vectors.iter().cloned() {
+            src.add_sparse_vector(vector).unwrap();
+        }
+
+        let dst = InvertedIndexSparseAnnNewDS::new();
+        let report = migrate_to_new_ds(&src, &dst).unwrap();
+
+        assert_eq!(report.entries_migrated, 10);
+        assert!(report.skipped_nodes.is_empty());
+        assert!(report.nodes_migrated >= 5);
+
+        // The same quantized value is found for every entry
+        for vector in &vectors {
+            for (dim_index, _) in &vector.entries {
+                let expected = src.get(*dim_index, vector.vector_id);
+                assert!(expected.is_some());
+                assert_eq!(expected, dst.get(*dim_index, vector.vector_id));
+            }
+        }
+
+        // Queries return the same documents with the same scores
+        let query_vector = SparseVector::new(0, vec![(5, 1.0), (17, 1.0), (100, 1.0)]);
+        let mut src_results: Vec<_> =
+            SparseAnnQueryBasic::new(query_vector.clone(), &HashMap::new())
+                .sequential_search(&src)
+                .into_iter()
+                .map(|result| (result.vector_id, result.similarity))
+                .collect();
+        let mut dst_results: Vec<_> = SparseAnnQueryNewDS::new(query_vector)
+            .sequential_search(&dst)
+            .into_iter()
+            .map(|result| (result.vector_id, result.similarity))
+            .collect();
+        src_results.sort();
+        dst_results.sort();
+        assert_eq!(
+            src_results,
+            vec![
+                (1, 63 * (12 + 31)),
+                (2, 63 * 44),
+                (3, 63 * (31 + 63)),
+                (70, 63 * (25 + 37))
+            ]
+        );
+        assert_eq!(src_results, dst_results);
+    }
+}
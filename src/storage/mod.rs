@@ -41,3 +41,18 @@ pub enum Storage {
         vec: Vec<f32>,
     },
 }
+
+impl Storage {
+    /// L2 norm of the vector, from the magnitude computed when it was
+    /// quantized. For `UnsignedByte`, that's the norm of the quantized
+    /// vector, for the others the norm of the original one.
+    pub fn norm(&self) -> f32 {
+        match self {
+            // Stored squared, to keep it an integer
+            Storage::UnsignedByte { mag, .. } => (*mag as f32).sqrt(),
+            Storage::SubByte { mag, .. }
+            | Storage::HalfPrecisionFP { mag, .. }
+            | Storage::FullPrecisionFP { mag, .. } => *mag,
+        }
+    }
+}
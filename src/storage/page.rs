@@ -1,6 +1,9 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, RwLock,
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
 };
 
 use crate::models::{types::FileOffset, versioning::Hash};
@@ -61,6 +64,28 @@ impl<const LEN: usize> VersionedPagepool<LEN> {
                 .as_ref()
                 .map_or(0, |next| next.len())
     }
+
+    /// Calls `f` with the ids pushed in any version
+    pub fn for_each_id(&self, f: &mut impl FnMut(u32)) {
+        for page in &self.pagepool.inner {
+            page.iter().for_each(|&id| f(id));
+        }
+        if let Some(next) = &*self.next.read().unwrap() {
+            next.for_each_id(f);
+        }
+    }
+
+    /// Calls `f` with the ids pushed in any of `versions`
+    pub fn for_each_id_in(&self, versions: &HashSet<Hash>, f: &mut impl FnMut(u32)) {
+        if versions.contains(&self.current_version) {
+            for page in &self.pagepool.inner {
+                page.iter().for_each(|&id| f(id));
+            }
+        }
+        if let Some(next) = &*self.next.read().unwrap() {
+            next.for_each_id_in(versions, f);
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq)]
@@ -6,13 +6,21 @@ use super::inverted_index_sparse_ann_basic::{
     InvertedIndexSparseAnnNodeBasicDashMap,
 };
 use crate::models::buffered_io::BufIoError;
+use crate::models::lru_cache::LRUCache;
 
 use crate::models::types::{SparseQueryVector, SparseQueryVectorDimensionType, SparseVector};
 use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
 use std::{cmp::Ordering, collections::BinaryHeap};
 
 const K: usize = 100;
 
+/// Top `(vector_id, similarity)` pairs of each query of a batch, see
+/// `SparseAnnQueryBasic::query_batch`
+pub type BatchResults = Vec<Vec<(u32, f32)>>;
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct SparseAnnResult {
     pub vector_id: u32,
@@ -23,24 +31,212 @@ impl Eq for SparseAnnResult {}
 
 impl PartialOrd for SparseAnnResult {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.similarity.partial_cmp(&self.similarity)
+        Some(self.cmp(other))
     }
 }
 
+/// Higher similarities come first, equal similarities are ordered by
+/// ascending vector id so results don't depend on traversal order
 impl Ord for SparseAnnResult {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        other
+            .similarity
+            .cmp(&self.similarity)
+            .then(self.vector_id.cmp(&other.vector_id))
+    }
+}
+
+/// Sorts scored results from the highest score to the lowest. Scores within
+/// `epsilon` of the highest score in their run are considered tied and are
+/// ordered by ascending id.
+pub fn sort_by_score_with_ties<T, I: Ord>(
+    results: &mut [T],
+    epsilon: f32,
+    key: impl Fn(&T) -> (I, f32),
+) {
+    results.sort_by(|a, b| {
+        let (a_id, a_score) = key(a);
+        let (b_id, b_score) = key(b);
+        b_score.total_cmp(&a_score).then(a_id.cmp(&b_id))
+    });
+
+    let mut start = 0;
+    while start < results.len() {
+        let top = key(&results[start]).1;
+        let tied = results[start..]
+            .iter()
+            .take_while(|result| top - key(result).1 <= epsilon)
+            .count();
+        results[start..start + tied].sort_by(|a, b| key(a).0.cmp(&key(b).0));
+        start += tied;
+    }
+}
+
+/// Counters describing the work done by a single sparse query
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Query dimensions that have a node in the index
+    pub dimensions_touched: usize,
+    /// Posting list entries iterated over
+    pub postings_scanned: usize,
+    /// Distinct vectors a similarity was computed for
+    pub candidates_scored: usize,
+    /// Node data and fixed sets loaded from disk
+    pub nodes_loaded: usize,
+    /// Node data and fixed sets that were already resident
+    pub nodes_from_cache: usize,
+}
+
+impl std::ops::AddAssign for QueryStats {
+    fn add_assign(&mut self, other: Self) {
+        self.dimensions_touched += other.dimensions_touched;
+        self.postings_scanned += other.postings_scanned;
+        self.candidates_scored += other.candidates_scored;
+        self.nodes_loaded += other.nodes_loaded;
+        self.nodes_from_cache += other.nodes_from_cache;
+    }
+}
+
+/// A query's terms as scored against the index: ordered by descending
+/// query value, each with its dimension type and boost resolved
+pub struct PreparedQuery {
+    /// Dimension, dimension type, query value and boost
+    pub terms: Vec<(u32, SparseQueryVectorDimensionType, f32, f32)>,
+    // What the terms were prepared from, to tell hash collisions and
+    // stale entries apart in `QueryVectorCache`
+    entries: Vec<(u32, f32)>,
+    boosts: Vec<(u32, f32)>,
+    generation: u64,
+}
+
+/// Bounded cache of prepared queries, so that a repeated query skips
+/// reading the posting list lengths its terms are classified by
+///
+/// Entries are keyed by the query's `(dim, value)` pairs and boosts, and
+/// are prepared again once the index has been modified since. A cache
+/// must only be used with queries against a single index.
+pub struct QueryVectorCache {
+    prepared: LRUCache<u64, Arc<PreparedQuery>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl QueryVectorCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            prepared: LRUCache::with_exact_eviction(capacity),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of queries that were served from the cache
+    pub fn hits(&self) -> usize {
+        self.hits.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Number of queries that had to be prepared
+    pub fn misses(&self) -> usize {
+        self.misses.load(atomic::Ordering::Relaxed)
+    }
+
+    fn key(entries: &[(u32, f32)], boosts: &[(u32, f32)]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for pairs in [entries, boosts] {
+            pairs.len().hash(&mut hasher);
+            for (dim_index, value) in pairs {
+                dim_index.hash(&mut hasher);
+                value.to_bits().hash(&mut hasher);
+            }
+        }
+        hasher.finish()
     }
 }
 
 pub struct SparseAnnQueryBasic {
     /// Query vector is a pair of non-zero values and its dimension
     query_vector: SparseVector,
+    /// Per-dimension multipliers applied to the score a dimension contributes
+    boosts: HashMap<u32, f32>,
+    query_cache: Option<Arc<QueryVectorCache>>,
 }
 
 impl SparseAnnQueryBasic {
-    pub fn new(query_vector: SparseVector) -> Self {
-        SparseAnnQueryBasic { query_vector }
+    /// Creates a query weighting the terms by `boosts`, dimensions without a
+    /// boost use a factor of 1.0
+    pub fn new(query_vector: SparseVector, boosts: &HashMap<u32, f32>) -> Self {
+        SparseAnnQueryBasic {
+            query_vector,
+            boosts: boosts.clone(),
+            query_cache: None,
+        }
+    }
+
+    /// Looks the prepared query up in `query_cache` before preparing it,
+    /// and stores it there afterwards
+    pub fn with_query_cache(mut self, query_cache: Arc<QueryVectorCache>) -> Self {
+        self.query_cache = Some(query_cache);
+        self
+    }
+
+    fn prepare(
+        &self,
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        stats: &mut QueryStats,
+    ) -> Result<Arc<PreparedQuery>, BufIoError> {
+        let generation = index.generation();
+        let mut boosts: Vec<(u32, f32)> = self
+            .boosts
+            .iter()
+            .map(|(dim_index, boost)| (*dim_index, *boost))
+            .collect();
+        boosts.sort_unstable_by_key(|(dim_index, _)| *dim_index);
+
+        let Some(query_cache) = &self.query_cache else {
+            return Ok(Arc::new(
+                self.prepare_uncached(index, stats, boosts, generation)?,
+            ));
+        };
+        let key = QueryVectorCache::key(&self.query_vector.entries, &boosts);
+        if let Some(prepared) = query_cache.prepared.get(&key) {
+            if prepared.generation == generation
+                && prepared.entries == self.query_vector.entries
+                && prepared.boosts == boosts
+            {
+                query_cache.hits.fetch_add(1, atomic::Ordering::Relaxed);
+                return Ok(prepared);
+            }
+        }
+        query_cache.misses.fetch_add(1, atomic::Ordering::Relaxed);
+        let prepared = Arc::new(self.prepare_uncached(index, stats, boosts, generation)?);
+        query_cache.prepared.insert(key, prepared.clone());
+        Ok(prepared)
+    }
+
+    fn prepare_uncached(
+        &self,
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        stats: &mut QueryStats,
+        boosts: Vec<(u32, f32)>,
+        generation: u64,
+    ) -> Result<PreparedQuery, BufIoError> {
+        let sparse_query_vector = self.create_sparse_query_vector(index, stats)?;
+        let mut sorted_query_dims: Vec<(u32, SparseQueryVectorDimensionType, f32)> =
+            sparse_query_vector.entries;
+        sorted_query_dims.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
+        let terms = sorted_query_dims
+            .into_iter()
+            .map(|(dim_index, dimension_type, dim_value)| {
+                let boost = self.boosts.get(&dim_index).copied().unwrap_or(1.0);
+                (dim_index, dimension_type, dim_value, boost)
+            })
+            .collect();
+        Ok(PreparedQuery {
+            terms,
+            entries: self.query_vector.entries.clone(),
+            boosts,
+            generation,
+        })
     }
 
     // Creates a sparse query vector with dimension types based on the posting list length.
@@ -48,17 +244,19 @@ impl SparseAnnQueryBasic {
     fn create_sparse_query_vector(
         &self,
         index: &InvertedIndexSparseAnnBasicTSHashmap,
+        stats: &mut QueryStats,
     ) -> Result<SparseQueryVector, BufIoError> {
         let mut posting_list_lengths: Vec<(u32, usize)> = Vec::new();
         for (dim_index, _) in &self.query_vector.entries {
             if let Some(node) = index.find_node(*dim_index) {
+                let data = unsafe { &*node.data }.try_get_data_with_stats(
+                    &index.cache,
+                    node.dim_index,
+                    stats,
+                )?;
                 let mut length = 0;
                 for key in 0..64 {
-                    length += unsafe { &*node.data }
-                        .try_get_data(&index.cache, node.dim_index)?
-                        .map
-                        .lookup(&key)
-                        .map_or(0, |p| p.len());
+                    length += data.map.lookup(&key).map_or(0, |p| p.len());
                 }
                 posting_list_lengths.push((*dim_index, length));
             }
@@ -136,13 +334,14 @@ impl SparseAnnQueryBasic {
                     32..=47 => 15,
                     _ => 0,
                 };
-                for key in start_key..=end_key {
+                // Scan from the highest values down to `end_key`
+                for key in (end_key..=start_key).rev() {
                     let arc_rwlock_vec_lazy_item = &node.shared_get().data[key as usize];
                     let p = arc_rwlock_vec_lazy_item.read().unwrap();
                     for lazy_item_u32 in p.iter() {
                         let vector_id = lazy_item_u32.get_data(index.cache.clone());
                         let dot_product = dot_products.entry(*vector_id).or_insert(0u32);
-                        *dot_product += (quantized_query_value * key) as u32;
+                        *dot_product += quantized_query_value as u32 * key as u32;
                     }
                 }
             }
@@ -164,11 +363,7 @@ impl SparseAnnQueryBasic {
 
         // Convert the heap to a vector and reverse it to get descending order
         let mut results: Vec<SparseAnnResult> = heap.into_vec();
-        results.sort_by(|a, b| {
-            b.similarity
-                .partial_cmp(&a.similarity)
-                .unwrap_or(Ordering::Equal)
-        });
+        results.sort();
         results
     }
 
@@ -182,7 +377,34 @@ impl SparseAnnQueryBasic {
         reranking_factor: usize,
         k: Option<usize>,
     ) -> Result<Vec<SparseAnnResult>, BufIoError> {
-        let sparse_query_vector = self.create_sparse_query_vector(index)?;
+        let (results, _) = self.query_with_stats(
+            index,
+            quantization_bits,
+            values_upper_bound,
+            early_terminate_threshold,
+            reranking_factor,
+            k,
+        )?;
+        Ok(results)
+    }
+
+    /// Same as `sequential_search_tshashmap`, also returning counters of
+    /// the work done by the query
+    pub fn query_with_stats(
+        &self,
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        // 4, 5, 6
+        quantization_bits: u8,
+        values_upper_bound: f32,
+        early_terminate_threshold: f32,
+        reranking_factor: usize,
+        k: Option<usize>,
+    ) -> Result<(Vec<SparseAnnResult>, QueryStats), BufIoError> {
+        let mut stats = QueryStats::default();
+        if index.is_empty()? {
+            return Ok((Vec::new(), stats));
+        }
+        let prepared = self.prepare(index, &mut stats)?;
         let mut dot_products: HashMap<u32, u32> = HashMap::new();
         // same as `0.5` quantized
         let half_quantized = 1u8 << (quantization_bits - 1);
@@ -192,17 +414,15 @@ impl SparseAnnQueryBasic {
         // same as `1` quantized
         let one_quantized = ((1u32 << quantization_bits) - 1) as u8;
 
-        let mut sorted_query_dims: Vec<(u32, SparseQueryVectorDimensionType, f32)> =
-            sparse_query_vector.entries.clone();
-        sorted_query_dims.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
-
         // Keep a list of ids that are shortlisted for cuckoo filter lookups
         let mut shortlisted_ids: HashSet<u32> = HashSet::new();
 
         // Iterate over the query vector dimensions
-        for &(dim_index, dimension_type, dim_value) in &sorted_query_dims {
+        for &(dim_index, dimension_type, dim_value, boost) in &prepared.terms {
             if let Some(node) = index.find_node(dim_index) {
+                stats.dimensions_touched += 1;
                 let quantized_query_value = node.quantize(dim_value, values_upper_bound) as u32;
+                let boosted = |score: u32| (score as f32 * boost).round() as u32;
 
                 let is_low = quantized_query_value < half_quantized as u32;
 
@@ -212,9 +432,13 @@ impl SparseAnnQueryBasic {
                         // Only read the values from the cuckoo filter tree, without
                         // going through the entire list of values for this dimension.
                         for vector_id in shortlisted_ids.iter() {
-                            if let Some(index) = node.find_key_of_id(*vector_id, &index.cache)? {
+                            if let Some(index) = node.find_key_of_id_with_stats(
+                                *vector_id,
+                                &index.cache,
+                                &mut stats,
+                            )? {
                                 let dot_product = dot_products.entry(*vector_id).or_insert(0u32);
-                                *dot_product += quantized_query_value * index as u32;
+                                *dot_product += boosted(quantized_query_value * index as u32);
                             }
                         }
                     }
@@ -232,19 +456,22 @@ impl SparseAnnQueryBasic {
                         let mut new_ids = HashSet::new();
 
                         // First iterate through the map/list
+                        let data = unsafe { &*node.data }.try_get_data_with_stats(
+                            &index.cache,
+                            node.dim_index,
+                            &mut stats,
+                        )?;
                         for key in (early_terminate_value..=one_quantized).rev() {
-                            let mut current_versioned_pagepool = unsafe { &*node.data }
-                                .try_get_data(&index.cache, node.dim_index)?
-                                .map
-                                .lookup(&key);
+                            let mut current_versioned_pagepool = data.map.lookup(&key);
                             while let Some(versioned_pagepool) = current_versioned_pagepool {
                                 for x in versioned_pagepool.pagepool.inner.iter() {
                                     for x in x.iter() {
                                         let vec_id = *x;
+                                        stats.postings_scanned += 1;
 
                                         let dot_product =
                                             dot_products.entry(vec_id).or_insert(0u32);
-                                        *dot_product += quantized_query_value * key as u32;
+                                        *dot_product += boosted(quantized_query_value * key as u32);
 
                                         new_ids.insert(vec_id);
                                     }
@@ -259,9 +486,13 @@ impl SparseAnnQueryBasic {
                             if new_ids.contains(vector_id) {
                                 continue;
                             }
-                            if let Some(index) = node.find_key_of_id(*vector_id, &index.cache)? {
+                            if let Some(index) = node.find_key_of_id_with_stats(
+                                *vector_id,
+                                &index.cache,
+                                &mut stats,
+                            )? {
                                 let dot_product = dot_products.entry(*vector_id).or_insert(0u32);
-                                *dot_product += quantized_query_value * index as u32;
+                                *dot_product += boosted(quantized_query_value * index as u32);
                             }
                         }
 
@@ -272,18 +503,21 @@ impl SparseAnnQueryBasic {
                         // Iterate through the full list of values for this dimension
                         // in inverted index, and then use these shortlisted ids to lookup
                         // when needed in the cuckoo filter tree of the other dims
+                        let data = unsafe { &*node.data }.try_get_data_with_stats(
+                            &index.cache,
+                            node.dim_index,
+                            &mut stats,
+                        )?;
                         for key in (0..=one_quantized).rev() {
-                            let mut current_versioned_pagepool = unsafe { &*node.data }
-                                .try_get_data(&index.cache, node.dim_index)?
-                                .map
-                                .lookup(&key);
+                            let mut current_versioned_pagepool = data.map.lookup(&key);
                             while let Some(versioned_pagepool) = current_versioned_pagepool {
                                 for x in versioned_pagepool.pagepool.inner.iter() {
                                     for x in x.iter() {
                                         let vec_id = *x;
+                                        stats.postings_scanned += 1;
                                         let dot_product =
                                             dot_products.entry(vec_id).or_insert(0u32);
-                                        *dot_product += quantized_query_value * key as u32;
+                                        *dot_product += boosted(quantized_query_value * key as u32);
 
                                         // Shortlist vector id for future cuckoo filter lookups
                                         shortlisted_ids.insert(vec_id);
@@ -298,6 +532,8 @@ impl SparseAnnQueryBasic {
             }
         }
 
+        stats.candidates_scored = dot_products.len();
+
         // Create a min-heap to keep track of the top K results
         let mut heap =
             BinaryHeap::with_capacity(k.map_or(dot_products.len(), |k| k * reranking_factor) + 1);
@@ -317,10 +553,195 @@ impl SparseAnnQueryBasic {
 
         // Convert the heap to a vector and reverse it to get descending order
         let mut results: Vec<SparseAnnResult> = heap.into_vec();
-        results.sort_by(|a, b| b.similarity.cmp(&a.similarity));
+        results.sort();
+        Ok((results, stats))
+    }
+
+    /// Runs several queries against the same index, returning the top `k`
+    /// `(vector_id, similarity)` pairs of each, in the order of `queries`
+    pub fn query_batch(
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        queries: &[Vec<(u32, f32)>],
+        // 4, 5, 6
+        quantization_bits: u8,
+        values_upper_bound: f32,
+        early_terminate_threshold: f32,
+        reranking_factor: usize,
+        k: usize,
+    ) -> Result<BatchResults, BufIoError> {
+        let (results, _) = Self::query_batch_with_stats(
+            index,
+            queries,
+            quantization_bits,
+            values_upper_bound,
+            early_terminate_threshold,
+            reranking_factor,
+            k,
+        )?;
         Ok(results)
     }
 
+    /// Same as `query_batch`, also returning the counters of the work
+    /// done by all the queries together
+    ///
+    /// The node data of every distinct dimension in the batch is resolved
+    /// through the index's cache upfront, once, so that queries sharing
+    /// dimensions don't load the same posting lists repeatedly.
+    pub fn query_batch_with_stats(
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        queries: &[Vec<(u32, f32)>],
+        // 4, 5, 6
+        quantization_bits: u8,
+        values_upper_bound: f32,
+        early_terminate_threshold: f32,
+        reranking_factor: usize,
+        k: usize,
+    ) -> Result<(BatchResults, QueryStats), BufIoError> {
+        let mut stats = QueryStats::default();
+        if index.is_empty()? {
+            return Ok((vec![Vec::new(); queries.len()], stats));
+        }
+
+        let mut dims: Vec<u32> = queries
+            .iter()
+            .flat_map(|query| query.iter().map(|(dim_index, _)| *dim_index))
+            .collect();
+        dims.sort_unstable();
+        dims.dedup();
+        for dim_index in dims {
+            if let Some(node) = index.find_node(dim_index) {
+                unsafe { &*node.data }.try_get_data_with_stats(
+                    &index.cache,
+                    node.dim_index,
+                    &mut stats,
+                )?;
+            }
+        }
+
+        let mut batch_results = Vec::with_capacity(queries.len());
+        for query in queries {
+            let (results, query_stats) =
+                Self::new(SparseVector::new(0, query.clone()), &HashMap::new()).query_with_stats(
+                    index,
+                    quantization_bits,
+                    values_upper_bound,
+                    early_terminate_threshold,
+                    reranking_factor,
+                    Some(k),
+                )?;
+            stats += query_stats;
+            batch_results.push(
+                results
+                    .into_iter()
+                    .take(k)
+                    .map(|result| (result.vector_id, result.similarity as f32))
+                    .collect(),
+            );
+        }
+        Ok((batch_results, stats))
+    }
+
+    /// Returns the top `k` `(vector_id, similarity)` pairs among the
+    /// vectors that have at least `min_terms` of the query's dimensions,
+    /// whatever their score
+    ///
+    /// Unlike `query_with_stats`, the whole posting list of every query
+    /// dimension is walked, so that the matched dimensions are counted
+    /// exactly. A dimension repeated in the query counts once.
+    pub fn query_min_match(
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        query: &[(u32, f32)],
+        min_terms: usize,
+        // 4, 5, 6
+        quantization_bits: u8,
+        values_upper_bound: f32,
+        k: usize,
+    ) -> Result<Vec<(u32, f32)>, BufIoError> {
+        if index.is_empty()? {
+            return Ok(Vec::new());
+        }
+        // same as `1` quantized
+        let one_quantized = ((1u32 << quantization_bits) - 1) as u8;
+
+        // vector id -> (dot product, matched dimensions, last dimension
+        // matched), the latter so that vectors listed more than once in a
+        // dimension are counted once
+        let mut matches: HashMap<u32, (u32, usize, u32)> = HashMap::new();
+        let mut seen_dims = HashSet::new();
+        for &(dim_index, dim_value) in query {
+            if !seen_dims.insert(dim_index) {
+                continue;
+            }
+            let Some(node) = index.find_node(dim_index) else {
+                continue;
+            };
+            let quantized_query_value = node.quantize(dim_value, values_upper_bound) as u32;
+            let data = unsafe { &*node.data }.try_get_data(&index.cache, node.dim_index)?;
+            for key in (0..=one_quantized).rev() {
+                let mut current_versioned_pagepool = data.map.lookup(&key);
+                while let Some(versioned_pagepool) = current_versioned_pagepool {
+                    for x in versioned_pagepool.pagepool.inner.iter() {
+                        for &vec_id in x.iter() {
+                            let (dot_product, matched, last_dim) =
+                                matches.entry(vec_id).or_insert((0, 0, u32::MAX));
+                            if *last_dim == dim_index {
+                                continue;
+                            }
+                            *dot_product += quantized_query_value * key as u32;
+                            *matched += 1;
+                            *last_dim = dim_index;
+                        }
+                    }
+                    current_versioned_pagepool = versioned_pagepool.next.read().unwrap().clone();
+                }
+            }
+        }
+
+        let mut results: Vec<_> = matches
+            .into_iter()
+            .filter(|(_, (_, matched, _))| *matched >= min_terms)
+            .map(|(vector_id, (similarity, _, _))| SparseAnnResult {
+                vector_id,
+                similarity,
+            })
+            .collect();
+        results.sort();
+        Ok(results
+            .into_iter()
+            .take(k)
+            .map(|result| (result.vector_id, result.similarity as f32))
+            .collect())
+    }
+
+    /// Breaks the score of `doc_id` down into what each query dimension
+    /// contributed to it, for relevance debugging
+    ///
+    /// The contributions are recomputed from the document's quantized
+    /// values, boosts included, in the units of the similarities returned
+    /// by `query_with_stats`. Dimensions the document has no value in are
+    /// left out.
+    pub fn query_explain(
+        &self,
+        index: &InvertedIndexSparseAnnBasicTSHashmap,
+        values_upper_bound: f32,
+        doc_id: u32,
+    ) -> Result<Vec<(u32, f32)>, BufIoError> {
+        let mut contributions = Vec::new();
+        for &(dim_index, dim_value) in &self.query_vector.entries {
+            let Some(node) = index.find_node(dim_index) else {
+                continue;
+            };
+            let Some(key) = node.find_key_of_id(doc_id, &index.cache)? else {
+                continue;
+            };
+            let quantized_query_value = node.quantize(dim_value, values_upper_bound) as u32;
+            let boost = self.boosts.get(&dim_index).copied().unwrap_or(1.0);
+            let score = quantized_query_value * key as u32;
+            contributions.push((dim_index, (score as f32 * boost).round()));
+        }
+        Ok(contributions)
+    }
+
     pub fn sequential_search_dashmap(
         &self,
         index: &InvertedIndexSparseAnnBasicDashMap,
@@ -370,11 +791,491 @@ impl SparseAnnQueryBasic {
 
         // Convert the heap to a vector and reverse it to get descending order
         let mut results: Vec<SparseAnnResult> = heap.into_vec();
-        results.sort_by(|a, b| {
-            b.similarity
-                .partial_cmp(&a.similarity)
-                .unwrap_or(Ordering::Equal)
-        });
+        results.sort();
         results
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_query_with_stats() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        // Quantized to 56 and 50, 44, and 12 and 37 respectively
+        for vector in [
+            SparseVector::new(1, vec![(1, 0.9), (2, 0.8)]),
+            SparseVector::new(2, vec![(1, 0.7)]),
+            SparseVector::new(3, vec![(1, 0.2), (2, 0.6)]),
+        ] {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+
+        // Both dimensions are common, so only the keys above the early
+        // termination value (32..=63) are scanned: vectors 1 and 2 on
+        // dimension 1, then vectors 1 and 3 on dimension 2, followed by
+        // a fixed sets lookup of vector 2 on dimension 2
+        let query = SparseAnnQueryBasic::new(
+            SparseVector::new(0, vec![(1, 1.0), (2, 1.0), (7, 1.0)]),
+            &HashMap::new(),
+        );
+        let (results, stats) = query
+            .query_with_stats(&index, 6, 1.0, 0.5, 1, None)
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            QueryStats {
+                dimensions_touched: 2,
+                postings_scanned: 4,
+                candidates_scored: 3,
+                nodes_loaded: 0,
+                // The node data of both dimensions is read once to
+                // classify them and once to scan them, plus the fixed
+                // sets lookup
+                nodes_from_cache: 5,
+            }
+        );
+        let mut ids: Vec<_> = results.iter().map(|result| result.vector_id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(results[0].vector_id, 1);
+    }
+
+    #[test]
+    fn test_query_with_stats_nodes_loaded() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        for vector in [
+            SparseVector::new(1, vec![(1, 0.9), (2, 0.8)]),
+            SparseVector::new(2, vec![(1, 0.7)]),
+            SparseVector::new(3, vec![(1, 0.2), (2, 0.6)]),
+        ] {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+        index.serialize().unwrap();
+        index.cache.dim_bufman.flush().unwrap();
+        index.cache.data_bufmans.flush_all().unwrap();
+
+        // Nothing of dimensions 1 and 2 is resident before the query
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::deserialize(dir.as_ref().into(), 6, 8).unwrap();
+        for dim_index in [1, 2] {
+            let node = index.find_node(dim_index).unwrap();
+            index
+                .cache
+                .unload(unsafe { &*node.data }, unsafe { &*node.fixed_sets });
+        }
+        let query = SparseAnnQueryBasic::new(
+            SparseVector::new(0, vec![(1, 1.0), (2, 1.0), (7, 1.0)]),
+            &HashMap::new(),
+        );
+
+        // The node data of both dimensions and the fixed sets of dimension
+        // 2 are loaded on first use, the data is read again from the cache
+        // to scan the dimensions
+        let (results, stats) = query
+            .query_with_stats(&index, 6, 1.0, 0.5, 1, None)
+            .unwrap();
+        assert_eq!(
+            stats,
+            QueryStats {
+                dimensions_touched: 2,
+                postings_scanned: 4,
+                candidates_scored: 3,
+                nodes_loaded: 3,
+                nodes_from_cache: 2,
+            }
+        );
+        let ids: Vec<_> = results.iter().map(|result| result.vector_id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // Everything is resident for the second run
+        let (_, stats) = query
+            .query_with_stats(&index, 6, 1.0, 0.5, 1, None)
+            .unwrap();
+        assert_eq!(stats.nodes_loaded, 0);
+        assert_eq!(stats.nodes_from_cache, 5);
+    }
+
+    #[test]
+    fn test_query_boosts() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        // Quantized to 56 and 37
+        index
+            .add_sparse_vector(SparseVector::new(1, vec![(1, 0.9)]), version, 1.0)
+            .unwrap();
+        index
+            .add_sparse_vector(SparseVector::new(2, vec![(2, 0.6)]), version, 1.0)
+            .unwrap();
+        let query_vector = SparseVector::new(0, vec![(1, 1.0), (2, 1.0)]);
+        let search = |boosts: HashMap<u32, f32>| {
+            SparseAnnQueryBasic::new(query_vector.clone(), &boosts)
+                .sequential_search_tshashmap(&index, 6, 1.0, 0.5, 1, None)
+                .unwrap()
+        };
+
+        let baseline = search(HashMap::new());
+        assert_eq!(
+            baseline,
+            vec![
+                SparseAnnResult {
+                    vector_id: 1,
+                    similarity: 63 * 56,
+                },
+                SparseAnnResult {
+                    vector_id: 2,
+                    similarity: 63 * 37,
+                },
+            ]
+        );
+
+        // Dimension 1 isn't boosted and keeps its score
+        let boosted = search(HashMap::from([(2, 2.0)]));
+        assert_eq!(
+            boosted,
+            vec![
+                SparseAnnResult {
+                    vector_id: 2,
+                    similarity: 63 * 37 * 2,
+                },
+                SparseAnnResult {
+                    vector_id: 1,
+                    similarity: 63 * 56,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_vector_cache() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        for vector in [
+            SparseVector::new(1, vec![(1, 0.9), (2, 0.8)]),
+            SparseVector::new(2, vec![(1, 0.7)]),
+            SparseVector::new(3, vec![(1, 0.2), (2, 0.6)]),
+        ] {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+        let query_cache = Arc::new(QueryVectorCache::new(4));
+        let query_vector = SparseVector::new(0, vec![(1, 1.0), (2, 1.0)]);
+        let search = |boosts: HashMap<u32, f32>, index: &InvertedIndexSparseAnnBasicTSHashmap| {
+            SparseAnnQueryBasic::new(query_vector.clone(), &boosts)
+                .with_query_cache(query_cache.clone())
+                .query_with_stats(index, 6, 1.0, 0.5, 1, None)
+                .unwrap()
+        };
+
+        let (first, first_stats) = search(HashMap::new(), &index);
+        assert_eq!((query_cache.hits(), query_cache.misses()), (0, 1));
+        // The second time the node data isn't read to classify the two
+        // dimensions
+        let (second, second_stats) = search(HashMap::new(), &index);
+        assert_eq!((query_cache.hits(), query_cache.misses()), (1, 1));
+        assert_eq!(first, second);
+        assert_eq!(
+            second_stats.nodes_from_cache,
+            first_stats.nodes_from_cache - 2
+        );
+
+        // Identical queries with different boosts are prepared separately
+        let (boosted, _) = search(HashMap::from([(2, 2.0)]), &index);
+        assert_eq!((query_cache.hits(), query_cache.misses()), (1, 2));
+        assert_ne!(boosted, first);
+        search(HashMap::from([(2, 2.0)]), &index);
+        assert_eq!((query_cache.hits(), query_cache.misses()), (2, 2));
+
+        // Modifying the index makes the prepared queries stale
+        index
+            .add_sparse_vector(SparseVector::new(4, vec![(2, 0.9)]), version, 1.0)
+            .unwrap();
+        let (results, _) = search(HashMap::new(), &index);
+        assert_eq!((query_cache.hits(), query_cache.misses()), (2, 3));
+        assert!(results.iter().any(|result| result.vector_id == 4));
+    }
+
+    #[test]
+    fn test_query_empty_index() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        assert!(index.is_empty().unwrap());
+        let query_vector = SparseVector::new(0, vec![(0, 1.0), (1, 0.5)]);
+
+        let (results, stats) = SparseAnnQueryBasic::new(query_vector, &HashMap::new())
+            .query_with_stats(&index, 6, 1.0, 0.5, 1, Some(10))
+            .unwrap();
+        assert!(results.is_empty());
+        assert_eq!(stats, QueryStats::default());
+
+        index
+            .add_sparse_vector(SparseVector::new(1, vec![(1, 0.9)]), version, 1.0)
+            .unwrap();
+        assert!(!index.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_query_ties_ordered_by_vector_id() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        for vector_id in [9, 3, 7, 5] {
+            index
+                .add_sparse_vector(SparseVector::new(vector_id, vec![(1, 0.5)]), version, 1.0)
+                .unwrap();
+        }
+        let query = SparseAnnQueryBasic::new(SparseVector::new(0, vec![(1, 1.0)]), &HashMap::new());
+
+        let ids = |k| {
+            query
+                .sequential_search_tshashmap(&index, 6, 1.0, 0.5, 1, k)
+                .unwrap()
+                .into_iter()
+                .map(|result| result.vector_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(None), vec![3, 5, 7, 9]);
+        // The heap evicts the higher vector ids among tied scores
+        assert_eq!(ids(Some(2)), vec![3, 5]);
+    }
+
+    #[test]
+    fn test_query_batch_shares_nodes() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        for vector in [
+            SparseVector::new(1, vec![(1, 0.9), (2, 0.8)]),
+            SparseVector::new(2, vec![(1, 0.7), (3, 0.4)]),
+            SparseVector::new(3, vec![(2, 0.6), (3, 0.9)]),
+        ] {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+        index.serialize().unwrap();
+        index.cache.dim_bufman.flush().unwrap();
+        index.cache.data_bufmans.flush_all().unwrap();
+        let load_index = || {
+            InvertedIndexSparseAnnBasicTSHashmap::deserialize(dir.as_ref().into(), 6, 8).unwrap()
+        };
+
+        // Nothing is loaded until queried
+        let cold_index = || {
+            let index = load_index();
+            for dim_index in 0..=3 {
+                let node = index.find_node(dim_index).unwrap();
+                index
+                    .cache
+                    .unload(unsafe { &*node.data }, unsafe { &*node.fixed_sets });
+            }
+            index
+        };
+
+        // Both queries share dimension 1
+        let queries = vec![vec![(1, 1.0), (2, 0.5)], vec![(1, 0.5), (3, 1.0)]];
+        let mut baselines = Vec::new();
+        let mut query_loads = 0;
+        for query in &queries {
+            let index = cold_index();
+            let (results, stats) =
+                SparseAnnQueryBasic::new(SparseVector::new(0, query.clone()), &HashMap::new())
+                    .query_with_stats(&index, 6, 1.0, 0.5, 1, Some(10))
+                    .unwrap();
+            query_loads += stats.nodes_loaded;
+            baselines.push(
+                results
+                    .into_iter()
+                    .map(|result| (result.vector_id, result.similarity as f32))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // Dimension 1 is loaded once for the whole batch
+        let index = cold_index();
+        let (results, stats) =
+            SparseAnnQueryBasic::query_batch_with_stats(&index, &queries, 6, 1.0, 0.5, 1, 10)
+                .unwrap();
+        assert_eq!(results, baselines);
+        assert!(stats.nodes_loaded > 0);
+        assert!(stats.nodes_loaded < query_loads);
+
+        // Once loaded, the nodes serve the next batches
+        let (results, stats) =
+            SparseAnnQueryBasic::query_batch_with_stats(&index, &queries, 6, 1.0, 0.5, 1, 1)
+                .unwrap();
+        assert_eq!(results, vec![vec![baselines[0][0]], vec![baselines[1][0]]]);
+        assert_eq!(stats.nodes_loaded, 0);
+    }
+
+    #[test]
+    fn test_query_min_match() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        // Vector 1 has one of the query's dimensions, 2 two of them and 3
+        // all three, vector 1 scores the highest nonetheless
+        let vectors = [
+            SparseVector::new(1, vec![(1, 1.0), (7, 0.5)]),
+            SparseVector::new(2, vec![(2, 0.2), (3, 0.2)]),
+            SparseVector::new(3, vec![(1, 0.1), (2, 0.1), (3, 0.1)]),
+            SparseVector::new(4, vec![(8, 1.0)]),
+        ];
+        for vector in vectors {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+        let query = [(1, 1.0), (2, 1.0), (3, 1.0), (2, 1.0)];
+        let ids = |min_terms, k| -> Vec<u32> {
+            SparseAnnQueryBasic::query_min_match(&index, &query, min_terms, 6, 1.0, k)
+                .unwrap()
+                .into_iter()
+                .map(|(vector_id, _)| vector_id)
+                .collect()
+        };
+
+        assert_eq!(ids(1, 10), vec![1, 2, 3]);
+        assert_eq!(ids(2, 10), vec![2, 3]);
+        assert_eq!(ids(3, 10), vec![3]);
+        assert!(ids(4, 10).is_empty());
+        assert_eq!(ids(1, 1), vec![1]);
+    }
+
+    #[test]
+    fn test_query_explain() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        // Both dimensions have as many values, so both are rare and fully
+        // scanned by the query
+        for vector in [
+            SparseVector::new(1, vec![(1, 0.9), (2, 0.6)]),
+            SparseVector::new(2, vec![(1, 0.3), (2, 0.2)]),
+        ] {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+        let query = SparseAnnQueryBasic::new(
+            SparseVector::new(0, vec![(1, 1.0), (2, 1.0)]),
+            &HashMap::from([(2, 2.0)]),
+        );
+        let results = query
+            .sequential_search_tshashmap(&index, 6, 1.0, 0.5, 1, None)
+            .unwrap();
+        assert_eq!(results.len(), 2);
+
+        for result in results {
+            let contributions = query.query_explain(&index, 1.0, result.vector_id).unwrap();
+            let dims: Vec<_> = contributions.iter().map(|(dim, _)| *dim).collect();
+            assert_eq!(dims, vec![1, 2]);
+            let total: f32 = contributions.iter().map(|(_, score)| score).sum();
+            assert_eq!(total, result.similarity as f32);
+        }
+        // Quantized to 56 and 37, the latter boosted
+        assert_eq!(
+            query.query_explain(&index, 1.0, 1).unwrap(),
+            vec![(1, (63 * 56) as f32), (2, (63 * 37 * 2) as f32)]
+        );
+        assert!(query.query_explain(&index, 1.0, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_estimate_candidate_count() {
+        let dir = tempdir().unwrap();
+        let version = 0.into();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::new(dir.as_ref().into(), 6, version, 8).unwrap();
+        let vectors = [
+            SparseVector::new(1, vec![(1, 0.9), (2, 0.8)]),
+            SparseVector::new(2, vec![(1, 0.1)]),
+            SparseVector::new(3, vec![(2, 0.6)]),
+            SparseVector::new(4, vec![(3, 0.3)]),
+        ];
+        for vector in vectors.clone() {
+            index.add_sparse_vector(vector, version, 1.0).unwrap();
+        }
+        let matched = |query: &[(u32, f32)]| {
+            vectors
+                .iter()
+                .filter(|vector| {
+                    vector
+                        .entries
+                        .iter()
+                        .any(|(dim, _)| query.iter().any(|(query_dim, _)| query_dim == dim))
+                })
+                .count()
+        };
+
+        // Vector 1 is in both dimensions and counted twice
+        let query = [(1, 1.0), (2, 0.5)];
+        assert_eq!(index.estimate_candidate_count(&query).unwrap(), 4);
+        assert_eq!(matched(&query), 3);
+
+        // No vector is shared between dimensions 1 and 3, and missing
+        // dimensions have no postings
+        let query = [(1, 1.0), (3, 0.2), (9, 1.0)];
+        assert_eq!(index.estimate_candidate_count(&query).unwrap(), 3);
+        assert_eq!(matched(&query), 3);
+
+        assert_eq!(index.estimate_candidate_count(&[]).unwrap(), 0);
+
+        // Unloaded dimensions are counted from disk and stay unloaded
+        index.serialize().unwrap();
+        index.cache.dim_bufman.flush().unwrap();
+        index.cache.data_bufmans.flush_all().unwrap();
+        let index =
+            InvertedIndexSparseAnnBasicTSHashmap::deserialize(dir.as_ref().into(), 6, 8).unwrap();
+        for dim_index in 1..=3 {
+            let node = index.find_node(dim_index).unwrap();
+            index
+                .cache
+                .unload(unsafe { &*node.data }, unsafe { &*node.fixed_sets });
+        }
+        assert_eq!(
+            index
+                .estimate_candidate_count(&[(1, 1.0), (2, 0.5)])
+                .unwrap(),
+            4
+        );
+        assert_eq!(
+            index
+                .estimate_candidate_count(&[(1, 1.0), (3, 0.2), (9, 1.0)])
+                .unwrap(),
+            3
+        );
+        for dim_index in 1..=3 {
+            let node = index.find_node(dim_index).unwrap();
+            assert!(unsafe { &*node.data }.get_lazy_data().is_none());
+        }
+    }
+
+    #[test]
+    fn test_sort_by_score_with_ties() {
+        let mut results = vec![(4, 0.5), (8, 0.9), (2, 0.5 + 1e-7), (6, 0.9), (1, 0.2)];
+        sort_by_score_with_ties(&mut results, 1e-6, |&(id, score)| (id, score));
+        assert_eq!(
+            results,
+            vec![(6, 0.9), (8, 0.9), (2, 0.5 + 1e-7), (4, 0.5), (1, 0.2)]
+        );
+
+        // Without an epsilon only exactly equal scores are tied
+        let mut results = vec![(4, 0.5), (2, 0.5 + 1e-7), (3, 0.5)];
+        sort_by_score_with_ties(&mut results, 0.0, |&(id, score)| (id, score));
+        assert_eq!(results, vec![(2, 0.5 + 1e-7), (3, 0.5), (4, 0.5)]);
+    }
+}
@@ -10,8 +10,8 @@ const K: usize = 5;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SparseAnnResult {
-    vector_id: u32,
-    similarity: u32,
+    pub vector_id: u32,
+    pub similarity: u32,
 }
 
 impl Eq for SparseAnnResult {}
@@ -55,16 +55,22 @@ impl SparseAnnQueryNewDS {
                     32..=47 => 15,
                     _ => 0,
                 };
-                for key in start_key..=end_key {
+                // Scan from the highest values down to `end_key`
+                for key in (end_key..=start_key).rev() {
                     let growable_data = &node.shared_get().data[key as usize];
 
                     growable_data.items.iter().for_each(|x| {
                         let res = x.get_data(index.cache.clone());
                         let vector_data = (*res).clone().get().clone();
-                        vector_data.data.iter().for_each(|vec_id| {
-                            let dot_product = dot_products.entry(*vec_id).or_insert(0u32);
-                            *dot_product += (quantized_query_value * key) as u32;
-                        });
+                        // Empty slots hold u32::MAX
+                        vector_data
+                            .data
+                            .iter()
+                            .filter(|vec_id| **vec_id != u32::MAX)
+                            .for_each(|vec_id| {
+                                let dot_product = dot_products.entry(*vec_id).or_insert(0u32);
+                                *dot_product += quantized_query_value as u32 * key as u32;
+                            });
                     });
                 }
             }
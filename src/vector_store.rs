@@ -120,6 +120,10 @@ pub fn ann_search(
     cur_level: HNSWLevel,
     hnsw_params: &HNSWHyperParams,
 ) -> Result<Vec<(SharedNode, MetricResult)>, WaCustomError> {
+    // No root yet, the index is empty
+    if cur_entry.is_null() {
+        return Ok(Vec::new());
+    }
     let fvec = vector_emb.quantized_vec.clone();
     let mut skipm = PerformantFixedSet::new(if cur_level.0 == 0 {
         hnsw_params.level_0_neighbors_count
@@ -1470,3 +1474,100 @@ fn traverse_find_nearest(
 
 //     Ok(())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::cache_loader::DenseIndexCache;
+    use crate::models::versioning::VersionControl;
+    use arcshift::ArcShift;
+    use lmdb::{DatabaseFlags, Environment};
+    use std::fs::OpenOptions;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ann_search_empty_index() {
+        let dir = tempdir().unwrap();
+        let env = Arc::new(
+            Environment::new()
+                .set_max_dbs(2)
+                .set_map_size(10485760) // 10MB
+                .open(dir.as_ref())
+                .unwrap(),
+        );
+        let lmdb = MetaDb::from_env(env.clone(), "test").unwrap();
+        let vcs_db = Arc::new(env.create_db(None, DatabaseFlags::empty()).unwrap());
+        let (vcs, hash) = VersionControl::new(env, vcs_db).unwrap();
+        let prop_file = Arc::new(RwLock::new(
+            OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(dir.as_ref().join("prop.data"))
+                .unwrap(),
+        ));
+        let config: Config = toml::from_str(include_str!("../config.toml")).unwrap();
+        let hnsw_params = HNSWHyperParams::default_from_config(&config);
+        let bufmans = |path_function: fn(&Path, &Hash) -> PathBuf| {
+            Arc::new(BufferManagerFactory::new(
+                dir.as_ref().into(),
+                path_function,
+                8192,
+            ))
+        };
+        let index_manager = bufmans(|root, ver| root.join(format!("{}.index", **ver)));
+        let level_0_index_manager = bufmans(|root, ver| root.join(format!("{}_0.index", **ver)));
+        let cache = Arc::new(DenseIndexCache::new(
+            index_manager.clone(),
+            level_0_index_manager.clone(),
+            prop_file.clone(),
+        ));
+        // No root node was created yet
+        let dense_index = Arc::new(DenseIndex::new(
+            "test".to_string(),
+            ptr::null_mut(),
+            Arc::new(Vec::new()),
+            4,
+            prop_file,
+            lmdb,
+            ArcShift::new(hash),
+            ArcShift::new(QuantizationMetric::Scalar),
+            ArcShift::new(DistanceMetric::Cosine),
+            ArcShift::new(StorageType::UnsignedByte),
+            Arc::new(vcs),
+            hnsw_params.clone(),
+            cache,
+            index_manager,
+            level_0_index_manager,
+            bufmans(|root, ver| root.join(format!("{}.vec_raw", **ver))),
+            (-1.0, 1.0),
+            0,
+            true,
+        ));
+        assert!(dense_index.cache.is_empty().unwrap());
+
+        let query = [0.1, 0.2, 0.3, 0.4];
+        let vector_emb = QuantizedVectorEmbedding {
+            quantized_vec: Arc::new(
+                dense_index
+                    .quantization_metric
+                    .clone()
+                    .get()
+                    .quantize(&query, StorageType::UnsignedByte, (-1.0, 1.0))
+                    .unwrap(),
+            ),
+            hash_vec: VectorId(u64::MAX - 1),
+        };
+        let results = ann_search(
+            &config,
+            dense_index.clone(),
+            vector_emb,
+            dense_index.get_root_vec(),
+            HNSWLevel(hnsw_params.num_layers),
+            &hnsw_params,
+        )
+        .unwrap();
+        assert!(results.is_empty());
+    }
+}